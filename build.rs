@@ -1,4 +1,14 @@
 fn main() {
+    // `RUSTFLAGS="--cfg loom"` runs the concurrency models in the tests
+    println!("cargo:rustc-check-cfg=cfg(loom)");
+
+    // Memory layout of the STM32F429 for the examples
+    if std::env::var_os("CARGO_FEATURE_CORTEX_M_RT").is_some() {
+        let dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        println!("cargo:rustc-link-search={}/examples", dir);
+        println!("cargo:rerun-if-changed=examples/memory.x");
+    }
+
     let profile = std::env::var("PROFILE").unwrap();
     let target = std::env::var("TARGET").unwrap();
 
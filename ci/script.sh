@@ -4,5 +4,16 @@ set -euxo pipefail
 
 cargo check --features "stm32f429xx fs"
 cargo check --features "stm32f429xx hs"
+cargo check --features "stm32f429xx fs hs"
+# The std APIs must be available in the rust-version of Cargo.toml
+cargo clippy --features "stm32f429xx fs hs" -- -A clippy::all -D clippy::incompatible_msrv
+cargo check --features "stm32f429xx hs fuzzing"
+cargo check --no-default-features --features "stm32f429xx fs"
+cargo test --no-default-features --features "stm32f429xx fs usb-device"
+cargo check --features "stm32f429xx fs defmt"
 cargo check --features "stm32f401xx"
 cargo check --features "gd32vf103xx"
+cargo build --release --target thumbv7em-none-eabihf --example cdc_throughput --features "stm32f429xx fs cortex-m-rt"
+cargo build --release --target thumbv7em-none-eabihf --example hs_dma_throughput --features "stm32f429xx hs cortex-m-rt"
+cargo build --release --target thumbv7em-none-eabihf --example low_power_suspend --features "stm32f429xx fs cortex-m-rt"
+RUSTFLAGS="--cfg loom" cargo test --release --features "stm32f429xx fs trace" loom_tests
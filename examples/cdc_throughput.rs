@@ -0,0 +1,99 @@
+//! CDC-ACM throughput test for the STM32F429 OTG_FS peripheral (PA11/PA12, 8 MHz HSE).
+//!
+//! The device enumerates as a serial port and streams a test pattern to the host as fast as the
+//! bus allows, using an `EndpointWriter`. Data sent by the host is read and dropped. This is the
+//! full-speed bulk benchmark of the driver, which aims at 1 MB/s or more in each direction (full
+//! speed carries at most 1.216 MB/s of bulk payload). Measure on the host side, with the port in
+//! raw mode:
+//!
+//! ```text
+//! stty -F /dev/ttyACM0 raw -echo
+//! pv < /dev/ttyACM0 > /dev/null                    # IN
+//! pv -S -s 64M < /dev/zero > /dev/ttyACM0          # OUT
+//! ```
+//!
+//! ```text
+//! cargo build --release --target thumbv7em-none-eabihf --example cdc_throughput --features "stm32f429xx fs cortex-m-rt"
+//! ```
+
+#![no_std]
+#![no_main]
+
+mod common;
+
+use common::{init_clocks, CdcStream};
+use core::panic::PanicInfo;
+use cortex_m_rt::entry;
+use stm32ral::{gpio, modify_reg, otg_fs_global, rcc};
+use synopsys_usb_otg::{Config, UsbBus, UsbPeripheral};
+use usb_device::prelude::*;
+
+struct Peripheral;
+
+unsafe impl Sync for Peripheral {}
+
+unsafe impl UsbPeripheral for Peripheral {
+    const REGISTERS: *const () = otg_fs_global::OTG_FS_GLOBAL as *const ();
+
+    const HIGH_SPEED: bool = false;
+    const FIFO_DEPTH_WORDS: usize = 320;
+    const ENDPOINT_COUNT: usize = 4;
+
+    fn enable() {
+        let rcc = unsafe { &*rcc::RCC };
+        modify_reg!(rcc, rcc, AHB2ENR, OTGFSEN: 1);
+        modify_reg!(rcc, rcc, AHB2RSTR, OTGFSRST: 1);
+        modify_reg!(rcc, rcc, AHB2RSTR, OTGFSRST: 0);
+    }
+}
+
+/// Switches PA11 (DM) and PA12 (DP) to OTG_FS.
+fn init_pins() {
+    let rcc = unsafe { &*rcc::RCC };
+    let gpioa = unsafe { &*gpio::GPIOA };
+
+    modify_reg!(rcc, rcc, AHB1ENR, GPIOAEN: 1);
+    modify_reg!(gpio, gpioa, OSPEEDR, OSPEEDR11: 0b11, OSPEEDR12: 0b11);
+    modify_reg!(gpio, gpioa, AFRH, AFRH11: 10, AFRH12: 10);
+    modify_reg!(gpio, gpioa, MODER, MODER11: 0b10, MODER12: 0b10);
+}
+
+const PACKET_SIZE: u16 = 64;
+
+static mut EP_MEMORY: [u32; 1024] = [0; 1024];
+
+#[entry]
+fn main() -> ! {
+    init_clocks();
+    init_pins();
+
+    // Leave most of the FIFO to the streaming endpoint so that it holds four packets
+    let config = Config::default().tx_fifo_size(2, 4 * PACKET_SIZE / 4);
+    let ep_memory = unsafe { &mut *core::ptr::addr_of_mut!(EP_MEMORY) };
+    let usb_bus = UsbBus::with_config(Peripheral, ep_memory, config);
+
+    let mut cdc = CdcStream::new(&usb_bus, PACKET_SIZE);
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x16c0, 0x27dd))
+        .manufacturer("Fake company")
+        .product("Throughput test")
+        .serial_number("TEST")
+        .device_class(0xef)
+        .device_sub_class(0x02)
+        .device_protocol(0x01)
+        .max_packet_size_0(64)
+        .build();
+
+    loop {
+        if usb_dev.poll(&mut [&mut cdc]) {
+            // Starts the stream once the port has been opened
+            cdc.stream();
+        }
+    }
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    loop {
+        cortex_m::asm::bkpt();
+    }
+}
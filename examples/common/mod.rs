@@ -0,0 +1,182 @@
+//! The CDC-ACM streaming function and the clock setup the throughput examples share.
+
+use stm32ral::{flash, modify_reg, rcc, read_reg};
+use synopsys_usb_otg::EndpointWriter;
+use usb_device::class_prelude::*;
+
+/// Runs the core at 168 MHz and the USB clock at 48 MHz from the 8 MHz HSE.
+pub fn init_clocks() {
+    let rcc = unsafe { &*rcc::RCC };
+    let flash = unsafe { &*flash::FLASH };
+
+    modify_reg!(rcc, rcc, CR, HSEON: 1);
+    while read_reg!(rcc, rcc, CR, HSERDY) == 0 {}
+
+    // 8 MHz / 8 * 336 = 336 MHz VCO, / 2 for the core and / 7 for USB
+    modify_reg!(rcc, rcc, PLLCFGR, PLLSRC: 1, PLLM: 8, PLLN: 336, PLLP: 0b00, PLLQ: 7);
+    modify_reg!(rcc, rcc, CR, PLLON: 1);
+    while read_reg!(rcc, rcc, CR, PLLRDY) == 0 {}
+
+    modify_reg!(flash, flash, ACR, LATENCY: 5, PRFTEN: 1, ICEN: 1, DCEN: 1);
+    modify_reg!(rcc, rcc, CFGR, HPRE: 0b0000, PPRE1: 0b101, PPRE2: 0b100);
+    modify_reg!(rcc, rcc, CFGR, SW: 0b10);
+    while read_reg!(rcc, rcc, CFGR, SWS) != 0b10 {}
+}
+
+const USB_CLASS_CDC: u8 = 0x02;
+const USB_CLASS_CDC_DATA: u8 = 0x0a;
+const CDC_SUBCLASS_ACM: u8 = 0x02;
+const CS_INTERFACE: u8 = 0x24;
+const CDC_TYPE_HEADER: u8 = 0x00;
+const CDC_TYPE_CALL_MANAGEMENT: u8 = 0x01;
+const CDC_TYPE_ACM: u8 = 0x02;
+const CDC_TYPE_UNION: u8 = 0x06;
+
+const REQ_SET_LINE_CODING: u8 = 0x20;
+const REQ_GET_LINE_CODING: u8 = 0x21;
+const REQ_SET_CONTROL_LINE_STATE: u8 = 0x22;
+
+/// Line length of the test pattern.
+const LINE_LENGTH: usize = 64;
+
+/// Largest read the function makes, enough for several high-speed bulk packets.
+const READ_SIZE: usize = 2048;
+
+/// 4 KiB of printable characters in 64-byte lines.
+static PATTERN: [u8; 4096] = {
+    let mut pattern = [0; 4096];
+    let mut i = 0;
+    while i < pattern.len() {
+        pattern[i] = if i % LINE_LENGTH == LINE_LENGTH - 1 {
+            b'\n'
+        } else {
+            b'0' + (i % LINE_LENGTH) as u8
+        };
+        i += 1;
+    }
+    pattern
+};
+
+/// A minimal CDC-ACM function that streams `PATTERN` while the host has the port open.
+pub struct CdcStream<'a, B: usb_device::bus::UsbBus> {
+    comm_if: InterfaceNumber,
+    comm_ep: EndpointIn<'a, B>,
+    data_if: InterfaceNumber,
+    read_ep: EndpointOut<'a, B>,
+    write_ep: EndpointIn<'a, B>,
+    writer: EndpointWriter<'static>,
+    line_coding: [u8; 7],
+    dtr: bool,
+}
+
+impl<'a, B: usb_device::bus::UsbBus> CdcStream<'a, B> {
+    /// Allocates the endpoints, with bulk packets of `packet_size` bytes.
+    pub fn new(alloc: &'a UsbBusAllocator<B>, packet_size: u16) -> Self {
+        Self {
+            comm_if: alloc.interface(),
+            comm_ep: alloc.interrupt(8, 255),
+            data_if: alloc.interface(),
+            read_ep: alloc.bulk(packet_size),
+            write_ep: alloc.bulk(packet_size),
+            // Queue four packets per write, the writer falls back to one if the FIFO is smaller
+            writer: EndpointWriter::new().chunk_size(4 * packet_size as usize),
+            // 115200 8N1
+            line_coding: [0x00, 0xc2, 0x01, 0x00, 0x00, 0x00, 0x08],
+            dtr: false,
+        }
+    }
+
+    /// Keeps the IN endpoint busy while the port is open.
+    pub fn stream(&mut self) {
+        if !self.dtr {
+            return;
+        }
+        let result = if self.writer.is_idle() {
+            self.writer.start(&self.write_ep, &PATTERN)
+        } else {
+            self.writer.poll(&self.write_ep)
+        };
+        match result {
+            Ok(()) | Err(UsbError::WouldBlock) => {}
+            Err(_) => {
+                self.writer.cancel();
+            }
+        }
+    }
+}
+
+impl<B: usb_device::bus::UsbBus> UsbClass<B> for CdcStream<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> usb_device::Result<()> {
+        writer.iad(self.comm_if, 2, USB_CLASS_CDC, CDC_SUBCLASS_ACM, 0)?;
+
+        writer.interface(self.comm_if, USB_CLASS_CDC, CDC_SUBCLASS_ACM, 0)?;
+        writer.write(CS_INTERFACE, &[CDC_TYPE_HEADER, 0x10, 0x01])?;
+        writer.write(CS_INTERFACE, &[CDC_TYPE_ACM, 0x00])?;
+        writer.write(CS_INTERFACE, &[CDC_TYPE_UNION, self.comm_if.into(), self.data_if.into()])?;
+        writer.write(CS_INTERFACE, &[CDC_TYPE_CALL_MANAGEMENT, 0x00, self.data_if.into()])?;
+        writer.endpoint(&self.comm_ep)?;
+
+        writer.interface(self.data_if, USB_CLASS_CDC_DATA, 0x00, 0x00)?;
+        writer.endpoint(&self.write_ep)?;
+        writer.endpoint(&self.read_ep)?;
+
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.writer.cancel();
+        self.dtr = false;
+    }
+
+    fn endpoint_out(&mut self, addr: EndpointAddress) {
+        if addr == self.read_ep.address() {
+            // Drop everything that has arrived, not just one packet per poll
+            let mut buf = [0; READ_SIZE];
+            while self.read_ep.read(&mut buf).is_ok() {}
+        }
+    }
+
+    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        if addr == self.write_ep.address() {
+            self.stream();
+        }
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = xfer.request();
+        if req.request_type == control::RequestType::Class
+            && req.recipient == control::Recipient::Interface
+            && req.index == u8::from(self.comm_if) as u16
+            && req.request == REQ_GET_LINE_CODING
+        {
+            let _ = xfer.accept_with(&self.line_coding);
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+        if req.request_type != control::RequestType::Class
+            || req.recipient != control::Recipient::Interface
+            || req.index != u8::from(self.comm_if) as u16
+        {
+            return;
+        }
+
+        match req.request {
+            REQ_SET_LINE_CODING if xfer.data().len() >= self.line_coding.len() => {
+                self.line_coding.copy_from_slice(&xfer.data()[..7]);
+                let _ = xfer.accept();
+            }
+            REQ_SET_CONTROL_LINE_STATE => {
+                self.dtr = req.value & 0x0001 != 0;
+                if !self.dtr {
+                    self.writer.cancel();
+                }
+                let _ = xfer.accept();
+            }
+            _ => {
+                let _ = xfer.reject();
+            }
+        }
+    }
+}
@@ -0,0 +1,127 @@
+//! CDC-ACM throughput test for the STM32F429 OTG_HS peripheral with an external ULPI PHY, e.g.
+//! a USB3300 (8 MHz HSE).
+//!
+//! This is the high-speed counterpart of `cdc_throughput`, set up as described under "High-speed
+//! bulk throughput" in the crate documentation: 512-byte bulk packets, a TX FIFO of four packets
+//! that a single write fills, a 2 KiB OUT buffer and the core's DMA with 4-beat bursts. It aims
+//! at 30 MB/s or more in each direction. Measure on the host side, with the port in raw mode:
+//!
+//! ```text
+//! stty -F /dev/ttyACM0 raw -echo
+//! pv < /dev/ttyACM0 > /dev/null                    # IN
+//! pv -S -s 1G < /dev/zero > /dev/ttyACM0           # OUT
+//! ```
+//!
+//! The ULPI signals are expected on CK PA5, D0 PA3, D1 PB0, D2 PB1, D3 PB10, D4 PB11, D5 PB12,
+//! D6 PB13, D7 PB5, STP PC0, DIR PC2 and NXT PC3; change `init_pins` for other boards.
+//!
+//! ```text
+//! cargo build --release --target thumbv7em-none-eabihf --example hs_dma_throughput --features "stm32f429xx hs cortex-m-rt"
+//! ```
+
+#![no_std]
+#![no_main]
+
+mod common;
+
+use common::{init_clocks, CdcStream};
+use core::panic::PanicInfo;
+use cortex_m_rt::entry;
+use stm32ral::{gpio, modify_reg, otg_hs_global, rcc};
+use synopsys_usb_otg::config::BurstLength;
+use synopsys_usb_otg::{Config, EndpointMemory, PhyType, UsbBus, UsbPeripheral};
+use usb_device::prelude::*;
+
+struct Peripheral;
+
+unsafe impl Sync for Peripheral {}
+
+unsafe impl UsbPeripheral for Peripheral {
+    const REGISTERS: *const () = otg_hs_global::OTG_HS_GLOBAL as *const ();
+
+    const HIGH_SPEED: bool = true;
+    const FIFO_DEPTH_WORDS: usize = 1024;
+    const ENDPOINT_COUNT: usize = 6;
+    const PHY_TYPE: PhyType = PhyType::ExternalHighSpeed;
+
+    fn enable() {
+        let rcc = unsafe { &*rcc::RCC };
+        modify_reg!(rcc, rcc, AHB1ENR, OTGHSEN: 1, OTGHSULPIEN: 1);
+        modify_reg!(rcc, rcc, AHB1RSTR, OTGHSRST: 1);
+        modify_reg!(rcc, rcc, AHB1RSTR, OTGHSRST: 0);
+    }
+}
+
+/// Switches the ULPI pins to OTG_HS.
+fn init_pins() {
+    let rcc = unsafe { &*rcc::RCC };
+    let gpioa = unsafe { &*gpio::GPIOA };
+    let gpiob = unsafe { &*gpio::GPIOB };
+    let gpioc = unsafe { &*gpio::GPIOC };
+
+    modify_reg!(rcc, rcc, AHB1ENR, GPIOAEN: 1, GPIOBEN: 1, GPIOCEN: 1);
+
+    // CK, D0
+    modify_reg!(gpio, gpioa, OSPEEDR, OSPEEDR3: 0b11, OSPEEDR5: 0b11);
+    modify_reg!(gpio, gpioa, AFRL, AFRL3: 10, AFRL5: 10);
+    modify_reg!(gpio, gpioa, MODER, MODER3: 0b10, MODER5: 0b10);
+
+    // D1 to D7
+    modify_reg!(gpio, gpiob, OSPEEDR,
+        OSPEEDR0: 0b11, OSPEEDR1: 0b11, OSPEEDR5: 0b11, OSPEEDR10: 0b11, OSPEEDR11: 0b11,
+        OSPEEDR12: 0b11, OSPEEDR13: 0b11);
+    modify_reg!(gpio, gpiob, AFRL, AFRL0: 10, AFRL1: 10, AFRL5: 10);
+    modify_reg!(gpio, gpiob, AFRH, AFRH10: 10, AFRH11: 10, AFRH12: 10, AFRH13: 10);
+    modify_reg!(gpio, gpiob, MODER,
+        MODER0: 0b10, MODER1: 0b10, MODER5: 0b10, MODER10: 0b10, MODER11: 0b10, MODER12: 0b10,
+        MODER13: 0b10);
+
+    // STP, DIR, NXT
+    modify_reg!(gpio, gpioc, OSPEEDR, OSPEEDR0: 0b11, OSPEEDR2: 0b11, OSPEEDR3: 0b11);
+    modify_reg!(gpio, gpioc, AFRL, AFRL0: 10, AFRL2: 10, AFRL3: 10);
+    modify_reg!(gpio, gpioc, MODER, MODER0: 0b10, MODER2: 0b10, MODER3: 0b10);
+}
+
+const PACKET_SIZE: u16 = 512;
+
+/// In the main SRAM, which the core's DMA reaches (the CCM RAM isn't)
+static EP_MEMORY: EndpointMemory<2048> = EndpointMemory::new();
+
+#[entry]
+fn main() -> ! {
+    init_clocks();
+    init_pins();
+
+    // Every write queues four packets, and a read returns up to four
+    let config = Config::default()
+        .dma(true)
+        .ahb_burst_length(BurstLength::Incr4)
+        .tx_fifo_size(2, 4 * PACKET_SIZE / 4)
+        .rx_buffer_size(1, 4 * PACKET_SIZE);
+    let usb_bus = UsbBus::with_config_uninit(Peripheral, EP_MEMORY.take().unwrap(), config);
+
+    let mut cdc = CdcStream::new(&usb_bus, PACKET_SIZE);
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x16c0, 0x27dd))
+        .manufacturer("Fake company")
+        .product("HS throughput test")
+        .serial_number("TEST")
+        .device_class(0xef)
+        .device_sub_class(0x02)
+        .device_protocol(0x01)
+        .max_packet_size_0(64)
+        .build();
+
+    loop {
+        if usb_dev.poll(&mut [&mut cdc]) {
+            // Starts the stream once the port has been opened
+            cdc.stream();
+        }
+    }
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    loop {
+        cortex_m::asm::bkpt();
+    }
+}
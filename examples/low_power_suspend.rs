@@ -0,0 +1,171 @@
+//! Low-power suspend on a NUCLEO-F429ZI (OTG_FS on the user USB connector, 8 MHz HSE from the
+//! ST-LINK).
+//!
+//! The device enumerates as a vendor-specific function that supports remote wakeup. When the host
+//! suspends the bus, the driver powers down the transceiver and gates the core's clocks, and the
+//! main loop puts the MCU into STOP mode until the resume signaling wakes it up through EXTI line
+//! 18. Pressing the user button while suspended wakes the MCU through EXTI line 13 and signals
+//! remote wakeup, if the host has enabled it.
+//!
+//! To measure the suspend current, replace JP5 (IDD) with an ammeter and let the host suspend the
+//! device, e.g. with `echo auto > /sys/bus/usb/devices/<port>/power/control` on Linux. The budget
+//! of a suspended bus-powered device is 2.5 mA, the ST-LINK half of the board isn't part of the
+//! measurement. The suspend current of this example hasn't been measured yet, so staying within
+//! the 2.5 mA is a target, not a verified result.
+//!
+//! ```text
+//! cargo build --release --target thumbv7em-none-eabihf --example low_power_suspend --features "stm32f429xx fs cortex-m-rt"
+//! ```
+
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use cortex_m_rt::entry;
+use stm32ral::{exti, flash, gpio, modify_reg, otg_fs_global, pwr, rcc, read_reg, syscfg};
+use synopsys_usb_otg::{Config, UsbBus, UsbPeripheral};
+use usb_device::class_prelude::*;
+use usb_device::prelude::*;
+
+struct Peripheral;
+
+unsafe impl Sync for Peripheral {}
+
+unsafe impl UsbPeripheral for Peripheral {
+    const REGISTERS: *const () = otg_fs_global::OTG_FS_GLOBAL as *const ();
+
+    const HIGH_SPEED: bool = false;
+    const FIFO_DEPTH_WORDS: usize = 320;
+    const ENDPOINT_COUNT: usize = 4;
+
+    fn enable() {
+        let rcc = unsafe { &*rcc::RCC };
+        modify_reg!(rcc, rcc, AHB2ENR, OTGFSEN: 1);
+        modify_reg!(rcc, rcc, AHB2RSTR, OTGFSRST: 1);
+        modify_reg!(rcc, rcc, AHB2RSTR, OTGFSRST: 0);
+    }
+
+    fn prepare_stop() {
+        let pwr = unsafe { &*pwr::PWR };
+        // STOP rather than STANDBY, with the regulator in low-power mode
+        modify_reg!(pwr, pwr, CR, PDDS: 0, LPDS: 1);
+        unsafe { cortex_m::Peripherals::steal() }.SCB.set_sleepdeep();
+    }
+
+    fn restore_clocks() {
+        unsafe { cortex_m::Peripherals::steal() }.SCB.clear_sleepdeep();
+        // The MCU leaves STOP mode running from the HSI
+        init_clocks();
+    }
+}
+
+/// Runs the core at 168 MHz and the USB clock at 48 MHz from the 8 MHz HSE.
+fn init_clocks() {
+    let rcc = unsafe { &*rcc::RCC };
+    let flash = unsafe { &*flash::FLASH };
+
+    // The ST-LINK provides the HSE as an external clock
+    modify_reg!(rcc, rcc, CR, HSEBYP: 1);
+    modify_reg!(rcc, rcc, CR, HSEON: 1);
+    while read_reg!(rcc, rcc, CR, HSERDY) == 0 {}
+
+    // 8 MHz / 8 * 336 = 336 MHz VCO, / 2 for the core and / 7 for USB
+    modify_reg!(rcc, rcc, PLLCFGR, PLLSRC: 1, PLLM: 8, PLLN: 336, PLLP: 0b00, PLLQ: 7);
+    modify_reg!(rcc, rcc, CR, PLLON: 1);
+    while read_reg!(rcc, rcc, CR, PLLRDY) == 0 {}
+
+    modify_reg!(flash, flash, ACR, LATENCY: 5, PRFTEN: 1, ICEN: 1, DCEN: 1);
+    modify_reg!(rcc, rcc, CFGR, HPRE: 0b0000, PPRE1: 0b101, PPRE2: 0b100);
+    modify_reg!(rcc, rcc, CFGR, SW: 0b10);
+    while read_reg!(rcc, rcc, CFGR, SWS) != 0b10 {}
+}
+
+/// Switches PA11 (DM) and PA12 (DP) to OTG_FS.
+fn init_pins() {
+    let rcc = unsafe { &*rcc::RCC };
+    let gpioa = unsafe { &*gpio::GPIOA };
+
+    modify_reg!(rcc, rcc, AHB1ENR, GPIOAEN: 1);
+    modify_reg!(gpio, gpioa, OSPEEDR, OSPEEDR11: 0b11, OSPEEDR12: 0b11);
+    modify_reg!(gpio, gpioa, AFRH, AFRH11: 10, AFRH12: 10);
+    modify_reg!(gpio, gpioa, MODER, MODER11: 0b10, MODER12: 0b10);
+}
+
+/// Turns the OTG_FS wakeup (EXTI line 18) and the rising edge of the user button on PC13 (EXTI
+/// line 13) into events, which end a `wfe` in STOP mode without an interrupt handler.
+fn init_wakeup_events() {
+    let rcc = unsafe { &*rcc::RCC };
+    let syscfg = unsafe { &*syscfg::SYSCFG };
+    let exti = unsafe { &*exti::EXTI };
+
+    modify_reg!(rcc, rcc, APB1ENR, PWREN: 1);
+    modify_reg!(rcc, rcc, APB2ENR, SYSCFGEN: 1);
+    // PC13 is an input after reset, the board pulls it down
+    modify_reg!(rcc, rcc, AHB1ENR, GPIOCEN: 1);
+
+    modify_reg!(syscfg, syscfg, EXTICR4, EXTI13: 0b0010);
+    modify_reg!(exti, exti, RTSR, TR13: 1, TR18: 1);
+    modify_reg!(exti, exti, EMR, MR13: 1, MR18: 1);
+}
+
+fn button_pressed() -> bool {
+    let gpioc = unsafe { &*gpio::GPIOC };
+    read_reg!(gpio, gpioc, IDR, IDR13) != 0
+}
+
+/// A vendor-specific interface without endpoints, everything happens on EP0.
+struct VendorFunction {
+    interface: InterfaceNumber,
+}
+
+impl<B: usb_device::bus::UsbBus> UsbClass<B> for VendorFunction {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> usb_device::Result<()> {
+        writer.interface(self.interface, 0xff, 0x00, 0x00)
+    }
+}
+
+static mut EP_MEMORY: [u32; 1024] = [0; 1024];
+
+#[entry]
+fn main() -> ! {
+    init_clocks();
+    init_pins();
+    init_wakeup_events();
+
+    let config = Config::default().suspend_stop_mode(true);
+    let ep_memory = unsafe { &mut *core::ptr::addr_of_mut!(EP_MEMORY) };
+    let usb_bus = UsbBus::with_config(Peripheral, ep_memory, config);
+
+    let mut function = VendorFunction {
+        interface: usb_bus.interface(),
+    };
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x16c0, 0x27dd))
+        .manufacturer("Fake company")
+        .product("Low-power suspend")
+        .serial_number("TEST")
+        .supports_remote_wakeup(true)
+        .build();
+
+    loop {
+        usb_dev.poll(&mut [&mut function]);
+
+        if usb_dev.state() != UsbDeviceState::Suspend {
+            continue;
+        }
+        if button_pressed() {
+            // Refused unless the host has enabled remote wakeup
+            let _ = usb_dev.bus().remote_wakeup();
+        } else {
+            // STOP mode until the host resumes the bus or the button is pressed, the driver
+            // restores the clocks when it handles the resume
+            cortex_m::asm::wfe();
+        }
+    }
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    loop {
+        cortex_m::asm::bkpt();
+    }
+}
@@ -0,0 +1,166 @@
+#![no_main]
+
+//! Allocates and frees random sets of endpoints and checks that the endpoint memory and the FIFO
+//! layout `configure_all()` would program stay consistent.
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use std::mem::MaybeUninit;
+use synopsys_usb_otg::fuzzing::{Allocator, MAX_ENDPOINTS};
+use synopsys_usb_otg::Config;
+use usb_device::endpoint::{EndpointAddress, EndpointType};
+use usb_device::UsbDirection;
+
+const MEMORY_WORDS: usize = 4096;
+
+static mut MEMORY: [MaybeUninit<u32>; MEMORY_WORDS] = [MaybeUninit::uninit(); MEMORY_WORDS];
+
+#[derive(Arbitrary, Debug)]
+enum Op {
+    Alloc {
+        out: bool,
+        number: Option<u8>,
+        ep_type: u8,
+        max_packet_size: u16,
+        interval: u8,
+    },
+    Free {
+        out: bool,
+        number: u8,
+    },
+    Compact,
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    high_speed: bool,
+    dma: bool,
+    fifo_depth_words: u16,
+    endpoint_count: u8,
+    memory_words: u16,
+    tx_fifo_size_words: Vec<(u8, u16)>,
+    rx_buffer_size: Vec<(u8, u16)>,
+    ops: Vec<Op>,
+}
+
+fn direction(out: bool) -> UsbDirection {
+    if out { UsbDirection::Out } else { UsbDirection::In }
+}
+
+fn ep_type(ep_type: u8) -> EndpointType {
+    match ep_type & 3 {
+        0 => EndpointType::Control,
+        1 => EndpointType::Isochronous,
+        2 => EndpointType::Bulk,
+        _ => EndpointType::Interrupt,
+    }
+}
+
+fn packet_size(max_packet_size: u16) -> usize {
+    (max_packet_size & 0x7ff) as usize
+}
+
+/// Endpoints currently allocated with their max packet sizes, indexed by direction and number.
+type Allocated = [[Option<u16>; MAX_ENDPOINTS]; 2];
+
+fn check(input: &Input, allocator: &Allocator, allocated: &Allocated, memory: (usize, usize)) {
+    // Without any endpoint memory the layout is just the fixed part of the RX FIFO
+    let empty = Allocator::new(&mut [], &Config::default(), false, 0, 0).fifo_layout();
+    let fifo_depth_words = std::cmp::max(input.fifo_depth_words, empty.total_words());
+
+    let layout = allocator.fifo_layout();
+    assert!(layout.total_words() <= fifo_depth_words, "{:?}", layout);
+
+    let mut fifo_top = layout.rx_size_words();
+    for number in 0..MAX_ENDPOINTS {
+        let fifo = layout.tx_fifo(number).unwrap();
+        assert!(fifo.start_words >= fifo_top, "TX FIFO {} overlaps: {:?}", number, layout);
+        fifo_top = fifo.start_words + fifo.size_words;
+
+        match allocated[0][number] {
+            Some(max_packet_size) => assert!(fifo.size_words as usize * 4 >= packet_size(max_packet_size)),
+            None => assert_eq!(fifo.size_words, 0),
+        }
+    }
+
+    let mut buffers = Vec::new();
+    for (dir, endpoints) in allocated.iter().enumerate() {
+        for (number, max_packet_size) in endpoints.iter().enumerate() {
+            let ep_addr = EndpointAddress::from_parts(number, direction(dir == 1));
+            match (max_packet_size, allocator.buffer_memory(ep_addr)) {
+                (Some(max_packet_size), Some((start, size))) => {
+                    assert!(size >= packet_size(*max_packet_size));
+                    assert!(start >= memory.0 && start + size <= memory.0 + memory.1);
+                    // Empty buffers take no memory and aren't relocated
+                    if size != 0 {
+                        buffers.push((start, size));
+                    }
+                }
+                // IN endpoints have a buffer only in DMA mode
+                (Some(_), None) => assert!(dir == 0),
+                (None, buffer) => assert_eq!(buffer, None),
+            }
+        }
+    }
+    buffers.sort_unstable();
+    for pair in buffers.windows(2) {
+        assert!(pair[0].0 + pair[0].1 <= pair[1].0, "buffers overlap: {:?}", buffers);
+    }
+}
+
+fn run(input: &Input) -> Vec<String> {
+    let mut config = Config::default().dma(input.dma);
+    for &(number, size_words) in &input.tx_fifo_size_words {
+        config = config.tx_fifo_size(number as usize, size_words);
+    }
+    for &(number, size) in &input.rx_buffer_size {
+        config = config.rx_buffer_size(number as usize, size);
+    }
+
+    let memory_words = input.memory_words as usize % (MEMORY_WORDS + 1);
+    let memory = unsafe { &mut MEMORY[..memory_words] };
+    let memory_range = (memory.as_ptr() as usize, memory_words * 4);
+    let endpoint_count = input.endpoint_count as usize % (MAX_ENDPOINTS + 1);
+    let mut allocator = Allocator::new(memory, &config, input.high_speed, input.fifo_depth_words as usize, endpoint_count);
+
+    let mut allocated: Allocated = [[None; MAX_ENDPOINTS]; 2];
+    let mut results = Vec::new();
+    for op in &input.ops {
+        let layout = allocator.fifo_layout();
+        match *op {
+            Op::Alloc { out, number, ep_type: ty, max_packet_size, interval } => {
+                let ep_addr = number.map(|number| EndpointAddress::from_parts(number as usize & 0x0f, direction(out)));
+                let result = allocator.alloc_ep(direction(out), ep_addr, ep_type(ty), max_packet_size, interval);
+                match result {
+                    Ok(ep_addr) => {
+                        assert!(ep_addr.index() < endpoint_count);
+                        assert_eq!(ep_addr.direction(), direction(out));
+                        let slot = &mut allocated[out as usize][ep_addr.index()];
+                        assert_eq!(*slot, None, "{:?} allocated twice", ep_addr);
+                        *slot = Some(max_packet_size);
+                    }
+                    // A failed allocation leaves everything as it was
+                    Err(_) => assert_eq!(allocator.fifo_layout(), layout),
+                }
+                results.push(format!("{:?}", result));
+            }
+            Op::Free { out, number } => {
+                let ep_addr = EndpointAddress::from_parts(number as usize & 0x0f, direction(out));
+                let result = allocator.free_ep(ep_addr);
+                let was_allocated = allocated[out as usize].get_mut(ep_addr.index()).and_then(|slot| slot.take());
+                assert_eq!(result.is_ok(), was_allocated.is_some());
+                results.push(format!("{:?}", result));
+            }
+            Op::Compact => allocator.compact_memory(),
+        }
+        check(input, &allocator, &allocated, memory_range);
+    }
+    results
+}
+
+fuzz_target!(|input: Input| {
+    // Failures don't depend on anything but the endpoints requested so far
+    let first = run(&input);
+    let second = run(&input);
+    assert_eq!(first, second);
+});
@@ -2,101 +2,2009 @@ use usb_device::{Result, UsbDirection, UsbError};
 use usb_device::bus::{UsbBusAllocator, PollResult};
 use usb_device::endpoint::{EndpointType, EndpointAddress};
 use crate::transition::{EndpointConfig, EndpointDescriptor};
-use crate::ral::{read_reg, write_reg, modify_reg, otg_global, otg_device, otg_pwrclk};
+use crate::ral::{read_reg, write_reg, modify_reg, otg_global, otg_device, otg_pwrclk, tx_fifo};
+use crate::ral::otg_device::ENDPOINT_COUNT;
 
 use crate::target::UsbRegisters;
 use crate::target::interrupt::{self, Mutex, CriticalSection};
-use crate::endpoint::{EndpointIn, EndpointOut};
-use crate::endpoint_memory::{EndpointMemoryAllocator, EndpointBufferState};
-use crate::UsbPeripheral;
+use crate::endpoint::{EndpointIn, EndpointOut, SETUP_PACKETS, is_valid_interval, is_valid_max_packet_size, packet_size, transactions_per_frame};
+use crate::endpoint_memory::{EndpointMemoryAllocator, EndpointBuffer, EndpointBufferState, RX_FIFO_EXTRA_WORDS};
+use crate::{UsbPeripheral, PhyType, Speed, Error, Frame, EnumerationState};
+use crate::config::{Config, EnableStep, OutRearmPoint, MAX_ENDPOINTS};
+use crate::otg::{OtgStatus, OtgEvent, OtgRole};
+use crate::events::Events;
+use crate::dwc_otg::{wait_for, Core, Direction, RxEntry, RxStatus};
+#[cfg(feature = "trace")]
+use crate::trace::{TraceEvent, TraceLog, TraceRecord};
+use core::cell::{Cell, RefCell};
+use core::mem::MaybeUninit;
+use core::slice;
+use core::task::Waker;
 
 /// USB peripheral driver for STM32 microcontrollers.
 pub struct UsbBus<USB> {
     peripheral: USB,
     regs: Mutex<UsbRegisters<USB>>,
-    allocator: EndpointAllocator,
+    allocator: Mutex<RefCell<EndpointAllocator>>,
+    config: Config,
+    erratic_errors: Mutex<Cell<u32>>,
+    /// Interrupts that asserted none of the causes the handler services
+    spurious_interrupts: Mutex<Cell<u32>>,
+    /// Waits for the core that gave up before it confirmed
+    core_timeouts: Mutex<Cell<u32>>,
+    /// RX FIFO entries with a status a device doesn't expect
+    unknown_rx_statuses: Mutex<Cell<u32>>,
+    rx_overflows: Mutex<Cell<RxOverflows>>,
+    /// Isochronous IN endpoints that dropped a packet since the application last checked
+    #[cfg(feature = "iso")]
+    missed_iso_in: Mutex<Cell<u16>>,
+    connected: Mutex<Cell<bool>>,
+    /// The last bus reset enumerated full speed although high speed is configured
+    full_speed_fallback: Mutex<Cell<bool>>,
+    /// IN endpoints reporting at FIFO empty that turned a `write()` away, their transfer
+    /// completion is reported too
+    tx_retry: Mutex<Cell<u16>>,
+    /// IN endpoints whose TX FIFO `write()` is filling with interrupts enabled
+    tx_filling: Mutex<Cell<u16>>,
+    /// IN endpoints whose TX FIFO was flushed while being filled, the rest of the data is stale
+    tx_fill_aborted: Mutex<Cell<u16>>,
+    remote_wakeup_enabled: Mutex<Cell<bool>>,
+    /// The configuration descriptor sent to the host advertises remote wakeup
+    remote_wakeup_supported: Mutex<Cell<bool>>,
+    /// The host has asked for the configuration descriptor, the next EP0 write carries it
+    config_descriptor_requested: Mutex<Cell<bool>>,
+    /// Address to program once the status stage of SET_ADDRESS has been sent
+    pending_address: Mutex<Cell<Option<u8>>>,
+    enumeration: Mutex<Cell<Enumeration>>,
+    /// The core is powered down for the suspended bus, see `Config::suspend_power_down`
+    low_power: Mutex<Cell<bool>>,
+    /// A session has ended, the next one starts with a reconnect
+    session_ended: Mutex<Cell<bool>>,
+    /// `UsbPeripheral::micros` time the device was disconnected at for a reconnect, which
+    /// `poll()` completes
+    reconnect_since: Mutex<Cell<Option<u32>>>,
+    otg_events: Mutex<Cell<u16>>,
+    wakers: Mutex<RefCell<EndpointWakers>>,
+    pending: Mutex<Cell<PendingEvents>>,
+    vbus_present: Mutex<Cell<Option<bool>>>,
+    vbus_change: Mutex<Cell<Option<VbusChange>>>,
+    role: Mutex<Cell<OtgRole>>,
+    role_change_callback: Mutex<Cell<Option<RoleChangeCallback>>>,
+    enable_error: Mutex<Cell<Option<Error>>>,
+    alloc_error: Mutex<Cell<Option<Error>>>,
+    #[cfg(feature = "trace")]
+    trace_log: Mutex<RefCell<TraceLog>>,
+}
+
+/// Records a driver event in the trace log, compiles to nothing without the `trace` feature.
+macro_rules! trace {
+    ($bus:expr, $cs:expr, $event:expr) => {
+        #[cfg(feature = "trace")]
+        $bus.trace($cs, $event);
+    };
+}
+
+/// Time the core is given to become idle after being clocked, in microseconds.
+const AHB_IDLE_TIMEOUT_US: u32 = 10_000;
+
+/// Time the core is left alone after a soft reset, in microseconds: 3 PHY clocks, with a wide
+/// margin for the slowest PHY.
+const CORE_RESET_DELAY_US: u32 = 3;
+
+/// Time a reconnecting device stays disconnected for the host to notice, in microseconds.
+const RECONNECT_US: u32 = 3_000;
+
+/// Callback invoked with the old and the new role, see [`UsbBus::on_role_change`].
+pub type RoleChangeCallback = fn(OtgRole, OtgRole);
+
+/// Events collected from the interrupts that `poll()` hasn't reported yet.
+#[derive(Copy, Clone, Default)]
+struct PendingEvents {
+    bus: BusEventQueue,
+    ep_in_complete: u16,
+}
+
+/// Enumeration progress since the last bus reset.
+#[derive(Copy, Clone)]
+struct Enumeration {
+    state: EnumerationState,
+    /// `Frame::microframes` of the last SOF the elapsed time was counted up to
+    microframes: u16,
+    /// Time since the bus reset in 125 µs units
+    elapsed: u32,
+}
+
+impl Default for Enumeration {
+    fn default() -> Self {
+        Self {
+            state: EnumerationState::Idle,
+            microframes: 0,
+            elapsed: 0,
+        }
+    }
+}
+
+/// VBUS change waiting for the debounce time to pass.
+#[derive(Copy, Clone)]
+struct VbusChange {
+    present: bool,
+    /// `UsbPeripheral::micros` time of the change
+    since: u32,
+    /// `poll()` has waited for the debounce time, for peripherals without `micros`
+    waited: bool,
+}
+
+/// Received packets the driver had to drop.
+#[derive(Copy, Clone, Default)]
+struct RxOverflows {
+    count: u32,
+    /// OUT endpoints that dropped packets since the application last checked
+    endpoints: u16,
+}
+
+/// Bus state change reported by `poll()`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum BusEvent {
+    Reset,
+    Resume,
+    Suspend,
+}
+
+impl From<UsbDirection> for Direction {
+    fn from(direction: UsbDirection) -> Self {
+        match direction {
+            UsbDirection::Out => Direction::Out,
+            UsbDirection::In => Direction::In,
+        }
+    }
+}
+
+/// Returns where cores with the given CID re-enable their OUT endpoints.
+#[cfg(feature = "quirks")]
+fn out_rearm_point(core_id: u32) -> Option<OutRearmPoint> {
+    match core_id {
+        0x0000_1200 | 0x0000_1100 => Some(OutRearmPoint::TransferComplete),
+        0x0000_2000 | 0x0000_2100 => Some(OutRearmPoint::PacketReceived),
+        _ => None,
+    }
+}
+
+/// Returns true if `memory` lies within one of the DMA `regions`, or no regions are given.
+fn is_dma_reachable(regions: &[core::ops::Range<usize>], memory: &core::ops::Range<usize>) -> bool {
+    regions.is_empty() || regions.iter().any(|region| region.start <= memory.start && memory.end <= region.end)
+}
+
+/// Number of bus state changes buffered between two polls.
+const BUS_EVENT_QUEUE_LEN: usize = 4;
+
+/// Bus state changes in the order they happened, so that sparse polls don't lose transitions.
+#[derive(Copy, Clone, Default)]
+struct BusEventQueue {
+    events: [Option<BusEvent>; BUS_EVENT_QUEUE_LEN],
+    len: usize,
+}
+
+impl BusEventQueue {
+    /// Appends `event`. A reset makes the earlier events stale and a repeated event is merged
+    /// with the previous one. When the queue is full, the oldest event is dropped.
+    fn push(&mut self, event: BusEvent) {
+        if event == BusEvent::Reset {
+            *self = Self::default();
+        }
+        if self.len > 0 && self.events[self.len - 1] == Some(event) {
+            return;
+        }
+        if self.len == BUS_EVENT_QUEUE_LEN {
+            self.pop();
+        }
+        self.events[self.len] = Some(event);
+        self.len += 1;
+    }
+
+    /// Removes and returns the oldest event.
+    fn pop(&mut self) -> Option<BusEvent> {
+        let event = self.events[0];
+        if event.is_some() {
+            self.events.copy_within(1.., 0);
+            self.events[BUS_EVENT_QUEUE_LEN - 1] = None;
+            self.len -= 1;
+        }
+        event
+    }
 }
 
 impl<USB: UsbPeripheral> UsbBus<USB> {
     /// Constructs a new USB peripheral driver.
     pub fn new(peripheral: USB, ep_memory: &'static mut [u32]) -> UsbBusAllocator<Self> {
-        let bus = UsbBus {
+        Self::with_config(peripheral, ep_memory, Config::default())
+    }
+
+    /// Constructs a new USB peripheral driver with a custom configuration.
+    pub fn with_config(peripheral: USB, ep_memory: &'static mut [u32], config: Config) -> UsbBusAllocator<Self> {
+        // u32 and MaybeUninit<u32> have the same layout
+        let ep_memory = unsafe {
+            slice::from_raw_parts_mut(ep_memory.as_mut_ptr() as *mut MaybeUninit<u32>, ep_memory.len())
+        };
+        Self::with_config_uninit(peripheral, ep_memory, config)
+    }
+
+    /// Constructs a new USB peripheral driver using uninitialized endpoint memory.
+    ///
+    /// The memory doesn't have to be zeroed, so it can be obtained without `static mut`, e.g. from
+    /// `cortex_m::singleton!(: [MaybeUninit<u32>; 1024] = [MaybeUninit::uninit(); 1024])`.
+    pub fn new_uninit(peripheral: USB, ep_memory: &'static mut [MaybeUninit<u32>]) -> UsbBusAllocator<Self> {
+        Self::with_config_uninit(peripheral, ep_memory, Config::default())
+    }
+
+    /// Constructs a new USB peripheral driver with a custom configuration using uninitialized
+    /// endpoint memory.
+    pub fn with_config_uninit(
+        peripheral: USB,
+        ep_memory: &'static mut [MaybeUninit<u32>],
+        config: Config,
+    ) -> UsbBusAllocator<Self> {
+        UsbBusAllocator::new(Self::new_bus(peripheral, ep_memory, config))
+    }
+
+    fn new_bus(peripheral: USB, ep_memory: &'static mut [MaybeUninit<u32>], config: Config) -> Self {
+        UsbBus {
             peripheral,
             regs: Mutex::new(UsbRegisters::new()),
-            allocator: EndpointAllocator::new(ep_memory),
+            allocator: Mutex::new(RefCell::new(EndpointAllocator::new(ep_memory, &config, Self::is_high_speed(&config), Self::is_hs_core() && config.dma, UsbRegisters::<USB>::base_address(), USB::FIFO_DEPTH_WORDS, Self::endpoint_count()))),
+            config,
+            erratic_errors: Mutex::new(Cell::new(0)),
+            spurious_interrupts: Mutex::new(Cell::new(0)),
+            core_timeouts: Mutex::new(Cell::new(0)),
+            unknown_rx_statuses: Mutex::new(Cell::new(0)),
+            rx_overflows: Mutex::new(Cell::new(RxOverflows::default())),
+            #[cfg(feature = "iso")]
+            missed_iso_in: Mutex::new(Cell::new(0)),
+            connected: Mutex::new(Cell::new(false)),
+            full_speed_fallback: Mutex::new(Cell::new(false)),
+            tx_retry: Mutex::new(Cell::new(0)),
+            tx_filling: Mutex::new(Cell::new(0)),
+            tx_fill_aborted: Mutex::new(Cell::new(0)),
+            remote_wakeup_enabled: Mutex::new(Cell::new(false)),
+            remote_wakeup_supported: Mutex::new(Cell::new(false)),
+            config_descriptor_requested: Mutex::new(Cell::new(false)),
+            pending_address: Mutex::new(Cell::new(None)),
+            enumeration: Mutex::new(Cell::new(Enumeration::default())),
+            low_power: Mutex::new(Cell::new(false)),
+            session_ended: Mutex::new(Cell::new(false)),
+            reconnect_since: Mutex::new(Cell::new(None)),
+            otg_events: Mutex::new(Cell::new(0)),
+            wakers: Mutex::new(RefCell::new(EndpointWakers::default())),
+            pending: Mutex::new(Cell::new(PendingEvents::default())),
+            vbus_present: Mutex::new(Cell::new(None)),
+            vbus_change: Mutex::new(Cell::new(None)),
+            role: Mutex::new(Cell::new(OtgRole::Device)),
+            role_change_callback: Mutex::new(Cell::new(None)),
+            enable_error: Mutex::new(Cell::new(None)),
+            alloc_error: Mutex::new(Cell::new(None)),
+            #[cfg(feature = "trace")]
+            trace_log: Mutex::new(RefCell::new(TraceLog::new())),
+        }
+    }
+
+    /// Returns the hardware layer of the peripheral.
+    fn core() -> Core {
+        Core::new::<USB>()
+    }
+
+    /// Returns true if the core moves the packet data by DMA.
+    fn dma_enabled(&self) -> bool {
+        Self::is_hs_core() && self.config.dma
+    }
+
+    /// Returns true if the peripheral is a high-speed core, which adds DMA, transmission
+    /// thresholding and the ULPI interface to the full-speed one.
+    fn is_hs_core() -> bool {
+        cfg!(feature = "hs") && USB::HIGH_SPEED
+    }
+
+    /// Returns the number of endpoints per direction the driver manages on this peripheral.
+    fn endpoint_count() -> usize {
+        core::cmp::min(USB::ENDPOINT_COUNT, ENDPOINT_COUNT)
+    }
+
+    /// Returns the PHY selected by the configuration, or the peripheral's default one.
+    fn phy_type(config: &Config) -> PhyType {
+        config.phy.unwrap_or(USB::PHY_TYPE)
+    }
+
+    /// Returns the USB turnaround time (GUSBCFG.TRDT) in PHY clocks for `speed`. The full-speed
+    /// value suits AHB clocks of 32 MHz and more.
+    fn turnaround_time(speed: Speed) -> u32 {
+        match speed {
+            Speed::High => 0x9,
+            Speed::Full => 0x6,
+        }
+    }
+
+    /// Returns true if the peripheral is configured for high-speed operation.
+    fn is_high_speed(config: &Config) -> bool {
+        USB::HIGH_SPEED && Self::phy_type(config) != PhyType::InternalFullSpeed && !config.ulpi_fs_ls
+    }
+
+    /// Returns the number of PHY erratic errors the driver has recovered from.
+    ///
+    /// After an erratic error the core stops responding until the device is soft-disconnected,
+    /// `poll()` does this automatically and the host then enumerates the device again.
+    pub fn erratic_error_count(&self) -> u32 {
+        interrupt::free(|cs| self.erratic_errors.borrow(cs).get())
+    }
+
+    /// Returns the number of times [`on_interrupt`](Self::on_interrupt) ran without any of the
+    /// interrupt causes it services being asserted (GINTSTS & GINTMSK).
+    ///
+    /// A growing count on a new part points to a cause the driver doesn't know about, or to an
+    /// interrupt line that isn't cleared, rather than to a hang with no explanation.
+    pub fn spurious_interrupt_count(&self) -> u32 {
+        interrupt::free(|cs| self.spurious_interrupts.borrow(cs).get())
+    }
+
+    /// Returns the number of times the driver gave up waiting for the core to flush a FIFO, to
+    /// disable an endpoint or to NAK the OUT endpoints, and carried on without the confirmation.
+    ///
+    /// The waits are bounded so that a core whose PHY clock has stopped can't hang the interrupt
+    /// handler. A growing count means the FIFOs or endpoints may hold stale data.
+    pub fn core_timeout_count(&self) -> u32 {
+        interrupt::free(|cs| self.core_timeouts.borrow(cs).get())
+    }
+
+    /// Counts a wait for the core that gave up, `confirmed` being its result.
+    fn note_core_wait(&self, cs: &CriticalSection, confirmed: bool) {
+        if !confirmed {
+            let timeouts = self.core_timeouts.borrow(cs);
+            timeouts.set(timeouts.get().wrapping_add(1));
+        }
+    }
+
+    /// Returns the number of RX FIFO entries with a status a device doesn't expect (GRXSTSP
+    /// PKTSTS), host-mode or reserved ones, which were dropped.
+    pub fn unknown_rx_status_count(&self) -> u32 {
+        interrupt::free(|cs| self.unknown_rx_statuses.borrow(cs).get())
+    }
+
+    /// Returns the number of received packets that have been dropped instead of delivered.
+    ///
+    /// Packets are dropped when they are larger than the endpoint buffer, when they are addressed
+    /// to an OUT endpoint that isn't allocated, or when the core finds no room for an isochronous
+    /// OUT packet in the RX FIFO (GINTSTS.ISOODRP). Host-side CRC errors or timeouts together
+    /// with a growing count point to an RX FIFO or buffer sizing problem.
+    pub fn rx_overflow_count(&self) -> u32 {
+        interrupt::free(|cs| self.rx_overflows.borrow(cs).get().count)
+    }
+
+    /// Returns the OUT endpoints that have dropped packets since the last call, one bit per
+    /// endpoint number. Isochronous packets dropped by the core aren't attributed to an endpoint
+    /// and only show in [`rx_overflow_count`](Self::rx_overflow_count).
+    pub fn take_rx_overflows(&self) -> u16 {
+        interrupt::free(|cs| {
+            let overflows = self.rx_overflows.borrow(cs);
+            let mut value = overflows.get();
+            let endpoints = value.endpoints;
+            value.endpoints = 0;
+            overflows.set(value);
+            endpoints
+        })
+    }
+
+    /// Returns how often the OUT endpoint `ep_addr` has NAKed the host, 0 if it isn't allocated.
+    ///
+    /// The core doesn't flag NAKed OUT tokens, so this counts the periods in which the driver
+    /// leaves the endpoint NAKing: the buffer has no room for another packet, a DMA packet
+    /// hasn't been read yet, or the endpoint waits for [`rearm_out`](Self::rearm_out). A count
+    /// growing with every packet means that the host is held up by the application's reads.
+    pub fn out_nak_count(&self, ep_addr: EndpointAddress) -> u32 {
+        if !ep_addr.is_out() {
+            return 0;
+        }
+
+        interrupt::free(|cs| {
+            let allocator = self.allocator.borrow(cs).borrow();
+            allocator.endpoints_out.get(ep_addr.index()).and_then(Option::as_ref).map_or(0, |ep| ep.nak_count(cs))
+        })
+    }
+
+    /// Returns how often the host has had to retry an IN transaction of the endpoint `ep_addr`,
+    /// 0 if it isn't allocated.
+    ///
+    /// This counts the IN tokens NAKed because the TX FIFO was empty (DIEPINT.ITTXFE) and the
+    /// timeouts of control transfers whose data the host didn't acknowledge (DIEPINT.TOC). The
+    /// flags are taken when the endpoint interrupt is handled, so each counts at most once per
+    /// completed transfer; a count close to the number of transfers means that the host polls
+    /// faster than the application writes.
+    pub fn in_retry_count(&self, ep_addr: EndpointAddress) -> u32 {
+        if !ep_addr.is_in() {
+            return 0;
+        }
+
+        interrupt::free(|cs| {
+            let allocator = self.allocator.borrow(cs).borrow();
+            allocator.endpoints_in.get(ep_addr.index()).and_then(Option::as_ref).map_or(0, |ep| ep.retry_count(cs))
+        })
+    }
+
+    /// Returns the isochronous IN endpoints that have dropped a packet since the last call, one
+    /// bit per endpoint number.
+    ///
+    /// A packet that hasn't been sent in the (micro)frame it was written for (GINTSTS.IISOIXFR)
+    /// is flushed from the TX FIFO and the write is reported as complete, so the endpoint is
+    /// ready for the payload of the next frame.
+    #[cfg(feature = "iso")]
+    pub fn take_missed_iso_in(&self) -> u16 {
+        interrupt::free(|cs| self.missed_iso_in.borrow(cs).replace(0))
+    }
+
+    /// Records a dropped packet of the OUT endpoint `ep_number`, if it's known.
+    fn record_rx_overflow(&self, cs: &CriticalSection, ep_number: Option<usize>) {
+        let overflows = self.rx_overflows.borrow(cs);
+        let mut value = overflows.get();
+        value.count = value.count.wrapping_add(1);
+        if let Some(ep_number) = ep_number {
+            value.endpoints |= 1 << ep_number;
+        }
+        overflows.set(value);
+        trace!(self, cs, TraceEvent::RxOverflow { ep_number: ep_number.map(|n| n as u8) });
+    }
+
+    /// Returns true if the device is connected to a host.
+    ///
+    /// The device is considered connected from the first bus reset until the end of the session
+    /// is detected. Session end detection requires VBUS sensing, see
+    /// [`Config::vbus_sensing`] and [`UsbPeripheral::vbus_present`]. While disconnected, `write()` fails with
+    /// `UsbError::InvalidState` instead of waiting for a host that is gone.
+    pub fn is_connected(&self) -> bool {
+        interrupt::free(|cs| self.connected.borrow(cs).get())
+    }
+
+    /// Returns true while a wait started by the interrupt handler is pending: the reconnect after
+    /// an erratic error or a brown-out, or the debounce of a VBUS change.
+    ///
+    /// The core raises no interrupt when such a wait is over, `poll()` finishes it. In interrupt
+    /// mode the application has to keep calling `poll()` while this returns true, e.g. from a
+    /// timer, otherwise the device may stay disconnected.
+    pub fn needs_poll(&self) -> bool {
+        interrupt::free(|cs| self.reconnect_since.borrow(cs).get().is_some() || self.vbus_change.borrow(cs).get().is_some())
+    }
+
+    // The status getters below only read read-only registers, so they don't need a critical
+    // section and can be called at any rate without adding interrupt latency.
+
+    /// Returns true if the USB link is in the suspended state (DSTS.SUSPSTS).
+    pub fn is_suspended(&self) -> bool {
+        Self::core().is_suspended()
+    }
+
+    /// Returns the number of the last (micro)frame received from the host (DSTS.FNSOF).
+    ///
+    /// On a high-speed bus this counts microframes, see [`frame`](Self::frame).
+    pub fn frame_number(&self) -> u16 {
+        Self::core().frame_number()
+    }
+
+    /// Returns the frame and, on a high-speed bus, the 3-bit microframe counter of the last SOF
+    /// received, so isochronous streams can be phased at 125 µs resolution.
+    pub fn frame(&self) -> Frame {
+        Self::core().frame()
+    }
+
+    /// Returns the arrival time, taken with [`UsbPeripheral::timestamp`], of the data last
+    /// returned by `read()` on the OUT endpoint `ep_addr`. When `read()` returns several
+    /// packets at once, this is the time of the first one.
+    ///
+    /// `None` if the peripheral provides no clock, before the first packet, and for data received
+    /// with [`start_read`](Self::start_read).
+    pub fn packet_timestamp(&self, ep_addr: EndpointAddress) -> Option<u32> {
+        if !ep_addr.is_out() {
+            return None;
+        }
+
+        interrupt::free(|cs| {
+            let allocator = self.allocator.borrow(cs).borrow();
+            allocator.endpoints_out.get(ep_addr.index())?.as_ref()?.read_timestamp(cs)
+        })
+    }
+
+    /// Returns the (micro)frame the packet last returned by `read()` on the isochronous OUT
+    /// endpoint `ep_addr` arrived in, `None` for other endpoints or before the first packet.
+    ///
+    /// Consecutive packets of a stream arrive in consecutive (micro)frames of the endpoint's
+    /// interval, a gap reveals a dropped packet, e.g. for the clock recovery of an audio sink.
+    #[cfg(feature = "iso")]
+    pub fn iso_out_frame(&self, ep_addr: EndpointAddress) -> Option<Frame> {
+        if !ep_addr.is_out() {
+            return None;
+        }
+
+        interrupt::free(|cs| {
+            let allocator = self.allocator.borrow(cs).borrow();
+            allocator.endpoints_out.get(ep_addr.index())?.as_ref()?.read_frame(cs)
+        })
+    }
+
+    /// Returns the speed enumerated at the last bus reset (DSTS.ENUMSPD).
+    pub fn speed(&self) -> Speed {
+        Self::core().speed()
+    }
+
+    /// Returns true if the device is configured for high speed but the last bus reset has
+    /// enumerated full speed: the host or a hub between them didn't answer the chirp, e.g. on a
+    /// full-speed only port.
+    ///
+    /// The driver adapts the turnaround time and the EP0 packet size, the application should
+    /// switch its endpoints to full-speed packet sizes, see
+    /// [`reconfigure_ep`](Self::reconfigure_ep), and can tell the user that the port limits the
+    /// throughput.
+    pub fn is_full_speed_fallback(&self) -> bool {
+        interrupt::free(|cs| self.full_speed_fallback.borrow(cs).get())
+    }
+
+    /// Returns how far the host has got with enumerating the device since the last bus reset.
+    ///
+    /// With [`Config::enumeration_timeout_ms`](crate::Config::enumeration_timeout_ms) set, a
+    /// host that resets the device but doesn't configure it in time leaves it
+    /// `EnumerationState::TimedOut`. The product can then fall back to charging only, or try
+    /// again with [`re_enumerate`](Self::re_enumerate). The state is updated by `poll()` and
+    /// [`on_interrupt`](Self::on_interrupt).
+    pub fn enumeration_state(&self) -> EnumerationState {
+        interrupt::free(|cs| self.enumeration.borrow(cs).get().state)
+    }
+
+    /// Returns the error that made the last attempt to enable the peripheral fail, if any.
+    ///
+    /// `enable()` is called by `UsbDeviceBuilder::build()` and can't report errors itself. After
+    /// a failure the core is left unconfigured and detached, so the application can e.g.
+    /// power-cycle the PHY and call [`UsbBus::try_enable`]. Until then `poll()` leaves the core
+    /// alone and reports nothing. A configuration the core doesn't support
+    /// (`Error::CoreUnsupported`, `Error::InvalidConfig`) is rejected before the core is powered
+    /// up.
+    pub fn enable_error(&self) -> Option<Error> {
+        interrupt::free(|cs| self.enable_error.borrow(cs).get())
+    }
+
+    /// Returns why the last endpoint allocation failed, if any.
+    ///
+    /// `UsbBusAllocator` reports the failures as `UsbError`s, e.g. `EndpointMemoryOverflow` both
+    /// when the FIFO RAM is full and when the endpoint memory is too small. The error is kept
+    /// until the next allocation succeeds.
+    pub fn alloc_error(&self) -> Option<Error> {
+        interrupt::free(|cs| self.alloc_error.borrow(cs).get())
+    }
+
+    /// Removes and returns the oldest record of the trace log, if any.
+    ///
+    /// With the `trace` feature the driver records its events, from bus state changes to every
+    /// packet received or sent, tagged with the (micro)frame number they happened in. The frame
+    /// numbers line up with the SOF packets in a USB analyzer capture, which helps to debug
+    /// timing problems of isochronous and interrupt endpoints. The log keeps the last
+    /// [`TRACE_LOG_LEN`](crate::trace::TRACE_LOG_LEN) records, drain it e.g. from the main loop.
+    #[cfg(feature = "trace")]
+    pub fn next_trace_record(&self) -> Option<TraceRecord> {
+        interrupt::free(|cs| self.trace_log.borrow(cs).borrow_mut().pop())
+    }
+
+    /// Returns the number of trace records that were overwritten before they were read.
+    #[cfg(feature = "trace")]
+    pub fn lost_trace_records(&self) -> u32 {
+        interrupt::free(|cs| self.trace_log.borrow(cs).borrow().lost())
+    }
+
+    #[cfg(feature = "trace")]
+    fn trace(&self, cs: &CriticalSection, event: TraceEvent) {
+        let record = TraceRecord {
+            frame_number: Self::core().frame_number(),
+            event,
         };
+        self.trace_log.borrow(cs).borrow_mut().push(record);
+    }
+
+    /// Enables the peripheral and returns the error `enable()` can only record, e.g.
+    /// `Error::PhyClockMissing` when the core doesn't become ready because its 48 MHz clock isn't
+    /// running. Call it again after [`UsbBus::enable_error`] reported a failure and the cause has
+    /// been fixed.
+    pub fn try_enable(&self) -> core::result::Result<(), Error> {
+        let result = self.initialize();
+        interrupt::free(|cs| self.enable_error.borrow(cs).set(result.err()));
+        result
+    }
+
+    /// Waits for the AHB master of the core to become idle, which requires the PHY clock.
+    fn wait_ahb_idle(&self) -> core::result::Result<(), Error> {
+        self.wait_core(EnableStep::AhbIdle, |regs| read_reg!(otg_global, regs.global, GRSTCTL, AHBIDL) != 0)
+    }
+
+    /// Polls `ready` for up to `AHB_IDLE_TIMEOUT_US`, reporting `step` to the progress callback
+    /// every millisecond. The core makes no progress without the PHY clock, so a timeout fails
+    /// with `Error::PhyClockMissing`.
+    fn wait_core(&self, step: EnableStep, ready: impl Fn(&UsbRegisters<USB>) -> bool) -> core::result::Result<(), Error> {
+        let regs = UsbRegisters::<USB>::new();
+        for i in 0..AHB_IDLE_TIMEOUT_US / 10 {
+            if ready(&regs) {
+                return Ok(());
+            }
+            if i % 100 == 0 {
+                self.report_progress(step);
+            }
+            USB::delay_us(10);
+        }
+        Err(Error::PhyClockMissing)
+    }
+
+    /// Blocks for `us` microseconds, reporting `step` to the progress callback every millisecond.
+    fn delay_with_progress(&self, step: EnableStep, us: u32) {
+        let mut remaining = us;
+        while remaining > 0 {
+            self.report_progress(step);
+            let delay = core::cmp::min(remaining, 1_000);
+            USB::delay_us(delay);
+            remaining -= delay;
+        }
+    }
+
+    fn report_progress(&self, step: EnableStep) {
+        if let Some(callback) = self.config.enable_progress {
+            callback(step);
+        }
+    }
+
+    /// Resets the state machines of the core and flushes its FIFOs (GRSTCTL.CSRST), whatever a
+    /// bootloader or the firmware before a warm reboot has left in them. The configuration
+    /// registers keep their values.
+    fn soft_reset_core(&self) -> core::result::Result<(), Error> {
+        self.wait_ahb_idle()?;
+
+        let regs = UsbRegisters::<USB>::new();
+        modify_reg!(otg_global, regs.global, GRSTCTL, CSRST: 1);
+        self.wait_core(EnableStep::CoreReset, |regs| read_reg!(otg_global, regs.global, GRSTCTL, CSRST) == 0)?;
+
+        // The core must not be accessed for 3 PHY clocks after the reset
+        USB::delay_us(CORE_RESET_DELAY_US);
+        self.wait_ahb_idle()
+    }
+
+    /// Checks that the core supports the configuration and that the options fit together.
+    fn check_config(config: &Config) -> core::result::Result<(), Error> {
+        if !Self::is_hs_core()
+            && (Self::phy_type(config) != PhyType::InternalFullSpeed
+                || config.tx_threshold_words.is_some()
+                || config.burst_length.is_some()
+                || config.dma)
+        {
+            return Err(Error::CoreUnsupported);
+        }
+
+        let ulpi_options = config.ulpi_fs_ls || config.ulpi_auto_resume || config.ulpi_clock_suspend;
+        if ulpi_options && Self::phy_type(config) != PhyType::ExternalHighSpeed {
+            return Err(Error::InvalidConfig);
+        }
+        // DTHRCTL.TXTHRLEN has 9 bits
+        if matches!(config.tx_threshold_words, Some(threshold) if threshold > 0x1ff) {
+            return Err(Error::InvalidConfig);
+        }
+        // Without DMA, write() fills the FIFO after enabling the endpoint, with interrupts
+        // enabled, so the core could run out of data in the middle of a thresholded packet
+        if config.tx_threshold_words.is_some() && !config.dma {
+            return Err(Error::InvalidConfig);
+        }
+        // Without the CID table nothing else picks the re-arm point
+        if !cfg!(feature = "quirks") && config.out_rearm_point.is_none() {
+            return Err(Error::InvalidConfig);
+        }
+
+        Ok(())
+    }
+
+    /// Powers the core up and configures it as a device.
+    fn initialize(&self) -> core::result::Result<(), Error> {
+        Self::check_config(&self.config)?;
+
+        // Enable USB_OTG in RCC
+        USB::enable();
+
+        // A device must not drive VBUS
+        USB::set_vbus_drive(false);
+
+        // The PHY is selected before the reset, which puts the core to work with it
+        #[cfg(feature = "hs")]
+        {
+            let regs = UsbRegisters::<USB>::new();
+            modify_reg!(otg_global, regs.global, GUSBCFG,
+                PHYSEL: (Self::phy_type(&self.config) == PhyType::InternalFullSpeed) as u32
+            );
+        }
+
+        // Start from a known state, this never completes without the PHY clock
+        self.soft_reset_core()?;
+
+        let device_mode = interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            // A bus re-created after shutdown() finds the core in device mode already, a
+            // device-only core is always in it
+            let device_mode = USB::DEVICE_ONLY
+                || (read_reg!(otg_global, regs.global, GUSBCFG, FDMOD) != 0
+                    && read_reg!(otg_global, regs.global, GINTSTS, CMOD) == 0);
+
+            // Configure OTG as device
+            if !USB::DEVICE_ONLY {
+                modify_reg!(otg_global, regs.global, GUSBCFG,
+                    SRPCAP: 0, // SRP capability is not enabled
+                    FDMOD: 1 // Force device mode
+                );
+            }
+            #[cfg(not(feature = "hs"))]
+            modify_reg!(otg_global, regs.global, GUSBCFG, TRDT: Self::turnaround_time(Speed::Full));
+            #[cfg(feature = "hs")]
+            modify_reg!(otg_global, regs.global, GUSBCFG,
+                TRDT: Self::turnaround_time(if Self::is_high_speed(&self.config) { Speed::High } else { Speed::Full })
+            );
+            // FS cores keep the reset value unless configured
+            let tocal = match self.config.timeout_calibration {
+                None if Self::is_hs_core() => Some(0x1),
+                tocal => tocal,
+            };
+            if let Some(tocal) = tocal {
+                modify_reg!(otg_global, regs.global, GUSBCFG, TOCAL: tocal as u32);
+            }
+            #[cfg(feature = "hs")]
+            {
+                if Self::is_hs_core() && Self::phy_type(&self.config) == PhyType::ExternalHighSpeed {
+                    modify_reg!(otg_global, regs.global, GUSBCFG,
+                        ULPIFSLS: self.config.ulpi_fs_ls as u32,
+                        ULPIAR: self.config.ulpi_auto_resume as u32,
+                        ULPICSM: self.config.ulpi_clock_suspend as u32
+                    );
+                }
+            }
+
+            device_mode
+        });
+
+        // The forced mode takes effect after 25ms, interrupts are served in the meantime
+        if !device_mode {
+            self.delay_with_progress(EnableStep::ModeSwitch, 25_000);
+        }
+
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            // Configuring Vbus sense and SOF output
+            if self.config.vbus_sensing {
+                write_reg!(otg_global, regs.global, GCCFG, VBUSBSEN: 1);
+            } else {
+                write_reg!(otg_global, regs.global, GCCFG, 1 << 21); // set NOVBUSSENS
+            }
+            if Self::phy_type(&self.config) == PhyType::InternalHighSpeed {
+                let gccfg = read_reg!(otg_global, regs.global, GCCFG);
+                write_reg!(otg_global, regs.global, GCCFG, gccfg | (1 << 23)); // set PHYHSEN
+            }
+
+            // Enable PHY clock
+            write_reg!(otg_pwrclk, regs.pwrclk, PCGCCTL, 0);
+
+            // Soft disconnect device
+            Self::core().set_soft_disconnect(true);
+
+            // Setup USB speed and frame interval
+            if Self::is_high_speed(&self.config) {
+                modify_reg!(otg_device, regs.device, DCFG,
+                    PFIVL: self.config.periodic_frame_interval as u32,
+                    DSPD: 0b00 // Device speed: High speed
+                );
+            } else if self.config.ulpi_fs_ls {
+                modify_reg!(otg_device, regs.device, DCFG,
+                    PFIVL: self.config.periodic_frame_interval as u32,
+                    DSPD: 0b01 // Device speed: Full speed using the HS PHY
+                );
+            } else {
+                modify_reg!(otg_device, regs.device, DCFG,
+                    PFIVL: self.config.periodic_frame_interval as u32,
+                    DSPD: 0b11 // Device speed: Full speed
+                );
+            }
+
+            // Setup IN transmission thresholding, a bus created after a dropped one doesn't
+            // inherit its setup
+            #[cfg(feature = "hs")]
+            {
+                if let Some(threshold) = self.config.tx_threshold_words.filter(|_| Self::is_hs_core()) {
+                    write_reg!(otg_device, regs.device, DTHRCTL,
+                        TXTHRLEN: threshold as u32,
+                        ISOTHREN: 1,
+                        NONISOTHREN: 1
+                    );
+                } else if Self::is_hs_core() {
+                    write_reg!(otg_device, regs.device, DTHRCTL, 0);
+                }
+            }
+
+            // Setup AHB burst length and DMA
+            #[cfg(feature = "hs")]
+            {
+                if Self::is_hs_core() {
+                    modify_reg!(otg_global, regs.global, GAHBCFG,
+                        HBSTLEN: self.config.burst_length.unwrap_or(crate::config::BurstLength::Single) as u32,
+                        DMAEN: self.dma_enabled() as u32
+                    );
+                }
+            }
+
+            // TXFE signals a completely empty TX FIFO
+            let txfelvl = (1..MAX_ENDPOINTS).any(|ep_number| self.config.fifo_empty_completion(ep_number));
+            modify_reg!(otg_global, regs.global, GAHBCFG, TXFELVL: txfelvl as u32);
+
+            // Clear the address and FIFO layout the previous bus may have left behind
+            modify_reg!(otg_device, regs.device, DCFG, DAD: 0);
+            self.configure_fifos(cs);
+
+            // unmask EP interrupts, OUT endpoint interrupts are used in DMA mode only
+            write_reg!(otg_device, regs.device, DIEPMSK, XFRCM: 1);
+            write_reg!(otg_device, regs.device, DOEPMSK, XFRCM: 1, STUPM: 1);
+
+            self.unmask_core_interrupts(regs);
+
+            // clear pending interrupts
+            write_reg!(otg_global, regs.global, GINTSTS, 0xffffffff);
+
+            // unmask global interrupt
+            modify_reg!(otg_global, regs.global, GAHBCFG, GINT: 1);
+
+            // power up the transceiver
+            if Self::phy_type(&self.config) == PhyType::InternalFullSpeed {
+                modify_reg!(otg_global, regs.global, GCCFG, PWRDWN: 1);
+            }
+
+            // connect(true)
+            if self.config.attach_on_enable {
+                Self::core().set_soft_disconnect(false);
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Connects the device to the bus by enabling the D+ pull-up.
+    pub fn attach(&self) {
+        interrupt::free(|cs| {
+            self.reconnect_since.borrow(cs).set(None);
+            Self::core().set_soft_disconnect(false);
+        });
+    }
+
+    /// Disconnects the device from the bus by disabling the D+ pull-up.
+    pub fn detach(&self) {
+        interrupt::free(|cs| {
+            self.reconnect_since.borrow(cs).set(None);
+            Self::core().set_soft_disconnect(true);
+        });
+    }
+
+    /// Forces the host to enumerate the device again, e.g. after a firmware update or when the
+    /// descriptors have changed.
+    ///
+    /// The device is disconnected for `disconnect_us` microseconds, a few milliseconds are
+    /// enough for most hosts to notice. Its address is cleared before it connects again. The
+    /// delay runs outside of a critical section.
+    pub fn re_enumerate(&self, disconnect_us: u32) {
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+            self.reconnect_since.borrow(cs).set(None);
+            Self::core().set_soft_disconnect(true);
+            modify_reg!(otg_device, regs.device, DCFG, DAD: 0);
+            self.remote_wakeup_enabled.borrow(cs).set(false);
+        });
+
+        USB::delay_us(disconnect_us);
+
+        self.attach();
+    }
+
+    /// Quiesces the peripheral, so that the bus can be dropped and a new one created right away,
+    /// e.g. to switch between the runtime and the DFU descriptor sets.
+    ///
+    /// The device is detached and its address cleared, the endpoints are disabled and the
+    /// interrupts masked. The core stays in device mode, which lets `enable()` of the new bus skip
+    /// the 25 ms mode switch. Create the new bus with
+    /// [`Config::attach_on_enable`](crate::Config::attach_on_enable) disabled and connect it with
+    /// [`re_enumerate`](Self::re_enumerate), which keeps the device disconnected for a precise
+    /// time before the host sees the new descriptors.
+    pub fn shutdown(&self) {
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+            self.reconnect_since.borrow(cs).set(None);
+            Self::core().set_soft_disconnect(true);
+            modify_reg!(otg_device, regs.device, DCFG, DAD: 0);
+
+            modify_reg!(otg_global, regs.global, GAHBCFG, GINT: 0);
+            write_reg!(otg_global, regs.global, GINTMSK, 0);
+
+            // Disabling the endpoints needs the PHY clock
+            self.exit_low_power(cs, regs);
+            self.deconfigure_all(cs);
+            write_reg!(otg_global, regs.global, GINTSTS, 0xffffffff);
+
+            self.connected.borrow(cs).set(false);
+            self.remote_wakeup_enabled.borrow(cs).set(false);
+            self.pending.borrow(cs).set(PendingEvents::default());
+            self.enumeration.borrow(cs).set(Enumeration::default());
+        });
+    }
+
+    /// Lets the OUT endpoint `ep_addr` accept the next packet.
+    ///
+    /// Only needed with [`Config::manual_out_rearm`], after the previous packet has been read.
+    /// Fails with `Error::BufferNotEmpty` while the endpoint buffer still holds a packet.
+    pub fn rearm_out(&self, ep_addr: EndpointAddress) -> core::result::Result<(), Error> {
+        if !ep_addr.is_out() || ep_addr.index() >= Self::endpoint_count() {
+            return Err(Error::EndpointNotAllocated);
+        }
+
+        interrupt::free(|cs| {
+            let allocator = self.allocator.borrow(cs).borrow();
+            let ep = allocator.endpoints_out[ep_addr.index()].as_ref().ok_or(Error::EndpointNotAllocated)?;
+            if ep.buffer_state() != EndpointBufferState::Empty {
+                return Err(Error::BufferNotEmpty);
+            }
+            ep.reenable(cs);
+            Ok(())
+        })
+    }
+
+    /// Returns how many bytes `write()` on the IN endpoint `ep_addr` accepts right now, so that a
+    /// streaming loop can size its writes instead of running into `WouldBlock`.
+    ///
+    /// This is the free space of the endpoint's TX FIFO (DTXFSTS), capped at the largest write
+    /// the endpoint accepts, and 0 while the previous transfer is still in progress.
+    pub fn tx_fifo_available(&self, ep_addr: EndpointAddress) -> core::result::Result<usize, Error> {
+        if !ep_addr.is_in() || ep_addr.index() >= Self::endpoint_count() {
+            return Err(Error::EndpointNotAllocated);
+        }
+
+        interrupt::free(|cs| {
+            let allocator = self.allocator.borrow(cs).borrow();
+            let ep = allocator.endpoints_in[ep_addr.index()].as_ref().ok_or(Error::EndpointNotAllocated)?;
+            if self.tx_filling.borrow(cs).get() & (1 << ep_addr.index()) != 0 {
+                return Ok(0);
+            }
+            Ok(ep.available_write_size())
+        })
+    }
+
+    /// Queues `buf` for a transfer of up to `buf.len()` bytes from the OUT endpoint `ep_addr`.
+    ///
+    /// The interrupt handler copies the packets from the RX FIFO straight into the oldest queued
+    /// buffer instead of the endpoint buffer, data buffered already is copied first. A transfer
+    /// ends when its buffer is full or the host sends a short packet; `poll()` then reports the
+    /// endpoint and [`take_read`](Self::take_read) returns the received data. The next packet
+    /// goes into the next queued buffer, so with buffers registered ahead of time the endpoint
+    /// never waits for the application. See also [`Config::direct_read`].
+    ///
+    /// Fails with `Error::ReadQueueFull` when [`READ_QUEUE_LEN`](crate::READ_QUEUE_LEN) buffers
+    /// are queued, and with `Error::InvalidConfig` in DMA mode. The error comes with `buf`, which
+    /// is left untouched and can be queued again later. A bus reset ends all the queued transfers.
+    pub fn start_read(&self, ep_addr: EndpointAddress, buf: &'static mut [u8]) -> core::result::Result<(), (Error, &'static mut [u8])> {
+        if !ep_addr.is_out() || ep_addr.index() >= Self::endpoint_count() {
+            return Err((Error::EndpointNotAllocated, buf));
+        }
+        if self.dma_enabled() {
+            return Err((Error::InvalidConfig, buf));
+        }
+
+        interrupt::free(move |cs| {
+            let allocator = self.allocator.borrow(cs).borrow();
+            let ep = match allocator.endpoints_out[ep_addr.index()].as_ref() {
+                Some(ep) => ep,
+                None => return Err((Error::EndpointNotAllocated, buf)),
+            };
+            ep.start_transfer(cs, buf)?;
+
+            // A packet waiting in the FIFO can go into the transfer now
+            let regs = self.regs.borrow(cs);
+            modify_reg!(otg_global, regs.global, GINTMSK, RXFLVLM: 1);
+            Ok(())
+        })
+    }
+
+    /// Returns the received part of the oldest buffer passed to [`start_read`](Self::start_read)
+    /// once its transfer has ended, `None` before that. The length of the returned slice is the
+    /// length of the transfer.
+    pub fn take_read(&self, ep_addr: EndpointAddress) -> Option<&'static mut [u8]> {
+        self.take_transfer(ep_addr, false)
+    }
+
+    /// Ends the oldest transfer queued with [`start_read`](Self::start_read) and returns the
+    /// received part of its buffer.
+    pub fn cancel_read(&self, ep_addr: EndpointAddress) -> Option<&'static mut [u8]> {
+        self.take_transfer(ep_addr, true)
+    }
+
+    fn take_transfer(&self, ep_addr: EndpointAddress, cancel: bool) -> Option<&'static mut [u8]> {
+        if !ep_addr.is_out() {
+            return None;
+        }
+
+        interrupt::free(|cs| {
+            let allocator = self.allocator.borrow(cs).borrow();
+            allocator.endpoints_out.get(ep_addr.index())?.as_ref()?.take_transfer(cs, cancel)
+        })
+    }
+
+    /// Returns the pending core and endpoint interrupts.
+    ///
+    /// The interrupts are only read, not acknowledged, so this doesn't interfere with `poll()`.
+    pub fn read_events(&self) -> Events {
+        interrupt::free(|_| Self::core().events())
+    }
+
+    /// Returns the state of the OTG session bits.
+    pub fn otg_status(&self) -> OtgStatus {
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+            OtgStatus::from_bits(read_reg!(otg_global, regs.global, GOTGCTL))
+        })
+    }
+
+    /// Returns the next pending OTG event, if any.
+    ///
+    /// Events are collected by `poll()`, each event kind is reported once no matter how many
+    /// times it occurred since the previous call.
+    pub fn next_otg_event(&self) -> Option<OtgEvent> {
+        interrupt::free(|cs| {
+            let events = self.otg_events.borrow(cs);
+            let mut pending = events.get();
+            let event = OtgEvent::pop(&mut pending);
+            events.set(pending);
+            event
+        })
+    }
+
+    fn push_otg_event(&self, cs: &CriticalSection, event: OtgEvent) {
+        let events = self.otg_events.borrow(cs);
+        events.set(events.get() | event.mask());
+    }
+
+    /// Returns the current role of the peripheral.
+    pub fn otg_role(&self) -> OtgRole {
+        interrupt::free(|cs| self.role.borrow(cs).get())
+    }
+
+    /// Registers a callback invoked with the old and the new role whenever the role changes,
+    /// after a successful HNP or when the ID pin changes. The driver only implements the device
+    /// role, on a change to `OtgRole::Host` the application has to hand over to a host stack.
+    ///
+    /// The callback runs from `poll()` or [`on_interrupt`](Self::on_interrupt) within a critical
+    /// section, keep it short.
+    pub fn on_role_change(&self, callback: RoleChangeCallback) {
+        interrupt::free(|cs| self.role_change_callback.borrow(cs).set(Some(callback)));
+    }
+
+    fn change_role(&self, cs: &CriticalSection, role: OtgRole) {
+        let old_role = self.role.borrow(cs).replace(role);
+        if old_role != role {
+            if let Some(callback) = self.role_change_callback.borrow(cs).get() {
+                callback(old_role, role);
+            }
+        }
+    }
+
+    /// Services the USB interrupt, to be called from the OTG interrupt handler.
+    ///
+    /// Acknowledges the hardware events and moves the received packets into the endpoint buffers,
+    /// so the interrupt doesn't stay pending and no data is lost when `UsbDevice::poll` runs later
+    /// in thread context. The events are kept until `poll()` reports them.
+    ///
+    /// The reconnect after an erratic error or a brown-out and the VBUS debounce are only started
+    /// here and finished by a later `poll()`. While they are pending, see
+    /// [`needs_poll`](Self::needs_poll), `poll()` must run even without another interrupt.
+    pub fn on_interrupt(&self) {
+        interrupt::free(|cs| {
+            self.count_spurious_interrupt(cs);
+            self.service_interrupts(cs);
+        });
+    }
+
+    /// Sets the driver up for interrupt mode: the OTG interrupts get `priority` and are unmasked
+    /// in the interrupt controller through the [`UsbPeripheral`] hooks. Their handlers must call
+    /// [`on_interrupt`](Self::on_interrupt).
+    pub fn enable_interrupts(&self, priority: u8) {
+        USB::set_interrupt_priority(priority);
+        USB::set_interrupts_enabled(true);
+    }
+
+    /// Masks the OTG interrupts in the interrupt controller again, the core is then only serviced
+    /// by `poll()`.
+    pub fn disable_interrupts(&self) {
+        USB::set_interrupts_enabled(false);
+    }
+
+    /// Counts the interrupt if none of the causes `service_interrupts` handles is pending.
+    fn count_spurious_interrupt(&self, cs: &CriticalSection) {
+        use otg_global::GINTSTS;
+
+        const HANDLED: u32 = GINTSTS::WKUPINT::mask | GINTSTS::USBSUSP::mask | GINTSTS::ESUSP::mask
+            | GINTSTS::ENUMDNE::mask | GINTSTS::USBRST::mask | GINTSTS::IEPINT::mask
+            | GINTSTS::OEPINT::mask | GINTSTS::RXFLVL::mask | GINTSTS::OTGINT::mask
+            | GINTSTS::SRQINT::mask | GINTSTS::CIDSCHG::mask | GINTSTS::ISOODRP::mask
+            | GINTSTS::IISOIXFR::mask;
+
+        let regs = self.regs.borrow(cs);
+        let pending = read_reg!(otg_global, regs.global, GINTSTS) & read_reg!(otg_global, regs.global, GINTMSK);
+        if pending & HANDLED == 0 {
+            let spurious = self.spurious_interrupts.borrow(cs);
+            spurious.set(spurious.get().wrapping_add(1));
+        }
+    }
+
+    /// Registers `waker` to be woken by `poll()` the next time the endpoint `ep_addr` completes a
+    /// write (IN) or has a packet to read (OUT, including SETUP packets).
+    ///
+    /// A registration is used up by the wakeup, async tasks register again every time they are
+    /// polled. Registering replaces the previous waker of the endpoint.
+    pub fn register_waker(&self, ep_addr: EndpointAddress, waker: &Waker) -> core::result::Result<(), Error> {
+        if ep_addr.index() >= Self::endpoint_count() {
+            return Err(Error::EndpointUnavailable);
+        }
+
+        interrupt::free(|cs| {
+            self.wakers.borrow(cs).borrow_mut().register(ep_addr, waker);
+        });
+        Ok(())
+    }
+
+    /// Requests the host role with the host negotiation protocol (GOTGCTL.HNPRQ).
+    ///
+    /// The host must have enabled HNP with SET_FEATURE(b_hnp_enable) and the bus must be
+    /// suspended, otherwise this fails with `Error::HostNegotiationDisabled` or
+    /// `Error::NotSuspended`. The outcome is reported by
+    /// [`next_otg_event`](Self::next_otg_event) as `OtgEvent::HostNegotiationSuccess` or
+    /// `OtgEvent::HostNegotiationFailure`. The driver itself only implements the device role.
+    pub fn request_host_role(&self) -> core::result::Result<(), Error> {
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            if read_reg!(otg_global, regs.global, GOTGCTL, DHNPEN) == 0 {
+                return Err(Error::HostNegotiationDisabled);
+            }
+            if read_reg!(otg_device, regs.device, DSTS, SUSPSTS) == 0 {
+                return Err(Error::NotSuspended);
+            }
+
+            modify_reg!(otg_global, regs.global, GUSBCFG, HNPCAP: 1);
+            modify_reg!(otg_global, regs.global, GOTGCTL, HNPRQ: 1);
+
+            Ok(())
+        })
+    }
+
+    /// Returns true if the host has enabled remote wakeup with SET_FEATURE(DEVICE_REMOTE_WAKEUP).
+    ///
+    /// The flag is cleared by CLEAR_FEATURE(DEVICE_REMOTE_WAKEUP) and by a bus reset. It follows
+    /// `UsbDevice::remote_wakeup_enabled()`, except that the request is ignored unless the
+    /// configuration descriptor advertises remote wakeup, see
+    /// [`remote_wakeup_supported`](Self::remote_wakeup_supported).
+    pub fn remote_wakeup_enabled(&self) -> bool {
+        interrupt::free(|cs| self.remote_wakeup_enabled.borrow(cs).get())
+    }
+
+    /// Returns true if the configuration descriptor last sent to the host has the remote wakeup
+    /// bit set, i.e. the device was built with `UsbDeviceBuilder::supports_remote_wakeup(true)`.
+    pub fn remote_wakeup_supported(&self) -> bool {
+        interrupt::free(|cs| self.remote_wakeup_supported.borrow(cs).get())
+    }
+
+    /// Signals remote wakeup to the host.
+    ///
+    /// Fails with `Error::RemoteWakeupDisabled` if the device doesn't advertise remote wakeup or
+    /// the host has not enabled it, and with `Error::NotSuspended` if the link is not suspended.
+    pub fn remote_wakeup(&self) -> core::result::Result<(), Error> {
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            if !self.remote_wakeup_supported.borrow(cs).get() || !self.remote_wakeup_enabled.borrow(cs).get() {
+                return Err(Error::RemoteWakeupDisabled);
+            }
+            if read_reg!(otg_device, regs.device, DSTS, SUSPSTS) == 0 {
+                return Err(Error::NotSuspended);
+            }
+
+            self.exit_low_power(cs, regs);
+            modify_reg!(otg_device, regs.device, DCTL, RWUSIG: 1);
+
+            Ok(())
+        })?;
+
+        // Resume signaling must last 1-15ms
+        USB::delay_us(5_000);
+
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+            modify_reg!(otg_device, regs.device, DCTL, RWUSIG: 0);
+        });
+
+        Ok(())
+    }
+
+    fn enter_low_power(&self, cs: &CriticalSection, regs: &UsbRegisters<USB>) {
+        if !self.config.suspend_power_down || self.low_power.borrow(cs).replace(true) {
+            return;
+        }
+
+        if Self::phy_type(&self.config) == PhyType::InternalFullSpeed {
+            modify_reg!(otg_global, regs.global, GCCFG, PWRDWN: 0);
+        }
+        modify_reg!(otg_pwrclk, regs.pwrclk, PCGCCTL, STPPCLK: 1);
+        modify_reg!(otg_pwrclk, regs.pwrclk, PCGCCTL, GATEHCLK: 1);
+
+        if self.config.suspend_stop_mode {
+            USB::prepare_stop();
+        }
+    }
+
+    fn exit_low_power(&self, cs: &CriticalSection, regs: &UsbRegisters<USB>) {
+        if !self.low_power.borrow(cs).replace(false) {
+            return;
+        }
+
+        // The core runs from the clocks that STOP mode has switched off
+        if self.config.suspend_stop_mode {
+            USB::restore_clocks();
+        }
+
+        modify_reg!(otg_pwrclk, regs.pwrclk, PCGCCTL, GATEHCLK: 0);
+        modify_reg!(otg_pwrclk, regs.pwrclk, PCGCCTL, STPPCLK: 0);
+        if Self::phy_type(&self.config) == PhyType::InternalFullSpeed {
+            modify_reg!(otg_global, regs.global, GCCFG, PWRDWN: 1);
+        }
+    }
+
+    /// Tracks the remote wakeup feature selector in the SETUP packets addressed to the device, and
+    /// notes requests for the configuration descriptor.
+    fn snoop_setup_packet(&self, cs: &CriticalSection, setup: &[u8; 8]) {
+        const SET_FEATURE: u8 = 0x03;
+        const CLEAR_FEATURE: u8 = 0x01;
+        const GET_DESCRIPTOR: u8 = 0x06;
+        const SET_CONFIGURATION: u8 = 0x09;
+        const DEVICE_REMOTE_WAKEUP: u16 = 0x0001;
+        const B_HNP_ENABLE: u16 = 0x0003;
+        const CONFIGURATION_DESCRIPTOR: u8 = 0x02;
+
+        let (request_type, request) = (setup[0], setup[1]);
+        let value = u16::from_le_bytes([setup[2], setup[3]]);
+
+        // Any other request cancels a pending GET_DESCRIPTOR
+        let config_descriptor =
+            request_type == 0x80 && request == GET_DESCRIPTOR && setup[3] == CONFIGURATION_DESCRIPTOR;
+        self.config_descriptor_requested.borrow(cs).set(config_descriptor);
+
+        if request_type != 0x00 {
+            return;
+        }
+
+        match (request, value) {
+            (SET_FEATURE, DEVICE_REMOTE_WAKEUP) => {
+                // usb-device accepts the request either way, but a device that doesn't advertise
+                // remote wakeup must not signal it
+                let supported = self.remote_wakeup_supported.borrow(cs).get();
+                self.remote_wakeup_enabled.borrow(cs).set(supported);
+            }
+            (CLEAR_FEATURE, DEVICE_REMOTE_WAKEUP) => self.remote_wakeup_enabled.borrow(cs).set(false),
+            (SET_CONFIGURATION, 0) => self.set_enumeration_state(cs, EnumerationState::Addressed),
+            (SET_CONFIGURATION, _) => self.set_enumeration_state(cs, EnumerationState::Configured),
+            (SET_FEATURE, B_HNP_ENABLE) => {
+                // b_hnp_enable stays set until the next bus reset
+                let regs = self.regs.borrow(cs);
+                modify_reg!(otg_global, regs.global, GOTGCTL, DHNPEN: 1);
+            }
+            _ => {}
+        }
+    }
+
+    /// Records whether the configuration descriptor in the first packet of the GET_DESCRIPTOR data
+    /// stage advertises remote wakeup (bmAttributes bit 5).
+    fn snoop_config_descriptor(&self, cs: &CriticalSection, buf: &[u8]) {
+        const CONFIGURATION_DESCRIPTOR: u8 = 0x02;
+        const REMOTE_WAKEUP: u8 = 0x20;
+
+        if buf.len() >= 8 && buf[1] == CONFIGURATION_DESCRIPTOR {
+            self.remote_wakeup_supported.borrow(cs).set(buf[7] & REMOTE_WAKEUP != 0);
+        }
+    }
+
+    /// Marks the packets the core has received by DMA as available to the application.
+    #[cfg(feature = "hs")]
+    fn complete_dma_transfers(&self, cs: &CriticalSection, allocator: &EndpointAllocator) {
+        use crate::ral::endpoint0_out;
+
+        for ep in &allocator.endpoints_out {
+            if let Some(ep) = ep {
+                if ep.address().index() == 0 {
+                    let regs = endpoint0_out::instance(UsbRegisters::<USB>::base_address());
+                    let (xfrc, stup) = read_reg!(endpoint0_out, regs, DOEPINT0, XFRC, STUP);
+                    if stup != 0 {
+                        write_reg!(endpoint0_out, regs, DOEPINT0, XFRC: 1, STUP: 1, B2BSTUP: 1);
+                        let offset = ep.dma_setup_offset_words(cs);
+
+                        // A new SETUP aborts the control transfer in progress
+                        let mut buffer = ep.buffer.borrow(cs).borrow_mut();
+                        let (address, len) = (buffer.as_ptr() as usize, buffer.capacity());
+                        USB::invalidate_dcache(address, len);
+                        buffer.clear();
+                        buffer.complete_dma_setup(offset).ok();
+                        // Write the moved packet back, otherwise evicting the dirty lines would
+                        // overwrite the next packets written by DMA
+                        USB::clean_dcache(address, len);
+                        trace!(self, cs, TraceEvent::Setup);
+
+                        if let Some(setup) = buffer.setup_packet() {
+                            self.snoop_setup_packet(cs, &setup);
+                        }
+                    } else if xfrc != 0 {
+                        write_reg!(endpoint0_out, regs, DOEPINT0, XFRC: 1);
+                        let mut buffer = ep.buffer.borrow(cs).borrow_mut();
+                        USB::invalidate_dcache(buffer.as_ptr() as usize, ep.dma_received_size() as usize);
+                        buffer.complete_dma(ep.dma_received_size(), false).ok();
+                        trace!(self, cs, TraceEvent::OutPacket { ep_number: 0, size: ep.dma_received_size() });
+                    }
+                } else if Self::core().take_transfer_complete(ep.address().index() as u8, Direction::Out) {
+                    // There's no RX FIFO entry in DMA mode, the packet has just arrived
+                    ep.set_received_timestamp(cs, USB::timestamp());
+                    #[cfg(feature = "iso")]
+                    ep.set_received_frame(cs, Self::core().frame());
+                    // The endpoint NAKs until read() has taken the packet
+                    ep.record_nak(cs);
+                    let mut buffer = ep.buffer.borrow(cs).borrow_mut();
+                    USB::invalidate_dcache(buffer.as_ptr() as usize, ep.dma_received_size() as usize);
+                    buffer.complete_dma(ep.dma_received_size(), false).ok();
+                    trace!(self, cs, TraceEvent::OutPacket { ep_number: ep.address().index() as u8, size: ep.dma_received_size() });
+                }
+            }
+        }
+    }
+
+    /// Moves the packet at the head of the RX FIFO into the buffer of `ep`.
+    ///
+    /// Returns false, leaving the packet in the FIFO, if the buffer has no room for it yet.
+    fn receive_packet(&self, cs: &CriticalSection, ep: &EndpointOut, status: RxStatus, data_size: u16, rearm_point: Option<OutRearmPoint>) -> bool {
+        let core = Self::core();
+        let mut buffer = ep.buffer.borrow(cs).borrow_mut();
+        if status == RxStatus::SetupData {
+            // A SETUP retried by the host supersedes the one still waiting in
+            // the buffer, as well as any data of the aborted control transfer
+            buffer.clear();
+        }
+        let is_setup = status == RxStatus::SetupData;
+        if !is_setup && buffer.state() == EndpointBufferState::Empty && ep.receive_into_transfer(cs, data_size) {
+            // Straight into the buffer of the application
+            trace!(self, cs, TraceEvent::OutPacket { ep_number: ep.address().index() as u8, size: data_size });
+            if rearm_point == Some(OutRearmPoint::PacketReceived) {
+                drop(buffer);
+                ep.rearm_after_receive(cs);
+            }
+            return true;
+        }
+        if ep.is_direct() && data_size != 0 {
+            // Waits in the FIFO for read() or a transfer, the entry is seen again until then
+            if !ep.has_fifo_packet(cs) {
+                ep.set_received_timestamp(cs, USB::timestamp());
+            }
+            ep.set_fifo_packet(cs);
+            return false;
+        }
+        let first = buffer.state() == EndpointBufferState::Empty;
+        if !first && !buffer.can_accept(data_size, is_setup) {
+            return false;
+        }
+
+        core.pop_rx_entry();
+        if first {
+            ep.set_received_timestamp(cs, USB::timestamp());
+        }
+
+        if buffer.fill_from_fifo(UsbRegisters::<USB>::base_address(), data_size, is_setup).is_err() {
+            // Larger than the whole buffer, it can never be received
+            core.discard_packet(data_size);
+            self.record_rx_overflow(cs, Some(ep.address().index()));
+        } else if is_setup {
+            trace!(self, cs, TraceEvent::Setup);
+        } else {
+            trace!(self, cs, TraceEvent::OutPacket { ep_number: ep.address().index() as u8, size: data_size });
+        }
+
+        if let Some(setup) = buffer.setup_packet() {
+            self.snoop_setup_packet(cs, &setup);
+        }
+
+        // Re-enable the endpoint, F446-like chips only
+        if rearm_point == Some(OutRearmPoint::PacketReceived) {
+            drop(buffer);
+            ep.rearm_after_receive(cs);
+        }
+
+        true
+    }
+
+    /// Returns where the OUT endpoints are re-enabled after a packet.
+    fn rearm_point(&self, regs: &UsbRegisters<USB>) -> Option<OutRearmPoint> {
+        #[cfg(feature = "quirks")]
+        return self.config.out_rearm_point.or_else(|| out_rearm_point(read_reg!(otg_global, regs.global, CID)));
+        #[cfg(not(feature = "quirks"))]
+        {
+            let _ = regs;
+            self.config.out_rearm_point
+        }
+    }
+
+    /// Adapts the core to the speed the bus reset has enumerated (DSTS.ENUMSPD): the turnaround
+    /// time and the EP0 packet size depend on it. A high-speed configuration that has ended up
+    /// at full speed is recorded as a fallback.
+    fn apply_enumerated_speed(&self, cs: &CriticalSection) {
+        let regs = self.regs.borrow(cs);
+        let speed = Self::core().speed();
+        modify_reg!(otg_global, regs.global, GUSBCFG, TRDT: Self::turnaround_time(speed));
+
+        if let Some(ep) = &self.allocator.borrow(cs).borrow().endpoints_in[0] {
+            ep.set_ep0_speed(speed);
+        }
+
+        let fallback = Self::is_high_speed(&self.config) && speed == Speed::Full;
+        self.full_speed_fallback.borrow(cs).set(fallback);
+        if fallback {
+            trace!(self, cs, TraceEvent::FullSpeedFallback);
+        }
+    }
+
+    /// Starts counting the enumeration time at the end of a bus reset.
+    fn start_enumeration(&self, cs: &CriticalSection) {
+        self.enumeration.borrow(cs).set(Enumeration {
+            state: EnumerationState::Reset,
+            microframes: Self::core().frame().microframes(),
+            elapsed: 0,
+        });
+    }
+
+    /// Records the enumeration progress the control requests made.
+    fn set_enumeration_state(&self, cs: &CriticalSection, state: EnumerationState) {
+        let enumeration = self.enumeration.borrow(cs);
+        let mut value = enumeration.get();
+        if value.state != EnumerationState::Idle {
+            value.state = state;
+            enumeration.set(value);
+        }
+    }
+
+    /// Adds the frames sent since the last check to the enumeration time and reports a timeout.
+    /// The microframe count wraps around after 2048 frames, it has to be checked more often.
+    fn check_enumeration_timeout(&self, cs: &CriticalSection) {
+        let timeout_ms = match self.config.enumeration_timeout_ms {
+            Some(timeout_ms) => timeout_ms,
+            None => return,
+        };
+
+        let enumeration = self.enumeration.borrow(cs);
+        let mut value = enumeration.get();
+        if !matches!(value.state, EnumerationState::Reset | EnumerationState::Addressed) {
+            return;
+        }
+
+        let microframes = Self::core().frame().microframes();
+        value.elapsed += (microframes.wrapping_sub(value.microframes) & 0x3fff) as u32;
+        value.microframes = microframes;
+        if value.elapsed >= timeout_ms as u32 * 8 {
+            value.state = EnumerationState::TimedOut;
+            trace!(self, cs, TraceEvent::EnumerationTimeout);
+        }
+        enumeration.set(value);
+    }
+
+    /// Services the pending interrupts: acknowledges the events, moves the received packets
+    /// into the endpoint buffers and records what `poll()` has to report.
+    fn service_interrupts(&self, cs: &CriticalSection) {
+        let regs = self.regs.borrow(cs);
+        let pending = self.pending.borrow(cs);
+        let mut events = pending.get();
+
+        let rearm_point = self.rearm_point(regs);
+
+        let (wakeup, suspend, early_suspend, enum_done, reset, iep, oep, rxflvl, otg, session_request) = read_reg!(otg_global, regs.global, GINTSTS,
+            WKUPINT, USBSUSP, ESUSP, ENUMDNE, USBRST, IEPINT, OEPINT, RXFLVL, OTGINT, SRQINT
+        );
+        let id_change = read_reg!(otg_global, regs.global, GINTSTS, CIDSCHG);
+        #[cfg(feature = "iso")]
+        let (iso_out_dropped, iso_in_incomplete) = read_reg!(otg_global, regs.global, GINTSTS, ISOODRP, IISOIXFR);
+
+        if reset != 0 || wakeup != 0 {
+            // The clocks and the PHY must be running before the reset or resume is handled
+            self.exit_low_power(cs, regs);
+        }
+
+        #[cfg(feature = "iso")]
+        if iso_out_dropped != 0 {
+            write_reg!(otg_global, regs.global, GINTSTS, ISOODRP: 1);
+            self.record_rx_overflow(cs, None);
+        }
+
+        if id_change != 0 {
+            write_reg!(otg_global, regs.global, GINTSTS, CIDSCHG: 1);
+
+            // A floating ID pin makes a B-device
+            let role = if read_reg!(otg_global, regs.global, GOTGCTL, CIDSTS) != 0 {
+                OtgRole::Device
+            } else {
+                OtgRole::Host
+            };
+            self.change_role(cs, role);
+        }
+
+        if session_request != 0 {
+            write_reg!(otg_global, regs.global, GINTSTS, SRQINT: 1);
+            self.note_vbus(cs, true);
+        }
+
+        let mut session_end = false;
+        if otg != 0 {
+            // OTGINT is cleared by clearing the GOTGINT flags
+            let flags = read_reg!(otg_global, regs.global, GOTGINT);
+            write_reg!(otg_global, regs.global, GOTGINT, flags);
+
+            if flags & otg_global::GOTGINT::SEDET::mask != 0 {
+                self.note_vbus(cs, false);
+            }
+
+            if flags & otg_global::GOTGINT::HNSSCHG::mask != 0 {
+                // The negotiation is over, whatever its outcome
+                modify_reg!(otg_global, regs.global, GOTGCTL, HNPRQ: 0);
+            }
+
+            let status = read_reg!(otg_global, regs.global, GOTGCTL);
+            if flags & otg_global::GOTGINT::HNSSCHG::mask != 0 && status & otg_global::GOTGCTL::HNGSCS::mask != 0 {
+                let role = match self.role.borrow(cs).get() {
+                    OtgRole::Device => OtgRole::Host,
+                    OtgRole::Host => OtgRole::Device,
+                };
+                self.change_role(cs, role);
+            }
+
+            let events = self.otg_events.borrow(cs);
+            events.set(events.get() | OtgEvent::from_interrupt_flags(flags, status));
+        }
+
+        // External VBUS sensing
+        if !self.config.vbus_sensing {
+            if let Some(present) = USB::vbus_present() {
+                self.note_vbus(cs, present);
+            }
+        }
+
+        if let Some(present) = self.settled_vbus(cs, regs) {
+            let previous = self.vbus_present.borrow(cs).replace(Some(present));
+            if present {
+                self.start_session(cs);
+            } else if self.config.vbus_sensing || previous.is_some() {
+                session_end = true;
+                self.end_session(cs);
+            }
+        }
+
+        if early_suspend != 0 {
+            write_reg!(otg_global, regs.global, GINTSTS, ESUSP: 1);
+
+            if read_reg!(otg_device, regs.device, DSTS, EERR) != 0 {
+                // The core went into suspend because of an erratic error,
+                // only a soft disconnect brings it back.
+                let errors = self.erratic_errors.borrow(cs);
+                errors.set(errors.get().wrapping_add(1));
+                trace!(self, cs, TraceEvent::ErraticError);
+
+                self.start_reconnect(cs);
+            }
+        }
+
+        // The flags don't tell in which order the bus state changed, the state of the link does:
+        // a suspend flagged next to a resume or reset came first unless the link is suspended now
+        let suspend_last = suspend != 0 && (Self::core().is_suspended() || (wakeup | reset | enum_done) == 0);
+        if suspend_last {
+            modify_reg!(otg_global, regs.global, GINTMSK, WUIM: 1);
+        } else if reset != 0 || wakeup != 0 {
+            // Nothing to wake up from while the bus is active
+            modify_reg!(otg_global, regs.global, GINTMSK, WUIM: 0);
+        }
+
+        if reset != 0 {
+            write_reg!(otg_global, regs.global, GINTSTS, USBRST: 1);
+
+            self.connected.borrow(cs).set(true);
+            self.remote_wakeup_enabled.borrow(cs).set(false);
+            modify_reg!(otg_global, regs.global, GOTGCTL, DHNPEN: 0, HNPRQ: 0);
+            self.deconfigure_all(cs);
+            self.flush_rx_fifo(cs, regs);
+        }
+
+        if session_end {
+            // Whatever happened in the ended session is stale now
+            events = PendingEvents::default();
+            events.bus.push(BusEvent::Suspend);
+        }
+        if suspend != 0 && !suspend_last {
+            self.push_suspend(cs, regs, &mut events);
+        }
+        if wakeup != 0 {
+            // Clear the interrupt
+            write_reg!(otg_global, regs.global, GINTSTS, WKUPINT: 1);
+
+            events.bus.push(BusEvent::Resume);
+            trace!(self, cs, TraceEvent::Resume);
+        }
+        if enum_done != 0 {
+            write_reg!(otg_global, regs.global, GINTSTS, ENUMDNE: 1);
+
+            // Whatever happened before the reset is stale now
+            events = PendingEvents::default();
+            events.bus.push(BusEvent::Reset);
+            trace!(self, cs, TraceEvent::Reset);
+            self.apply_enumerated_speed(cs);
+            self.start_enumeration(cs);
+        }
+        if suspend_last {
+            self.push_suspend(cs, regs, &mut events);
+        }
+        self.check_enumeration_timeout(cs);
+
+        // The data events are handled whatever happened to the bus, the packets received since
+        // the reset belong to the new session
+        let allocator = self.allocator.borrow(cs).borrow();
+
+        let mut ep_in_complete = 0;
+
+        use crate::ral::endpoint_in;
+
+        // In DMA mode the core has already written the packets into the buffers
+        #[cfg(feature = "hs")]
+        {
+            if oep != 0 && self.dma_enabled() {
+                self.complete_dma_transfers(cs, &allocator);
+            }
+        }
+        #[cfg(not(feature = "hs"))]
+        let _ = oep;
+
+        // RXFLVL & IEPINT flags are read-only, there is no need to clear them.
+        // Drain all the packets the application buffers can take in one go.
+        let core = Self::core();
+        let mut entry = if rxflvl != 0 && !self.dma_enabled() { core.peek_rx_entry() } else { None };
+        while let Some(RxEntry { ep_number: epnum, status, byte_count: data_size, frame_lsb }) = entry {
+            match status {
+                RxStatus::OutData => {}
+                RxStatus::SetupData => {
+                    // flushing TX if something stuck in control endpoint
+                    let ep = endpoint_in::instance(UsbRegisters::<USB>::base_address(), epnum);
+                    if read_reg!(endpoint_in, ep, DIEPTSIZ, PKTCNT) != 0 {
+                        self.flush_tx_fifo(cs, epnum);
+                    }
+                }
+                RxStatus::OutComplete | RxStatus::SetupComplete => {
+                    // Re-enable the endpoint, F429-like chips only
+                    if rearm_point == Some(OutRearmPoint::TransferComplete) {
+                        if let Some(ep) = allocator.endpoints_out.get(epnum as usize).and_then(Option::as_ref) {
+                            ep.rearm_after_receive(cs);
+                        }
+                    }
+                    core.pop_rx_entry();
+                }
+                RxStatus::GlobalOutNak => {
+                    // Only a marker, set_global_out_nak() waits for the interrupt flag
+                    core.pop_rx_entry();
+                }
+                RxStatus::ChannelHalted | RxStatus::Reserved(_) => {
+                    // Host mode and reserved entries carry no data for a device
+                    core.pop_rx_entry();
+                    let unknown = self.unknown_rx_statuses.borrow(cs);
+                    unknown.set(unknown.get().wrapping_add(1));
+                }
+            }
+
+            if status.has_data() {
+                let mut blocked = false;
+                if let Some(ep) = allocator.endpoints_out.get(epnum as usize).and_then(Option::as_ref) {
+                    if !self.receive_packet(cs, ep, status, data_size, rearm_point) {
+                        // The packet stays in the FIFO until the application reads the
+                        // buffer, don't let RXFLVL fire over and over in the meantime
+                        modify_reg!(otg_global, regs.global, GINTMSK, RXFLVLM: 0);
+                        blocked = true;
+                    } else {
+                        #[cfg(feature = "iso")]
+                        ep.set_received_frame(cs, core.packet_frame(frame_lsb));
+                        #[cfg(not(feature = "iso"))]
+                        let _ = frame_lsb;
+                    }
+                } else {
+                    // Nothing is ever going to read the packet, drop it so that it doesn't
+                    // block the FIFO with RXFLVL asserted
+                    core.pop_rx_entry();
+                    core.discard_packet(data_size);
+                    self.record_rx_overflow(cs, None);
+                }
+
+                if blocked {
+                    // The packet stays at the head of the FIFO
+                    break;
+                }
+            }
+
+            entry = core.peek_rx_entry();
+        }
+
+        #[cfg(feature = "iso")]
+        if iso_in_incomplete != 0 {
+            write_reg!(otg_global, regs.global, GINTSTS, IISOIXFR: 1);
+
+            let missed = self.drop_missed_iso_in(cs, &allocator);
+            ep_in_complete |= missed;
+            if missed != 0 {
+                trace!(self, cs, TraceEvent::IsoInMissed { endpoints: missed });
+            }
+        }
+
+        if iep != 0 {
+            for ep in &allocator.endpoints_in {
+                if let Some(ep) = ep {
+                    let index = ep.address().index();
+                    ep.record_retries(cs, core.take_in_retries(index as u8));
+                    let xfrc = core.take_transfer_complete(index as u8, Direction::In);
+                    #[cfg(feature = "iso")]
+                    if xfrc {
+                        ep.record_service_frame(cs, core.frame_number());
+                    }
+                    if self.config.fifo_empty_completion(index) {
+                        // TXFE stays set while the FIFO is empty, report it once per write
+                        let ep_regs = endpoint_in::instance(UsbRegisters::<USB>::base_address(), index as u8);
+                        let txfe = read_reg!(endpoint_in, ep_regs, DIEPINT, TXFE);
+                        let mask = 1 << index;
+                        let retry = self.tx_retry.borrow(cs);
+                        if txfe != 0 && read_reg!(otg_device, regs.device, DIEPEMPMSK) & mask != 0 {
+                            modify_reg!(otg_device, regs.device, DIEPEMPMSK, |v| v & !mask);
+                            ep_in_complete |= mask as u16;
+                            trace!(self, cs, TraceEvent::InComplete { ep_number: index as u8 });
+                        } else if xfrc && retry.get() & mask as u16 != 0 {
+                            // write() has been turned away since the FIFO empty report, the
+                            // endpoint takes the next transfer now
+                            ep_in_complete |= mask as u16;
+                        }
+                        retry.set(retry.get() & !(mask as u16));
+                    } else if xfrc {
+                        ep_in_complete |= 1 << index;
+                        trace!(self, cs, TraceEvent::InComplete { ep_number: index as u8 });
+                    }
+                }
+            }
+        }
+
+        if ep_in_complete & 1 != 0 {
+            // The status stage of SET_ADDRESS has been sent
+            if let Some(addr) = self.pending_address.borrow(cs).take() {
+                modify_reg!(otg_device, regs.device, DCFG, DAD: addr as u32);
+                trace!(self, cs, TraceEvent::AddressSet { address: addr });
+            }
+        }
+
+        events.ep_in_complete |= ep_in_complete;
 
-        UsbBusAllocator::new(bus)
+        let (ep_out, ep_setup) = allocator.out_events();
+        self.wakers.borrow(cs).borrow_mut().wake(ep_in_complete, ep_out | ep_setup);
+
+        pending.set(events);
     }
 
-    pub fn free(self) -> USB {
-        self.peripheral
+    /// Acknowledges the suspend interrupt (USBSUSP) and queues the event for `poll()`.
+    fn push_suspend(&self, cs: &CriticalSection, regs: &UsbRegisters<USB>, events: &mut PendingEvents) {
+        write_reg!(otg_global, regs.global, GINTSTS, USBSUSP: 1);
+
+        events.bus.push(BusEvent::Suspend);
+        trace!(self, cs, TraceEvent::Suspend);
+        #[cfg(not(feature = "trace"))]
+        let _ = cs;
     }
 
-    pub fn configure_all(&self, cs: &CriticalSection) {
+
+    /// Disables the isochronous IN endpoints still holding a packet for the (micro)frame that is
+    /// ending and flushes their TX FIFOs. Returns the endpoints, one bit per endpoint number.
+    #[cfg(feature = "iso")]
+    fn drop_missed_iso_in(&self, cs: &CriticalSection, allocator: &EndpointAllocator) -> u16 {
+        use crate::ral::endpoint_in;
+
         let regs = self.regs.borrow(cs);
+        let frame_number = read_reg!(otg_device, regs.device, DSTS, FNSOF);
+        let frame_parity = frame_number & 1;
 
-        // Rx FIFO
-        // This calculation doesn't correspond to one in a Reference Manual.
-        // In fact, the required number of words is higher than indicated in RM.
-        // The following numbers are pessimistic and were figured out empirically.
-        let rx_fifo_size = if USB::HIGH_SPEED {
-            self.allocator.memory_allocator.total_rx_buffer_size_words() + 30
+        let mut missed = 0;
+        for ep in allocator.endpoints_in.iter().flatten() {
+            if ep.ep_type() != EndpointType::Isochronous {
+                continue;
+            }
+
+            let index = ep.address().index();
+            let ep_regs = endpoint_in::instance(UsbRegisters::<USB>::base_address(), index as u8);
+            let (enabled, eonum) = read_reg!(endpoint_in, ep_regs, DIEPCTL, EPENA, EONUM_DPID);
+            // With an interval, the host polls only one of the (micro)frames of the parity
+            if enabled != 0 && eonum == frame_parity && ep.missed_service_frame(cs, frame_number as u16) {
+                let core = Self::core();
+                self.note_core_wait(cs, core.disable_endpoint(index as u8, Direction::In));
+                self.flush_tx_fifo(cs, index as u8);
+                missed |= 1 << index;
+            }
+        }
+
+        let missed_iso_in = self.missed_iso_in.borrow(cs);
+        missed_iso_in.set(missed_iso_in.get() | missed);
+        missed
+    }
+
+    /// Returns whether VBUS is present, from the internal sensing or from
+    /// `UsbPeripheral::vbus_present`.
+    fn vbus_valid(&self, regs: &UsbRegisters<USB>) -> Option<bool> {
+        if self.config.vbus_sensing {
+            Some(read_reg!(otg_global, regs.global, GOTGCTL, BSVLD) != 0)
         } else {
-            // F429 requires 35+ words for the (EP0[8] + EP2[64]) setup
-            // F446 requires 39+ words for the same setup
-            self.allocator.memory_allocator.total_rx_buffer_size_words() + 30
+            USB::vbus_present()
+        }
+    }
+
+    /// Unmasks the core interrupts the driver handles. WKUPINT is unmasked only while the bus is
+    /// suspended, the OTG, session request and ID pin interrupts only on OTG cores.
+    fn unmask_core_interrupts(&self, regs: &UsbRegisters<USB>) {
+        let dma = self.dma_enabled() as u32;
+        let otg = !USB::DEVICE_ONLY as u32;
+        write_reg!(otg_global, regs.global, GINTMSK,
+            USBRST: 1, ENUMDNEM: 1,
+            USBSUSPM: 1, ESUSPM: 1, WUIM: 0,
+            OTGINT: otg, SRQIM: otg, CIDSCHGM: otg,
+            ISOODRPM: cfg!(feature = "iso") as u32, IISOIXFRM: cfg!(feature = "iso") as u32,
+            IEPINT: 1, RXFLVLM: dma ^ 1, OEPINT: dma
+        );
+    }
+
+    /// Notes that VBUS is `present` now. The session changes once VBUS has stayed so for the
+    /// debounce time, a return to the settled state before cancels the change as a glitch.
+    fn note_vbus(&self, cs: &CriticalSection, present: bool) {
+        let change = self.vbus_change.borrow(cs);
+        if self.vbus_present.borrow(cs).get() == Some(present) {
+            change.set(None);
+        } else if change.get().map(|change| change.present) != Some(present) {
+            change.set(Some(VbusChange {
+                present,
+                since: USB::micros().unwrap_or(0),
+                waited: false,
+            }));
+        }
+    }
+
+    /// Returns whether VBUS is present once a change has lasted for the debounce time, `None`
+    /// while there's none or it's still being debounced.
+    fn settled_vbus(&self, cs: &CriticalSection, regs: &UsbRegisters<USB>) -> Option<bool> {
+        let pending = self.vbus_change.borrow(cs);
+        let change = pending.get()?;
+
+        let debounce_us = self.config.vbus_debounce_us;
+        let elapsed = matches!(USB::micros(), Some(now) if now.wrapping_sub(change.since) >= debounce_us);
+        if debounce_us != 0 && !change.waited && !elapsed {
+            return None;
+        }
+        pending.set(None);
+
+        // The internal sensing only interrupts on the edges, the state must still be the same
+        if debounce_us != 0 && self.config.vbus_sensing && self.vbus_valid(regs) == Some(!change.present) {
+            return None;
+        }
+        Some(change.present)
+    }
+
+    /// Waits for the debounce time of a VBUS change outside of the critical section, on
+    /// peripherals without `UsbPeripheral::micros` to tell when it has passed.
+    fn wait_vbus_debounce(&self) {
+        let debounce_us = self.config.vbus_debounce_us;
+        if debounce_us == 0 || USB::micros().is_some() {
+            return;
+        }
+
+        let present = match interrupt::free(|cs| self.vbus_change.borrow(cs).get()) {
+            Some(change) if !change.waited => change.present,
+            _ => return,
         };
-        write_reg!(otg_global, regs.global, GRXFSIZ, rx_fifo_size as u32);
-        let mut fifo_top = rx_fifo_size;
+        USB::delay_us(debounce_us);
 
-        // Tx FIFO #0
-        let fifo_size = self.allocator.memory_allocator.tx_fifo_size_words(0);
+        interrupt::free(|cs| {
+            let pending = self.vbus_change.borrow(cs);
+            if let Some(change) = pending.get().filter(|change| change.present == present) {
+                pending.set(Some(VbusChange { waited: true, ..change }));
+            }
+        });
+    }
 
-        #[cfg(feature = "fs")]
-        write_reg!(otg_global, regs.global, DIEPTXF0,
-            TX0FD: fifo_size as u32,
-            TX0FSA: fifo_top as u32
-        );
-        #[cfg(feature = "hs")]
-        write_reg!(otg_global, regs.global, GNPTXFSIZ,
-            TX0FD: fifo_size as u32,
-            TX0FSA: fifo_top as u32
-        );
+    /// Starts a session once VBUS has appeared.
+    fn start_session(&self, cs: &CriticalSection) {
+        self.push_otg_event(cs, OtgEvent::SessionStart);
+        trace!(self, cs, TraceEvent::SessionStart);
 
-        fifo_top += fifo_size;
+        // After a brown-out the host may still have the device configured, a reconnect makes it
+        // enumerate the device from scratch. A detached device stays detached.
+        if self.session_ended.borrow(cs).replace(false) && !Self::core().is_soft_disconnected() {
+            self.start_reconnect(cs);
+        }
+    }
 
-        // Tx FIFO #1
-        let fifo_size = self.allocator.memory_allocator.tx_fifo_size_words(1);
-        write_reg!(otg_global, regs.global, DIEPTXF1,
-            INEPTXFD: fifo_size as u32,
-            INEPTXSA: fifo_top as u32
-        );
-        fifo_top += fifo_size;
+    /// Drops the state of a session once VBUS has gone away: the endpoints are disabled, their
+    /// buffers and FIFOs emptied and the address cleared, so that the next session starts with a
+    /// fresh control pipe.
+    fn end_session(&self, cs: &CriticalSection) {
+        let regs = self.regs.borrow(cs);
 
-        // Tx FIFO #2
-        let fifo_size = self.allocator.memory_allocator.tx_fifo_size_words(2);
-        write_reg!(otg_global, regs.global, DIEPTXF2,
-            INEPTXFD: fifo_size as u32,
-            INEPTXSA: fifo_top as u32
-        );
-        fifo_top += fifo_size;
+        self.connected.borrow(cs).set(false);
+        self.session_ended.borrow(cs).set(true);
+        self.push_otg_event(cs, OtgEvent::SessionEnd);
+        trace!(self, cs, TraceEvent::SessionEnd);
+
+        // Disabling the endpoints needs the PHY clock
+        self.exit_low_power(cs, regs);
+        self.deconfigure_all(cs);
+        self.flush_rx_fifo(cs, regs);
+
+        modify_reg!(otg_device, regs.device, DCFG, DAD: 0);
+        self.pending_address.borrow(cs).set(None);
+        self.remote_wakeup_enabled.borrow(cs).set(false);
+        self.config_descriptor_requested.borrow(cs).set(false);
+        self.enumeration.borrow(cs).set(Enumeration::default());
+    }
 
-        // Tx FIFO #3
-        let fifo_size = self.allocator.memory_allocator.tx_fifo_size_words(3);
-        write_reg!(otg_global, regs.global, DIEPTXF3,
-            INEPTXFD: fifo_size as u32,
-            INEPTXSA: fifo_top as u32
-        );
-        fifo_top += fifo_size;
+    /// Pulls D+ down for the host to notice the disconnection. `poll()` connects the device again
+    /// once `RECONNECT_US` have passed, the interrupt handler doesn't wait for it.
+    fn start_reconnect(&self, cs: &CriticalSection) {
+        Self::core().set_soft_disconnect(true);
+        self.reconnect_since.borrow(cs).set(Some(USB::micros().unwrap_or(0)));
+    }
+
+    /// Connects the device again once it has been disconnected for `RECONNECT_US`. Without
+    /// `UsbPeripheral::micros` the rest of the wait blocks, but outside of the critical section.
+    fn finish_reconnect(&self) {
+        let since = match interrupt::free(|cs| self.reconnect_since.borrow(cs).get()) {
+            Some(since) => since,
+            None => return,
+        };
+        match USB::micros() {
+            Some(now) if now.wrapping_sub(since) < RECONNECT_US => return,
+            Some(_) => {}
+            None => USB::delay_us(RECONNECT_US),
+        }
 
-        assert!(fifo_top as u32 <= crate::ral::otg_fifo::FIFO_DEPTH_WORDS);
+        interrupt::free(|cs| {
+            // Unless attach() or detach() has taken over in the meantime
+            if self.reconnect_since.borrow(cs).take().is_some() {
+                Self::core().set_soft_disconnect(false);
+            }
+        });
+    }
+
+    /// Quiesces the peripheral like [`shutdown`](Self::shutdown) and returns it, so that a bus
+    /// with different endpoints can be created from it without a chip reset.
+    pub fn free(self) -> USB {
+        self.shutdown();
+        self.peripheral
+    }
+
+    pub fn configure_all(&self, cs: &CriticalSection) {
+        self.allocator.borrow(cs).borrow_mut().compact_memory(cs);
+
+        let regs = self.regs.borrow(cs);
+        let allocator = self.allocator.borrow(cs).borrow();
+
+        self.configure_fifos(cs);
 
         // Flush Rx & Tx FIFOs
         modify_reg!(otg_global, regs.global, GRSTCTL, RXFFLSH: 1, TXFFLSH: 1, TXFNUM: 0x10);
-        while read_reg!(otg_global, regs.global, GRSTCTL, RXFFLSH, TXFFLSH) != (0, 0) {}
+        let flushed = wait_for(|| read_reg!(otg_global, regs.global, GRSTCTL, RXFFLSH, TXFFLSH) == (0, 0));
+        self.note_core_wait(cs, flushed);
 
-        for ep in &self.allocator.endpoints_in {
+        for ep in &allocator.endpoints_in {
             if let Some(ep) = ep {
                 // enabling EP TX interrupt
                 modify_reg!(otg_device, regs.device, DAINTMSK, |v| v | (0x0001 << ep.address().index()));
@@ -105,11 +2013,11 @@ impl<USB: UsbPeripheral> UsbBus<USB> {
             }
         }
 
-        for ep in &self.allocator.endpoints_out {
+        for ep in &allocator.endpoints_out {
             if let Some(ep) = ep {
-                if ep.address().index() == 0 {
-                    // enabling RX interrupt from EP0
-                    modify_reg!(otg_device, regs.device, DAINTMSK, |v| v | 0x00010000);
+                if ep.address().index() == 0 || self.dma_enabled() {
+                    // enabling RX interrupt from EP0, in DMA mode from all OUT endpoints
+                    modify_reg!(otg_device, regs.device, DAINTMSK, |v| v | (0x00010000 << ep.address().index()));
                 }
 
                 ep.configure(cs);
@@ -117,109 +2025,647 @@ impl<USB: UsbPeripheral> UsbBus<USB> {
         }
     }
 
+    /// Returns the FIFO layout `configure_all()` programs for the currently allocated endpoints.
+    pub fn fifo_layout(&self) -> FifoLayout {
+        interrupt::free(|cs| self.compute_fifo_layout(cs))
+    }
+
+    fn compute_fifo_layout(&self, cs: &CriticalSection) -> FifoLayout {
+        self.allocator.borrow(cs).borrow().fifo_layout()
+    }
+
+    fn configure_fifos(&self, cs: &CriticalSection) {
+        let regs = self.regs.borrow(cs);
+        let layout = self.compute_fifo_layout(cs);
+
+        // Rx FIFO
+        write_reg!(otg_global, regs.global, GRXFSIZ, layout.rx_size_words as u32);
+
+        // Tx FIFO #0
+        let fifo = layout.tx[0];
+
+        #[cfg(not(feature = "hs"))]
+        write_reg!(otg_global, regs.global, DIEPTXF0,
+            TX0FD: fifo.size_words as u32,
+            TX0FSA: fifo.start_words as u32
+        );
+        #[cfg(feature = "hs")]
+        write_reg!(otg_global, regs.global, GNPTXFSIZ,
+            TX0FD: fifo.size_words as u32,
+            TX0FSA: fifo.start_words as u32
+        );
+
+        // Tx FIFO #1..
+        for i in 1..Self::endpoint_count() as u8 {
+            let layout = layout.tx[i as usize];
+            if let Some(fifo) = tx_fifo::instance(UsbRegisters::<USB>::base_address(), i) {
+                write_reg!(tx_fifo, fifo, DIEPTXF,
+                    INEPTXFD: layout.size_words as u32,
+                    INEPTXSA: layout.start_words as u32
+                );
+            }
+        }
+
+        // The allocator keeps the endpoints within the FIFO RAM
+        debug_assert!(layout.total_words() as usize <= USB::FIFO_DEPTH_WORDS);
+    }
+
     pub fn deconfigure_all(&self, cs: &CriticalSection) {
         let regs = self.regs.borrow(cs);
+        let allocator = self.allocator.borrow(cs).borrow();
 
         // disable interrupts
         modify_reg!(otg_device, regs.device, DAINTMSK, IEPM: 0, OEPM: 0);
+        write_reg!(otg_device, regs.device, DIEPEMPMSK, 0);
 
-        for ep in &self.allocator.endpoints_in {
+        for ep in &allocator.endpoints_in {
             if let Some(ep) = ep {
-                ep.deconfigure(cs);
+                self.note_core_wait(cs, ep.deconfigure(cs));
             }
         }
 
-        for ep in &self.allocator.endpoints_out {
+        // Flush all Tx FIFOs, the endpoints are disabled now
+        self.flush_tx_fifo(cs, 0x10);
+
+        self.note_core_wait(cs, Self::core().set_global_out_nak());
+
+        for ep in &allocator.endpoints_out {
             if let Some(ep) = ep {
-                ep.deconfigure(cs);
+                self.note_core_wait(cs, ep.deconfigure(cs));
+            }
+        }
+
+        Self::core().clear_global_out_nak();
+    }
+
+    /// Reports the completion of an IN transfer once its FIFO has been emptied, with
+    /// `InCompletion::FifoEmpty`.
+    fn unmask_fifo_empty(&self, cs: &CriticalSection, ep_addr: EndpointAddress) {
+        if self.config.fifo_empty_completion(ep_addr.index()) {
+            let regs = self.regs.borrow(cs);
+            modify_reg!(otg_device, regs.device, DIEPEMPMSK, |v| v | (1 << ep_addr.index()));
+        }
+    }
+
+    /// Flushes the TX FIFO `fifo_number`, or all of them with `0x10`. A `write()` filling one of
+    /// them flushes it again once it's done, the data it writes after this flush is stale.
+    fn flush_tx_fifo(&self, cs: &CriticalSection, fifo_number: u8) {
+        let flushed = if fifo_number == 0x10 { u16::MAX } else { 1 << fifo_number };
+        let aborted = self.tx_fill_aborted.borrow(cs);
+        aborted.set(aborted.get() | (self.tx_filling.borrow(cs).get() & flushed));
+        self.note_core_wait(cs, Self::core().flush_tx_fifo(fifo_number));
+    }
+
+    /// Drops the packets in the RX FIFO and lets the interrupt handler receive the next ones.
+    fn flush_rx_fifo(&self, cs: &CriticalSection, regs: &UsbRegisters<USB>) {
+        self.note_core_wait(cs, Self::core().flush_rx_fifo());
+        if !self.dma_enabled() {
+            modify_reg!(otg_global, regs.global, GINTMSK, RXFLVLM: 1);
+        }
+    }
+
+    /// Releases an allocated endpoint together with its FIFO memory.
+    ///
+    /// The endpoint number can be allocated again with [`realloc_ep`](Self::realloc_ep), e.g. when
+    /// the host selects an alternate setting that uses a different max packet size. EP0 can't be
+    /// freed.
+    pub fn free_ep(&self, ep_addr: EndpointAddress) -> core::result::Result<(), Error> {
+        if ep_addr.index() == 0 {
+            return Err(Error::EndpointUnavailable);
+        }
+
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+            let mut allocator = self.allocator.borrow(cs).borrow_mut();
+
+            match ep_addr.direction() {
+                UsbDirection::In => {
+                    if let Some(Some(ep)) = allocator.endpoints_in.get(ep_addr.index()) {
+                        modify_reg!(otg_device, regs.device, DAINTMSK, |v| v & !(0x0001 << ep_addr.index()));
+                        self.note_core_wait(cs, ep.deconfigure(cs));
+                        self.flush_tx_fifo(cs, ep_addr.index() as u8);
+                    }
+                },
+                UsbDirection::Out => {
+                    if let Some(Some(ep)) = allocator.endpoints_out.get(ep_addr.index()) {
+                        self.note_core_wait(cs, Self::core().set_global_out_nak());
+                        self.note_core_wait(cs, ep.deconfigure(cs));
+                        Self::core().clear_global_out_nak();
+
+                        // The packets held back for a full buffer have been discarded
+                        if !self.dma_enabled() {
+                            modify_reg!(otg_global, regs.global, GINTMSK, RXFLVLM: 1);
+                        }
+                    }
+                },
+            }
+
+            allocator.free_ep(ep_addr, cs)
+        })
+    }
+
+    /// Allocates a previously freed endpoint number again and configures it right away.
+    ///
+    /// The FIFO layout is recomputed, so this should be called only while the other endpoints
+    /// are idle, typically while handling SET_INTERFACE. While another IN endpoint has a
+    /// transfer armed or OUT packets wait in the RX FIFO, it fails with
+    /// `Error::TransferPending`.
+    pub fn realloc_ep(
+        &self,
+        ep_addr: EndpointAddress,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval: u8) -> core::result::Result<(), Error>
+    {
+        interrupt::free(|cs| {
+            if self.transfers_pending(cs, ep_addr) {
+                return Err(Error::TransferPending);
+            }
+            self.alloc_and_configure_ep(cs, ep_addr, ep_type, max_packet_size, interval)
+        })
+    }
+
+    /// Allocates and configures an endpoint number, see `realloc_ep`.
+    fn alloc_and_configure_ep(
+        &self,
+        cs: &CriticalSection,
+        ep_addr: EndpointAddress,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval: u8) -> core::result::Result<(), Error>
+    {
+        self.compact_memory(cs);
+        self.allocator.borrow(cs).borrow_mut()
+            .alloc_ep(ep_addr.direction(), Some(ep_addr), ep_type, max_packet_size, interval)?;
+
+        let regs = self.regs.borrow(cs);
+        let allocator = self.allocator.borrow(cs).borrow();
+
+        self.configure_fifos(cs);
+
+        match ep_addr.direction() {
+            UsbDirection::In => {
+                if let Some(ep) = &allocator.endpoints_in[ep_addr.index()] {
+                    self.flush_tx_fifo(cs, ep_addr.index() as u8);
+                    modify_reg!(otg_device, regs.device, DAINTMSK, |v| v | (0x0001 << ep_addr.index()));
+                    ep.configure(cs);
+                }
+            },
+            UsbDirection::Out => {
+                if let Some(ep) = &allocator.endpoints_out[ep_addr.index()] {
+                    if self.dma_enabled() {
+                        modify_reg!(otg_device, regs.device, DAINTMSK, |v| v | (0x00010000 << ep_addr.index()));
+                    }
+                    ep.configure(cs);
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Returns true if an endpoint other than `ep_addr` has data in flight through the FIFOs: an
+    /// armed IN transfer, or OUT packets still in the RX FIFO. The OUT endpoints themselves
+    /// stay armed for the next packet all the time.
+    fn transfers_pending(&self, cs: &CriticalSection, ep_addr: EndpointAddress) -> bool {
+        let core = Self::core();
+        let in_armed = self.allocator.borrow(cs).borrow().endpoints_in.iter().flatten()
+            .map(|ep| ep.address())
+            .any(|address| address != ep_addr && core.is_endpoint_enabled(address.index() as u8, Direction::In));
+        in_armed || (!self.dma_enabled() && core.peek_rx_entry().is_some())
+    }
+
+    /// Packs the endpoint buffers together while the endpoints stay configured. In DMA mode, the
+    /// transfers armed into buffers that have moved follow them to their new place.
+    fn compact_memory(&self, cs: &CriticalSection) {
+        let mut allocator = self.allocator.borrow(cs).borrow_mut();
+        #[cfg(feature = "hs")]
+        let before = allocator.buffer_addresses(cs);
+
+        allocator.compact_memory(cs);
+
+        #[cfg(feature = "hs")]
+        if self.dma_enabled() {
+            let core = Self::core();
+            let after = allocator.buffer_addresses(cs);
+            for (slot, (&old, &new)) in before.iter().zip(after.iter()).enumerate() {
+                let (ep_number, direction) = if slot < ENDPOINT_COUNT {
+                    (slot as u8, Direction::Out)
+                } else {
+                    ((slot - ENDPOINT_COUNT) as u8, Direction::In)
+                };
+                if old != new && core.is_endpoint_enabled(ep_number, direction) {
+                    // The DMA address may have advanced past the start, e.g. over SETUP packets
+                    let address = core.dma_address(ep_number, direction);
+                    core.set_dma_address(ep_number, direction, address.wrapping_sub(old).wrapping_add(new));
+                }
+            }
+        }
+    }
+
+    /// Changes the transfer type and max packet size of an allocated endpoint.
+    ///
+    /// This is mainly useful for high-speed devices that have to switch from full-speed to
+    /// high-speed packet sizes depending on the negotiated speed. The restrictions of
+    /// [`realloc_ep`](Self::realloc_ep) apply. If the new configuration doesn't fit into the FIFO
+    /// memory, the previous one is restored and the error is returned. Should even the previous
+    /// one fail, the endpoint is left freed and `Error::EndpointNotAllocated` is returned.
+    pub fn reconfigure_ep(&self, ep_addr: EndpointAddress, ep_type: EndpointType, max_packet_size: u16) -> core::result::Result<(), Error> {
+        interrupt::free(|cs| {
+            let (old_type, old_size, interval) = {
+                let allocator = self.allocator.borrow(cs).borrow();
+                let ep = match ep_addr.direction() {
+                    UsbDirection::In => allocator.endpoints_in.get(ep_addr.index())
+                        .and_then(|ep| ep.as_ref())
+                        .map(|ep| (ep.ep_type(), ep.max_packet_size(), ep.interval())),
+                    UsbDirection::Out => allocator.endpoints_out.get(ep_addr.index())
+                        .and_then(|ep| ep.as_ref())
+                        .map(|ep| (ep.ep_type(), ep.max_packet_size(), ep.interval())),
+                };
+                ep.ok_or(Error::EndpointNotAllocated)?
+            };
+            if self.transfers_pending(cs, ep_addr) {
+                return Err(Error::TransferPending);
+            }
+
+            // Packets the core receives for the other endpoints meanwhile must not stop the
+            // rollback, so the pending transfers are only checked once above
+            self.free_ep(ep_addr)?;
+            match self.alloc_and_configure_ep(cs, ep_addr, ep_type, max_packet_size, interval) {
+                Ok(()) => Ok(()),
+                Err(err) => match self.alloc_and_configure_ep(cs, ep_addr, old_type, old_size, interval) {
+                    Ok(()) => Err(err),
+                    Err(_) => Err(Error::EndpointNotAllocated),
+                },
+            }
+        })
+    }
+}
+
+/// Location of an IN endpoint's TX FIFO in the shared FIFO RAM.
+#[derive(Copy, Clone, Default, Eq, PartialEq, Debug)]
+pub struct TxFifo {
+    /// Start address in 32-bit words
+    pub start_words: u16,
+    /// Size in 32-bit words, zero if the endpoint is not allocated
+    pub size_words: u16,
+}
+
+/// Partitioning of the shared FIFO RAM between the RX FIFO and the TX FIFOs.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct FifoLayout {
+    rx_size_words: u16,
+    tx: [TxFifo; ENDPOINT_COUNT],
+}
+
+impl FifoLayout {
+    /// Returns the size of the RX FIFO shared by all OUT endpoints, in 32-bit words.
+    ///
+    /// The RX FIFO starts at address 0.
+    pub fn rx_size_words(&self) -> u16 {
+        self.rx_size_words
+    }
+
+    /// Returns the TX FIFO of the IN endpoint `ep_number`, or `None` if the peripheral doesn't
+    /// have such an endpoint.
+    pub fn tx_fifo(&self, ep_number: usize) -> Option<TxFifo> {
+        self.tx.get(ep_number).copied()
+    }
+
+    /// Returns the number of words used by all the FIFOs.
+    pub fn total_words(&self) -> u16 {
+        self.rx_size_words + self.tx.iter().map(|fifo| fifo.size_words).sum::<u16>()
+    }
+}
+
+/// Wakers registered for endpoint events, indexed by endpoint number.
+#[derive(Default)]
+struct EndpointWakers {
+    ep_in: [Option<Waker>; ENDPOINT_COUNT],
+    ep_out: [Option<Waker>; ENDPOINT_COUNT],
+}
+
+impl EndpointWakers {
+    fn register(&mut self, ep_addr: EndpointAddress, waker: &Waker) {
+        let slots = match ep_addr.direction() {
+            UsbDirection::In => &mut self.ep_in,
+            UsbDirection::Out => &mut self.ep_out,
+        };
+        match &mut slots[ep_addr.index()] {
+            Some(registered) if registered.will_wake(waker) => {},
+            slot => *slot = Some(waker.clone()),
+        }
+    }
+
+    /// Wakes the tasks waiting for the endpoints set in the `ep_in` and `ep_out` bitmaps.
+    fn wake(&mut self, ep_in: u16, ep_out: u16) {
+        for (index, (waker_in, waker_out)) in self.ep_in.iter_mut().zip(&mut self.ep_out).enumerate() {
+            if ep_in & (1 << index) != 0 {
+                if let Some(waker) = waker_in.take() {
+                    waker.wake();
+                }
+            }
+            if ep_out & (1 << index) != 0 {
+                if let Some(waker) = waker_out.take() {
+                    waker.wake();
+                }
             }
         }
     }
 }
 
 pub struct EndpointAllocator {
-    bitmap_in: u8,
-    bitmap_out: u8,
-    endpoints_in: [Option<EndpointIn>; 4],
-    endpoints_out: [Option<EndpointOut>; 4],
+    bitmap_in: u16,
+    bitmap_out: u16,
+    endpoints_in: [Option<EndpointIn>; ENDPOINT_COUNT],
+    endpoints_out: [Option<EndpointOut>; ENDPOINT_COUNT],
     memory_allocator: EndpointMemoryAllocator,
+    high_speed: bool,
+    tx_fifo_size_words: [u16; MAX_ENDPOINTS],
+    rx_buffer_size: [u16; MAX_ENDPOINTS],
+    direct_read: [bool; MAX_ENDPOINTS],
+    dma: bool,
+    manual_out_rearm: bool,
+    endpoint_count: usize,
+    /// Address of the peripheral's register block
+    base_address: usize,
 }
 
 impl EndpointAllocator {
-    const ENDPOINT_COUNT: u8 = 4;
-
-    fn new(memory: &'static mut [u32]) -> Self {
+    pub(crate) fn new(
+        memory: &'static mut [MaybeUninit<u32>],
+        config: &Config,
+        high_speed: bool,
+        dma: bool,
+        base_address: usize,
+        fifo_depth_words: usize,
+        endpoint_count: usize,
+    ) -> Self {
+        let endpoint_count = core::cmp::min(endpoint_count, ENDPOINT_COUNT);
         Self {
             bitmap_in: 0,
             bitmap_out: 0,
-            // [None; 4] requires Copy
-            endpoints_in: [None, None, None, None],
-            endpoints_out: [None, None, None, None],
-            memory_allocator: EndpointMemoryAllocator::new(memory),
+            endpoints_in: Default::default(),
+            endpoints_out: Default::default(),
+            memory_allocator: EndpointMemoryAllocator::new(memory, fifo_depth_words, endpoint_count),
+            high_speed,
+            tx_fifo_size_words: config.tx_fifo_size_words,
+            rx_buffer_size: config.rx_buffer_size,
+            direct_read: config.direct_read,
+            dma,
+            manual_out_rearm: config.manual_out_rearm,
+            endpoint_count,
+            base_address,
         }
     }
 
-    fn alloc_number(bitmap: &mut u8, number: Option<u8>) -> Result<u8> {
+    fn alloc_number(bitmap: &mut u16, number: Option<u8>, endpoint_count: usize) -> core::result::Result<u8, Error> {
         if let Some(number) = number {
-            if number >= Self::ENDPOINT_COUNT {
-                return Err(UsbError::InvalidEndpoint);
+            if number as usize >= endpoint_count {
+                return Err(Error::EndpointUnavailable);
             }
             if *bitmap & (1 << number) == 0 {
                 *bitmap |= 1 << number;
                 Ok(number)
             } else {
-                Err(UsbError::InvalidEndpoint)
+                Err(Error::EndpointUnavailable)
             }
         } else {
             // Skip EP0
-            for number in 1..Self::ENDPOINT_COUNT {
+            for number in 1..endpoint_count as u8 {
                 if *bitmap & (1 << number) == 0 {
                     *bitmap |= 1 << number;
                     return Ok(number)
                 }
             }
-            Err(UsbError::EndpointOverflow)
+            Err(Error::EndpointsExhausted)
+        }
+    }
+
+    fn alloc(
+        bitmap: &mut u16,
+        config: &EndpointConfig,
+        direction: UsbDirection,
+        high_speed: bool,
+        endpoint_count: usize,
+    ) -> core::result::Result<EndpointDescriptor, Error> {
+        // The speed is negotiated only during the bus reset, so high-speed capable devices may use
+        // packet sizes valid for either speed.
+        let valid = is_valid_max_packet_size(config.ep_type, config.max_packet_size, false)
+            || (high_speed && is_valid_max_packet_size(config.ep_type, config.max_packet_size, true));
+        // OUT transfers are programmed one packet at a time, so high-bandwidth OUT endpoints with
+        // several transactions per microframe aren't supported
+        let high_bandwidth_out = direction == UsbDirection::Out && transactions_per_frame(config.max_packet_size) > 1;
+        if !valid || high_bandwidth_out {
+            return Err(Error::InvalidMaxPacketSize);
+        }
+        if !is_valid_interval(config.ep_type, config.interval) {
+            return Err(Error::InvalidInterval);
+        }
+        if !cfg!(feature = "iso") && config.ep_type == EndpointType::Isochronous {
+            return Err(Error::IsochronousDisabled);
+        }
+
+        let number = Self::alloc_number(bitmap, config.number, endpoint_count)?;
+        let address = EndpointAddress::from_parts(number as usize, direction);
+        Ok(EndpointDescriptor {
+            address,
+            ep_type: config.ep_type,
+            max_packet_size: config.max_packet_size,
+            interval: config.interval
+        })
+    }
+
+    fn alloc_in(&mut self, config: &EndpointConfig) -> core::result::Result<EndpointIn, Error> {
+        let descr = Self::alloc(&mut self.bitmap_in, config, UsbDirection::In, self.high_speed, self.endpoint_count)?;
+
+        // All transactions of a (micro)frame are written into the FIFO at once
+        let size = packet_size(descr.max_packet_size) as usize * transactions_per_frame(descr.max_packet_size) as usize;
+        let requested_size = self.tx_fifo_size_words[descr.address.index()] as usize * 4;
+        let size = core::cmp::max(size, requested_size);
+        self.memory_allocator.allocate_tx_buffer(descr.address.index() as u8, size)?;
+        let fifo_size_words = self.memory_allocator.tx_fifo_size_words(descr.address.index() as u8);
+        let mut ep = EndpointIn::new(descr, self.base_address, fifo_size_words);
+
+        if self.dma {
+            let buffer = match self.memory_allocator.allocate_dma_buffer(ep.max_write_size()) {
+                Ok(buffer) => buffer,
+                Err(err) => {
+                    self.memory_allocator.free_tx_buffer(ep.address().index() as u8);
+                    return Err(err);
+                }
+            };
+            ep.dma_buffer = Some(Mutex::new(RefCell::new(buffer)));
+        }
+
+        Ok(ep)
+    }
+
+    fn alloc_out(&mut self, config: &EndpointConfig) -> core::result::Result<EndpointOut, Error> {
+        let descr = Self::alloc(&mut self.bitmap_out, config, UsbDirection::Out, self.high_speed, self.endpoint_count)?;
+
+        let mut size = packet_size(descr.max_packet_size) as usize;
+        if self.dma && descr.address.index() == 0 {
+            // The core writes back-to-back SETUP packets one after another
+            size = core::cmp::max(size, 8 * SETUP_PACKETS as usize);
+        }
+        let index = descr.address.index();
+        let direct = self.direct_read[index] && !self.dma && index != 0;
+        let buffer = if direct {
+            self.memory_allocator.allocate_rx_fifo(size)?
+        } else {
+            let requested_size = self.rx_buffer_size[index] as usize;
+            self.memory_allocator.allocate_rx_buffer_with_size(size, requested_size)?
+        };
+        let ep = EndpointOut::new(descr, self.base_address, buffer, self.dma, self.manual_out_rearm, direct);
+
+        Ok(ep)
+    }
+
+    /// Returns the FIFO layout for the currently allocated endpoints.
+    pub(crate) fn fifo_layout(&self) -> FifoLayout {
+        let rx_size_words = self.memory_allocator.total_rx_buffer_size_words() + RX_FIFO_EXTRA_WORDS as u16;
+
+        let mut layout = FifoLayout {
+            rx_size_words,
+            tx: [TxFifo::default(); ENDPOINT_COUNT],
+        };
+
+        let mut fifo_top = rx_size_words;
+        for (i, fifo) in layout.tx.iter_mut().enumerate() {
+            let size_words = self.memory_allocator.tx_fifo_size_words(i as u8);
+            *fifo = TxFifo {
+                start_words: fifo_top,
+                size_words,
+            };
+            fifo_top += size_words;
+        }
+
+        layout
+    }
+
+    pub(crate) fn free_ep(&mut self, ep_addr: EndpointAddress, cs: &CriticalSection) -> core::result::Result<(), Error> {
+        let index = ep_addr.index();
+        match ep_addr.direction() {
+            UsbDirection::Out => {
+                let ep = self.endpoints_out.get_mut(index)
+                    .and_then(|ep| ep.take())
+                    .ok_or(Error::EndpointNotAllocated)?;
+                self.memory_allocator.free_rx_buffer(&ep.buffer.borrow(cs).borrow());
+                self.bitmap_out &= !(1 << index);
+            },
+            UsbDirection::In => {
+                let ep = self.endpoints_in.get_mut(index)
+                    .and_then(|ep| ep.take())
+                    .ok_or(Error::EndpointNotAllocated)?;
+                if let Some(buffer) = &ep.dma_buffer {
+                    self.memory_allocator.free_dma_buffer(&buffer.borrow(cs).borrow());
+                }
+                self.memory_allocator.free_tx_buffer(index as u8);
+                self.bitmap_in &= !(1 << index);
+            },
+        }
+        Ok(())
+    }
+
+    /// Returns the endpoint memory buffer of an OUT endpoint (`0..ENDPOINT_COUNT`) or the DMA
+    /// buffer of an IN endpoint (`ENDPOINT_COUNT..2 * ENDPOINT_COUNT`).
+    fn buffer<'a>(
+        endpoints_in: &'a [Option<EndpointIn>],
+        endpoints_out: &'a [Option<EndpointOut>],
+        slot: usize,
+    ) -> Option<&'a Mutex<RefCell<EndpointBuffer>>> {
+        if slot < ENDPOINT_COUNT {
+            endpoints_out[slot].as_ref().map(|ep| &ep.buffer)
+        } else {
+            endpoints_in.get(slot - ENDPOINT_COUNT)
+                .and_then(|ep| ep.as_ref())
+                .and_then(|ep| ep.dma_buffer.as_ref())
         }
     }
 
-    fn alloc(bitmap: &mut u8, config: &EndpointConfig, direction: UsbDirection) -> Result<EndpointDescriptor> {
-        let number = Self::alloc_number(bitmap, config.number)?;
-        let address = EndpointAddress::from_parts(number as usize, direction);
-        Ok(EndpointDescriptor {
-            address,
-            ep_type: config.ep_type,
-            max_packet_size: config.max_packet_size,
-            interval: config.interval
+    /// Returns the address and the size in bytes of the endpoint memory buffer used by `ep_addr`.
+    #[cfg(feature = "fuzzing")]
+    pub(crate) fn buffer_memory(&self, ep_addr: EndpointAddress, cs: &CriticalSection) -> Option<(usize, usize)> {
+        let slot = match ep_addr.direction() {
+            UsbDirection::Out => ep_addr.index(),
+            UsbDirection::In => ENDPOINT_COUNT + ep_addr.index(),
+        };
+        if ep_addr.index() >= ENDPOINT_COUNT {
+            return None;
+        }
+        Self::buffer(&self.endpoints_in, &self.endpoints_out, slot).map(|buffer| {
+            let buffer = buffer.borrow(cs).borrow();
+            (buffer.as_ptr() as usize, buffer.capacity())
         })
     }
 
-    fn alloc_in(&mut self, config: &EndpointConfig) -> Result<EndpointIn> {
-        let descr = Self::alloc(&mut self.bitmap_in, config, UsbDirection::In)?;
-
-        self.memory_allocator.allocate_tx_buffer(descr.address.index() as u8, descr.max_packet_size as usize)?;
-        let ep = EndpointIn::new(descr);
-
-        Ok(ep)
+    /// Returns the DMA address of every endpoint memory buffer, indexed like `buffer`'s slots,
+    /// 0 for the slots without a buffer.
+    #[cfg(feature = "hs")]
+    fn buffer_addresses(&self, cs: &CriticalSection) -> [u32; 2 * ENDPOINT_COUNT] {
+        let mut addresses = [0; 2 * ENDPOINT_COUNT];
+        for (slot, address) in addresses.iter_mut().enumerate() {
+            if let Some(buffer) = Self::buffer(&self.endpoints_in, &self.endpoints_out, slot) {
+                *address = buffer.borrow(cs).borrow().as_ptr() as u32;
+            }
+        }
+        addresses
     }
 
-    fn alloc_out(&mut self, config: &EndpointConfig) -> Result<EndpointOut> {
-        let descr = Self::alloc(&mut self.bitmap_out, config, UsbDirection::Out)?;
+    /// Packs the endpoint buffers together to reclaim the memory of the freed endpoints.
+    pub(crate) fn compact_memory(&mut self, cs: &CriticalSection) {
+        let mut order = [0; 2 * ENDPOINT_COUNT];
+        let mut count = 0;
+        let (endpoints_in, endpoints_out) = (&self.endpoints_in, &self.endpoints_out);
+        for slot in 0..2 * ENDPOINT_COUNT {
+            if Self::buffer(endpoints_in, endpoints_out, slot).is_some() {
+                order[count] = slot;
+                count += 1;
+            }
+        }
+
+        let buffer_ptr = |slot: &usize| {
+            Self::buffer(endpoints_in, endpoints_out, *slot).map(|buffer| buffer.borrow(cs).borrow().as_ptr())
+        };
+        order[..count].sort_unstable_by_key(buffer_ptr);
 
-        let buffer = self.memory_allocator.allocate_rx_buffer(descr.max_packet_size as usize)?;
-        let ep = EndpointOut::new(descr, buffer);
+        self.memory_allocator.begin_compaction();
+        for &slot in &order[..count] {
+            if let Some(buffer) = Self::buffer(endpoints_in, endpoints_out, slot) {
+                self.memory_allocator.relocate_rx_buffer(&mut buffer.borrow(cs).borrow_mut());
+            }
+        }
+    }
 
-        Ok(ep)
+    /// Returns the bitmaps of the OUT endpoints that have a data and a SETUP packet to read.
+    fn out_events(&self) -> (u16, u16) {
+        let mut ep_out = 0;
+        let mut ep_setup = 0;
+        for ep in &self.endpoints_out {
+            if let Some(ep) = ep {
+                match ep.buffer_state() {
+                    EndpointBufferState::DataOut => {
+                        ep_out |= 1 << ep.address().index();
+                    },
+                    EndpointBufferState::DataSetup => {
+                        ep_setup |= 1 << ep.address().index();
+                    },
+                    EndpointBufferState::Empty => {},
+                }
+            }
+        }
+        (ep_out, ep_setup)
     }
 
-    fn alloc_ep(
+    pub(crate) fn alloc_ep(
         &mut self,
         ep_dir: UsbDirection,
         ep_addr: Option<EndpointAddress>,
         ep_type: EndpointType,
         max_packet_size: u16,
-        interval: u8) -> Result<EndpointAddress>
+        interval: u8) -> core::result::Result<EndpointAddress, Error>
     {
-        let ep_type = unsafe { core::mem::transmute(ep_type) };
         let number = ep_addr.map(|a| a.index() as u8);
 
         let config = EndpointConfig {
@@ -229,20 +2675,25 @@ impl EndpointAllocator {
             number,
             pair_of: None
         };
-        match ep_dir {
-            UsbDirection::Out => {
-                let ep = self.alloc_out(&config)?;
+        // A number is reserved before the memory, give it back if the memory runs out
+        let bitmaps = (self.bitmap_in, self.bitmap_out);
+        let result = match ep_dir {
+            UsbDirection::Out => self.alloc_out(&config).map(|ep| {
                 let address = ep.address();
                 self.endpoints_out[address.index()] = Some(ep);
-                Ok(address)
-            },
-            UsbDirection::In => {
-                let ep = self.alloc_in(&config)?;
+                address
+            }),
+            UsbDirection::In => self.alloc_in(&config).map(|ep| {
                 let address = ep.address();
                 self.endpoints_in[address.index()] = Some(ep);
-                Ok(address)
-            },
+                address
+            }),
+        };
+        if result.is_err() {
+            self.bitmap_in = bitmaps.0;
+            self.bitmap_out = bitmaps.1;
         }
+        result
     }
 }
 
@@ -255,70 +2706,21 @@ impl<USB: UsbPeripheral> usb_device::bus::UsbBus for UsbBus<USB> {
         max_packet_size: u16,
         interval: u8) -> Result<EndpointAddress>
     {
-        self.allocator.alloc_ep(ep_dir, ep_addr, ep_type, max_packet_size, interval)
+        interrupt::free(|cs| {
+            let mut allocator = self.allocator.borrow(cs).borrow_mut();
+            let result = if self.dma_enabled() && !is_dma_reachable(USB::DMA_REGIONS, &allocator.memory_allocator.memory_range()) {
+                Err(Error::DmaMemoryUnreachable)
+            } else {
+                allocator.alloc_ep(ep_dir, ep_addr, ep_type, max_packet_size, interval)
+            };
+            self.alloc_error.borrow(cs).set(result.err());
+            result.map_err(UsbError::from)
+        })
     }
 
     fn enable(&mut self) {
-        // Enable USB_OTG in RCC
-        USB::enable();
-
-        interrupt::free(|cs| {
-            let regs = self.regs.borrow(cs);
-
-            // Wait for AHB ready
-            while read_reg!(otg_global, regs.global, GRSTCTL, AHBIDL) == 0 {}
-
-            // Configure OTG as device
-            #[cfg(feature = "fs")]
-            modify_reg!(otg_global, regs.global, GUSBCFG,
-                SRPCAP: 0, // SRP capability is not enabled
-                TRDT: 0x6, // ??? USB turnaround time
-                FDMOD: 1 // Force device mode
-            );
-            #[cfg(feature = "hs")]
-            modify_reg!(otg_global, regs.global, GUSBCFG,
-                SRPCAP: 0, // SRP capability is not enabled
-                TRDT: 0x9, // ??? USB turnaround time
-                TOCAL: 0x1,
-                FDMOD: 1, // Force device mode
-                PHYSEL: 1
-            );
-
-            // Configuring Vbus sense and SOF output
-            //write_reg!(otg_global, regs.global, GCCFG, VBUSBSEN: 1);
-            write_reg!(otg_global, regs.global, GCCFG, 1 << 21); // set NOVBUSSENS
-
-            // Enable PHY clock
-            write_reg!(otg_pwrclk, regs.pwrclk, PCGCCTL, 0);
-
-            // Soft disconnect device
-            modify_reg!(otg_device, regs.device, DCTL, SDIS: 1);
-
-            // Setup USB FS speed [and frame interval]
-            modify_reg!(otg_device, regs.device, DCFG,
-                DSPD: 0b11 // Device speed: Full speed
-            );
-
-            // unmask EP interrupts
-            write_reg!(otg_device, regs.device, DIEPMSK, XFRCM: 1);
-
-            // unmask core interrupts
-            write_reg!(otg_global, regs.global, GINTMSK,
-                USBRST: 1, ENUMDNEM: 1,
-                USBSUSPM: 1, WUIM: 1,
-                IEPINT: 1, RXFLVLM: 1
-            );
-
-            // clear pending interrupts
-            write_reg!(otg_global, regs.global, GINTSTS, 0xffffffff);
-
-            // unmask global interrupt
-            modify_reg!(otg_global, regs.global, GAHBCFG, GINT: 1);
-
-            // connect(true)
-            modify_reg!(otg_global, regs.global, GCCFG, PWRDWN: 1);
-            modify_reg!(otg_device, regs.device, DCTL, SDIS: 0);
-        });
+        let result = self.initialize();
+        interrupt::free(|cs| self.enable_error.borrow(cs).set(result.err()));
     }
 
     fn reset(&self) {
@@ -328,186 +2730,726 @@ impl<USB: UsbPeripheral> usb_device::bus::UsbBus for UsbBus<USB> {
             self.configure_all(cs);
 
             modify_reg!(otg_device, regs.device, DCFG, DAD: 0);
+            self.pending_address.borrow(cs).set(None);
+            self.config_descriptor_requested.borrow(cs).set(false);
         });
     }
 
     fn set_device_address(&self, addr: u8) {
         interrupt::free(|cs| {
-            let regs = self.regs.borrow(cs);
-
-            modify_reg!(otg_device, regs.device, DCFG, DAD: addr as u32);
+            if addr != 0 {
+                self.set_enumeration_state(cs, EnumerationState::Addressed);
+            }
+            if self.config.set_address_before_status {
+                let regs = self.regs.borrow(cs);
+                modify_reg!(otg_device, regs.device, DCFG, DAD: addr as u32);
+                trace!(self, cs, TraceEvent::AddressSet { address: addr });
+            } else {
+                self.pending_address.borrow(cs).set(Some(addr));
+            }
         });
     }
 
     fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
-        if !ep_addr.is_in() || ep_addr.index() >= 4 {
+        if !ep_addr.is_in() || ep_addr.index() >= Self::endpoint_count() {
             return Err(UsbError::InvalidEndpoint);
         }
-        if let Some(ep) = &self.allocator.endpoints_in[ep_addr.index()] {
-            ep.write(buf).map(|_| buf.len())
-        } else {
-            Err(UsbError::InvalidEndpoint)
+        let ep_bit = 1 << ep_addr.index();
+
+        let fill = interrupt::free(|cs| {
+            if !self.connected.borrow(cs).get() {
+                return Err(UsbError::InvalidState);
+            }
+
+            let filling = self.tx_filling.borrow(cs);
+            if filling.get() & ep_bit != 0 {
+                // Another write() is still filling the FIFO
+                return Err(UsbError::WouldBlock);
+            }
+
+            if ep_addr.index() == 0 && self.config_descriptor_requested.borrow(cs).replace(false) {
+                self.snoop_config_descriptor(cs, buf);
+            }
+
+            if let Some(ep) = &self.allocator.borrow(cs).borrow().endpoints_in[ep_addr.index()] {
+                let frame_number = read_reg!(otg_device, self.regs.borrow(cs).device, DSTS, FNSOF);
+                let result = ep.start_write(cs, buf, frame_number as u16, USB::clean_dcache);
+                if matches!(result, Err(UsbError::WouldBlock)) && self.config.fifo_empty_completion(ep_addr.index()) {
+                    // The FIFO empty report came before the host acknowledged the last packet
+                    let retry = self.tx_retry.borrow(cs);
+                    retry.set(retry.get() | ep_bit);
+                }
+                result?;
+                let fill = ep.dma_buffer.is_none() && !buf.is_empty();
+                if fill {
+                    filling.set(filling.get() | ep_bit);
+                } else {
+                    self.unmask_fifo_empty(cs, ep_addr);
+                }
+                Ok(fill)
+            } else {
+                Err(UsbError::InvalidEndpoint)
+            }
+        })?;
+
+        if fill {
+            // The FIFO has room for all of buf, which is copied with interrupts enabled so that
+            // a long write doesn't hold off the interrupt handler. The endpoint number has been
+            // checked against endpoint_count() above, so the FIFO exists.
+            let _ = Self::core().write_packet(ep_addr.index() as u8, buf);
+
+            interrupt::free(|cs| {
+                let filling = self.tx_filling.borrow(cs);
+                filling.set(filling.get() & !ep_bit);
+                let aborted = self.tx_fill_aborted.borrow(cs);
+                if aborted.get() & ep_bit != 0 {
+                    // The FIFO has been flushed meanwhile, e.g. by a bus reset
+                    aborted.set(aborted.get() & !ep_bit);
+                    self.note_core_wait(cs, Self::core().flush_tx_fifo(ep_addr.index() as u8));
+                } else {
+                    self.unmask_fifo_empty(cs, ep_addr);
+                }
+            });
         }
+
+        Ok(buf.len())
     }
 
     fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<usize> {
-        if !ep_addr.is_out() || ep_addr.index() >= 4 {
+        if !ep_addr.is_out() || ep_addr.index() >= Self::endpoint_count() {
             return Err(UsbError::InvalidEndpoint);
         }
 
-        if let Some(ep) = &self.allocator.endpoints_out[ep_addr.index()] {
-            ep.read(buf)
-        } else {
-            Err(UsbError::InvalidEndpoint)
-        }
+        interrupt::free(|cs| {
+            if let Some(ep) = &self.allocator.borrow(cs).borrow().endpoints_out[ep_addr.index()] {
+                let result = if ep.has_fifo_packet(cs) {
+                    let result = ep.read_fifo_packet(cs, buf);
+                    if let Ok(_size) = result {
+                        trace!(self, cs, TraceEvent::OutPacket { ep_number: ep_addr.index() as u8, size: _size as u16 });
+                        if self.rearm_point(self.regs.borrow(cs)) == Some(OutRearmPoint::PacketReceived) {
+                            ep.rearm_after_receive(cs);
+                        }
+                    }
+                    result
+                } else {
+                    ep.read(buf)
+                };
+                if result.is_ok() && !self.dma_enabled() {
+                    // A packet waiting for this buffer can be received now
+                    let regs = self.regs.borrow(cs);
+                    modify_reg!(otg_global, regs.global, GINTMSK, RXFLVLM: 1);
+                }
+                result
+            } else {
+                Err(UsbError::InvalidEndpoint)
+            }
+        })
     }
 
     fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
-        if ep_addr.index() >= 4 {
+        if ep_addr.index() >= Self::endpoint_count() {
             return;
         }
 
-        crate::endpoint::set_stalled(ep_addr, stalled)
+        interrupt::free(|_| Self::core().set_stalled(ep_addr.index() as u8, ep_addr.direction().into(), stalled))
     }
 
     fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
-        if ep_addr.index() >= 4 {
+        if ep_addr.index() >= Self::endpoint_count() {
             return true;
         }
 
-        crate::endpoint::is_stalled(ep_addr)
+        Self::core().is_stalled(ep_addr.index() as u8, ep_addr.direction().into())
     }
 
     fn suspend(&self) {
-        // Nothing to do here?
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            self.enter_low_power(cs, regs);
+        });
     }
 
     fn resume(&self) {
-        // Nothing to do here?
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            self.exit_low_power(cs, regs);
+        });
     }
 
     fn poll(&self) -> PollResult {
+        self.finish_reconnect();
+        self.wait_vbus_debounce();
+
         interrupt::free(|cs| {
-            let regs = self.regs.borrow(cs);
+            // The core isn't configured, its interrupt status means nothing
+            if self.enable_error.borrow(cs).get().is_some() {
+                return PollResult::None;
+            }
+
+            self.service_interrupts(cs);
+
+            let pending = self.pending.borrow(cs);
+            let mut events = pending.get();
+            let result = if let Some(event) = events.bus.pop() {
+                match event {
+                    BusEvent::Reset => PollResult::Reset,
+                    BusEvent::Resume => PollResult::Resume,
+                    BusEvent::Suspend => PollResult::Suspend,
+                }
+            } else {
+                let (ep_out, ep_setup) = self.allocator.borrow(cs).borrow().out_events();
+                let ep_in_complete = events.ep_in_complete;
+                events.ep_in_complete = 0;
+
+                if (ep_in_complete | ep_out | ep_setup) != 0 {
+                    PollResult::Data { ep_out, ep_in_complete, ep_setup }
+                } else {
+                    PollResult::None
+                }
+            };
+            pending.set(events);
+
+            result
+        })
+    }
+
+    // set_device_address() holds the address back itself when the core needs it after the status
+    // stage, see Config::set_address_before_status
+    const QUIRK_SET_ADDRESS_BEFORE_STATUS: bool = true;
+}
+
+#[cfg(test)]
+mod register_model;
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+
+    struct Peripheral;
+
+    unsafe impl UsbPeripheral for Peripheral {
+        const REGISTERS: *const () = core::ptr::null();
+        const HIGH_SPEED: bool = cfg!(feature = "hs");
+        const FIFO_DEPTH_WORDS: usize = if cfg!(feature = "hs") { 1024 } else { 320 };
+
+        fn enable() {}
+    }
+
+    fn allocator_with_config(config: &Config, high_speed: bool, memory_words: usize) -> EndpointAllocator {
+        let memory = std::vec![MaybeUninit::uninit(); memory_words].leak();
+        EndpointAllocator::new(memory, config, high_speed, cfg!(feature = "hs") && config.dma, 0, Peripheral::FIFO_DEPTH_WORDS, Peripheral::ENDPOINT_COUNT)
+    }
+
+    fn allocator(high_speed: bool) -> EndpointAllocator {
+        allocator_with_config(&Config::default(), high_speed, 256)
+    }
+
+    #[test]
+    #[cfg(feature = "hs")]
+    fn phy_selects_the_speed() {
+        assert!(!UsbBus::<Peripheral>::is_high_speed(&Config::default()));
+        assert!(UsbBus::<Peripheral>::is_high_speed(&Config::default().phy(PhyType::ExternalHighSpeed)));
+        assert!(UsbBus::<Peripheral>::is_high_speed(&Config::default().phy(PhyType::InternalHighSpeed)));
+        assert!(!UsbBus::<Peripheral>::is_high_speed(&Config::default().phy(PhyType::ExternalHighSpeed).ulpi_fs_ls(true)));
+    }
+
+    #[test]
+    fn unsupported_configurations_are_rejected() {
+        let check = UsbBus::<Peripheral>::check_config;
+        let config = || Config::default().out_rearm_point(OutRearmPoint::TransferComplete);
+        assert_eq!(check(&config()), Ok(()));
+        assert_eq!(check(&config().ulpi_auto_resume(true)), Err(Error::InvalidConfig));
+        if cfg!(feature = "hs") {
+            assert_eq!(check(&config().phy(PhyType::ExternalHighSpeed).ulpi_clock_suspend(true)), Ok(()));
+            assert_eq!(check(&config().dma(true).tx_threshold(64)), Ok(()));
+            assert_eq!(check(&config().dma(true).tx_threshold(0x200)), Err(Error::InvalidConfig));
+            assert_eq!(check(&config().tx_threshold(64)), Err(Error::InvalidConfig));
+        } else {
+            assert_eq!(check(&config().dma(true)), Err(Error::CoreUnsupported));
+            assert_eq!(check(&config().phy(PhyType::ExternalHighSpeed)), Err(Error::CoreUnsupported));
+        }
+        // The re-arm point comes from the CID table unless it's configured
+        let expected = if cfg!(feature = "quirks") { Ok(()) } else { Err(Error::InvalidConfig) };
+        assert_eq!(check(&Config::default()), expected);
+    }
+
+    #[test]
+    #[cfg(not(feature = "iso"))]
+    fn isochronous_endpoints_need_the_iso_feature() {
+        let mut allocator = allocator(false);
+
+        let result = allocator.alloc_ep(UsbDirection::In, None, EndpointType::Isochronous, 64, 1);
+        assert!(matches!(result, Err(Error::IsochronousDisabled)));
+        assert!(allocator.alloc_ep(UsbDirection::In, None, EndpointType::Interrupt, 64, 1).is_ok());
+    }
+
+    #[test]
+    fn high_speed_bulk_allocation() {
+        let mut allocator = allocator(true);
+
+        let ep_in = allocator.alloc_ep(UsbDirection::In, None, EndpointType::Bulk, 512, 0).unwrap();
+        let ep_out = allocator.alloc_ep(UsbDirection::Out, None, EndpointType::Bulk, 512, 0).unwrap();
+        assert_eq!(allocator.memory_allocator.tx_fifo_size_words(ep_in.index() as u8), 128);
+        assert_eq!(allocator.endpoints_out[ep_out.index()].as_ref().unwrap().max_packet_size(), 512);
+    }
+
+    #[test]
+    #[cfg(all(feature = "hs", feature = "iso"))]
+    fn high_bandwidth_isochronous_allocation() {
+        let mut allocator = allocator(true);
+
+        let max_packet_size = 1024 | (1 << 11);
+        let ep_in = allocator.alloc_ep(UsbDirection::In, None, EndpointType::Isochronous, max_packet_size, 1).unwrap();
+        let ep_out = allocator.alloc_ep(UsbDirection::Out, None, EndpointType::Isochronous, 1024, 1).unwrap();
+        assert_eq!(allocator.memory_allocator.tx_fifo_size_words(ep_in.index() as u8), 512);
+        assert_eq!(allocator.endpoints_out[ep_out.index()].as_ref().unwrap().packet_size(), 1024);
+    }
+
+    #[test]
+    fn requested_tx_fifo_size() {
+        let config = Config::default().tx_fifo_size(1, 64);
+        let mut allocator = allocator_with_config(&config, false, 256);
+
+        allocator.alloc_ep(UsbDirection::In, None, EndpointType::Bulk, 64, 0).unwrap();
+        allocator.alloc_ep(UsbDirection::In, None, EndpointType::Bulk, 64, 0).unwrap();
+        assert_eq!(allocator.memory_allocator.tx_fifo_size_words(1), 64);
+        assert_eq!(allocator.memory_allocator.tx_fifo_size_words(2), 16);
+    }
+
+    #[test]
+    fn bulk_write_fills_tx_fifo() {
+        let config = Config::default().tx_fifo_size(1, 64);
+        let mut allocator = allocator_with_config(&config, false, 256);
+
+        let bulk = allocator.alloc_ep(UsbDirection::In, None, EndpointType::Bulk, 64, 0).unwrap();
+        let interrupt = allocator.alloc_ep(UsbDirection::In, None, EndpointType::Interrupt, 64, 1).unwrap();
+        assert_eq!(allocator.endpoints_in[bulk.index()].as_ref().unwrap().max_write_size(), 256);
+        assert_eq!(allocator.endpoints_in[interrupt.index()].as_ref().unwrap().max_write_size(), 64);
+    }
 
-            let core_id = read_reg!(otg_global, regs.global, CID);
+    #[test]
+    #[cfg(feature = "hs")]
+    fn dma_buffers_stay_out_of_rx_fifo() {
+        let config = Config::default().dma(true);
+        let mut allocator = allocator_with_config(&config, true, 512);
 
-            let (wakeup, suspend, enum_done, reset, iep, rxflvl) = read_reg!(otg_global, regs.global, GINTSTS,
-                WKUPINT, USBSUSP, ENUMDNE, USBRST, IEPINT, RXFLVL
+        let ep_in = allocator.alloc_ep(UsbDirection::In, None, EndpointType::Bulk, 512, 0).unwrap();
+        allocator.alloc_ep(UsbDirection::Out, None, EndpointType::Bulk, 512, 0).unwrap();
+        assert!(allocator.endpoints_in[ep_in.index()].as_ref().unwrap().dma_buffer.is_some());
+        assert_eq!(allocator.memory_allocator.total_rx_buffer_size_words(), 128);
+    }
+
+    #[test]
+    fn high_bandwidth_is_refused_on_out_endpoints() {
+        let mut allocator = allocator(true);
+        for transactions in [2, 3] {
+            let max_packet_size = 1024 | ((transactions - 1) << 11);
+            assert_eq!(
+                allocator.alloc_ep(UsbDirection::Out, None, EndpointType::Interrupt, max_packet_size, 1),
+                Err(Error::InvalidMaxPacketSize)
             );
+            assert_eq!(
+                allocator.alloc_ep(UsbDirection::Out, None, EndpointType::Isochronous, max_packet_size, 1),
+                Err(Error::InvalidMaxPacketSize)
+            );
+        }
 
-            if reset != 0 {
-                write_reg!(otg_global, regs.global, GINTSTS, USBRST: 1);
+        // IN endpoints write all the transactions of a microframe at once, as far as the FIFO
+        // RAM allows
+        let result = allocator.alloc_ep(UsbDirection::In, None, EndpointType::Interrupt, 1024 | (1 << 11), 1);
+        assert_ne!(result, Err(Error::InvalidMaxPacketSize));
+        allocator.alloc_ep(UsbDirection::Out, None, EndpointType::Interrupt, 1024, 1).unwrap();
+    }
+
+    #[test]
+    fn fifo_budget() {
+        crate::fifo_budget!(Peripheral, rx: [64, 64], tx: [64, 64, 64]);
+
+        let mut allocator = allocator(false);
+        allocator.memory_allocator.allocate_rx_buffer(64).unwrap();
+        allocator.memory_allocator.allocate_rx_buffer(64).unwrap();
+        let rx_words = allocator.memory_allocator.total_rx_buffer_size_words() as usize;
+
+        assert_eq!(
+            crate::config::fifo_budget_words(Peripheral::ENDPOINT_COUNT, &[64, 64], &[64, 256]),
+            rx_words + 30 + 64 + 16 * (Peripheral::ENDPOINT_COUNT - 1)
+        );
+    }
 
-                self.deconfigure_all(cs);
+    #[test]
+    fn wakers_are_woken_once() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
 
-                // Flush RX
-                modify_reg!(otg_global, regs.global, GRSTCTL, RXFFLSH: 1);
-                while read_reg!(otg_global, regs.global, GRSTCTL, RXFFLSH) == 1 {}
+        struct Counter(AtomicUsize);
+
+        impl std::task::Wake for Counter {
+            fn wake(self: Arc<Self>) {
+                self.0.fetch_add(1, Ordering::Relaxed);
             }
+        }
 
-            if enum_done != 0 {
-                write_reg!(otg_global, regs.global, GINTSTS, ENUMDNE: 1);
+        let counter = Arc::new(Counter(AtomicUsize::new(0)));
+        let waker = Waker::from(counter.clone());
+        let mut wakers = EndpointWakers::default();
+        wakers.register(EndpointAddress::from_parts(1, UsbDirection::Out), &waker);
 
-                PollResult::Reset
-            } else if wakeup != 0 {
-                // Clear the interrupt
-                write_reg!(otg_global, regs.global, GINTSTS, WKUPINT: 1);
+        wakers.wake(1 << 1, 0);
+        assert_eq!(counter.0.load(Ordering::Relaxed), 0);
+        wakers.wake(0, 1 << 1);
+        wakers.wake(0, 1 << 1);
+        assert_eq!(counter.0.load(Ordering::Relaxed), 1);
+    }
 
-                PollResult::Resume
-            } else if suspend != 0 {
-                write_reg!(otg_global, regs.global, GINTSTS, USBSUSP: 1);
+    #[test]
+    fn bus_events_are_reported_in_order() {
+        let mut queue = BusEventQueue::default();
+        queue.push(BusEvent::Suspend);
+        queue.push(BusEvent::Resume);
+        queue.push(BusEvent::Resume);
+        queue.push(BusEvent::Suspend);
+        assert_eq!(queue.pop(), Some(BusEvent::Suspend));
+        assert_eq!(queue.pop(), Some(BusEvent::Resume));
+        assert_eq!(queue.pop(), Some(BusEvent::Suspend));
+        assert_eq!(queue.pop(), None);
+
+        queue.push(BusEvent::Suspend);
+        queue.push(BusEvent::Reset);
+        queue.push(BusEvent::Suspend);
+        assert_eq!(queue.pop(), Some(BusEvent::Reset));
+        assert_eq!(queue.pop(), Some(BusEvent::Suspend));
+        assert_eq!(queue.pop(), None);
+    }
 
-                PollResult::Suspend
-            } else {
-                let mut ep_out = 0;
-                let mut ep_in_complete = 0;
-                let mut ep_setup = 0;
-
-                use crate::ral::{endpoint_in, endpoint_out};
-
-                // RXFLVL & IEPINT flags are read-only, there is no need to clear them
-                if rxflvl != 0 {
-                    let (epnum, data_size, status) = read_reg!(otg_global, regs.global, GRXSTSR, EPNUM, BCNT, PKTSTS);
-                    match status {
-                        0x02 => { // OUT received
-                            ep_out |= 1 << epnum;
-                        }
-                        0x06 => { // SETUP received
-                            // flushing TX if something stuck in control endpoint
-                            let ep = endpoint_in::instance(epnum as u8);
-                            if read_reg!(endpoint_in, ep, DIEPTSIZ, PKTCNT) != 0 {
-                                modify_reg!(otg_global, regs.global, GRSTCTL, TXFNUM: epnum, TXFFLSH: 1);
-                                while read_reg!(otg_global, regs.global, GRSTCTL, TXFFLSH) == 1 {}
-                            }
-                            ep_setup |= 1 << epnum;
-                        }
-                        0x03 | 0x04 => { // OUT completed | SETUP completed
-                            // Re-enable the endpoint, F429-like chips only
-                            if core_id == 0x0000_1200 || core_id == 0x0000_1100 {
-                                let ep = endpoint_out::instance(epnum as u8);
-                                modify_reg!(endpoint_out, ep, DOEPCTL, CNAK: 1, EPENA: 1);
-                            }
-                            read_reg!(otg_global, regs.global, GRXSTSP); // pop GRXSTSP
-                        }
-                        _ => {
-                            read_reg!(otg_global, regs.global, GRXSTSP); // pop GRXSTSP
-                        }
-                    }
+    #[test]
+    #[cfg(feature = "quirks")]
+    fn out_endpoints_are_rearmed_where_the_core_needs_it() {
+        assert_eq!(out_rearm_point(0x0000_1200), Some(OutRearmPoint::TransferComplete));
+        assert_eq!(out_rearm_point(0x0000_1100), Some(OutRearmPoint::TransferComplete));
+        assert_eq!(out_rearm_point(0x0000_2000), Some(OutRearmPoint::PacketReceived));
+        assert_eq!(out_rearm_point(0x0000_2100), Some(OutRearmPoint::PacketReceived));
+        assert_eq!(out_rearm_point(0x0000_3000), None);
+    }
 
-                    if status == 0x02 || status == 0x06 {
-                        if let Some(ep) = &self.allocator.endpoints_out[epnum as usize] {
-                            let mut buffer = ep.buffer.borrow(cs).borrow_mut();
-                            if buffer.state() == EndpointBufferState::Empty {
-                                read_reg!(otg_global, regs.global, GRXSTSP); // pop GRXSTSP
-
-                                let is_setup = status == 0x06;
-                                buffer.fill_from_fifo(data_size as u16, is_setup).ok();
-
-                                // Re-enable the endpoint, F446-like chips only
-                                if core_id == 0x0000_2000 || core_id == 0x0000_2100 {
-                                    let ep = endpoint_out::instance(epnum as u8);
-                                    modify_reg!(endpoint_out, ep, DOEPCTL, CNAK: 1, EPENA: 1);
-                                }
-                            }
-                        }
-                    }
-                }
+    #[test]
+    fn fixed_numbers_conflict_per_direction() {
+        let mut allocator = allocator(false);
 
-                if iep != 0 {
-                    for ep in &self.allocator.endpoints_in {
-                        if let Some(ep) = ep {
-                            let ep_regs = endpoint_in::instance(ep.address().index() as u8);
-                            if read_reg!(endpoint_in, ep_regs, DIEPINT, XFRC) != 0 {
-                                write_reg!(endpoint_in, ep_regs, DIEPINT, XFRC: 1);
-                                ep_in_complete |= 1 << ep.address().index();
-                            }
-                        }
-                    }
-                }
+        let ep_out = allocator.alloc_ep(UsbDirection::Out, Some(EndpointAddress::from(0x01)), EndpointType::Bulk, 64, 0).unwrap();
+        assert_eq!(ep_out, EndpointAddress::from(0x01));
+        let result = allocator.alloc_ep(UsbDirection::Out, Some(EndpointAddress::from(0x01)), EndpointType::Bulk, 64, 0);
+        assert!(matches!(result, Err(Error::EndpointUnavailable)));
 
-                for ep in &self.allocator.endpoints_out {
-                    if let Some(ep) = ep {
-                        match ep.buffer_state() {
-                            EndpointBufferState::DataOut => {
-                                ep_out |= 1 << ep.address().index();
-                            },
-                            EndpointBufferState::DataSetup => {
-                                ep_setup |= 1 << ep.address().index();
-                            },
-                            EndpointBufferState::Empty => {},
-                        }
-                    }
+        // IN and OUT endpoints are numbered independently
+        let ep_in = allocator.alloc_ep(UsbDirection::In, Some(EndpointAddress::from(0x81)), EndpointType::Bulk, 64, 0).unwrap();
+        assert_eq!(ep_in, EndpointAddress::from(0x81));
+    }
+
+    #[test]
+    fn automatic_numbers_skip_ep0_and_taken_numbers() {
+        let mut allocator = allocator(false);
+
+        allocator.alloc_ep(UsbDirection::Out, Some(EndpointAddress::from(0x00)), EndpointType::Control, 64, 0).unwrap();
+        allocator.alloc_ep(UsbDirection::Out, Some(EndpointAddress::from(0x02)), EndpointType::Bulk, 64, 0).unwrap();
+
+        let first = allocator.alloc_ep(UsbDirection::Out, None, EndpointType::Bulk, 64, 0).unwrap();
+        let second = allocator.alloc_ep(UsbDirection::Out, None, EndpointType::Bulk, 64, 0).unwrap();
+        assert_eq!(first.index(), 1);
+        assert_eq!(second.index(), 3);
+
+        let ep_in = allocator.alloc_ep(UsbDirection::In, None, EndpointType::Bulk, 64, 0).unwrap();
+        assert_eq!(ep_in.index(), 1);
+    }
+
+    #[test]
+    fn running_out_of_endpoints() {
+        let mut allocator = allocator(false);
+
+        for _ in 1..Peripheral::ENDPOINT_COUNT {
+            allocator.alloc_ep(UsbDirection::In, None, EndpointType::Interrupt, 8, 1).unwrap();
+        }
+        let result = allocator.alloc_ep(UsbDirection::In, None, EndpointType::Interrupt, 8, 1);
+        assert!(matches!(result, Err(Error::EndpointsExhausted)));
+
+        let beyond = EndpointAddress::from_parts(Peripheral::ENDPOINT_COUNT, UsbDirection::Out);
+        let result = allocator.alloc_ep(UsbDirection::Out, Some(beyond), EndpointType::Interrupt, 8, 1);
+        assert!(matches!(result, Err(Error::EndpointUnavailable)));
+    }
+
+    #[test]
+    fn memory_exhaustion_keeps_the_number_free() {
+        let mut allocator = allocator_with_config(&Config::default(), false, 16);
+
+        allocator.alloc_ep(UsbDirection::Out, None, EndpointType::Bulk, 64, 0).unwrap();
+        let result = allocator.alloc_ep(UsbDirection::Out, None, EndpointType::Bulk, 64, 0);
+        assert!(matches!(result, Err(Error::EndpointMemoryOverflow)));
+
+        // The failed allocation doesn't leave its number taken
+        let ep_out = allocator.alloc_ep(UsbDirection::Out, Some(EndpointAddress::from(0x02)), EndpointType::Bulk, 8, 0);
+        assert!(matches!(ep_out, Err(Error::EndpointMemoryOverflow)));
+        let ep_in = allocator.alloc_ep(UsbDirection::In, None, EndpointType::Bulk, 64, 0).unwrap();
+        assert_eq!(ep_in.index(), 1);
+    }
+
+    #[test]
+    fn tx_fifo_exhaustion() {
+        let config = Config::default().tx_fifo_size(1, Peripheral::FIFO_DEPTH_WORDS as u16);
+        let mut allocator = allocator_with_config(&config, false, 256);
+
+        let result = allocator.alloc_ep(UsbDirection::In, None, EndpointType::Bulk, 64, 0);
+        assert!(matches!(result, Err(Error::FifoOverflow)));
+
+        // Retrying runs out of memory again rather than finding the number taken
+        let result = allocator.alloc_ep(UsbDirection::In, Some(EndpointAddress::from(0x81)), EndpointType::Bulk, 64, 0);
+        assert!(matches!(result, Err(Error::FifoOverflow)));
+        let ep_in = allocator.alloc_ep(UsbDirection::In, Some(EndpointAddress::from(0x82)), EndpointType::Bulk, 64, 0).unwrap();
+        assert_eq!(ep_in.index(), 2);
+    }
+
+    #[test]
+    fn high_speed_bulk_rejected_at_full_speed() {
+        let mut allocator = allocator(false);
+
+        let result = allocator.alloc_ep(UsbDirection::In, None, EndpointType::Bulk, 512, 0);
+        assert!(matches!(result, Err(Error::InvalidMaxPacketSize)));
+    }
+
+    #[test]
+    fn periodic_endpoint_needs_a_valid_interval() {
+        let mut allocator = allocator(false);
+
+        let result = allocator.alloc_ep(UsbDirection::In, None, EndpointType::Interrupt, 8, 0);
+        assert!(matches!(result, Err(Error::InvalidInterval)));
+        let ep_in = allocator.alloc_ep(UsbDirection::In, None, EndpointType::Interrupt, 8, 10).unwrap();
+        assert_eq!(ep_in.index(), 1);
+    }
+
+    #[test]
+    fn dma_memory_must_lie_in_a_region() {
+        let regions = [0x2400_0000..0x2408_0000, 0x3000_0000..0x3004_8000];
+        assert!(is_dma_reachable(&regions, &(0x2400_1000..0x2400_2000)));
+        assert!(is_dma_reachable(&regions, &(0x3004_7000..0x3004_8000)));
+        // DTCM, and memory straddling the end of a region
+        assert!(!is_dma_reachable(&regions, &(0x2000_0000..0x2000_1000)));
+        assert!(!is_dma_reachable(&regions, &(0x2407_f000..0x2408_1000)));
+        assert!(is_dma_reachable(&[], &(0x2000_0000..0x2000_1000)));
+    }
+
+    #[test]
+    #[cfg(feature = "hs")]
+    fn hs_core_with_embedded_fs_phy() {
+        let config = Config::default().phy(PhyType::InternalFullSpeed);
+        let high_speed = UsbBus::<Peripheral>::is_high_speed(&config);
+        let mut allocator = allocator_with_config(&config, high_speed, 256);
+
+        let result = allocator.alloc_ep(UsbDirection::In, None, EndpointType::Bulk, 512, 0);
+        assert!(matches!(result, Err(Error::InvalidMaxPacketSize)));
+        for _ in 1..Peripheral::ENDPOINT_COUNT {
+            allocator.alloc_ep(UsbDirection::In, None, EndpointType::Bulk, 64, 0).unwrap();
+            allocator.alloc_ep(UsbDirection::Out, None, EndpointType::Bulk, 64, 0).unwrap();
+        }
+    }
+}
+
+/// Models of the interrupt handler racing the application, run with
+/// `RUSTFLAGS="--cfg loom" cargo test --release --features "stm32f429xx fs" loom_tests`.
+///
+/// The registers are plain memory, the models put the values there the core would.
+#[cfg(all(test, loom))]
+mod loom_tests {
+    extern crate std;
+
+    use super::*;
+    use super::register_model::*;
+    use crate::config::InCompletion;
+    use crate::endpoint::READ_QUEUE_LEN;
+    use crate::ral::otg_fifo;
+    use loom::sync::atomic::{AtomicBool, Ordering};
+    use loom::thread;
+    use std::task::Wake;
+    use usb_device::bus::UsbBus as _;
+
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: std::sync::Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn waker() -> (std::sync::Arc<Flag>, Waker) {
+        let flag = std::sync::Arc::new(Flag(AtomicBool::new(false)));
+        let waker = Waker::from(flag.clone());
+        (flag, waker)
+    }
+
+    #[test]
+    fn out_packet_wakes_the_reader() {
+        model(|| {
+            let bus = bus();
+            let (woken, waker) = waker();
+
+            let reader = {
+                let bus = bus.clone();
+                thread::spawn(move || {
+                    // An async read registers the waker before it looks at the buffer
+                    bus.register_waker(ep_out(), &waker).unwrap();
+                    let mut buf = [0; 64];
+                    bus.read(ep_out(), &mut buf).ok().map(|size| (size, buf))
+                })
+            };
+            interrupt_out_packet(&bus);
+
+            let (size, buf) = match reader.join().unwrap() {
+                Some(read) => read,
+                None => {
+                    assert!(woken.0.load(Ordering::SeqCst), "the packet arrived without a wakeup");
+                    let mut buf = [0; 64];
+                    (bus.read(ep_out(), &mut buf).unwrap(), buf)
                 }
+            };
+            assert_eq!(&buf[..size], &[1, 2, 3, 4]);
+        });
+    }
 
-                if (ep_in_complete | ep_out | ep_setup) != 0 {
-                    PollResult::Data { ep_out, ep_in_complete, ep_setup }
-                } else {
-                    PollResult::None
+    #[test]
+    fn out_packet_is_read_once() {
+        model(|| {
+            let bus = bus();
+
+            let poller = {
+                let bus = bus.clone();
+                thread::spawn(move || bus.poll())
+            };
+            let reader = {
+                let bus = bus.clone();
+                thread::spawn(move || bus.read(ep_out(), &mut [0; 64]).ok())
+            };
+            interrupt_out_packet(&bus);
+
+            // poll() runs the interrupt handler as well, which must leave the buffer alone
+            poller.join().unwrap();
+            let read = reader.join().unwrap();
+            let remaining = bus.read(ep_out(), &mut [0; 64]).ok();
+            match (read, remaining) {
+                (Some(4), None) | (None, Some(4)) => {}
+                other => panic!("packet read as {:?}", other),
+            }
+        });
+    }
+
+    #[test]
+    fn endpoint_memory_is_taken_once() {
+        model(|| {
+            let memory: &'static crate::EndpointMemory<16> = std::boxed::Box::leak(std::boxed::Box::default());
+            let other = thread::spawn(move || memory.take().map(|words| words.len()));
+            let taken = memory.take().map(|words| words.len());
+            match (taken, other.join().unwrap()) {
+                (Some(16), None) | (None, Some(16)) => {}
+                other => panic!("memory taken as {:?}", other),
+            }
+        });
+    }
+
+    #[test]
+    fn transfer_takes_packets_from_the_fifo() {
+        model(|| {
+            let bus = bus();
+            let (woken, waker) = waker();
+            bus.start_read(ep_out(), std::vec![0; 4].leak()).unwrap();
+
+            let reader = {
+                let bus = bus.clone();
+                thread::spawn(move || {
+                    bus.register_waker(ep_out(), &waker).unwrap();
+                    bus.take_read(ep_out()).map(|buf| buf.to_vec())
+                })
+            };
+            interrupt_out_packet(&bus);
+
+            let received = match reader.join().unwrap() {
+                Some(received) => received,
+                None => {
+                    assert!(woken.0.load(Ordering::SeqCst), "the transfer ended without a wakeup");
+                    bus.take_read(ep_out()).unwrap().to_vec()
                 }
+            };
+            assert_eq!(received, [1, 2, 3, 4]);
+            assert!(matches!(bus.read(ep_out(), &mut [0; 64]), Err(UsbError::WouldBlock)));
+        });
+    }
+
+    #[test]
+    fn queued_buffers_take_consecutive_transfers() {
+        model(|| {
+            let bus = bus();
+            for _ in 0..READ_QUEUE_LEN {
+                bus.start_read(ep_out(), std::vec![0; 4].leak()).unwrap();
             }
-        })
+            assert!(matches!(bus.start_read(ep_out(), std::vec![0; 4].leak()), Err((Error::ReadQueueFull, buf)) if buf.len() == 4));
+
+            let reader = {
+                let bus = bus.clone();
+                thread::spawn(move || bus.take_read(ep_out()).map(|buf| buf.to_vec()))
+            };
+            interrupt_out_packet(&bus);
+            interrupt_out_packet(&bus);
+
+            // The second packet has gone into the second buffer, not the endpoint buffer
+            let first = reader.join().unwrap().or_else(|| bus.take_read(ep_out()).map(|buf| buf.to_vec()));
+            assert_eq!(first.unwrap(), [1, 2, 3, 4]);
+            assert_eq!(bus.take_read(ep_out()).unwrap(), [1, 2, 3, 4]);
+            assert!(bus.take_read(ep_out()).is_none());
+            assert!(matches!(bus.read(ep_out(), &mut [0; 64]), Err(UsbError::WouldBlock)));
+        });
     }
 
-    const QUIRK_SET_ADDRESS_BEFORE_STATUS: bool = true;
+    #[test]
+    fn fifo_empty_interrupt_waits_for_the_filled_fifo() {
+        model(|| {
+            let bus = bus_with_config(Config::default().in_completion(InCompletion::FifoEmpty));
+
+            let writer = {
+                let bus = bus.clone();
+                let data: std::vec::Vec<u8> = (1..=64).collect();
+                thread::spawn(move || bus.write(ep_in(), &data).unwrap())
+            };
+            interrupt::free(|cs| {
+                // The interrupt handler may run while the FIFO is being filled, but the FIFO
+                // empty interrupt only fires once the whole packet is in
+                let regs = bus.regs.borrow(cs);
+                if read_reg!(otg_device, regs.device, DIEPEMPMSK) & 0b10 != 0 {
+                    let fifo = otg_fifo::instance(UsbRegisters::<Peripheral>::base_address(), 1).unwrap();
+                    assert_eq!(fifo.read(), 0x403f_3e3d);
+                }
+            });
+            assert_eq!(writer.join().unwrap(), 64);
+        });
+    }
+
+    #[test]
+    fn in_completion_is_reported_once() {
+        model(|| {
+            let bus = bus();
+            let (woken, waker) = waker();
+            bus.write(ep_in(), &[1, 2, 3, 4]).unwrap();
+
+            let writer = {
+                let bus = bus.clone();
+                thread::spawn(move || {
+                    bus.register_waker(ep_in(), &waker).unwrap();
+                    in_complete(bus.poll())
+                })
+            };
+            interrupt_in_complete(&bus);
+
+            let reported = writer.join().unwrap();
+            assert!(reported || woken.0.load(Ordering::SeqCst), "the completion came without a wakeup");
+            assert_ne!(reported, in_complete(bus.poll()));
+        });
+    }
 }
@@ -0,0 +1,883 @@
+//! A model of the core's registers in plain memory, for the tests of the driver's behaviour and
+//! for the loom models of its races. Registers keep what is written to them, the tests set the
+//! bits the core would.
+
+extern crate std;
+
+use super::*;
+use crate::ral::{endpoint_in, otg_fifo};
+#[cfg(loom)]
+use loom::sync::Arc;
+#[cfg(not(loom))]
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use usb_device::bus::{PollResult, UsbBus as _};
+
+const REGISTER_FILE_WORDS: usize = 0x8000;
+
+static mut REGISTER_FILE: [u32; REGISTER_FILE_WORDS] = [0; REGISTER_FILE_WORDS];
+
+pub(super) static STOPS: AtomicUsize = AtomicUsize::new(0);
+pub(super) static TIME: AtomicUsize = AtomicUsize::new(0);
+/// `micros()` of the peripheral, `usize::MAX` if it has no microsecond clock
+pub(super) static MICROS: AtomicUsize = AtomicUsize::new(usize::MAX);
+pub(super) static CLOCK_RESTORES: AtomicUsize = AtomicUsize::new(0);
+/// Calls of the interrupt controller hooks
+pub(super) static INTERRUPT_CONTROLLER: std::sync::Mutex<std::vec::Vec<std::string::String>> = std::sync::Mutex::new(std::vec::Vec::new());
+pub(super) static ENABLE_PROGRESS: std::sync::Mutex<std::vec::Vec<EnableStep>> = std::sync::Mutex::new(std::vec::Vec::new());
+/// Successive results of `vbus_present()`, `None` once they have run out
+pub(super) static VBUS_SAMPLES: std::sync::Mutex<std::collections::VecDeque<bool>> =
+    std::sync::Mutex::new(std::collections::VecDeque::new());
+
+pub(super) struct Peripheral;
+
+unsafe impl UsbPeripheral for Peripheral {
+    const REGISTERS: *const () = core::ptr::addr_of!(REGISTER_FILE) as *const ();
+    const HIGH_SPEED: bool = false;
+    const FIFO_DEPTH_WORDS: usize = 320;
+
+    fn enable() {}
+
+    fn vbus_present() -> Option<bool> {
+        VBUS_SAMPLES.lock().unwrap().pop_front()
+    }
+
+    fn prepare_stop() {
+        STOPS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn timestamp() -> Option<u32> {
+        Some(TIME.load(Ordering::SeqCst) as u32)
+    }
+
+    fn micros() -> Option<u32> {
+        Some(MICROS.load(Ordering::SeqCst)).filter(|us| *us != usize::MAX).map(|us| us as u32)
+    }
+
+    fn restore_clocks() {
+        // The core must still be gated, nothing has touched it yet
+        let regs = UsbRegisters::<Self>::new();
+        assert_eq!(read_reg!(otg_pwrclk, regs.pwrclk, PCGCCTL, STPPCLK, GATEHCLK), (1, 1));
+        CLOCK_RESTORES.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn set_interrupts_enabled(enabled: bool) {
+        INTERRUPT_CONTROLLER.lock().unwrap().push(std::format!("enabled {}", enabled));
+    }
+
+    fn set_interrupt_priority(priority: u8) {
+        INTERRUPT_CONTROLLER.lock().unwrap().push(std::format!("priority {:#x}", priority));
+    }
+}
+
+/// Runs a test against the register file. The tests share it, so they run one at a time, each
+/// on cleared registers and peripheral hooks. Under loom, `f` is explored as a model.
+pub(super) fn model<F>(f: F)
+where
+    F: Fn() + Sync + Send + 'static,
+{
+    static SERIAL: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    let _serial = SERIAL.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    let run = move || {
+        reset();
+        f();
+    };
+    #[cfg(loom)]
+    loom::model(run);
+    #[cfg(not(loom))]
+    run();
+}
+
+fn reset() {
+    clear_registers();
+    STOPS.store(0, Ordering::SeqCst);
+    TIME.store(0, Ordering::SeqCst);
+    MICROS.store(usize::MAX, Ordering::SeqCst);
+    CLOCK_RESTORES.store(0, Ordering::SeqCst);
+    INTERRUPT_CONTROLLER.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clear();
+    ENABLE_PROGRESS.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clear();
+    VBUS_SAMPLES.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clear();
+}
+
+fn clear_registers() {
+    unsafe { core::ptr::addr_of_mut!(REGISTER_FILE).write_bytes(0, 1) };
+}
+
+/// Sets DSTS as the core would, the register is read-only for the driver.
+pub(super) fn set_dsts(value: u32) {
+    let regs = UsbRegisters::<Peripheral>::new();
+    let dsts = &regs.device.DSTS as *const _ as *mut u32;
+    unsafe { dsts.write_volatile(value) };
+}
+
+pub(super) fn ep_out() -> EndpointAddress {
+    EndpointAddress::from(0x01)
+}
+
+pub(super) fn ep_in() -> EndpointAddress {
+    EndpointAddress::from(0x81)
+}
+
+pub(super) fn ep_in_regs() -> endpoint_in::Instance {
+    endpoint_in::instance(UsbRegisters::<Peripheral>::base_address(), 1)
+}
+
+/// Returns a bus with the bulk endpoints 0x01 and 0x81 and the device connected.
+pub(super) fn bus() -> Arc<UsbBus<Peripheral>> {
+    bus_with_config(Config::default())
+}
+
+pub(super) fn bus_with_config(config: Config) -> Arc<UsbBus<Peripheral>> {
+    clear_registers();
+    write_reg!(endpoint_in, ep_in_regs(), DTXFSTS, INEPTFSAV: 0xffff);
+
+    let memory = std::vec![MaybeUninit::uninit(); 64].leak();
+    let mut bus = UsbBus::new_bus(Peripheral, memory, config);
+    bus.alloc_ep(UsbDirection::In, Some(EndpointAddress::from(0x80)), EndpointType::Control, 8, 0).unwrap();
+    bus.alloc_ep(UsbDirection::Out, Some(ep_out()), EndpointType::Bulk, 64, 0).unwrap();
+    bus.alloc_ep(UsbDirection::In, Some(ep_in()), EndpointType::Bulk, 64, 0).unwrap();
+    interrupt::free(|cs| bus.connected.borrow(cs).set(true));
+    Arc::new(bus)
+}
+
+/// Runs the interrupt handler for a 4-byte packet the core has put into the RX FIFO.
+pub(super) fn interrupt_out_packet(bus: &UsbBus<Peripheral>) {
+    interrupt::free(|cs| {
+        otg_fifo::rx(UsbRegisters::<Peripheral>::base_address()).write(0x0403_0201);
+        let allocator = bus.allocator.borrow(cs).borrow();
+        let ep = allocator.endpoints_out[1].as_ref().unwrap();
+        assert!(bus.receive_packet(cs, ep, RxStatus::OutData, 4, None));
+        drop(allocator);
+        bus.service_interrupts(cs);
+    });
+}
+
+/// Runs the interrupt handler for a completed transfer of the IN endpoint 1.
+pub(super) fn interrupt_in_complete(bus: &UsbBus<Peripheral>) {
+    interrupt_in_complete_ep(bus, 1);
+}
+
+pub(super) fn interrupt_in_complete_ep(bus: &UsbBus<Peripheral>, index: u8) {
+    let ep_regs = endpoint_in::instance(UsbRegisters::<Peripheral>::base_address(), index);
+    interrupt::free(|cs| {
+        let regs = bus.regs.borrow(cs);
+        write_reg!(otg_global, regs.global, GINTSTS, IEPINT: 1);
+        write_reg!(endpoint_in, ep_regs, DIEPINT, XFRC: 1);
+        bus.service_interrupts(cs);
+        write_reg!(otg_global, regs.global, GINTSTS, 0);
+        write_reg!(endpoint_in, ep_regs, DIEPINT, 0);
+    });
+}
+
+pub(super) fn device_address(bus: &UsbBus<Peripheral>) -> u32 {
+    interrupt::free(|cs| read_reg!(otg_device, bus.regs.borrow(cs).device, DCFG, DAD))
+}
+
+pub(super) fn in_complete(result: PollResult) -> bool {
+    matches!(result, PollResult::Data { ep_in_complete, .. } if ep_in_complete & 1 << 1 != 0)
+}
+
+/// Runs the interrupt handler for a suspend and a resume flagged together with a completed
+/// transfer of the IN endpoint 1, with the link `suspended` or active by now.
+pub(super) fn interrupt_suspend_resume(bus: &UsbBus<Peripheral>, suspended: bool) {
+    interrupt::free(|cs| {
+        let regs = bus.regs.borrow(cs);
+        set_dsts((suspended as u32) << otg_device::DSTS::SUSPSTS::offset);
+        write_reg!(otg_global, regs.global, GINTSTS, USBSUSP: 1, WKUPINT: 1, IEPINT: 1);
+        write_reg!(endpoint_in, ep_in_regs(), DIEPINT, XFRC: 1);
+        bus.service_interrupts(cs);
+        write_reg!(otg_global, regs.global, GINTSTS, 0);
+        write_reg!(endpoint_in, ep_in_regs(), DIEPINT, 0);
+    });
+}
+
+mod tests {
+    use super::*;
+    use crate::config::InCompletion;
+    use crate::endpoint::READ_QUEUE_LEN;
+
+    #[cfg(feature = "iso")]
+    #[test]
+    fn iso_out_packet_carries_its_frame() {
+        model(|| {
+            let memory = std::vec![MaybeUninit::uninit(); 64].leak();
+            let mut bus = UsbBus::new_bus(Peripheral, memory, Config::default());
+            let iso_out = EndpointAddress::from(0x02);
+            bus.alloc_ep(UsbDirection::Out, Some(iso_out), EndpointType::Isochronous, 64, 1).unwrap();
+
+            interrupt::free(|cs| {
+                let regs = bus.regs.borrow(cs);
+                // A full-speed bus in frame 7, the packet arrived in frame 5
+                set_dsts((7 << otg_device::DSTS::FNSOF::offset) | otg_device::DSTS::ENUMSPD::mask);
+                write_reg!(otg_global, regs.global, GINTSTS, RXFLVL: 1);
+                write_reg!(otg_global, regs.global, GRXSTSR, EPNUM: 2, BCNT: 4, PKTSTS: 0b0010, FRMNUM: 5);
+                otg_fifo::rx(UsbRegisters::<Peripheral>::base_address()).write(0x0403_0201);
+                bus.service_interrupts(cs);
+                write_reg!(otg_global, regs.global, GINTSTS, 0);
+            });
+
+            assert_eq!(bus.iso_out_frame(iso_out), None);
+            let mut buf = [0; 64];
+            assert!(matches!(bus.read(iso_out, &mut buf), Ok(4)));
+            assert_eq!(bus.iso_out_frame(iso_out), Some(Frame { number: 5, microframe: None }));
+        });
+    }
+
+    #[test]
+    fn every_interrupt_cause_is_handled_in_one_poll() {
+        model(|| {
+            let bus = bus();
+            bus.write(ep_in(), &[1, 2, 3, 4]).unwrap();
+            interrupt_suspend_resume(&bus, false);
+
+            assert!(matches!(bus.poll(), PollResult::Suspend));
+            assert!(matches!(bus.poll(), PollResult::Resume));
+            assert!(in_complete(bus.poll()));
+            assert!(matches!(bus.poll(), PollResult::None));
+        });
+    }
+
+    #[test]
+    fn suspended_link_reports_the_suspend_last() {
+        model(|| {
+            let bus = bus();
+            bus.write(ep_in(), &[1, 2, 3, 4]).unwrap();
+            interrupt_suspend_resume(&bus, true);
+
+            assert!(matches!(bus.poll(), PollResult::Resume));
+            assert!(matches!(bus.poll(), PollResult::Suspend));
+            assert!(in_complete(bus.poll()));
+        });
+    }
+
+    #[test]
+    fn enable_waits_report_progress() {
+        model(|| {
+            ENABLE_PROGRESS.lock().unwrap().clear();
+            let bus = bus_with_config(Config::default().enable_progress(|step| ENABLE_PROGRESS.lock().unwrap().push(step)));
+
+            bus.delay_with_progress(EnableStep::ModeSwitch, 2_500);
+            // AHBIDL never gets set in the register file
+            assert!(matches!(bus.wait_ahb_idle(), Err(Error::PhyClockMissing)));
+
+            let progress = ENABLE_PROGRESS.lock().unwrap();
+            assert_eq!(progress.iter().filter(|step| **step == EnableStep::ModeSwitch).count(), 3);
+            assert_eq!(progress.iter().filter(|step| **step == EnableStep::AhbIdle).count(), 10);
+        });
+    }
+
+    #[test]
+    fn failed_enable_is_reported_and_poll_leaves_the_core_alone() {
+        model(|| {
+            // Without the quirks table, the re-arm point must be set for enable() to get that far
+            let bus = bus_with_config(Config::default().out_rearm_point(OutRearmPoint::TransferComplete));
+            // AHBIDL never gets set in the register file, as without the PHY clock
+            assert!(matches!(bus.try_enable(), Err(Error::PhyClockMissing)));
+            assert!(matches!(bus.enable_error(), Some(Error::PhyClockMissing)));
+
+            let regs = UsbRegisters::<Peripheral>::new();
+            write_reg!(otg_global, regs.global, GINTSTS, USBRST: 1);
+            assert!(matches!(bus.poll(), PollResult::None));
+            assert_eq!(read_reg!(otg_global, regs.global, GINTSTS, USBRST), 1);
+        });
+    }
+
+    #[test]
+    fn enumeration_times_out_without_configuration() {
+        /// Runs the interrupt handler on a full-speed bus in frame `frame_number`, at the end of a
+        /// bus reset if `enum_done`.
+        fn interrupt_in_frame(bus: &UsbBus<Peripheral>, frame_number: u32, enum_done: bool) {
+            interrupt::free(|cs| {
+                let regs = bus.regs.borrow(cs);
+                set_dsts((frame_number << otg_device::DSTS::FNSOF::offset) | otg_device::DSTS::ENUMSPD::mask);
+                write_reg!(otg_global, regs.global, GINTSTS, ENUMDNE: enum_done as u32);
+                bus.service_interrupts(cs);
+                write_reg!(otg_global, regs.global, GINTSTS, 0);
+            });
+        }
+
+        model(|| {
+            let bus = bus_with_config(Config::default().enumeration_timeout_ms(3000));
+            interrupt_in_frame(&bus, 100, false);
+            assert_eq!(bus.enumeration_state(), EnumerationState::Idle);
+
+            interrupt_in_frame(&bus, 2000, true);
+            assert_eq!(bus.enumeration_state(), EnumerationState::Reset);
+            bus.set_device_address(5);
+            assert_eq!(bus.enumeration_state(), EnumerationState::Addressed);
+
+            // The frame number wraps around after 2047
+            interrupt_in_frame(&bus, 1500, false);
+            interrupt_in_frame(&bus, 500, false);
+            assert_eq!(bus.enumeration_state(), EnumerationState::Addressed);
+            interrupt_in_frame(&bus, 1000, false);
+            assert_eq!(bus.enumeration_state(), EnumerationState::TimedOut);
+
+            // The host tries again and configures the device in time
+            interrupt_in_frame(&bus, 0, true);
+            interrupt::free(|cs| bus.snoop_setup_packet(cs, &[0x00, 0x09, 1, 0, 0, 0, 0, 0]));
+            interrupt_in_frame(&bus, 1500, false);
+            interrupt_in_frame(&bus, 1000, false);
+            assert_eq!(bus.enumeration_state(), EnumerationState::Configured);
+        });
+    }
+
+    #[test]
+    fn interrupt_without_a_handled_cause_is_counted() {
+        model(|| {
+            let bus = bus();
+            let regs = UsbRegisters::<Peripheral>::new();
+            write_reg!(otg_global, regs.global, GINTMSK, 0xffffffff);
+
+            write_reg!(otg_global, regs.global, GINTSTS, SOF: 1);
+            bus.on_interrupt();
+            assert_eq!(bus.spurious_interrupt_count(), 1);
+
+            write_reg!(otg_global, regs.global, GINTSTS, SOF: 1, ESUSP: 1);
+            bus.on_interrupt();
+            assert_eq!(bus.spurious_interrupt_count(), 1);
+        });
+    }
+
+    #[test]
+    fn interrupts_get_their_priority_before_being_unmasked() {
+        model(|| {
+            INTERRUPT_CONTROLLER.lock().unwrap().clear();
+            let bus = bus();
+
+            bus.enable_interrupts(0x40);
+            bus.disable_interrupts();
+            assert_eq!(*INTERRUPT_CONTROLLER.lock().unwrap(), ["priority 0x40", "enabled true", "enabled false"]);
+        });
+    }
+
+    #[test]
+    fn released_endpoint_memory_is_taken_again() {
+        model(|| {
+            let memory: &'static crate::EndpointMemory<16> = std::boxed::Box::leak(std::boxed::Box::default());
+            let words = memory.take().unwrap();
+            let first = words.as_ptr();
+            assert!(memory.take().is_none());
+
+            unsafe { memory.release() };
+            assert_eq!(memory.take().map(|words| words.as_ptr()), Some(first));
+        });
+    }
+
+    #[test]
+    fn enumerated_speed_sets_turnaround_and_ep0_size() {
+        model(|| {
+            let bus = bus();
+            let enumerate = |enumspd: u32| interrupt::free(|cs| {
+                let regs = bus.regs.borrow(cs);
+                set_dsts(enumspd << otg_device::DSTS::ENUMSPD::offset);
+                write_reg!(otg_global, regs.global, GINTSTS, ENUMDNE: 1);
+                bus.service_interrupts(cs);
+                write_reg!(otg_global, regs.global, GINTSTS, 0);
+                let ep0_regs = endpoint_in::instance(UsbRegisters::<Peripheral>::base_address(), 0);
+                (read_reg!(otg_global, regs.global, GUSBCFG, TRDT), read_reg!(endpoint_in, ep0_regs, DIEPCTL, MPSIZ))
+            });
+
+            // The 8-byte EP0 only exists at full speed
+            assert_eq!(enumerate(0b11), (0x6, 0b11));
+            assert_eq!(enumerate(0b00), (0x9, 0b00));
+            assert!(!bus.is_full_speed_fallback());
+        });
+    }
+
+    #[test]
+    fn packet_carries_its_arrival_time() {
+        model(|| {
+            let bus = bus();
+            TIME.store(100, Ordering::SeqCst);
+            interrupt_out_packet(&bus);
+            TIME.store(200, Ordering::SeqCst);
+
+            assert_eq!(bus.packet_timestamp(ep_out()), None);
+            bus.read(ep_out(), &mut [0; 64]).unwrap();
+            assert_eq!(bus.packet_timestamp(ep_out()), Some(100));
+        });
+    }
+
+    #[test]
+    fn direct_endpoint_is_read_from_the_fifo() {
+        model(|| {
+            let bus = bus_with_config(Config::default().direct_read(1, true));
+            interrupt::free(|cs| {
+                let regs = bus.regs.borrow(cs);
+                write_reg!(otg_global, regs.global, GINTSTS, RXFLVL: 1);
+                write_reg!(otg_global, regs.global, GRXSTSR, EPNUM: 1, BCNT: 4, PKTSTS: 0b0010);
+                otg_fifo::rx(UsbRegisters::<Peripheral>::base_address()).write(0x0403_0201);
+                let allocator = bus.allocator.borrow(cs).borrow();
+                let ep = allocator.endpoints_out[1].as_ref().unwrap();
+                // The packet stays in the FIFO until read() asks for it
+                assert!(!bus.receive_packet(cs, ep, RxStatus::OutData, 4, None));
+            });
+
+            match bus.poll() {
+                PollResult::Data { ep_out, .. } => assert_eq!(ep_out & 0b10, 0b10),
+                _ => panic!("the packet was not reported"),
+            }
+            let mut buf = [0; 64];
+            assert!(matches!(bus.read(ep_out(), &mut buf), Ok(4)));
+            assert_eq!(&buf[..4], &[1, 2, 3, 4]);
+            assert!(matches!(bus.read(ep_out(), &mut buf), Err(UsbError::WouldBlock)));
+        });
+    }
+
+    #[test]
+    fn full_read_queue_leaves_the_buffered_packet_alone() {
+        model(|| {
+            let bus = bus();
+            for _ in 0..READ_QUEUE_LEN {
+                bus.start_read(ep_out(), std::vec![0; 4].leak()).unwrap();
+            }
+            for _ in 0..=READ_QUEUE_LEN {
+                interrupt_out_packet(&bus);
+            }
+
+            // Every transfer has ended, the last packet waits in the endpoint buffer
+            let buf = match bus.start_read(ep_out(), std::vec![0; 4].leak()) {
+                Err((Error::ReadQueueFull, buf)) => buf,
+                _ => panic!("the transfer was queued"),
+            };
+            for _ in 0..READ_QUEUE_LEN {
+                assert_eq!(bus.take_read(ep_out()).unwrap(), [1, 2, 3, 4]);
+            }
+            bus.start_read(ep_out(), buf).unwrap();
+            assert_eq!(bus.take_read(ep_out()).unwrap(), [1, 2, 3, 4]);
+        });
+    }
+
+    #[test]
+    fn tx_fifo_space_is_reported() {
+        model(|| {
+            let bus = bus();
+            write_reg!(endpoint_in, ep_in_regs(), DTXFSTS, INEPTFSAV: 8);
+            assert_eq!(bus.tx_fifo_available(ep_in()), Ok(32));
+            assert_eq!(bus.tx_fifo_available(ep_out()), Err(Error::EndpointNotAllocated));
+
+            // Nothing more goes in until the transfer has completed
+            bus.write(ep_in(), &[0; 32]).unwrap();
+            assert_eq!(bus.tx_fifo_available(ep_in()), Ok(0));
+        });
+    }
+
+    #[test]
+    fn naks_and_retries_are_counted() {
+        model(|| {
+            let bus = bus();
+            interrupt_out_packet(&bus);
+            interrupt::free(|cs| {
+                // The buffer keeps the packet, there's no room for a full-sized one
+                let allocator = bus.allocator.borrow(cs).borrow();
+                allocator.endpoints_out[1].as_ref().unwrap().rearm_after_receive(cs);
+            });
+            assert_eq!(bus.out_nak_count(ep_out()), 1);
+            bus.read(ep_out(), &mut [0; 64]).unwrap();
+            assert_eq!(bus.out_nak_count(ep_out()), 1);
+
+            interrupt::free(|cs| {
+                // The host has polled the empty FIFO and timed out before the transfer completed
+                let regs = bus.regs.borrow(cs);
+                write_reg!(otg_global, regs.global, GINTSTS, IEPINT: 1);
+                write_reg!(endpoint_in, ep_in_regs(), DIEPINT, XFRC: 1, ITTXFE: 1, TOC: 1);
+                bus.service_interrupts(cs);
+                write_reg!(otg_global, regs.global, GINTSTS, 0);
+                write_reg!(endpoint_in, ep_in_regs(), DIEPINT, 0);
+            });
+            assert_eq!(bus.in_retry_count(ep_in()), 2);
+            interrupt_in_complete(&bus);
+            assert_eq!(bus.in_retry_count(ep_in()), 2);
+            assert_eq!(bus.in_retry_count(ep_out()), 0);
+        });
+    }
+
+    #[test]
+    fn refused_write_is_reported_at_transfer_complete() {
+        model(|| {
+            let bus = bus_with_config(Config::default().ep_in_completion(1, InCompletion::FifoEmpty));
+            let fifo_empty = || interrupt::free(|cs| {
+                let regs = bus.regs.borrow(cs);
+                write_reg!(otg_global, regs.global, GINTSTS, IEPINT: 1);
+                write_reg!(endpoint_in, ep_in_regs(), DIEPINT, TXFE: 1);
+                bus.service_interrupts(cs);
+                write_reg!(otg_global, regs.global, GINTSTS, 0);
+                write_reg!(endpoint_in, ep_in_regs(), DIEPINT, 0);
+            });
+            let transfer_complete = || {
+                // The core disables the endpoint once the host has acknowledged the data
+                modify_reg!(endpoint_in, ep_in_regs(), DIEPCTL, EPENA: 0);
+                interrupt_in_complete(&bus);
+            };
+
+            bus.write(ep_in(), &[1, 2, 3, 4]).unwrap();
+            fifo_empty();
+            assert!(in_complete(bus.poll()));
+            transfer_complete();
+            assert!(!in_complete(bus.poll()));
+
+            bus.write(ep_in(), &[1, 2, 3, 4]).unwrap();
+            fifo_empty();
+            assert!(in_complete(bus.poll()));
+            assert!(matches!(bus.write(ep_in(), &[5, 6, 7, 8]), Err(UsbError::WouldBlock)));
+            transfer_complete();
+            assert!(in_complete(bus.poll()));
+        });
+    }
+
+    #[test]
+    fn remote_wakeup_needs_the_descriptor_bit() {
+        model(|| {
+            let bus = bus();
+            let ep0_regs = endpoint_in::instance(UsbRegisters::<Peripheral>::base_address(), 0);
+            write_reg!(endpoint_in, ep0_regs, DTXFSTS, INEPTFSAV: 0xffff);
+            set_dsts(otg_device::DSTS::SUSPSTS::mask);
+
+            let get_config_descriptor = [0x80, 0x06, 0x00, 0x02, 0x00, 0x00, 0x09, 0x00];
+            let set_remote_wakeup = [0x00, 0x03, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00];
+            let send_descriptor = |attributes: u8| {
+                interrupt::free(|cs| bus.snoop_setup_packet(cs, &get_config_descriptor));
+                // The first packet of the data stage, bmAttributes is its last byte
+                let descriptor = [0x09, 0x02, 0x20, 0x00, 0x01, 0x01, 0x00, attributes];
+                bus.write(EndpointAddress::from(0x80), &descriptor).unwrap();
+                interrupt_in_complete_ep(&bus, 0);
+            };
+
+            send_descriptor(0x80);
+            interrupt::free(|cs| bus.snoop_setup_packet(cs, &set_remote_wakeup));
+            assert!(!bus.remote_wakeup_supported());
+            assert!(!bus.remote_wakeup_enabled());
+            assert!(matches!(bus.remote_wakeup(), Err(Error::RemoteWakeupDisabled)));
+
+            send_descriptor(0xa0);
+            interrupt::free(|cs| bus.snoop_setup_packet(cs, &set_remote_wakeup));
+            assert!(bus.remote_wakeup_supported());
+            assert!(bus.remote_wakeup_enabled());
+
+            let clear_remote_wakeup = [0x00, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00];
+            interrupt::free(|cs| bus.snoop_setup_packet(cs, &clear_remote_wakeup));
+            assert!(!bus.remote_wakeup_enabled());
+            assert!(matches!(bus.remote_wakeup(), Err(Error::RemoteWakeupDisabled)));
+        });
+    }
+
+    #[test]
+    fn stop_mode_is_left_once_on_resume() {
+        model(|| {
+            let bus = bus_with_config(Config::default().suspend_stop_mode(true));
+            let clocks_gated = || {
+                interrupt::free(|cs| read_reg!(otg_pwrclk, bus.regs.borrow(cs).pwrclk, PCGCCTL, STPPCLK, GATEHCLK))
+            };
+            let stops = STOPS.load(Ordering::SeqCst);
+            let restores = CLOCK_RESTORES.load(Ordering::SeqCst);
+
+            bus.suspend();
+            bus.suspend();
+            assert_eq!(clocks_gated(), (1, 1));
+            assert_eq!(STOPS.load(Ordering::SeqCst), stops + 1);
+
+            interrupt::free(|cs| {
+                let regs = bus.regs.borrow(cs);
+                write_reg!(otg_global, regs.global, GINTSTS, WKUPINT: 1);
+                bus.service_interrupts(cs);
+                write_reg!(otg_global, regs.global, GINTSTS, 0);
+            });
+            assert_eq!(clocks_gated(), (0, 0));
+            // usb-device follows up with resume(), which has nothing left to do
+            bus.resume();
+            assert_eq!(CLOCK_RESTORES.load(Ordering::SeqCst), restores + 1);
+        });
+    }
+
+    #[test]
+    fn vbus_glitch_keeps_the_session() {
+        model(|| {
+            let bus = bus_with_config(Config::default().vbus_debounce_us(1_000));
+            let sample_vbus = |micros: usize, present: bool| {
+                MICROS.store(micros, Ordering::SeqCst);
+                VBUS_SAMPLES.lock().unwrap().push_back(present);
+                interrupt::free(|cs| bus.service_interrupts(cs));
+                assert!(VBUS_SAMPLES.lock().unwrap().is_empty());
+            };
+
+            // Without a clock poll() waits for the debounce time
+            MICROS.store(usize::MAX, Ordering::SeqCst);
+            VBUS_SAMPLES.lock().unwrap().push_back(true);
+            interrupt::free(|cs| bus.service_interrupts(cs));
+            assert_eq!(bus.next_otg_event(), None);
+            VBUS_SAMPLES.lock().unwrap().push_back(true);
+            bus.poll();
+            assert_eq!(bus.next_otg_event(), Some(OtgEvent::SessionStart));
+
+            // Gone for less than the debounce time
+            sample_vbus(2_000, false);
+            sample_vbus(2_999, false);
+            assert!(bus.needs_poll());
+            sample_vbus(3_000, true);
+            assert!(!bus.needs_poll());
+            sample_vbus(5_000, true);
+            assert!(bus.is_connected());
+            assert_eq!(bus.next_otg_event(), None);
+            MICROS.store(usize::MAX, Ordering::SeqCst);
+        });
+    }
+
+    #[test]
+    fn device_only_cores_leave_the_otg_interrupts_masked() {
+        struct DeviceOnlyPeripheral;
+
+        unsafe impl UsbPeripheral for DeviceOnlyPeripheral {
+            const REGISTERS: *const () = Peripheral::REGISTERS;
+            const HIGH_SPEED: bool = false;
+            const FIFO_DEPTH_WORDS: usize = 320;
+            const DEVICE_ONLY: bool = true;
+
+            fn enable() {}
+        }
+
+        model(|| {
+            let regs = UsbRegisters::<Peripheral>::new();
+            let otg_mask = || read_reg!(otg_global, regs.global, GINTMSK, OTGINT, SRQIM, CIDSCHGM);
+
+            let bus = bus();
+            interrupt::free(|cs| bus.unmask_core_interrupts(bus.regs.borrow(cs)));
+            assert_eq!(otg_mask(), (1, 1, 1));
+            assert_eq!(read_reg!(otg_global, regs.global, GINTMSK, USBRST, IEPINT), (1, 1));
+
+            let memory = std::vec![MaybeUninit::uninit(); 64].leak();
+            let bus = UsbBus::new_bus(DeviceOnlyPeripheral, memory, Config::default());
+            interrupt::free(|cs| bus.unmask_core_interrupts(bus.regs.borrow(cs)));
+            assert_eq!(otg_mask(), (0, 0, 0));
+            assert_eq!(read_reg!(otg_global, regs.global, GINTMSK, USBRST, IEPINT), (1, 1));
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "hs")]
+    fn realloc_moves_the_armed_dma_transfers_along() {
+        use crate::ral::endpoint;
+
+        struct HsPeripheral;
+
+        unsafe impl UsbPeripheral for HsPeripheral {
+            const REGISTERS: *const () = Peripheral::REGISTERS;
+            const HIGH_SPEED: bool = true;
+            const FIFO_DEPTH_WORDS: usize = 1024;
+
+            fn enable() {}
+        }
+
+        model(|| {
+            let regs = UsbRegisters::<HsPeripheral>::new();
+            // Global OUT NAK takes effect and endpoints get disabled right away
+            write_reg!(otg_global, regs.global, GINTSTS, BOUTNAKEFF: 1);
+            for ep_number in 1..4 {
+                let ep = endpoint::instance(UsbRegisters::<HsPeripheral>::base_address(), true, ep_number);
+                write_reg!(endpoint, ep, DEPINT, EPDISD: 1);
+            }
+
+            let memory = std::vec![MaybeUninit::uninit(); 256].leak();
+            let mut bus = UsbBus::new_bus(HsPeripheral, memory, Config::default().dma(true));
+            bus.alloc_ep(UsbDirection::In, Some(EndpointAddress::from(0x80)), EndpointType::Control, 64, 0).unwrap();
+            for ep_number in 1..4 {
+                bus.alloc_ep(UsbDirection::Out, Some(EndpointAddress::from(ep_number)), EndpointType::Bulk, 64, 0).unwrap();
+            }
+
+            let buffer_address = |ep_number: usize| interrupt::free(|cs| {
+                let allocator = bus.allocator.borrow(cs).borrow();
+                let buffer = allocator.endpoints_out[ep_number].as_ref().unwrap().buffer.borrow(cs).borrow().as_ptr();
+                buffer as u32
+            });
+            let dma_address = |ep_number: u8| Core::new::<HsPeripheral>().dma_address(ep_number, Direction::Out);
+
+            // Arm the OUT endpoints, EP3 is into its second packet
+            interrupt::free(|cs| {
+                let allocator = bus.allocator.borrow(cs).borrow();
+                for ep in allocator.endpoints_out.iter().flatten() {
+                    ep.configure(cs);
+                }
+            });
+            let ep3_offset = 16;
+            Core::new::<HsPeripheral>().set_dma_address(3, Direction::Out, buffer_address(3) + ep3_offset);
+            let (ep2_before, ep3_before) = (buffer_address(2), buffer_address(3));
+
+            bus.free_ep(EndpointAddress::from(0x01)).unwrap();
+            bus.realloc_ep(EndpointAddress::from(0x01), EndpointType::Bulk, 32, 0).unwrap();
+
+            assert!(buffer_address(2) < ep2_before);
+            assert!(buffer_address(3) < ep3_before);
+            assert_eq!(dma_address(2), buffer_address(2));
+            assert_eq!(dma_address(3), buffer_address(3) + ep3_offset);
+            assert_eq!(dma_address(1), buffer_address(1));
+        });
+    }
+
+    #[test]
+    fn fifos_are_not_repartitioned_under_pending_transfers() {
+        model(|| {
+            let bus = bus();
+            let regs = UsbRegisters::<Peripheral>::new();
+            // Global OUT NAK takes effect right away
+            #[cfg(not(feature = "hs"))]
+            write_reg!(otg_global, regs.global, GINTSTS, GOUTNAKEFF: 1);
+            #[cfg(feature = "hs")]
+            write_reg!(otg_global, regs.global, GINTSTS, BOUTNAKEFF: 1);
+            bus.free_ep(ep_out()).unwrap();
+
+            // An IN transfer is armed
+            modify_reg!(endpoint_in, ep_in_regs(), DIEPCTL, EPENA: 1);
+            let realloc = || bus.realloc_ep(ep_out(), EndpointType::Bulk, 32, 0);
+            assert_eq!(realloc(), Err(Error::TransferPending));
+            modify_reg!(endpoint_in, ep_in_regs(), DIEPCTL, EPENA: 0);
+
+            // OUT packets wait in the RX FIFO
+            modify_reg!(otg_global, regs.global, GINTSTS, RXFLVL: 1);
+            assert_eq!(realloc(), Err(Error::TransferPending));
+            modify_reg!(otg_global, regs.global, GINTSTS, RXFLVL: 0);
+
+            assert_eq!(realloc(), Ok(()));
+
+            // reconfigure_ep() refuses before it has freed the endpoint
+            modify_reg!(endpoint_in, ep_in_regs(), DIEPCTL, EPENA: 1);
+            assert_eq!(bus.reconfigure_ep(ep_out(), EndpointType::Bulk, 64), Err(Error::TransferPending));
+            let max_packet_size = interrupt::free(|cs| {
+                bus.allocator.borrow(cs).borrow().endpoints_out[1].as_ref().map(|ep| ep.max_packet_size())
+            });
+            assert_eq!(max_packet_size, Some(32));
+        });
+    }
+
+    #[test]
+    fn stuck_core_waits_give_up_and_are_counted() {
+        model(|| {
+            let bus = bus();
+            // The core never NAKs, disables the endpoint or finishes the flush
+            modify_reg!(endpoint_in, ep_in_regs(), DIEPCTL, EPENA: 1);
+            assert_eq!(bus.core_timeout_count(), 0);
+
+            assert_eq!(bus.free_ep(ep_in()), Ok(()));
+            // The NAK, then the TX FIFO flush
+            assert_eq!(bus.core_timeout_count(), 2);
+        });
+    }
+
+    #[test]
+    fn failed_reconfiguration_restores_the_endpoint() {
+        model(|| {
+            let bus = bus();
+            let regs = UsbRegisters::<Peripheral>::new();
+            #[cfg(not(feature = "hs"))]
+            write_reg!(otg_global, regs.global, GINTSTS, GOUTNAKEFF: 1);
+            #[cfg(feature = "hs")]
+            write_reg!(otg_global, regs.global, GINTSTS, BOUTNAKEFF: 1);
+
+            // Too large for a full-speed bulk endpoint
+            assert_eq!(bus.reconfigure_ep(ep_out(), EndpointType::Bulk, 512), Err(Error::InvalidMaxPacketSize));
+            let ep = interrupt::free(|cs| {
+                bus.allocator.borrow(cs).borrow().endpoints_out[1].as_ref().map(|ep| (ep.ep_type(), ep.max_packet_size()))
+            });
+            assert_eq!(ep, Some((EndpointType::Bulk, 64)));
+        });
+    }
+
+    #[test]
+    fn erratic_error_reconnects_on_a_later_poll() {
+        fn interrupt_erratic_error(bus: &UsbBus<Peripheral>) {
+            interrupt::free(|cs| {
+                let regs = bus.regs.borrow(cs);
+                set_dsts(otg_device::DSTS::EERR::mask);
+                write_reg!(otg_global, regs.global, GINTSTS, ESUSP: 1);
+                bus.service_interrupts(cs);
+                write_reg!(otg_global, regs.global, GINTSTS, 0);
+                set_dsts(0);
+            });
+        }
+
+        model(|| {
+            let bus = bus();
+            MICROS.store(0xffff_ff00, Ordering::SeqCst);
+
+            // The handler only disconnects
+            interrupt_erratic_error(&bus);
+            assert_eq!(bus.erratic_error_count(), 1);
+            assert!(Core::new::<Peripheral>().is_soft_disconnected());
+            assert!(bus.needs_poll());
+
+            // The clock wraps around in the meantime
+            MICROS.store(0x0000_0100, Ordering::SeqCst);
+            bus.poll();
+            assert!(Core::new::<Peripheral>().is_soft_disconnected());
+            assert!(bus.needs_poll());
+            MICROS.store(0x0000_0c00, Ordering::SeqCst);
+            bus.poll();
+            assert!(!Core::new::<Peripheral>().is_soft_disconnected());
+            assert!(!bus.needs_poll());
+
+            // A detach in the meantime is kept
+            interrupt_erratic_error(&bus);
+            bus.detach();
+            MICROS.store(0x0001_0000, Ordering::SeqCst);
+            bus.poll();
+            assert!(Core::new::<Peripheral>().is_soft_disconnected());
+
+            // Without a clock poll() waits
+            MICROS.store(usize::MAX, Ordering::SeqCst);
+            bus.attach();
+            interrupt_erratic_error(&bus);
+            bus.poll();
+            assert!(!Core::new::<Peripheral>().is_soft_disconnected());
+        });
+    }
+
+    #[test]
+    fn address_is_set_when_the_core_needs_it() {
+        model(|| {
+            let bus = bus();
+            bus.set_device_address(5);
+            assert_eq!(device_address(&bus), 5);
+
+            // The status stage of SET_ADDRESS goes out with the old address
+            let bus = bus_with_config(Config::default().set_address_before_status(false));
+            bus.set_device_address(5);
+            assert_eq!(device_address(&bus), 0);
+            interrupt_in_complete(&bus);
+            assert_eq!(device_address(&bus), 0);
+            interrupt_in_complete_ep(&bus, 0);
+            assert_eq!(device_address(&bus), 5);
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "trace")]
+    fn trace_records_carry_the_frame_number() {
+        use crate::trace::{TraceEvent, TraceRecord};
+
+        // The core counts the SOFs
+        fn set_frame_number(frame_number: u32) {
+            set_dsts(frame_number << otg_device::DSTS::FNSOF::offset);
+        }
+
+        model(|| {
+            let bus = bus();
+
+            set_frame_number(0x123);
+            bus.write(ep_in(), &[1, 2, 3, 4]).unwrap();
+            interrupt_in_complete(&bus);
+            set_frame_number(0x124);
+            interrupt_out_packet(&bus);
+
+            assert_eq!(bus.next_trace_record(), Some(TraceRecord {
+                frame_number: 0x123,
+                event: TraceEvent::InComplete { ep_number: 1 },
+            }));
+            assert_eq!(bus.next_trace_record(), Some(TraceRecord {
+                frame_number: 0x124,
+                event: TraceEvent::OutPacket { ep_number: 1, size: 4 },
+            }));
+            assert_eq!(bus.next_trace_record(), None);
+            assert_eq!(bus.lost_trace_records(), 0);
+        });
+    }
+}
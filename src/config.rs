@@ -0,0 +1,473 @@
+use crate::PhyType;
+
+/// Maximum number of endpoints per direction supported by the core architecture.
+pub(crate) const MAX_ENDPOINTS: usize = 16;
+
+/// AHB master burst length used by the core's DMA.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum BurstLength {
+    /// Single transfers
+    Single = 0b0000,
+    /// Incrementing bursts of unspecified length
+    Incr = 0b0001,
+    /// 4-beat incrementing bursts
+    Incr4 = 0b0011,
+    /// 8-beat incrementing bursts
+    Incr8 = 0b0101,
+    /// 16-beat incrementing bursts
+    Incr16 = 0b0111,
+}
+
+/// Point of the (micro)frame at which the end of periodic frame interrupt is signaled.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum PeriodicFrameInterval {
+    /// 80% of the frame interval
+    Percent80 = 0b00,
+    /// 85% of the frame interval
+    Percent85 = 0b01,
+    /// 90% of the frame interval
+    Percent90 = 0b10,
+    /// 95% of the frame interval
+    Percent95 = 0b11,
+}
+
+/// Point at which a write to an IN endpoint is reported as complete by `poll()`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum InCompletion {
+    /// When the host has acknowledged the data (DIEPINT.XFRC)
+    TransferComplete,
+    /// As soon as the TX FIFO has been emptied (DIEPINT.TXFE), i.e. the endpoint is ready for
+    /// more data. Applies to all IN endpoints but EP0. A `write()` turned away with `WouldBlock`
+    /// because the host hasn't acknowledged the last packet yet is followed by another report
+    /// at transfer complete.
+    FifoEmpty,
+}
+
+/// Point of the RX FIFO processing at which the driver re-enables an OUT endpoint for the next
+/// packet. Core revisions differ here, see [`Config::out_rearm_point`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum OutRearmPoint {
+    /// When the transfer completed status entry is popped (F429-like cores)
+    TransferComplete,
+    /// When the packet data has been read from the FIFO (F446-like cores)
+    PacketReceived,
+}
+
+/// Wait of `enable()` reported to the callback of [`Config::enable_progress`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum EnableStep {
+    /// Waiting for the AHB master of the core to become idle
+    AhbIdle,
+    /// Waiting for the core soft reset to complete
+    CoreReset,
+    /// Waiting for the forced device mode to take effect
+    ModeSwitch,
+}
+
+/// Optional bus configuration.
+///
+/// The default configuration is suitable for most devices, use the builder methods to tune it.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub(crate) tx_fifo_size_words: [u16; MAX_ENDPOINTS],
+    pub(crate) rx_buffer_size: [u16; MAX_ENDPOINTS],
+    pub(crate) direct_read: [bool; MAX_ENDPOINTS],
+    pub(crate) tx_threshold_words: Option<u16>,
+    pub(crate) burst_length: Option<BurstLength>,
+    pub(crate) periodic_frame_interval: PeriodicFrameInterval,
+    pub(crate) vbus_sensing: bool,
+    pub(crate) vbus_debounce_us: u32,
+    pub(crate) suspend_power_down: bool,
+    pub(crate) suspend_stop_mode: bool,
+    pub(crate) attach_on_enable: bool,
+    pub(crate) dma: bool,
+    pub(crate) manual_out_rearm: bool,
+    pub(crate) out_rearm_point: Option<OutRearmPoint>,
+    pub(crate) set_address_before_status: bool,
+    pub(crate) in_completion: [InCompletion; MAX_ENDPOINTS],
+    pub(crate) timeout_calibration: Option<u8>,
+    pub(crate) phy: Option<PhyType>,
+    pub(crate) ulpi_fs_ls: bool,
+    pub(crate) ulpi_auto_resume: bool,
+    pub(crate) ulpi_clock_suspend: bool,
+    pub(crate) enable_progress: Option<fn(EnableStep)>,
+    pub(crate) enumeration_timeout_ms: Option<u16>,
+}
+
+impl Config {
+    /// Requests a TX FIFO of at least `size_words` 32-bit words for the IN endpoint `ep_number`.
+    ///
+    /// By default the TX FIFO holds exactly one max packet. A deeper FIFO lets the core keep
+    /// transmitting while the application writes the next packets.
+    ///
+    /// Requests for endpoint numbers the core can't have are ignored.
+    pub fn tx_fifo_size(mut self, ep_number: usize, size_words: u16) -> Self {
+        if let Some(size) = self.tx_fifo_size_words.get_mut(ep_number) {
+            *size = size_words;
+        }
+        self
+    }
+
+    /// Requests a buffer of at least `size` bytes in the endpoint memory for the OUT endpoint
+    /// `ep_number`.
+    ///
+    /// By default the buffer holds exactly one max packet. A larger buffer collects consecutive
+    /// full packets of a bulk or interrupt transfer, so `read()` can return e.g. 2 KiB at once from
+    /// a 64-byte endpoint. Only one packet takes space in the RX FIFO.
+    ///
+    /// Requests for endpoint numbers the core can't have are ignored.
+    pub fn rx_buffer_size(mut self, ep_number: usize, size: u16) -> Self {
+        if let Some(buffer_size) = self.rx_buffer_size.get_mut(ep_number) {
+            *buffer_size = size;
+        }
+        self
+    }
+
+    /// Lets the OUT endpoint `ep_number` receive without a buffer in the endpoint memory.
+    ///
+    /// A packet then waits at the head of the RX FIFO until `read()` copies it straight into the
+    /// caller's buffer, or until the interrupt handler moves it into the buffer passed to
+    /// [`UsbBus::start_read`](crate::UsbBus::start_read). This saves the endpoint memory and a
+    /// copy for large bulk OUT endpoints. While the packet waits, the packets of the other OUT
+    /// endpoints, SETUP packets included, wait behind it, so the endpoint must be read promptly.
+    ///
+    /// EP0 and endpoints in DMA mode always receive into the endpoint memory.
+    pub fn direct_read(mut self, ep_number: usize, enabled: bool) -> Self {
+        if let Some(direct) = self.direct_read.get_mut(ep_number) {
+            *direct = enabled;
+        }
+        self
+    }
+
+    /// Enables IN transmission thresholding: the core starts sending a packet as soon as
+    /// `threshold_words` 32-bit words of it are in the TX FIFO, instead of waiting for the whole
+    /// packet. This reduces latency for large high-speed packets.
+    ///
+    /// The rest of the packet must reach the FIFO faster than the core sends it, otherwise the
+    /// packet is corrupted, so thresholding needs DMA mode (see [`dma`](Self::dma)); without it
+    /// the bus fails to initialize with `Error::InvalidConfig`. Supported only by high-speed
+    /// peripherals.
+    pub fn tx_threshold(mut self, threshold_words: u16) -> Self {
+        self.tx_threshold_words = Some(threshold_words);
+        self
+    }
+
+    /// Sets the AHB burst length (GAHBCFG.HBSTLEN) used when the core acts as a bus master in
+    /// DMA mode. Longer bursts improve bus utilization, shorter ones reduce the latency for other
+    /// masters. If not set, the reset value is kept. Supported only by high-speed peripherals.
+    pub fn ahb_burst_length(mut self, burst_length: BurstLength) -> Self {
+        self.burst_length = Some(burst_length);
+        self
+    }
+
+    /// Enables the core's internal DMA: received packets are written straight into the endpoint
+    /// memory and IN packets are fetched from it, instead of the CPU copying every word through
+    /// the FIFO registers. IN endpoints then take a buffer from the endpoint memory as well.
+    ///
+    /// The endpoint memory must be accessible by the peripheral's AHB master, see
+    /// [`EndpointMemory`](crate::EndpointMemory); peripherals listing their
+    /// [`DMA_REGIONS`](crate::UsbPeripheral::DMA_REGIONS) refuse to allocate endpoints in other
+    /// memory. Supported only by high-speed peripherals.
+    pub fn dma(mut self, enabled: bool) -> Self {
+        self.dma = enabled;
+        self
+    }
+
+    /// Sets the FS timeout calibration (GUSBCFG.TOCAL): the number of PHY clocks, 0 to 7, added to
+    /// the inter-packet timeout of the core. Long cables and deep hub chains may need a longer
+    /// timeout. Larger values are clamped.
+    ///
+    /// If not set, high-speed peripherals use 1 and full-speed peripherals keep the reset value.
+    pub fn timeout_calibration(mut self, phy_clocks: u8) -> Self {
+        self.timeout_calibration = Some(core::cmp::min(phy_clocks, 7));
+        self
+    }
+
+    /// Selects the PHY at runtime, overriding
+    /// [`UsbPeripheral::PHY_TYPE`](crate::UsbPeripheral::PHY_TYPE). This lets one binary
+    /// drive board variants that route the peripheral to different transceivers.
+    ///
+    /// The high-speed PHYs are supported only by high-speed peripherals.
+    pub fn phy(mut self, phy: PhyType) -> Self {
+        self.phy = Some(phy);
+        self
+    }
+
+    /// Talks to the ULPI PHY through its FS/LS serial interface (GUSBCFG.ULPIFSLS) instead of the
+    /// parallel data bus, for PHYs wired or strapped for full-speed only operation. The device
+    /// then runs at full speed.
+    ///
+    /// Applies only to `PhyType::ExternalHighSpeed`.
+    pub fn ulpi_fs_ls(mut self, enabled: bool) -> Self {
+        self.ulpi_fs_ls = enabled;
+        self
+    }
+
+    /// Lets the ULPI PHY resume the bus on its own when it detects resume signaling while its
+    /// clock is suspended (GUSBCFG.ULPIAR).
+    ///
+    /// Applies only to `PhyType::ExternalHighSpeed`.
+    pub fn ulpi_auto_resume(mut self, enabled: bool) -> Self {
+        self.ulpi_auto_resume = enabled;
+        self
+    }
+
+    /// Lets the ULPI PHY power down its 60 MHz clock while the bus is suspended
+    /// (GUSBCFG.ULPICSM). Enable it only for PHYs that support clock suspend.
+    ///
+    /// Applies only to `PhyType::ExternalHighSpeed`.
+    pub fn ulpi_clock_suspend(mut self, enabled: bool) -> Self {
+        self.ulpi_clock_suspend = enabled;
+        self
+    }
+
+    /// Sets the periodic frame interval (DCFG.PFIVL), i.e. the point of the frame at which the
+    /// end of periodic frame interrupt is generated. Isochronous schedulers use it to arm their
+    /// endpoints for the next frame in time. Defaults to 80%.
+    pub fn periodic_frame_interval(mut self, interval: PeriodicFrameInterval) -> Self {
+        self.periodic_frame_interval = interval;
+        self
+    }
+
+    /// Enables VBUS sensing on the dedicated VBUS pin. This lets the driver detect that the
+    /// host has gone away, see [`UsbBus::is_connected`](crate::UsbBus::is_connected).
+    pub fn vbus_sensing(mut self, enabled: bool) -> Self {
+        self.vbus_sensing = enabled;
+        self
+    }
+
+    /// Requires VBUS to stay present or absent for `us` microseconds before a session starts or
+    /// ends, so that glitches don't tear the device state down. Applies to the internal sensing
+    /// and to [`UsbPeripheral::vbus_present`](crate::UsbPeripheral::vbus_present).
+    ///
+    /// A session that has ended drops all endpoint data and the address, and the device reconnects
+    /// when VBUS comes back, so that the host enumerates it again even if it didn't notice the
+    /// brown-out, e.g. when it cycles the port power. The interrupt handler doesn't wait, the
+    /// session changes on the first `poll()` after the debounce time, which is timed with
+    /// [`UsbPeripheral::micros`](crate::UsbPeripheral::micros) or else waited for by `poll()`.
+    /// Disabled by default.
+    pub fn vbus_debounce_us(mut self, us: u32) -> Self {
+        self.vbus_debounce_us = us;
+        self
+    }
+
+    /// Stops the PHY clock, gates the core's AHB clock and powers down the embedded full-speed
+    /// transceiver while the bus is suspended, to reach the suspend current budget of bus-powered
+    /// devices. All of them are restored on resume or bus reset.
+    ///
+    /// Check that resume signaling is still detected on your part with the transceiver off.
+    pub fn suspend_power_down(mut self, enabled: bool) -> Self {
+        self.suspend_power_down = enabled;
+        self
+    }
+
+    /// Lets the MCU enter its STOP mode while the bus is suspended. Implies
+    /// [`suspend_power_down`](Self::suspend_power_down).
+    ///
+    /// Once the core is powered down, the driver calls
+    /// [`UsbPeripheral::prepare_stop`](crate::UsbPeripheral::prepare_stop), and on resume or bus
+    /// reset [`UsbPeripheral::restore_clocks`](crate::UsbPeripheral::restore_clocks) before it
+    /// touches the core again. The application enters STOP with `wfi` or `wfe` while
+    /// [`UsbBus::is_suspended`](crate::UsbBus::is_suspended) and must route the peripheral's wakeup
+    /// EXTI line to an interrupt or event, see the `low_power_suspend` example.
+    pub fn suspend_stop_mode(mut self, enabled: bool) -> Self {
+        self.suspend_stop_mode = enabled;
+        if enabled {
+            self.suspend_power_down = true;
+        }
+        self
+    }
+
+    /// Controls whether the device connects to the bus (pulls D+ up) as part of `enable()`.
+    ///
+    /// When disabled, the device stays detached until
+    /// [`UsbBus::attach`](crate::UsbBus::attach) is called, e.g. once the class stack is ready or
+    /// VBUS has been detected. Enabled by default.
+    pub fn attach_on_enable(mut self, enabled: bool) -> Self {
+        self.attach_on_enable = enabled;
+        self
+    }
+
+    /// Selects when IN endpoints report `ep_in_complete`. Reporting at FIFO empty lets streaming
+    /// classes queue the next packet earlier, reporting at transfer complete guarantees the host
+    /// has received the data. Defaults to `InCompletion::TransferComplete`.
+    pub fn in_completion(mut self, in_completion: InCompletion) -> Self {
+        self.in_completion = [in_completion; MAX_ENDPOINTS];
+        self
+    }
+
+    /// Selects when the IN endpoint `ep_number` reports `ep_in_complete`, overriding
+    /// [`in_completion`](Self::in_completion) for it. A streaming endpoint can be notified as
+    /// soon as its TX FIFO is empty while the others wait for the host to acknowledge their data.
+    ///
+    /// EP0 always reports at transfer complete. Requests for endpoint numbers the core can't
+    /// have are ignored.
+    pub fn ep_in_completion(mut self, ep_number: usize, in_completion: InCompletion) -> Self {
+        if let Some(completion) = self.in_completion.get_mut(ep_number) {
+            *completion = in_completion;
+        }
+        self
+    }
+
+    /// Leaves re-arming the OUT endpoints other than EP0 to the application.
+    ///
+    /// By default the driver lets an OUT endpoint accept the next packet as soon as the previous
+    /// one has been taken from the FIFO. With manual re-arming the endpoint NAKs after every
+    /// packet until [`UsbBus::rearm_out`](crate::UsbBus::rearm_out) is called, so a class can
+    /// hold the host off until it's ready to process more data.
+    pub fn manual_out_rearm(mut self, enabled: bool) -> Self {
+        self.manual_out_rearm = enabled;
+        self
+    }
+
+    /// Overrides the point at which OUT endpoints are re-enabled after a packet.
+    ///
+    /// By default the driver picks it from the core ID (CID register), which only knows the
+    /// revisions found in STM32 parts. Set it for clones or new silicon revisions whose OUT
+    /// endpoints stop after the first packet or receive garbage. Without the `quirks` feature
+    /// there's no CID table and the point must be set.
+    pub fn out_rearm_point(mut self, point: OutRearmPoint) -> Self {
+        self.out_rearm_point = Some(point);
+        self
+    }
+
+    /// Controls when the device address from SET_ADDRESS takes effect.
+    ///
+    /// Synopsys cores expect the new address before the status stage of the request and finish
+    /// the status stage with the old one, which is the default. When disabled, the driver
+    /// programs the address after the status stage has been sent, for cores that switch to the
+    /// new address immediately.
+    pub fn set_address_before_status(mut self, enabled: bool) -> Self {
+        self.set_address_before_status = enabled;
+        self
+    }
+
+    /// Registers a callback that `enable()` invokes at least once per millisecond while it waits
+    /// for the core, e.g. to kick an independent watchdog during the bring-up. The waits add up to
+    /// 25 ms for the mode switch, and up to 10 ms each for the core reset without a PHY clock.
+    ///
+    /// The waits run outside of critical sections, interrupts are served meanwhile.
+    pub fn enable_progress(mut self, callback: fn(EnableStep)) -> Self {
+        self.enable_progress = Some(callback);
+        self
+    }
+
+    /// Reports `EnumerationState::TimedOut` when the host hasn't configured the device within
+    /// `ms` milliseconds after a bus reset, see
+    /// [`UsbBus::enumeration_state`](crate::UsbBus::enumeration_state). Hosts usually take less
+    /// than a second.
+    ///
+    /// The time is counted in the frames the host sends, while `poll()` or `on_interrupt()` runs
+    /// at least every 2 seconds. Disabled by default.
+    pub fn enumeration_timeout_ms(mut self, ms: u16) -> Self {
+        self.enumeration_timeout_ms = Some(ms);
+        self
+    }
+
+    /// Returns true if the IN endpoint `ep_number` reports its completion at FIFO empty.
+    pub(crate) fn fifo_empty_completion(&self, ep_number: usize) -> bool {
+        ep_number != 0 && self.in_completion.get(ep_number) == Some(&InCompletion::FifoEmpty)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            tx_fifo_size_words: [0; MAX_ENDPOINTS],
+            rx_buffer_size: [0; MAX_ENDPOINTS],
+            direct_read: [false; MAX_ENDPOINTS],
+            tx_threshold_words: None,
+            burst_length: None,
+            periodic_frame_interval: PeriodicFrameInterval::Percent80,
+            vbus_sensing: false,
+            vbus_debounce_us: 0,
+            suspend_power_down: false,
+            suspend_stop_mode: false,
+            attach_on_enable: true,
+            dma: false,
+            manual_out_rearm: false,
+            out_rearm_point: None,
+            set_address_before_status: true,
+            in_completion: [InCompletion::TransferComplete; MAX_ENDPOINTS],
+            timeout_calibration: None,
+            phy: None,
+            ulpi_fs_ls: false,
+            ulpi_auto_resume: false,
+            ulpi_clock_suspend: false,
+            enable_progress: None,
+            enumeration_timeout_ms: None,
+        }
+    }
+}
+
+/// Computes the number of FIFO words the driver needs for a planned endpoint set.
+///
+/// `rx_sizes` holds the max packet size in bytes of every OUT endpoint, `tx_sizes` holds the
+/// TX FIFO size in bytes of every IN endpoint, indexed by endpoint number. The result follows the
+/// allocator's accounting: the RX FIFO gets 30 spare words and every TX FIFO takes at least 16
+/// words, including the ones of unused IN endpoints among the `endpoint_count` of the peripheral.
+pub const fn fifo_budget_words(endpoint_count: usize, rx_sizes: &[u16], tx_sizes: &[u16]) -> usize {
+    const MIN_TX_FIFO_WORDS: usize = 16;
+
+    let mut total = 30;
+
+    let mut i = 0;
+    while i < rx_sizes.len() {
+        total += (rx_sizes[i] as usize).div_ceil(4);
+        i += 1;
+    }
+
+    let mut i = 0;
+    while i < endpoint_count {
+        let size_words = if i < tx_sizes.len() { (tx_sizes[i] as usize).div_ceil(4) } else { 0 };
+        total += if size_words > MIN_TX_FIFO_WORDS { size_words } else { MIN_TX_FIFO_WORDS };
+        i += 1;
+    }
+
+    total
+}
+
+/// Checks at compile time that a planned endpoint set fits into the FIFO of a peripheral type,
+/// see [`UsbPeripheral::FIFO_DEPTH_WORDS`](crate::UsbPeripheral::FIFO_DEPTH_WORDS).
+///
+/// See [`fifo_budget_words`](crate::config::fifo_budget_words) for the meaning of the sizes.
+///
+/// ```
+/// # struct USB;
+/// # unsafe impl synopsys_usb_otg::UsbPeripheral for USB {
+/// #     const REGISTERS: *const () = 0x5000_0000 as *const ();
+/// #     const HIGH_SPEED: bool = false;
+/// #     const FIFO_DEPTH_WORDS: usize = 320;
+/// #     fn enable() {}
+/// # }
+/// // EP0 with 64-byte packets, a 64-byte bulk OUT and two 64-byte IN endpoints
+/// synopsys_usb_otg::fifo_budget!(USB, rx: [64, 64], tx: [64, 64, 64]);
+/// ```
+///
+/// A plan that needs more than the 320 words of an OTG_FS FIFO doesn't compile:
+///
+/// ```compile_fail
+/// # struct USB;
+/// # unsafe impl synopsys_usb_otg::UsbPeripheral for USB {
+/// #     const REGISTERS: *const () = 0x5000_0000 as *const ();
+/// #     const HIGH_SPEED: bool = false;
+/// #     const FIFO_DEPTH_WORDS: usize = 320;
+/// #     fn enable() {}
+/// # }
+/// // Two 512-byte OUT endpoints
+/// synopsys_usb_otg::fifo_budget!(USB, rx: [64, 512, 512], tx: [64]);
+/// ```
+#[macro_export]
+macro_rules! fifo_budget {
+    ($peripheral:ty, rx: [$($rx:expr),* $(,)?], tx: [$($tx:expr),* $(,)?] $(,)?) => {
+        const _: () = assert!(
+            $crate::config::fifo_budget_words(
+                <$peripheral as $crate::UsbPeripheral>::ENDPOINT_COUNT,
+                &[$($rx),*],
+                &[$($tx),*],
+            )
+                <= <$peripheral as $crate::UsbPeripheral>::FIFO_DEPTH_WORDS,
+            "endpoint set doesn't fit into the USB FIFO"
+        );
+    };
+}
@@ -0,0 +1,437 @@
+use crate::events::Events;
+use crate::ral::{read_reg, write_reg, modify_reg, otg_global, otg_device, endpoint};
+use crate::target::{UsbRegisters, fifo_read, fifo_write, fifo_discard};
+use crate::{Frame, Speed, UsbPeripheral};
+
+/// Register reads after which a wait for the core gives up. Each read takes a few AHB cycles,
+/// so this is far longer than the core needs for a flush or an endpoint disable while its PHY
+/// is clocked.
+pub(crate) const WAIT_POLLS: u32 = 100_000;
+
+/// Polls `done` until it returns true, at most [`WAIT_POLLS`] times. Returns false if the core
+/// didn't get there, e.g. because the PHY clock has stopped.
+pub(crate) fn wait_for(mut done: impl FnMut() -> bool) -> bool {
+    (0..WAIT_POLLS).any(|_| done())
+}
+
+/// Endpoint direction, as seen from the host.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Direction {
+    /// Host to device
+    Out,
+    /// Device to host
+    In,
+}
+
+/// Packet status (GRXSTSR.PKTSTS) of the entry at the head of the RX FIFO.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum RxStatus {
+    /// Global OUT NAK has taken effect
+    GlobalOutNak,
+    /// OUT data packet received
+    OutData,
+    /// OUT transfer completed
+    OutComplete,
+    /// SETUP transaction completed
+    SetupComplete,
+    /// SETUP data packet received
+    SetupData,
+    /// Host channel halted, in host mode only
+    ChannelHalted,
+    /// Reserved status code
+    Reserved(u8),
+}
+
+impl RxStatus {
+    pub(crate) fn from_bits(pktsts: u32) -> Self {
+        match pktsts {
+            0b0001 => RxStatus::GlobalOutNak,
+            0b0010 => RxStatus::OutData,
+            0b0011 => RxStatus::OutComplete,
+            0b0100 => RxStatus::SetupComplete,
+            0b0110 => RxStatus::SetupData,
+            0b0111 => RxStatus::ChannelHalted,
+            other => RxStatus::Reserved(other as u8),
+        }
+    }
+
+    /// Returns true if the packet data follows the status entry in the FIFO.
+    pub fn has_data(self) -> bool {
+        matches!(self, RxStatus::OutData | RxStatus::SetupData)
+    }
+}
+
+/// Status entry of the RX FIFO (GRXSTSR).
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct RxEntry {
+    /// Endpoint the entry belongs to
+    pub ep_number: u8,
+    /// What the entry reports
+    pub status: RxStatus,
+    /// Number of data bytes that follow the entry in the FIFO
+    pub byte_count: u16,
+    /// Four least significant bits of the (micro)frame number the packet arrived in, for
+    /// isochronous OUT data, see [`Core::packet_frame`]
+    pub frame_lsb: u8,
+}
+
+/// Register-level access to a core in device mode: the endpoint controls, the FIFOs and the
+/// interrupt status, without any `usb-device` types.
+///
+/// This is the layer [`UsbBus`](crate::UsbBus) is built on, other USB stacks can drive the core
+/// through it as well. The methods don't take critical sections, the owner of the core
+/// serializes the accesses, e.g. by only using it from the interrupt handler.
+///
+/// `Core` only keeps the register addresses, so a firmware driving several peripherals shares
+/// one copy of its code.
+pub struct Core {
+    global: &'static otg_global::RegisterBlock,
+    device: &'static otg_device::RegisterBlock,
+    base_address: usize,
+}
+
+impl Core {
+    /// Gives access to the core of the peripheral `USB`, at [`UsbPeripheral::REGISTERS`].
+    pub fn new<USB: UsbPeripheral>() -> Self {
+        Self::at(UsbRegisters::<USB>::base_address())
+    }
+
+    /// Gives access to the core whose registers start at `base_address`.
+    pub(crate) fn at(base_address: usize) -> Self {
+        unsafe {
+            Self {
+                global: &*(base_address as *const otg_global::RegisterBlock),
+                device: &*((base_address + 0x800) as *const otg_device::RegisterBlock),
+                base_address,
+            }
+        }
+    }
+
+    /// Returns the pending core and endpoint interrupts, without acknowledging them.
+    pub fn events(&self) -> Events {
+        let gintsts = read_reg!(otg_global, self.global, GINTSTS);
+        let daint = read_reg!(otg_device, self.device, DAINT);
+        Events::from_bits(gintsts, daint)
+    }
+
+    /// Returns the number of the last (micro)frame received from the host (DSTS.FNSOF).
+    ///
+    /// On a high-speed bus this counts microframes, see [`frame`](Self::frame).
+    pub fn frame_number(&self) -> u16 {
+        read_reg!(otg_device, self.device, DSTS, FNSOF) as u16
+    }
+
+    /// Returns the frame and, on a high-speed bus, the microframe of the last SOF received.
+    /// Both come from the same register read.
+    pub fn frame(&self) -> Frame {
+        let (fnsof, enumspd) = read_reg!(otg_device, self.device, DSTS, FNSOF, ENUMSPD);
+        Frame::from_fnsof(fnsof as u16, enumspd == 0b00)
+    }
+
+    /// Returns the (micro)frame an isochronous OUT packet arrived in, from the `frame_lsb` of its
+    /// RX FIFO entry and the current frame. The packet must have arrived within the last 16
+    /// (micro)frames.
+    pub fn packet_frame(&self, frame_lsb: u8) -> Frame {
+        let (fnsof, enumspd) = read_reg!(otg_device, self.device, DSTS, FNSOF, ENUMSPD);
+        Frame::from_fnsof(packet_fnsof(fnsof as u16, frame_lsb), enumspd == 0b00)
+    }
+
+    /// Returns the speed enumerated at the last bus reset (DSTS.ENUMSPD).
+    pub fn speed(&self) -> Speed {
+        match read_reg!(otg_device, self.device, DSTS, ENUMSPD) {
+            0b00 => Speed::High,
+            _ => Speed::Full,
+        }
+    }
+
+    /// Returns true if the USB link is in the suspended state (DSTS.SUSPSTS).
+    pub fn is_suspended(&self) -> bool {
+        read_reg!(otg_device, self.device, DSTS, SUSPSTS) != 0
+    }
+
+    /// Disconnects the device from the bus (DCTL.SDIS) or connects it again.
+    pub fn set_soft_disconnect(&self, disconnected: bool) {
+        modify_reg!(otg_device, self.device, DCTL, SDIS: disconnected as u32);
+    }
+
+    /// Returns true if the device is disconnected from the bus (DCTL.SDIS).
+    pub fn is_soft_disconnected(&self) -> bool {
+        read_reg!(otg_device, self.device, DCTL, SDIS) != 0
+    }
+
+    /// Sets the device address the core responds to (DCFG.DAD).
+    pub fn set_address(&self, address: u8) {
+        modify_reg!(otg_device, self.device, DCFG, DAD: address as u32);
+    }
+
+    fn endpoint(&self, ep_number: u8, direction: Direction) -> endpoint::Instance {
+        endpoint::instance(self.base_address, direction == Direction::Out, ep_number)
+    }
+
+    /// Sets or clears the STALL handshake of an endpoint.
+    pub fn set_stalled(&self, ep_number: u8, direction: Direction, stalled: bool) {
+        let ep = self.endpoint(ep_number, direction);
+        modify_reg!(endpoint, ep, DEPCTL, STALL: stalled as u32);
+    }
+
+    /// Returns true if an endpoint responds with STALL.
+    pub fn is_stalled(&self, ep_number: u8, direction: Direction) -> bool {
+        let ep = self.endpoint(ep_number, direction);
+        read_reg!(endpoint, ep, DEPCTL, STALL) != 0
+    }
+
+    /// Returns true if an endpoint is enabled for a transfer (DxEPCTL.EPENA).
+    pub fn is_endpoint_enabled(&self, ep_number: u8, direction: Direction) -> bool {
+        let ep = self.endpoint(ep_number, direction);
+        read_reg!(endpoint, ep, DEPCTL, EPENA) != 0
+    }
+
+    /// Enables an endpoint for the transfer programmed into its size register and stops it
+    /// NAKing.
+    pub fn enable_endpoint(&self, ep_number: u8, direction: Direction) {
+        let ep = self.endpoint(ep_number, direction);
+        modify_reg!(endpoint, ep, DEPCTL, CNAK: 1, EPENA: 1);
+    }
+
+    /// Aborts the transfer of an enabled endpoint and waits until the core has stopped it. OUT
+    /// endpoints require global OUT NAK to be in effect, IN endpoints must NAK already.
+    ///
+    /// Returns false if the core didn't confirm the disable in time.
+    #[must_use]
+    pub fn disable_endpoint(&self, ep_number: u8, direction: Direction) -> bool {
+        let ep = self.endpoint(ep_number, direction);
+        modify_reg!(endpoint, ep, DEPCTL, SNAK: 1, EPDIS: 1);
+        if !wait_for(|| read_reg!(endpoint, ep, DEPINT, EPDISD) != 0) {
+            return false;
+        }
+        write_reg!(endpoint, ep, DEPINT, EPDISD: 1);
+        true
+    }
+
+    /// Deactivates an endpoint, so that the core ignores its tokens, and clears its interrupts.
+    pub fn deactivate_endpoint(&self, ep_number: u8, direction: Direction) {
+        let ep = self.endpoint(ep_number, direction);
+        modify_reg!(endpoint, ep, DEPCTL, USBAEP: 0);
+        write_reg!(endpoint, ep, DEPINT, 0xff);
+    }
+
+    /// Returns and clears the transfer completed interrupt of an endpoint (DxEPINT.XFRC).
+    pub fn take_transfer_complete(&self, ep_number: u8, direction: Direction) -> bool {
+        let ep = self.endpoint(ep_number, direction);
+        let xfrc = read_reg!(endpoint, ep, DEPINT, XFRC) != 0;
+        if xfrc {
+            write_reg!(endpoint, ep, DEPINT, XFRC: 1);
+        }
+        xfrc
+    }
+
+    /// Returns and clears the retry flags of an IN endpoint: the host has timed out waiting for
+    /// the data (DIEPINT.TOC) or has been NAKed because the TX FIFO was empty (DIEPINT.ITTXFE).
+    /// The flags latch without being unmasked, so each counts once however often it occurred.
+    pub fn take_in_retries(&self, ep_number: u8) -> u32 {
+        let ep = self.endpoint(ep_number, Direction::In);
+        let (toc, ittxfe) = read_reg!(endpoint, ep, DEPINT, TOC, ITTXFE);
+        if toc | ittxfe != 0 {
+            write_reg!(endpoint, ep, DEPINT, TOC: toc, ITTXFE: ittxfe);
+        }
+        toc + ittxfe
+    }
+
+    /// Sets the buffer the core's DMA reads the next IN packet from or writes the next OUT
+    /// packet to.
+    #[cfg(feature = "hs")]
+    pub fn set_dma_address(&self, ep_number: u8, direction: Direction, address: u32) {
+        let ep = self.endpoint(ep_number, direction);
+        write_reg!(endpoint, ep, DEPDMA, address);
+    }
+
+    /// Returns the address the core's DMA accesses next for an endpoint.
+    #[cfg(feature = "hs")]
+    pub fn dma_address(&self, ep_number: u8, direction: Direction) -> u32 {
+        let ep = self.endpoint(ep_number, direction);
+        read_reg!(endpoint, ep, DEPDMA)
+    }
+
+    /// Flushes the TX FIFO `fifo_number`, or all of them with `0x10`. The endpoints using the
+    /// FIFO must be disabled or NAKing. Returns false if the flush didn't finish in time.
+    #[must_use]
+    pub fn flush_tx_fifo(&self, fifo_number: u8) -> bool {
+        modify_reg!(otg_global, self.global, GRSTCTL, TXFFLSH: 1, TXFNUM: fifo_number as u32);
+        wait_for(|| read_reg!(otg_global, self.global, GRSTCTL, TXFFLSH) == 0)
+    }
+
+    /// Flushes the RX FIFO. Returns false if the flush didn't finish in time.
+    #[must_use]
+    pub fn flush_rx_fifo(&self) -> bool {
+        modify_reg!(otg_global, self.global, GRSTCTL, RXFFLSH: 1);
+        wait_for(|| read_reg!(otg_global, self.global, GRSTCTL, RXFFLSH) == 0)
+    }
+
+    /// Makes all OUT endpoints NAK and waits until that is in effect. OUT endpoints can only be
+    /// disabled in this state.
+    ///
+    /// The packets still in the RX FIFO are dropped, as the NAK takes effect only after they
+    /// have been popped. Returns false if the NAK didn't take effect in time.
+    #[must_use]
+    pub fn set_global_out_nak(&self) -> bool {
+        modify_reg!(otg_device, self.device, DCTL, SGONAK: 1);
+        wait_for(|| {
+            #[cfg(not(feature = "hs"))]
+            let (nak_effective, rxflvl) = read_reg!(otg_global, self.global, GINTSTS, GOUTNAKEFF, RXFLVL);
+            #[cfg(feature = "hs")]
+            let (nak_effective, rxflvl) = read_reg!(otg_global, self.global, GINTSTS, BOUTNAKEFF, RXFLVL);
+
+            if nak_effective == 0 && rxflvl != 0 {
+                let entry = self.pop_rx_entry();
+                self.discard_packet(entry.byte_count);
+            }
+            nak_effective != 0
+        })
+    }
+
+    /// Lets the OUT endpoints accept packets again.
+    pub fn clear_global_out_nak(&self) {
+        modify_reg!(otg_device, self.device, DCTL, CGONAK: 1);
+    }
+
+    /// Returns the entry at the head of the RX FIFO without removing it, if there is one.
+    pub fn peek_rx_entry(&self) -> Option<RxEntry> {
+        if read_reg!(otg_global, self.global, GINTSTS, RXFLVL) == 0 {
+            return None;
+        }
+        let (ep_number, byte_count, status, frame_lsb) = read_reg!(otg_global, self.global, GRXSTSR, EPNUM, BCNT, PKTSTS, FRMNUM);
+        Some(RxEntry {
+            ep_number: ep_number as u8,
+            status: RxStatus::from_bits(status),
+            byte_count: byte_count as u16,
+            frame_lsb: frame_lsb as u8,
+        })
+    }
+
+    /// Removes the entry at the head of the RX FIFO. Its data, if any, must be read with
+    /// [`read_packet`](Self::read_packet) or dropped with [`discard_packet`](Self::discard_packet)
+    /// next.
+    pub fn pop_rx_entry(&self) -> RxEntry {
+        let (ep_number, byte_count, status, frame_lsb) = read_reg!(otg_global, self.global, GRXSTSP, EPNUM, BCNT, PKTSTS, FRMNUM);
+        RxEntry {
+            ep_number: ep_number as u8,
+            status: RxStatus::from_bits(status),
+            byte_count: byte_count as u16,
+            frame_lsb: frame_lsb as u8,
+        }
+    }
+
+    /// Reads the data of the popped RX FIFO entry into `buf`, which must be `byte_count` long.
+    pub fn read_packet(&self, buf: &mut [u8]) {
+        fifo_read(self.base_address, buf);
+    }
+
+    /// Drops the `byte_count` bytes of data of the popped RX FIFO entry.
+    pub fn discard_packet(&self, byte_count: u16) {
+        fifo_discard(self.base_address, byte_count as usize);
+    }
+
+    /// Writes a packet into the TX FIFO of the IN endpoint `ep_number`. The endpoint must have
+    /// been enabled for it and the FIFO must have room. Returns false without writing if
+    /// `ep_number` is beyond the 16 FIFOs the core decodes.
+    #[must_use]
+    pub fn write_packet(&self, ep_number: u8, data: &[u8]) -> bool {
+        fifo_write(self.base_address, ep_number as usize, data)
+    }
+
+    /// Acknowledges core interrupts, the flags set in `events`.
+    pub fn clear_events(&self, events: Events) {
+        write_reg!(otg_global, self.global, GINTSTS, events.bits());
+    }
+}
+
+/// Completes the four least significant bits of a (micro)frame number in the last 16 before
+/// `fnsof`, both in the DSTS.FNSOF format.
+fn packet_fnsof(fnsof: u16, frame_lsb: u8) -> u16 {
+    fnsof.wrapping_sub(fnsof.wrapping_sub(frame_lsb as u16) & 0xf) & 0x3fff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rx_status_is_decoded() {
+        assert_eq!(RxStatus::from_bits(0b0001), RxStatus::GlobalOutNak);
+        assert_eq!(RxStatus::from_bits(0b0010), RxStatus::OutData);
+        assert_eq!(RxStatus::from_bits(0b0011), RxStatus::OutComplete);
+        assert_eq!(RxStatus::from_bits(0b0100), RxStatus::SetupComplete);
+        assert_eq!(RxStatus::from_bits(0b0110), RxStatus::SetupData);
+        assert_eq!(RxStatus::from_bits(0b0111), RxStatus::ChannelHalted);
+        assert_eq!(RxStatus::from_bits(0b0101), RxStatus::Reserved(0b0101));
+
+        assert!(RxStatus::OutData.has_data());
+        assert!(RxStatus::SetupData.has_data());
+        assert!(!RxStatus::OutComplete.has_data());
+        assert!(!RxStatus::GlobalOutNak.has_data());
+    }
+
+    #[test]
+    fn fnsof_counts_microframes_at_high_speed() {
+        let frame = Frame::from_fnsof((1234 << 3) | 5, true);
+        assert_eq!(frame, Frame { number: 1234, microframe: Some(5) });
+        assert_eq!(frame.microframes(), (1234 << 3) | 5);
+
+        let frame = Frame::from_fnsof(1234, false);
+        assert_eq!(frame, Frame { number: 1234, microframe: None });
+        assert_eq!(frame.microframes(), 1234 << 3);
+
+        // The frame number wraps around after 2047
+        let last = Frame::from_fnsof(0x3fff, true);
+        assert_eq!(last.number, 2047);
+        let first = Frame::from_fnsof(1, true);
+        assert_eq!(first.microframes().wrapping_sub(last.microframes()) & 0x3fff, 2);
+    }
+
+    #[test]
+    fn packet_frame_is_completed_from_its_low_bits() {
+        assert_eq!(packet_fnsof(1234, (1234 & 0xf) as u8), 1234);
+        assert_eq!(packet_fnsof(1234, ((1234 - 3) & 0xf) as u8), 1231);
+        assert_eq!(packet_fnsof(1234, ((1234 - 15) & 0xf) as u8), 1219);
+
+        // Across the wrap-around of the frame number
+        assert_eq!(Frame::from_fnsof(packet_fnsof(1, 0xf), false).number, 2047);
+        assert_eq!(Frame::from_fnsof(packet_fnsof(1, 0xf), true), Frame { number: 2047, microframe: Some(7) });
+    }
+
+    #[test]
+    #[cfg(feature = "usb-device")]
+    fn fifo_numbers_beyond_the_core_are_refused() {
+        use crate::ral::{otg_fifo, tx_fifo};
+
+        let base_address = 0x5000_0000;
+        assert!(otg_fifo::instance(base_address, 15).is_some());
+        assert!(otg_fifo::instance(base_address, 16).is_none());
+        assert!(tx_fifo::instance(base_address, 0).is_none());
+        assert_eq!(tx_fifo::instance(base_address, 15).map(|fifo| fifo.addr), Some(base_address + 0x13c));
+        assert!(tx_fifo::instance(base_address, 16).is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "usb-device")]
+    fn shared_endpoint_registers_match_both_directions() {
+        use crate::ral::{endpoint_in, endpoint_out, endpoint0_out};
+
+        let address = |register: &stm32ral::RWRegister<u32>| register as *const _ as usize;
+        let base_address = 0x5000_0000;
+        for ep_number in 0..8 {
+            let ep_in = endpoint_in::instance(base_address, ep_number);
+            let ep = endpoint::instance(base_address, false, ep_number);
+            assert_eq!(address(&ep.DEPCTL), address(&ep_in.DIEPCTL));
+            assert_eq!(address(&ep.DEPINT), address(&ep_in.DIEPINT));
+
+            let ep_out = endpoint_out::instance(base_address, ep_number);
+            let ep = endpoint::instance(base_address, true, ep_number);
+            assert_eq!(address(&ep.DEPCTL), address(&ep_out.DOEPCTL));
+        }
+
+        let ep0_out = endpoint0_out::instance(base_address);
+        let ep = endpoint::instance(base_address, true, 0);
+        assert_eq!(address(&ep.DEPINT), address(&ep0_out.DOEPINT0));
+    }
+}
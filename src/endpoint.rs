@@ -1,130 +1,304 @@
-use usb_device::{Result, UsbError, UsbDirection};
-use usb_device::endpoint::EndpointAddress;
+use usb_device::{Result, UsbError};
+use usb_device::endpoint::{EndpointAddress, EndpointType};
 use crate::endpoint_memory::{EndpointBuffer, EndpointBufferState};
+use crate::dwc_otg::{wait_for, Core, Direction};
 use crate::ral::{read_reg, write_reg, modify_reg, endpoint_in, endpoint_out, endpoint0_out};
-use crate::target::fifo_write;
 use crate::target::interrupt::{self, CriticalSection, Mutex};
 use core::ops::{Deref, DerefMut};
-use core::cell::RefCell;
+use core::cell::{Cell, RefCell};
 use crate::transition::EndpointDescriptor;
+use crate::{Error, Speed};
+#[cfg(feature = "iso")]
+use crate::Frame;
 
-pub fn set_stalled(address: EndpointAddress, stalled: bool) {
-    interrupt::free(|_| {
-        match address.direction() {
-            UsbDirection::Out => {
-                let ep = endpoint_out::instance(address.index() as u8);
-                modify_reg!(endpoint_out, ep, DOEPCTL, STALL: stalled as u32);
-            },
-            UsbDirection::In => {
-                let ep = endpoint_in::instance(address.index() as u8);
-                modify_reg!(endpoint_in, ep, DIEPCTL, STALL: stalled as u32);
-            },
-        }
-    })
-}
-
-pub fn is_stalled(address: EndpointAddress) -> bool {
-    let stall = match address.direction() {
-        UsbDirection::Out => {
-            let ep = endpoint_out::instance(address.index() as u8);
-            read_reg!(endpoint_out, ep, DOEPCTL, STALL)
-        },
-        UsbDirection::In => {
-            let ep = endpoint_in::instance(address.index() as u8);
-            read_reg!(endpoint_in, ep, DIEPCTL, STALL)
+/// Returns the packet size encoded in a `wMaxPacketSize` value.
+pub fn packet_size(max_packet_size: u16) -> u16 {
+    max_packet_size & 0x7ff
+}
+
+/// Returns the number of transactions per (micro)frame encoded in a `wMaxPacketSize` value.
+/// Values other than 1 are valid only for high-speed periodic endpoints.
+pub fn transactions_per_frame(max_packet_size: u16) -> u16 {
+    ((max_packet_size >> 11) & 0b11) + 1
+}
+
+/// Checks `max_packet_size` against the limits imposed by the USB 2.0 specification for the
+/// given transfer type and bus speed.
+pub fn is_valid_max_packet_size(ep_type: EndpointType, max_packet_size: u16, high_speed: bool) -> bool {
+    let size = packet_size(max_packet_size);
+    match (ep_type, high_speed) {
+        (EndpointType::Control, false) => matches!(max_packet_size, 8 | 16 | 32 | 64),
+        (EndpointType::Control, true) => max_packet_size == 64,
+        (EndpointType::Bulk, false) => matches!(max_packet_size, 8 | 16 | 32 | 64),
+        (EndpointType::Bulk, true) => max_packet_size == 512,
+        (EndpointType::Interrupt, false) => max_packet_size <= 64,
+        (EndpointType::Isochronous, false) => max_packet_size <= 1023,
+        (EndpointType::Interrupt, true) | (EndpointType::Isochronous, true) => {
+            match max_packet_size >> 11 {
+                0 => size <= 1024,
+                1 => (513..=1024).contains(&size),
+                2 => (683..=1024).contains(&size),
+                _ => false,
+            }
         },
-    };
-    stall != 0
+    }
+}
+
+/// Checks the `bInterval` of a periodic endpoint against the USB 2.0 specification: 1 to 255
+/// frames for full-speed interrupt endpoints, an exponent of 1 to 16 for high-speed interrupt
+/// and all isochronous endpoints. High-speed capable devices may run at full speed, so interrupt
+/// endpoints accept the full-speed range at either speed.
+pub fn is_valid_interval(ep_type: EndpointType, interval: u8) -> bool {
+    match ep_type {
+        EndpointType::Control | EndpointType::Bulk => true,
+        EndpointType::Interrupt => interval >= 1,
+        EndpointType::Isochronous => (1..=16).contains(&interval),
+    }
+}
+
+/// Returns the first (micro)frame after `frame_number` at `phase` within a service period of
+/// `period` (micro)frames, a power of two. `mask` is the range of DSTS.FNSOF.
+#[cfg(feature = "iso")]
+fn service_frame_after(frame_number: u16, phase: u16, period: u16, mask: u16) -> u16 {
+    let next = frame_number.wrapping_add(1);
+    next.wrapping_add(phase.wrapping_sub(next) & (period - 1)) & mask
+}
+
+/// Encodes the transfer type in the DIEPCTLx/DOEPCTLx EPTYP format.
+fn eptyp(ep_type: EndpointType) -> u32 {
+    match ep_type {
+        EndpointType::Control => 0b00,
+        EndpointType::Isochronous => 0b01,
+        EndpointType::Bulk => 0b10,
+        EndpointType::Interrupt => 0b11,
+    }
+}
+
+/// Encodes the EP0 max packet size in the DIEPCTL0/DOEPCTL0 MPSIZ format.
+///
+/// The size is validated when the endpoint is allocated, anything else falls back to 64 bytes.
+/// High speed only allows 64 bytes, the smaller sizes of a high-speed capable device apply when
+/// it has fallen back to full speed.
+fn ep0_mpsiz(max_packet_size: u16, speed: Speed) -> u32 {
+    if speed == Speed::High {
+        return 0b00;
+    }
+    match max_packet_size {
+        8 => 0b11,
+        16 => 0b10,
+        32 => 0b01,
+        _ => 0b00,
+    }
 }
 
 /// Arbitrates access to the endpoint-specific registers and packet buffer memory.
 pub struct Endpoint {
     descriptor: EndpointDescriptor,
+    /// Address of the register block of the peripheral the endpoint belongs to
+    base_address: usize,
 }
 
 impl Endpoint {
-    pub fn new(descriptor: EndpointDescriptor) -> Endpoint {
-        Endpoint { descriptor }
+    pub fn new(descriptor: EndpointDescriptor, base_address: usize) -> Endpoint {
+        Endpoint { descriptor, base_address }
     }
 
     pub fn address(&self) -> EndpointAddress {
         self.descriptor.address
     }
 
+    pub fn ep_type(&self) -> EndpointType {
+        self.descriptor.ep_type
+    }
+
+    pub fn max_packet_size(&self) -> u16 {
+        self.descriptor.max_packet_size
+    }
+
+    /// Size of a single packet, without the additional transactions bits.
+    pub fn packet_size(&self) -> u16 {
+        packet_size(self.descriptor.max_packet_size)
+    }
+
+    pub fn transactions_per_frame(&self) -> u16 {
+        transactions_per_frame(self.descriptor.max_packet_size)
+    }
+
+    pub fn interval(&self) -> u8 {
+        self.descriptor.interval
+    }
+
     #[inline(always)]
     fn index(&self) -> u8 {
         self.descriptor.address.index() as u8
     }
+
+    fn core(&self) -> Core {
+        Core::at(self.base_address)
+    }
 }
 
 
 pub struct EndpointIn {
     common: Endpoint,
+    tx_fifo_size_words: u16,
+    /// Buffer the core fetches the packets from in DMA mode
+    pub(crate) dma_buffer: Option<Mutex<RefCell<EndpointBuffer>>>,
+    /// Position of the (micro)frames the host polls the isochronous endpoint in within its
+    /// service period, learnt from the completed transfers
+    #[cfg(feature = "iso")]
+    iso_phase: Mutex<Cell<u16>>,
+    /// (Micro)frame the armed isochronous packet is meant for
+    #[cfg(feature = "iso")]
+    iso_target: Mutex<Cell<u16>>,
+    /// IN transactions the host had to retry, see `Core::take_in_retries`
+    retries: Mutex<Cell<u32>>,
 }
 
 impl EndpointIn {
-    pub fn new(descriptor: EndpointDescriptor) -> EndpointIn {
+    pub fn new(descriptor: EndpointDescriptor, base_address: usize, tx_fifo_size_words: u16) -> EndpointIn {
         EndpointIn {
-            common: Endpoint::new(descriptor),
+            common: Endpoint::new(descriptor, base_address),
+            tx_fifo_size_words,
+            dma_buffer: None,
+            #[cfg(feature = "iso")]
+            iso_phase: Mutex::new(Cell::new(0)),
+            #[cfg(feature = "iso")]
+            iso_target: Mutex::new(Cell::new(0)),
+            retries: Mutex::new(Cell::new(0)),
+        }
+    }
+
+    /// Returns the largest buffer a single `write` accepts.
+    ///
+    /// Bulk endpoints can queue as many back-to-back packets as fit into their TX FIFO, the other
+    /// endpoints send at most the transactions of a single (micro)frame.
+    pub fn max_write_size(&self) -> usize {
+        let packet_size = self.packet_size() as usize;
+        match self.descriptor.ep_type {
+            EndpointType::Bulk => core::cmp::max(packet_size, self.tx_fifo_size_words as usize * 4),
+            _ => packet_size * self.transactions_per_frame() as usize,
         }
     }
 
+    /// Returns how many bytes `start_write` accepts right now: none while the previous transfer
+    /// keeps the endpoint busy, otherwise the free space of the TX FIFO, at most
+    /// [`max_write_size`](Self::max_write_size).
+    pub fn available_write_size(&self) -> usize {
+        if self.is_busy() {
+            return 0;
+        }
+        if self.dma_buffer.is_some() {
+            // The core fetches the data into the FIFO itself
+            return self.max_write_size();
+        }
+
+        let ep = endpoint_in::instance(self.base_address, self.index());
+        let fifo_bytes = read_reg!(endpoint_in, ep, DTXFSTS, INEPTFSAV) as usize * 4;
+        core::cmp::min(fifo_bytes, self.max_write_size())
+    }
+
+    /// Adds the retry flags the interrupt handler has just taken to the endpoint's count.
+    pub fn record_retries(&self, cs: &CriticalSection, retries: u32) {
+        let count = self.retries.borrow(cs);
+        count.set(count.get().wrapping_add(retries));
+    }
+
+    pub fn retry_count(&self, cs: &CriticalSection) -> u32 {
+        self.retries.borrow(cs).get()
+    }
+
+    /// Returns true if the endpoint can't take another transfer yet.
+    fn is_busy(&self) -> bool {
+        // In DMA mode the core may still be fetching the previous packet from the buffer
+        (self.index() != 0 || self.dma_buffer.is_some()) && self.core().is_endpoint_enabled(self.index(), Direction::In)
+    }
+
     pub fn configure(&self, _cs: &CriticalSection) {
         if self.index() == 0 {
-            let mpsiz = match self.descriptor.max_packet_size {
-                8 => 0b11,
-                16 => 0b10,
-                32 => 0b01,
-                64 => 0b00,
-                other => panic!("Unsupported EP0 size: {}", other),
-            };
+            let mpsiz = ep0_mpsiz(self.descriptor.max_packet_size, self.core().speed());
 
-            let regs = endpoint_in::instance(self.index());
-            write_reg!(endpoint_in, regs, DIEPCTL, MPSIZ: mpsiz as u32, SNAK: 1);
+            let regs = endpoint_in::instance(self.base_address, self.index());
+            write_reg!(endpoint_in, regs, DIEPCTL, MPSIZ: mpsiz, SNAK: 1);
             write_reg!(endpoint_in, regs, DIEPTSIZ, PKTCNT: 0, XFRSIZ: self.descriptor.max_packet_size as u32);
         } else {
-            let regs = endpoint_in::instance(self.index());
+            let regs = endpoint_in::instance(self.base_address, self.index());
             write_reg!(endpoint_in, regs, DIEPCTL,
                 SNAK: 1,
                 USBAEP: 1,
-                EPTYP: self.descriptor.ep_type as u32,
+                EPTYP: eptyp(self.descriptor.ep_type),
                 SD0PID_SEVNFRM: 1,
                 TXFNUM: self.index() as u32,
-                MPSIZ: self.descriptor.max_packet_size as u32
+                MPSIZ: self.packet_size() as u32
             );
         }
     }
 
-    pub fn deconfigure(&self, _cs: &CriticalSection) {
-        let regs = endpoint_in::instance(self.index());
-
-        // deactivating endpoint
-        modify_reg!(endpoint_in, regs, DIEPCTL, USBAEP: 0);
+    /// Programs the packet size of EP0 for the speed the bus reset has enumerated.
+    pub fn set_ep0_speed(&self, speed: Speed) {
+        debug_assert_eq!(self.index(), 0);
+        let regs = endpoint_in::instance(self.base_address, 0);
+        modify_reg!(endpoint_in, regs, DIEPCTL, MPSIZ: ep0_mpsiz(self.descriptor.max_packet_size, speed));
+    }
 
-        // TODO: flushing FIFO
+    /// Disables the endpoint. The caller is responsible for flushing the TX FIFO afterwards.
+    ///
+    /// Returns false if the core didn't confirm the NAK or the disable in time. The endpoint is
+    /// deactivated either way.
+    #[must_use]
+    pub fn deconfigure(&self, _cs: &CriticalSection) -> bool {
+        let core = self.core();
+        let mut confirmed = true;
 
         // disabling endpoint
-        if read_reg!(endpoint_in, regs, DIEPCTL, EPENA) != 0 && self.index() != 0 {
-            modify_reg!(endpoint_in, regs, DIEPCTL, EPDIS: 1)
+        if core.is_endpoint_enabled(self.index(), Direction::In) && self.index() != 0 {
+            // stop responding to IN tokens with data first
+            let regs = endpoint_in::instance(self.base_address, self.index());
+            modify_reg!(endpoint_in, regs, DIEPCTL, SNAK: 1);
+            // Disabling an endpoint that doesn't NAK yet is undefined, so that is skipped
+            confirmed = wait_for(|| read_reg!(endpoint_in, regs, DIEPINT, INEPNE) != 0)
+                && core.disable_endpoint(self.index(), Direction::In);
         }
 
-        // clean EP interrupts
-        write_reg!(endpoint_in, regs, DIEPINT, 0xff);
-
-        // TODO: deconfiguring TX FIFO
+        core.deactivate_endpoint(self.index(), Direction::In);
+        confirmed
     }
 
-    pub fn write(&self, buf: &[u8]) -> Result<()> {
-        let ep = endpoint_in::instance(self.index());
-        if self.index() != 0 && read_reg!(endpoint_in, ep, DIEPCTL, EPENA) != 0{
+    /// Starts sending `buf`: programs the transfer and enables the endpoint. Isochronous packets
+    /// go out in the first (micro)frame of the endpoint's interval after `frame_number`, the
+    /// current one.
+    ///
+    /// Only the free space of the TX FIFO is checked, the caller then writes `buf` into the FIFO
+    /// with [`Core::write_packet`], except in DMA mode. There `buf` is copied into the DMA buffer
+    /// and `clean_dcache` is called for it before the endpoint is enabled.
+    pub fn start_write(&self, cs: &CriticalSection, buf: &[u8], frame_number: u16, clean_dcache: fn(usize, usize)) -> Result<()> {
+        let core = self.core();
+        let ep = endpoint_in::instance(self.base_address, self.index());
+        if self.is_busy() {
             return Err(UsbError::WouldBlock);
         }
 
-        if buf.len() > self.descriptor.max_packet_size as usize {
+        let packet_size = self.packet_size() as usize;
+        if buf.len() > self.max_write_size() {
             return Err(UsbError::BufferOverflow);
         }
 
-        if !buf.is_empty() {
+        #[cfg(feature = "hs")]
+        {
+            if let Some(dma_buffer) = &self.dma_buffer {
+                interrupt::free(|cs| -> Result<()> {
+                    let mut dma_buffer = dma_buffer.borrow(cs).borrow_mut();
+                    dma_buffer.write_packet(buf)?;
+                    clean_dcache(dma_buffer.as_ptr() as usize, buf.len());
+                    core.set_dma_address(self.index(), Direction::In, dma_buffer.as_ptr() as u32);
+                    Ok(())
+                })?;
+            }
+        }
+        #[cfg(not(feature = "hs"))]
+        let _ = clean_dcache;
+
+        if self.dma_buffer.is_none() && !buf.is_empty() {
             // Check for FIFO free space
             let size_words = (buf.len() + 3) / 4;
             if size_words > read_reg!(endpoint_in, ep, DTXFSTS, INEPTFSAV) as usize {
@@ -132,84 +306,518 @@ impl EndpointIn {
             }
         }
 
-        #[cfg(feature = "fs")]
-        write_reg!(endpoint_in, ep, DIEPTSIZ, PKTCNT: 1, XFRSIZ: buf.len() as u32);
+        // A buffer longer than a packet is sent as back-to-back packets
+        let packets = core::cmp::max(buf.len().div_ceil(packet_size), 1) as u32;
+
+        #[cfg(not(feature = "hs"))]
+        write_reg!(endpoint_in, ep, DIEPTSIZ, PKTCNT: packets, XFRSIZ: buf.len() as u32);
         #[cfg(feature = "hs")]
-        write_reg!(endpoint_in, ep, DIEPTSIZ, MCNT: 1, PKTCNT: 1, XFRSIZ: buf.len() as u32);
+        {
+            // High-bandwidth periodic endpoints send up to 3 packets per microframe
+            let mcnt = match self.descriptor.ep_type {
+                EndpointType::Isochronous | EndpointType::Interrupt => packets,
+                _ => 1,
+            };
+            write_reg!(endpoint_in, ep, DIEPTSIZ, MCNT: mcnt, PKTCNT: packets, XFRSIZ: buf.len() as u32);
+        }
 
-        modify_reg!(endpoint_in, ep, DIEPCTL, CNAK: 1, EPENA: 1);
+        #[cfg(not(feature = "iso"))]
+        let _ = (cs, frame_number);
+        #[cfg(feature = "iso")]
+        if self.descriptor.ep_type == EndpointType::Isochronous {
+            // The core only sends the packet in a (micro)frame of the selected parity
+            let target = self.next_service_frame(cs, frame_number);
+            self.iso_target.borrow(cs).set(target);
+            let odd = target & 1 != 0;
+            #[cfg(not(feature = "hs"))]
+            modify_reg!(endpoint_in, ep, DIEPCTL, SODDFRM_SD1PID: odd as u32, SD0PID_SEVNFRM: !odd as u32);
+            #[cfg(feature = "hs")]
+            modify_reg!(endpoint_in, ep, DIEPCTL, SODDFRM: odd as u32, SD0PID_SEVNFRM: !odd as u32);
+        }
 
-        fifo_write(self.index(), buf);
+        core.enable_endpoint(self.index(), Direction::In);
 
         Ok(())
     }
+
+    /// Returns the mask of DSTS.FNSOF and the service period of the isochronous endpoint, both
+    /// in (micro)frames of the enumerated speed.
+    #[cfg(feature = "iso")]
+    fn iso_schedule(&self) -> (u16, u16) {
+        let mask = match self.core().speed() {
+            Speed::High => 0x3fff,
+            Speed::Full => 0x7ff,
+        };
+        let period = 1u32 << (self.descriptor.interval.clamp(1, 16) - 1);
+        (mask, core::cmp::min(period, mask as u32 + 1) as u16)
+    }
+
+    /// Returns the first (micro)frame after `frame_number` the host polls the endpoint in.
+    #[cfg(feature = "iso")]
+    fn next_service_frame(&self, cs: &CriticalSection, frame_number: u16) -> u16 {
+        let (mask, period) = self.iso_schedule();
+        service_frame_after(frame_number, self.iso_phase.borrow(cs).get(), period, mask)
+    }
+
+    /// Records that the host has polled the isochronous endpoint in `frame_number`.
+    #[cfg(feature = "iso")]
+    pub fn record_service_frame(&self, cs: &CriticalSection, frame_number: u16) {
+        if self.descriptor.ep_type == EndpointType::Isochronous {
+            let (_, period) = self.iso_schedule();
+            self.iso_phase.borrow(cs).set(frame_number & (period - 1));
+        }
+    }
+
+    /// Returns true if the (micro)frame the armed isochronous packet is meant for has ended with
+    /// `frame_number` without the host polling the endpoint. The next packet then tries the
+    /// following (micro)frame of the period, until a completed transfer reveals the host's one.
+    #[cfg(feature = "iso")]
+    pub fn missed_service_frame(&self, cs: &CriticalSection, frame_number: u16) -> bool {
+        let (mask, period) = self.iso_schedule();
+        let target = self.iso_target.borrow(cs).get();
+        // The frames before the target wrap around to the upper half
+        if frame_number.wrapping_sub(target) & mask > mask / 2 {
+            return false;
+        }
+
+        let phase = self.iso_phase.borrow(cs);
+        phase.set(phase.get().wrapping_add(1) & (period - 1));
+        true
+    }
 }
 
 pub struct EndpointOut {
     common: Endpoint,
     pub(crate) buffer: Mutex<RefCell<EndpointBuffer>>,
+    dma: bool,
+    manual_rearm: bool,
+    /// The endpoint NAKs because the buffer had no room for another packet
+    waiting_for_room: Mutex<Cell<bool>>,
+    /// The packets are read straight from the RX FIFO, the buffer only takes zero-length ones
+    direct: bool,
+    /// A packet of the endpoint waits at the head of the RX FIFO
+    fifo_packet: Mutex<Cell<bool>>,
+    /// Transfers the interrupt handler receives the packets into
+    transfers: Mutex<RefCell<ReadQueue>>,
+    /// Arrival time of the first packet in the buffer or waiting in the RX FIFO
+    received_timestamp: Mutex<Cell<Option<u32>>>,
+    /// Arrival time of the data last returned by `read()`
+    read_timestamp: Mutex<Cell<Option<u32>>>,
+    /// Frame the isochronous packet in the buffer arrived in
+    #[cfg(feature = "iso")]
+    received_frame: Mutex<Cell<Option<Frame>>>,
+    /// Frame the isochronous packet last returned by `read()` arrived in
+    #[cfg(feature = "iso")]
+    read_frame: Mutex<Cell<Option<Frame>>>,
+    /// Times the endpoint has been left NAKing after a packet
+    naks: Mutex<Cell<u32>>,
+}
+
+/// Buffer of the application the packets of an OUT endpoint are received into.
+struct ReadTransfer {
+    buf: &'static mut [u8],
+    received: usize,
+    /// The buffer is full or the host has sent a short packet
+    done: bool,
 }
 
+impl ReadTransfer {
+    fn advance(&mut self, size: usize, packet_size: usize) {
+        self.received += size;
+        self.done = self.received == self.buf.len() || size % packet_size != 0 || size == 0;
+    }
+
+    fn into_received(self) -> &'static mut [u8] {
+        &mut self.buf[..self.received]
+    }
+}
+
+/// Number of buffers that can be queued for the transfers of an OUT endpoint.
+pub const READ_QUEUE_LEN: usize = 2;
+
+/// Transfers queued for an OUT endpoint, oldest first. The ended transfers come before the ones
+/// still waiting for data.
+struct ReadQueue {
+    transfers: [Option<ReadTransfer>; READ_QUEUE_LEN],
+}
+
+impl ReadQueue {
+    const fn new() -> Self {
+        const NONE: Option<ReadTransfer> = None;
+        Self { transfers: [NONE; READ_QUEUE_LEN] }
+    }
+
+    fn is_full(&self) -> bool {
+        self.transfers.iter().all(Option::is_some)
+    }
+
+    /// Adds a transfer at the end of the queue. There has to be room for it, see `is_full()`.
+    fn push(&mut self, transfer: ReadTransfer) {
+        if let Some(slot) = self.transfers.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(transfer);
+        }
+    }
+
+    fn pop(&mut self) -> Option<ReadTransfer> {
+        let transfer = self.transfers[0].take();
+        self.transfers.rotate_left(1);
+        transfer
+    }
+
+    /// Returns the transfer the next packet goes into.
+    fn active(&mut self) -> Option<&mut ReadTransfer> {
+        self.transfers.iter_mut().flatten().find(|transfer| !transfer.done)
+    }
+
+    fn has_ended(&self) -> bool {
+        matches!(&self.transfers[0], Some(transfer) if transfer.done)
+    }
+
+    fn end_all(&mut self) {
+        for transfer in self.transfers.iter_mut().flatten() {
+            transfer.done = true;
+        }
+    }
+}
+
+/// Number of back-to-back SETUP packets EP0 accepts before the application has to re-arm it.
+pub const SETUP_PACKETS: u32 = 3;
+
 impl EndpointOut {
-    pub fn new(descriptor: EndpointDescriptor, buffer: EndpointBuffer) -> EndpointOut {
+    /// Creates an OUT endpoint. In DMA mode the core writes the received packets straight into
+    /// `buffer`. With `manual_rearm` the endpoint isn't re-armed automatically after a packet,
+    /// except for EP0.
+    pub fn new(descriptor: EndpointDescriptor, base_address: usize, mut buffer: EndpointBuffer, dma: bool, manual_rearm: bool, direct: bool) -> EndpointOut {
+        // The core writes DMA transfers to the start of the buffer. Control and isochronous
+        // packets have to be read one at a time.
+        let streaming = matches!(descriptor.ep_type, EndpointType::Bulk | EndpointType::Interrupt);
+        if !dma && streaming && descriptor.address.index() != 0 {
+            buffer.enable_multi_packet(packet_size(descriptor.max_packet_size));
+        }
+
         EndpointOut {
-            common: Endpoint::new(descriptor),
+            common: Endpoint::new(descriptor, base_address),
             buffer: Mutex::new(RefCell::new(buffer)),
+            dma,
+            manual_rearm,
+            waiting_for_room: Mutex::new(Cell::new(false)),
+            direct,
+            fifo_packet: Mutex::new(Cell::new(false)),
+            transfers: Mutex::new(RefCell::new(ReadQueue::new())),
+            received_timestamp: Mutex::new(Cell::new(None)),
+            read_timestamp: Mutex::new(Cell::new(None)),
+            #[cfg(feature = "iso")]
+            received_frame: Mutex::new(Cell::new(None)),
+            #[cfg(feature = "iso")]
+            read_frame: Mutex::new(Cell::new(None)),
+            naks: Mutex::new(Cell::new(0)),
         }
     }
 
-    pub fn configure(&self, _cs: &CriticalSection) {
+    /// Returns true if the endpoint is read straight from the RX FIFO.
+    pub fn is_direct(&self) -> bool {
+        self.direct
+    }
+
+    /// Programs the transfer size and, in DMA mode, the destination of the next packet.
+    fn prepare_transfer(&self, cs: &CriticalSection) {
         if self.index() == 0 {
-            let mpsiz = match self.descriptor.max_packet_size {
-                8 => 0b11,
-                16 => 0b10,
-                32 => 0b01,
-                64 => 0b00,
-                other => panic!("Unsupported EP0 size: {}", other),
-            };
+            // Hosts may retry a SETUP before the previous one has been handled
+            let regs = endpoint0_out::instance(self.base_address);
+            write_reg!(endpoint0_out, regs, DOEPTSIZ0, STUPCNT: SETUP_PACKETS, PKTCNT: 1, XFRSIZ: self.descriptor.max_packet_size as u32);
+        } else {
+            let regs = endpoint_out::instance(self.base_address, self.index());
+            write_reg!(endpoint_out, regs, DOEPTSIZ, PKTCNT: 1, XFRSIZ: self.packet_size() as u32);
+        }
+
+        #[cfg(feature = "hs")]
+        {
+            if self.dma {
+                let address = self.buffer.borrow(cs).borrow().as_ptr() as u32;
+                self.core().set_dma_address(self.index(), Direction::Out, address);
+            }
+        }
+        #[cfg(not(feature = "hs"))]
+        let _ = cs;
+    }
+
+    /// Returns the number of bytes the last DMA transfer has written into the buffer.
+    #[cfg(feature = "hs")]
+    pub fn dma_received_size(&self) -> u16 {
+        let remaining = if self.index() == 0 {
+            let regs = endpoint0_out::instance(self.base_address);
+            read_reg!(endpoint0_out, regs, DOEPTSIZ0, XFRSIZ)
+        } else {
+            let regs = endpoint_out::instance(self.base_address, self.index());
+            read_reg!(endpoint_out, regs, DOEPTSIZ, XFRSIZ)
+        };
+        let requested = if self.index() == 0 { self.descriptor.max_packet_size } else { self.packet_size() };
+        requested.saturating_sub(remaining as u16)
+    }
+
+    /// Returns the offset in words of the last SETUP packet EP0 has received by DMA.
+    ///
+    /// The DMA address advances past every SETUP packet, so the last one lies right before it.
+    /// This stays correct when the host sends more back-to-back SETUP packets than STUPCNT allows.
+    #[cfg(feature = "hs")]
+    pub fn dma_setup_offset_words(&self, cs: &CriticalSection) -> usize {
+        let regs = endpoint0_out::instance(self.base_address);
+        let address = read_reg!(endpoint0_out, regs, DOEPDMA0) as usize;
+        let start = self.buffer.borrow(cs).borrow().as_ptr() as usize;
+        (address.saturating_sub(start) / 4).saturating_sub(2)
+    }
+
+    pub fn configure(&self, cs: &CriticalSection) {
+        self.prepare_transfer(cs);
+
+        if self.index() == 0 {
+            let mpsiz = ep0_mpsiz(self.descriptor.max_packet_size, self.core().speed());
 
-            let regs = endpoint0_out::instance();
-            write_reg!(endpoint0_out, regs, DOEPTSIZ0, STUPCNT: 1, PKTCNT: 1, XFRSIZ: self.descriptor.max_packet_size as u32);
-            modify_reg!(endpoint0_out, regs, DOEPCTL0, MPSIZ: mpsiz as u32, EPENA: 1, CNAK: 1);
+            let regs = endpoint0_out::instance(self.base_address);
+            modify_reg!(endpoint0_out, regs, DOEPCTL0, MPSIZ: mpsiz, EPENA: 1, CNAK: 1);
         } else {
-            let regs = endpoint_out::instance(self.index());
+            let regs = endpoint_out::instance(self.base_address, self.index());
             write_reg!(endpoint_out, regs, DOEPCTL,
                 SD0PID_SEVNFRM: 1,
                 CNAK: 1,
                 EPENA: 1,
                 USBAEP: 1,
-                EPTYP: self.descriptor.ep_type as u32,
-                MPSIZ: self.descriptor.max_packet_size as u32
+                EPTYP: eptyp(self.descriptor.ep_type),
+                MPSIZ: self.packet_size() as u32
             );
         }
     }
 
-    pub fn deconfigure(&self, _cs: &CriticalSection) {
-        let regs = endpoint_out::instance(self.index());
-
-        // deactivating endpoint
-        modify_reg!(endpoint_out, regs, DOEPCTL, USBAEP: 0);
+    /// Disables the endpoint and drops the buffered packets. Global OUT NAK must be in effect
+    /// when this is called.
+    ///
+    /// Returns false if the core didn't confirm the disable in time. The endpoint is deactivated
+    /// either way.
+    #[must_use]
+    pub fn deconfigure(&self, cs: &CriticalSection) -> bool {
+        let core = self.core();
+        let mut confirmed = true;
 
         // disabling endpoint
-        if read_reg!(endpoint_out, regs, DOEPCTL, EPENA) != 0 && self.index() != 0 {
-            modify_reg!(endpoint_out, regs, DOEPCTL, EPDIS: 1)
+        if core.is_endpoint_enabled(self.index(), Direction::Out) && self.index() != 0 {
+            confirmed = core.disable_endpoint(self.index(), Direction::Out);
+        }
+
+        core.deactivate_endpoint(self.index(), Direction::Out);
+
+        // Packets of the previous session are of no use anymore
+        self.buffer.borrow(cs).borrow_mut().clear();
+        self.waiting_for_room.borrow(cs).set(false);
+        self.fifo_packet.borrow(cs).set(false);
+        self.transfers.borrow(cs).borrow_mut().end_all();
+        self.received_timestamp.borrow(cs).set(None);
+        self.read_timestamp.borrow(cs).set(None);
+        #[cfg(feature = "iso")]
+        {
+            self.received_frame.borrow(cs).set(None);
+            self.read_frame.borrow(cs).set(None);
         }
+        confirmed
+    }
+
+    /// Re-arms the endpoint for the next packet.
+    pub fn reenable(&self, cs: &CriticalSection) {
+        // The transfer size has been decremented by the previous packet, restore it so that
+        // multi-packet data stages work with EP0 sizes smaller than 64 bytes.
+        self.prepare_transfer(cs);
+        self.waiting_for_room.borrow(cs).set(false);
 
-        // clean EP interrupts
-        write_reg!(endpoint_out, regs, DOEPINT, 0xff);
+        self.core().enable_endpoint(self.index(), Direction::Out);
+    }
+
+    /// Re-arms the endpoint after a packet unless the application does it.
+    pub fn auto_reenable(&self, cs: &CriticalSection) {
+        if self.index() == 0 || !self.manual_rearm {
+            self.reenable(cs);
+        } else if !self.dma && !self.waiting_for_room.borrow(cs).get() {
+            // A DMA endpoint has been counted when the packet arrived, and one waiting for room
+            // when it ran out of it
+            self.record_nak(cs);
+        }
+    }
+
+    /// Re-arms the endpoint after a packet has been moved from the FIFO into the buffer, if the
+    /// buffer has room for another one. Otherwise the endpoint NAKs until `read()` makes room,
+    /// so the host retries instead of the data being dropped.
+    pub fn rearm_after_receive(&self, cs: &CriticalSection) {
+        // The RX FIFO has room for the next packet of a direct endpoint once the last one is out
+        if self.direct || self.buffer.borrow(cs).borrow().can_accept(self.packet_size(), false) {
+            self.auto_reenable(cs);
+        } else {
+            self.waiting_for_room.borrow(cs).set(true);
+            self.record_nak(cs);
+        }
+    }
+
+    /// Counts a period in which the endpoint NAKs the host's OUT tokens.
+    pub fn record_nak(&self, cs: &CriticalSection) {
+        let count = self.naks.borrow(cs);
+        count.set(count.get().wrapping_add(1));
+    }
+
+    pub fn nak_count(&self, cs: &CriticalSection) -> u32 {
+        self.naks.borrow(cs).get()
     }
 
     pub fn read(&self, buf: &mut [u8]) -> Result<usize> {
         interrupt::free(|cs| {
-            self.buffer.borrow(cs).borrow_mut().read_packet(buf)
+            let result = self.buffer.borrow(cs).borrow_mut().read_packet(buf);
+            if result.is_ok() {
+                self.read_timestamp.borrow(cs).set(self.received_timestamp.borrow(cs).take());
+                #[cfg(feature = "iso")]
+                self.read_frame.borrow(cs).set(self.received_frame.borrow(cs).take());
+
+                if self.dma {
+                    // The endpoint NAKs until the buffer is free again
+                    self.auto_reenable(cs);
+                } else if self.waiting_for_room.borrow(cs).get() {
+                    self.rearm_after_receive(cs);
+                }
+            }
+            result
         })
     }
 
+    /// Returns the state of the buffer. A packet waiting in the RX FIFO and an ended transfer
+    /// count as received data.
     pub fn buffer_state(&self) -> EndpointBufferState {
         interrupt::free(|cs| {
-            self.buffer.borrow(cs).borrow().state()
+            let state = self.buffer.borrow(cs).borrow().state();
+            let transfer_ended = self.transfers.borrow(cs).borrow().has_ended();
+            if state == EndpointBufferState::Empty && (self.fifo_packet.borrow(cs).get() || transfer_ended) {
+                EndpointBufferState::DataOut
+            } else {
+                state
+            }
         })
     }
+
+    /// Records that a packet of the endpoint waits at the head of the RX FIFO.
+    pub fn set_fifo_packet(&self, cs: &CriticalSection) {
+        self.fifo_packet.borrow(cs).set(true);
+    }
+
+    /// Returns true if a packet of the endpoint waits at the head of the RX FIFO.
+    pub fn has_fifo_packet(&self, cs: &CriticalSection) -> bool {
+        self.fifo_packet.borrow(cs).get()
+    }
+
+    /// Copies the packet waiting at the head of the RX FIFO into `buf`.
+    pub fn read_fifo_packet(&self, cs: &CriticalSection, buf: &mut [u8]) -> Result<usize> {
+        let core = self.core();
+        let entry = match core.peek_rx_entry() {
+            Some(entry) if entry.ep_number == self.index() && entry.status.has_data() => entry,
+            _ => {
+                self.fifo_packet.borrow(cs).set(false);
+                return Err(UsbError::WouldBlock);
+            }
+        };
+        let size = entry.byte_count as usize;
+        if size > buf.len() {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        core.pop_rx_entry();
+        core.read_packet(&mut buf[..size]);
+        self.fifo_packet.borrow(cs).set(false);
+        self.read_timestamp.borrow(cs).set(self.received_timestamp.borrow(cs).take());
+        #[cfg(feature = "iso")]
+        if self.descriptor.ep_type == EndpointType::Isochronous {
+            self.read_frame.borrow(cs).set(Some(core.packet_frame(entry.frame_lsb)));
+        }
+        Ok(size)
+    }
+
+    /// Records the arrival time of a packet that starts the data of the next `read()`.
+    pub fn set_received_timestamp(&self, cs: &CriticalSection, timestamp: Option<u32>) {
+        self.received_timestamp.borrow(cs).set(timestamp);
+    }
+
+    /// Returns the arrival time of the data last returned by `read()`.
+    pub fn read_timestamp(&self, cs: &CriticalSection) -> Option<u32> {
+        self.read_timestamp.borrow(cs).get()
+    }
+
+    /// Records the frame the isochronous packet just moved into the buffer arrived in.
+    #[cfg(feature = "iso")]
+    pub fn set_received_frame(&self, cs: &CriticalSection, frame: Frame) {
+        if self.descriptor.ep_type == EndpointType::Isochronous {
+            self.received_frame.borrow(cs).set(Some(frame));
+        }
+    }
+
+    /// Returns the frame the isochronous packet last returned by `read()` arrived in.
+    #[cfg(feature = "iso")]
+    pub fn read_frame(&self, cs: &CriticalSection) -> Option<Frame> {
+        self.read_frame.borrow(cs).get()
+    }
+
+    /// Queues a transfer into `buf`. If no other transfer waits for data, the data already
+    /// buffered is copied right away. `buf` is returned with the error when the queue is full.
+    pub fn start_transfer(&self, cs: &CriticalSection, buf: &'static mut [u8]) -> core::result::Result<(), (Error, &'static mut [u8])> {
+        let mut transfers = self.transfers.borrow(cs).borrow_mut();
+        // Checked before the buffered packet is moved into the transfer, it would be lost otherwise
+        if transfers.is_full() {
+            return Err((Error::ReadQueueFull, buf));
+        }
+        let first = transfers.active().is_none();
+
+        let done = buf.is_empty();
+        let mut new_transfer = ReadTransfer { buf, received: 0, done };
+        let mut buffer = self.buffer.borrow(cs).borrow_mut();
+        if first && !done && buffer.state() == EndpointBufferState::DataOut {
+            match buffer.read_packet(new_transfer.buf) {
+                Ok(size) => new_transfer.advance(size, self.packet_size() as usize),
+                // The packet is left for read()
+                Err(_) => new_transfer.done = true,
+            }
+            drop(buffer);
+            if self.waiting_for_room.borrow(cs).get() {
+                self.rearm_after_receive(cs);
+            }
+        }
+
+        transfers.push(new_transfer);
+        Ok(())
+    }
+
+    /// Moves the packet at the head of the RX FIFO, `data_size` bytes, into the oldest transfer
+    /// waiting for data. A transfer the packet doesn't fit into ends and the packet goes into the
+    /// next one. Returns false, leaving the packet in the FIFO, if no transfer is left.
+    pub fn receive_into_transfer(&self, cs: &CriticalSection, data_size: u16) -> bool {
+        let mut transfers = self.transfers.borrow(cs).borrow_mut();
+        let size = data_size as usize;
+        while let Some(transfer) = transfers.active() {
+            if transfer.received + size > transfer.buf.len() {
+                transfer.done = true;
+                continue;
+            }
+
+            let core = self.core();
+            core.pop_rx_entry();
+            core.read_packet(&mut transfer.buf[transfer.received..transfer.received + size]);
+            transfer.advance(size, self.packet_size() as usize);
+            self.fifo_packet.borrow(cs).set(false);
+            return true;
+        }
+        false
+    }
+
+    /// Returns the received part of the oldest transfer buffer once the transfer has ended, or
+    /// right away with `cancel`.
+    pub fn take_transfer(&self, cs: &CriticalSection, cancel: bool) -> Option<&'static mut [u8]> {
+        let mut transfers = self.transfers.borrow(cs).borrow_mut();
+        if transfers.has_ended() || cancel {
+            transfers.pop().map(ReadTransfer::into_received)
+        } else {
+            None
+        }
+    }
 }
 
 
@@ -240,3 +848,55 @@ impl DerefMut for EndpointOut {
         &mut self.common
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bulk_packet_size_depends_on_speed() {
+        assert!(is_valid_max_packet_size(EndpointType::Bulk, 64, false));
+        assert!(!is_valid_max_packet_size(EndpointType::Bulk, 512, false));
+        assert!(is_valid_max_packet_size(EndpointType::Bulk, 512, true));
+        assert!(!is_valid_max_packet_size(EndpointType::Bulk, 64, true));
+    }
+
+    #[test]
+    fn high_bandwidth_periodic_packet_size() {
+        let two_transactions = 1024 | (1 << 11);
+        let three_transactions = 1024 | (2 << 11);
+
+        assert!(is_valid_max_packet_size(EndpointType::Isochronous, 1024, true));
+        assert!(is_valid_max_packet_size(EndpointType::Interrupt, three_transactions, true));
+        assert!(!is_valid_max_packet_size(EndpointType::Isochronous, three_transactions, false));
+        assert!(!is_valid_max_packet_size(EndpointType::Isochronous, 512 | (2 << 11), true));
+        assert!(!is_valid_max_packet_size(EndpointType::Isochronous, 1024 | (3 << 11), true));
+
+        assert_eq!(packet_size(two_transactions), 1024);
+        assert_eq!(transactions_per_frame(two_transactions), 2);
+    }
+
+    #[test]
+    fn periodic_interval_ranges() {
+        assert!(is_valid_interval(EndpointType::Interrupt, 255));
+        assert!(!is_valid_interval(EndpointType::Interrupt, 0));
+        assert!(is_valid_interval(EndpointType::Isochronous, 16));
+        assert!(!is_valid_interval(EndpointType::Isochronous, 17));
+        assert!(!is_valid_interval(EndpointType::Isochronous, 0));
+        assert!(is_valid_interval(EndpointType::Bulk, 0));
+    }
+
+    #[cfg(feature = "iso")]
+    #[test]
+    fn isochronous_packets_wait_for_their_service_frame() {
+        // Every frame
+        assert_eq!(service_frame_after(10, 0, 1, 0x7ff), 11);
+        // Every 4th frame, the host polls in frames 1, 5, 9...
+        assert_eq!(service_frame_after(10, 1, 4, 0x7ff), 13);
+        assert_eq!(service_frame_after(12, 1, 4, 0x7ff), 13);
+        assert_eq!(service_frame_after(13, 1, 4, 0x7ff), 17);
+        // Across the wrap-around of the frame number
+        assert_eq!(service_frame_after(0x7fe, 0, 4, 0x7ff), 0);
+        assert_eq!(service_frame_after(0x3fff, 3, 8, 0x3fff), 3);
+    }
+}
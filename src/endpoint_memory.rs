@@ -1,9 +1,105 @@
 #![allow(dead_code)]
 use core::{slice, mem};
+use core::mem::MaybeUninit;
 use vcell::VolatileCell;
 use crate::target::fifo_read_into;
 use usb_device::{Result, UsbError};
-use crate::ral::otg_fifo::FIFO_DEPTH_WORDS;
+use crate::Error;
+use crate::endpoint::SETUP_PACKETS;
+use crate::ral::otg_device::ENDPOINT_COUNT;
+use crate::target::interrupt::{self, Mutex};
+use core::cell::{Cell, UnsafeCell};
+
+/// Words the RX FIFO needs on top of the OUT endpoint buffers.
+///
+/// This calculation doesn't correspond to one in a Reference Manual.
+/// In fact, the required number of words is higher than indicated in RM.
+/// The following numbers are pessimistic and were figured out empirically.
+/// F429 requires 35+ words for the (EP0[8] + EP2[64]) setup
+/// F446 requires 39+ words for the same setup
+pub const RX_FIFO_EXTRA_WORDS: usize = 30;
+
+/// Words EP0 takes in DMA mode, where the core writes back-to-back SETUP packets into its buffer.
+const EP0_DMA_WORDS: usize = 8 * SETUP_PACKETS as usize / 4;
+
+/// Endpoint memory for a `static`, so that it can be placed in a RAM region the core's DMA can
+/// reach:
+///
+/// ```no_run
+/// # use synopsys_usb_otg::{EndpointMemory, UsbBus};
+/// # struct USB;
+/// # unsafe impl synopsys_usb_otg::UsbPeripheral for USB {
+/// #     const REGISTERS: *const () = 0x5000_0000 as *const ();
+/// #     const HIGH_SPEED: bool = false;
+/// #     const FIFO_DEPTH_WORDS: usize = 320;
+/// #     fn enable() {}
+/// # }
+/// # let usb = USB;
+/// #[link_section = ".axisram"]
+/// static EP_MEMORY: EndpointMemory<1024> = EndpointMemory::new();
+///
+/// let usb_bus = UsbBus::new_uninit(usb, EP_MEMORY.take().unwrap());
+/// ```
+///
+/// The words are 4-byte aligned, as the DMA requires of every buffer, and the driver only hands
+/// out whole words of them. The memory starts on a 32-byte Cortex-M7 cache line, so that the
+/// cache maintenance of its DMA buffers doesn't touch other data as long as `WORDS` is a
+/// multiple of 8. A size smaller than the buffer EP0 takes for SETUP packets in DMA mode fails to
+/// compile, the endpoint sizes themselves are checked when they are allocated:
+///
+/// ```compile_fail
+/// # use synopsys_usb_otg::EndpointMemory;
+/// static EP_MEMORY: EndpointMemory<4> = EndpointMemory::new();
+/// ```
+#[repr(C, align(32))]
+pub struct EndpointMemory<const WORDS: usize> {
+    words: UnsafeCell<[MaybeUninit<u32>; WORDS]>,
+    taken: Mutex<Cell<bool>>,
+}
+
+// The words are only reachable through the single reference take() returns
+unsafe impl<const WORDS: usize> Sync for EndpointMemory<WORDS> {}
+
+impl<const WORDS: usize> EndpointMemory<WORDS> {
+    const HOLDS_EP0: () = assert!(WORDS >= EP0_DMA_WORDS, "the endpoint memory is smaller than the EP0 SETUP buffer");
+
+    pub const fn new() -> Self {
+        let () = Self::HOLDS_EP0;
+        Self {
+            words: UnsafeCell::new([MaybeUninit::uninit(); WORDS]),
+            taken: Mutex::new(Cell::new(false)),
+        }
+    }
+
+    /// Returns the words for [`UsbBus::new_uninit`](crate::UsbBus::new_uninit) the first time
+    /// it's called, `None` afterwards.
+    #[allow(clippy::mut_from_ref)]
+    pub fn take(&'static self) -> Option<&'static mut [MaybeUninit<u32>]> {
+        interrupt::free(|cs| {
+            if self.taken.borrow(cs).replace(true) {
+                return None;
+            }
+            Some(unsafe { &mut *self.words.get() }.as_mut_slice())
+        })
+    }
+
+    /// Lets [`take`](Self::take) return the words again, e.g. to create a bus with different
+    /// endpoints after the previous one was dropped.
+    ///
+    /// # Safety
+    ///
+    /// The bus the words were taken for, together with its `UsbBusAllocator`, must have been
+    /// dropped or freed, no reference to the words may be left.
+    pub unsafe fn release(&self) {
+        interrupt::free(|cs| self.taken.borrow(cs).set(false));
+    }
+}
+
+impl<const WORDS: usize> Default for EndpointMemory<WORDS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 #[derive(Eq, PartialEq)]
 pub enum EndpointBufferState {
@@ -17,30 +113,70 @@ pub struct EndpointBuffer {
     data_size: u16,
     has_data: bool,
     is_setup: bool,
+    /// Offset in words of the data not read yet
+    read_offset: usize,
+    /// Max packet size of the endpoint if full packets may be queued behind each other, else 0
+    packet_size: usize,
+    /// The last packet received was a full one, so the transfer may go on
+    last_packet_full: bool,
+    /// Space the buffer takes in the RX FIFO
+    fifo_size_words: usize,
 }
 
 impl EndpointBuffer {
-    pub fn new(buffer: &'static mut [u32]) -> Self {
+    pub fn new(buffer: &'static mut [MaybeUninit<u32>]) -> Self {
         Self {
             buffer: unsafe { mem::transmute(buffer) },
             data_size: 0,
             has_data: false,
-            is_setup: false
+            is_setup: false,
+            read_offset: 0,
+            packet_size: 0,
+            last_packet_full: false,
+            fifo_size_words: 0,
+        }
+    }
+
+    /// Lets the buffer hold several packets of the same transfer. Packets are appended as long
+    /// as the buffered data ends with a full packet of `packet_size` bytes, so a short packet
+    /// still terminates what a single read returns.
+    pub fn enable_multi_packet(&mut self, packet_size: u16) {
+        if packet_size & 3 == 0 {
+            self.packet_size = packet_size as usize;
         }
     }
 
+    /// Returns true if a received packet of `data_size` bytes can be stored right now.
+    pub fn can_accept(&self, data_size: u16, is_setup: bool) -> bool {
+        if !self.has_data {
+            return data_size as usize <= self.capacity();
+        }
+
+        self.packet_size != 0
+            && !is_setup
+            && !self.is_setup
+            && data_size != 0
+            && self.last_packet_full
+            && self.read_offset * 4 + self.data_size as usize + data_size as usize <= self.capacity()
+    }
+
+    /// Copies as much buffered data as fits into `buf`: all of it, or else as many full packets
+    /// as fit.
     pub fn read_packet(&mut self, mut buf: &mut [u8]) -> Result<usize> {
         if !self.has_data {
             return Err(UsbError::WouldBlock)
         }
 
-        let data_size = self.data_size as usize;
-
-        if buf.len() < data_size {
+        let available = self.data_size as usize;
+        let data_size = if buf.len() >= available {
+            available
+        } else if self.packet_size != 0 && buf.len() >= self.packet_size {
+            buf.len() / self.packet_size * self.packet_size
+        } else {
             return Err(UsbError::BufferOverflow);
-        }
+        };
 
-        let mut index = 0;
+        let mut index = self.read_offset;
         let mut current_size = data_size;
         while current_size >= 4 {
             let word = self.buffer[index].get();
@@ -58,30 +194,114 @@ impl EndpointBuffer {
             buf[..current_size].copy_from_slice(&bytes[..current_size]);
         }
 
-        self.has_data = false;
+        if data_size == available {
+            self.clear();
+        } else {
+            // Only full packets, which are a multiple of 4 bytes, are left behind
+            self.read_offset += data_size / 4;
+            self.data_size -= data_size as u16;
+        }
 
         Ok(data_size)
     }
 
-    pub fn fill_from_fifo(&mut self, data_size: u16, is_setup: bool) -> Result<()> {
+    pub fn fill_from_fifo(&mut self, base_address: usize, data_size: u16, is_setup: bool) -> Result<()> {
+        self.receive(data_size, is_setup, |buf| fifo_read_into(base_address, buf))
+    }
+
+    fn receive(&mut self, data_size: u16, is_setup: bool, read: impl FnOnce(&[VolatileCell<u32>])) -> Result<()> {
+        if !self.can_accept(data_size, is_setup) {
+            return Err(if self.has_data { UsbError::WouldBlock } else { UsbError::BufferOverflow });
+        }
+
+        let start = if self.has_data { self.read_offset + self.data_size as usize / 4 } else { 0 };
+        let words = (data_size as usize + 3) / 4;
+        read(&self.buffer[start..start + words]);
+
+        self.last_packet_full = data_size as usize == self.packet_size;
         if self.has_data {
-            return Err(UsbError::WouldBlock);
+            self.data_size += data_size;
+        } else {
+            self.is_setup = is_setup;
+            self.data_size = data_size;
+            self.has_data = true;
         }
 
+        Ok(())
+    }
+
+    /// Marks a packet the core has written into the buffer by DMA as received.
+    pub fn complete_dma(&mut self, data_size: u16, is_setup: bool) -> Result<()> {
         if data_size as usize > self.capacity() {
             return Err(UsbError::BufferOverflow);
         }
 
-        let words = (data_size as usize + 3) / 4;
-        fifo_read_into(&self.buffer[..words]);
-
         self.is_setup = is_setup;
         self.data_size = data_size;
         self.has_data = true;
+        self.read_offset = 0;
 
         Ok(())
     }
 
+    /// Marks the SETUP packet written by DMA at `offset_words` as received. Earlier back-to-back
+    /// SETUP packets in the buffer are dropped.
+    pub fn complete_dma_setup(&mut self, offset_words: usize) -> Result<()> {
+        if self.buffer.len() < 2 {
+            return Err(UsbError::BufferOverflow);
+        }
+        let offset = core::cmp::min(offset_words, self.buffer.len() - 2);
+
+        // Only the most recent SETUP packet is relevant
+        if offset != 0 {
+            self.buffer[0].set(self.buffer[offset].get());
+            self.buffer[1].set(self.buffer[offset + 1].get());
+        }
+
+        self.complete_dma(8, true)
+    }
+
+    /// Copies a packet to be sent by DMA into the buffer.
+    pub fn write_packet(&mut self, mut buf: &[u8]) -> Result<()> {
+        if buf.len() > self.capacity() {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        let mut index = 0;
+        while buf.len() >= 4 {
+            let mut u32_bytes = [0u8; 4];
+            u32_bytes.copy_from_slice(&buf[..4]);
+            buf = &buf[4..];
+            self.buffer[index].set(u32::from_ne_bytes(u32_bytes));
+            index += 1;
+        }
+        if !buf.is_empty() {
+            let mut u32_bytes = [0u8; 4];
+            u32_bytes[..buf.len()].copy_from_slice(buf);
+            self.buffer[index].set(u32::from_ne_bytes(u32_bytes));
+        }
+
+        Ok(())
+    }
+
+    /// Returns a copy of the buffered SETUP packet, if any.
+    pub fn setup_packet(&self) -> Option<[u8; 8]> {
+        if !self.has_data || !self.is_setup || self.data_size != 8 {
+            return None;
+        }
+
+        let mut packet = [0; 8];
+        packet[..4].copy_from_slice(&self.buffer[0].get().to_ne_bytes());
+        packet[4..].copy_from_slice(&self.buffer[1].get().to_ne_bytes());
+        Some(packet)
+    }
+
+    /// Drops the buffered packet, if any.
+    pub fn clear(&mut self) {
+        self.has_data = false;
+        self.read_offset = 0;
+    }
+
     pub fn state(&self) -> EndpointBufferState {
         if self.has_data {
             if self.is_setup {
@@ -97,6 +317,10 @@ impl EndpointBuffer {
     pub fn capacity(&self) -> usize {
         self.buffer.len() * 4
     }
+
+    pub fn as_ptr(&self) -> *const u32 {
+        self.buffer.as_ptr() as *const u32
+    }
 }
 
 impl Default for EndpointBuffer {
@@ -109,26 +333,75 @@ impl Default for EndpointBuffer {
 pub struct EndpointMemoryAllocator {
     next_free_offset: usize,
     max_size_words: usize,
-    memory: &'static mut [u32],
-    tx_fifo_size_words: [u16; 4],
+    memory: &'static mut [MaybeUninit<u32>],
+    tx_fifo_size_words: [u16; ENDPOINT_COUNT],
+    rx_fifo_size_words: usize,
+    fifo_depth_words: usize,
+    endpoint_count: usize,
 }
 
 impl EndpointMemoryAllocator {
-    pub fn new(memory: &'static mut [u32]) -> Self {
+    pub fn new(memory: &'static mut [MaybeUninit<u32>], fifo_depth_words: usize, endpoint_count: usize) -> Self {
         Self {
             next_free_offset: 0,
             max_size_words: 0,
             memory,
-            tx_fifo_size_words: [0; 4],
+            tx_fifo_size_words: [0; ENDPOINT_COUNT],
+            rx_fifo_size_words: 0,
+            fifo_depth_words,
+            endpoint_count: core::cmp::min(endpoint_count, ENDPOINT_COUNT),
         }
     }
 
-    pub fn allocate_rx_buffer(&mut self, size: usize) -> Result<EndpointBuffer> {
+    /// Returns the addresses of the endpoint memory.
+    pub fn memory_range(&self) -> core::ops::Range<usize> {
+        self.memory.as_ptr_range().start as usize..self.memory.as_ptr_range().end as usize
+    }
+
+    /// Allocates the buffer an IN endpoint sends from in DMA mode. Unlike the OUT buffers, it
+    /// doesn't take any space in the RX FIFO.
+    pub fn allocate_dma_buffer(&mut self, size: usize) -> core::result::Result<EndpointBuffer, Error> {
+        self.allocate(size, 0)
+    }
+
+    pub fn free_dma_buffer(&mut self, buffer: &EndpointBuffer) {
+        self.free_rx_buffer(buffer);
+    }
+
+    pub fn allocate_rx_buffer(&mut self, size: usize) -> core::result::Result<EndpointBuffer, Error> {
+        self.allocate(size, size)
+    }
+
+    /// Allocates an OUT buffer of `size` bytes for an endpoint receiving packets of up to
+    /// `packet_size` bytes. Only one packet has to fit into the RX FIFO, so a buffer larger than
+    /// the packet size takes the extra space from the endpoint memory alone.
+    pub fn allocate_rx_buffer_with_size(&mut self, packet_size: usize, size: usize) -> core::result::Result<EndpointBuffer, Error> {
+        self.allocate(core::cmp::max(size, packet_size), packet_size)
+    }
+
+    /// Reserves RX FIFO space for packets of up to `packet_size` bytes without a buffer in the
+    /// endpoint memory, for an endpoint that is read straight from the FIFO.
+    pub fn allocate_rx_fifo(&mut self, packet_size: usize) -> core::result::Result<EndpointBuffer, Error> {
+        self.allocate(0, packet_size)
+    }
+
+    fn allocate(&mut self, size: usize, fifo_size: usize) -> core::result::Result<EndpointBuffer, Error> {
         let size_words = (size + 3) / 4;
+        let fifo_size_words = fifo_size.div_ceil(4);
 
         let offset = self.next_free_offset;
         if offset + size_words > self.memory.len() {
-            return Err(UsbError::EndpointMemoryOverflow);
+            return Err(Error::EndpointMemoryOverflow);
+        }
+
+        if fifo_size_words != 0 {
+            let tx_size_words: usize = self.tx_fifo_size_words[..self.endpoint_count].iter()
+                .map(|size| *size as usize)
+                .sum();
+            let used = self.rx_fifo_size_words + RX_FIFO_EXTRA_WORDS + tx_size_words;
+            if used + fifo_size_words > self.fifo_depth_words {
+                return Err(Error::FifoOverflow);
+            }
         }
 
         self.next_free_offset += size_words;
@@ -138,26 +411,84 @@ impl EndpointMemoryAllocator {
             let ptr = self.memory.as_mut_ptr().offset(offset as isize);
             slice::from_raw_parts_mut(ptr, size_words)
         };
-        Ok(EndpointBuffer::new(buffer))
+        let mut buffer = EndpointBuffer::new(buffer);
+        buffer.fifo_size_words = fifo_size_words;
+        self.rx_fifo_size_words += buffer.fifo_size_words;
+        Ok(buffer)
     }
 
-    pub fn allocate_tx_buffer(&mut self, ep_number: u8, size: usize) -> Result<()> {
-        let ep_number = ep_number as usize;
-        assert!(ep_number < self.tx_fifo_size_words.len());
+    /// Releases an OUT buffer. Only the most recently allocated buffer is returned to the free
+    /// memory right away, space of the other buffers stays reserved until the buffers are
+    /// compacted.
+    pub fn free_rx_buffer(&mut self, buffer: &EndpointBuffer) {
+        let size_words = buffer.capacity() / 4;
+        if size_words == 0 {
+            // Only the FIFO space to give back
+            self.rx_fifo_size_words -= core::cmp::min(buffer.fifo_size_words, self.rx_fifo_size_words);
+            return;
+        }
 
-        if self.tx_fifo_size_words[ep_number] != 0 {
-            return Err(UsbError::InvalidEndpoint)
+        let offset = (buffer.as_ptr() as usize - self.memory.as_ptr() as usize) / 4;
+        if offset + size_words == self.next_free_offset {
+            self.next_free_offset = offset;
+            self.rx_fifo_size_words -= core::cmp::min(buffer.fifo_size_words, self.rx_fifo_size_words);
         }
+    }
+
+    /// Starts compacting the OUT buffers. All the buffers still in use must then be passed to
+    /// `relocate_rx_buffer` in order of their current position in memory.
+    pub fn begin_compaction(&mut self) {
+        self.next_free_offset = 0;
+        self.max_size_words = 0;
+        self.rx_fifo_size_words = 0;
+    }
+
+    /// Moves a buffer to the lowest free position, keeping its contents.
+    pub fn relocate_rx_buffer(&mut self, buffer: &mut EndpointBuffer) {
+        let size_words = buffer.buffer.len();
+        if size_words == 0 {
+            self.rx_fifo_size_words += buffer.fifo_size_words;
+            return;
+        }
+
+        let offset = self.next_free_offset;
+        let old_offset = (buffer.as_ptr() as usize - self.memory.as_ptr() as usize) / 4;
+        debug_assert!(offset <= old_offset);
+
+        self.next_free_offset += size_words;
+        self.max_size_words = core::cmp::max(self.max_size_words, size_words);
+        self.rx_fifo_size_words += buffer.fifo_size_words;
 
-        let mut used = self.total_rx_buffer_size_words() as usize + 30;
-        for sz in &self.tx_fifo_size_words {
+        if offset == old_offset {
+            return;
+        }
+
+        let new_buffer = unsafe {
+            let ptr = self.memory.as_mut_ptr().add(offset);
+            core::ptr::copy(buffer.as_ptr(), ptr as *mut u32, size_words);
+            slice::from_raw_parts_mut(ptr, size_words)
+        };
+        buffer.buffer = unsafe {
+            mem::transmute::<&'static mut [MaybeUninit<u32>], &'static mut [VolatileCell<u32>]>(new_buffer)
+        };
+    }
+
+    pub fn allocate_tx_buffer(&mut self, ep_number: u8, size: usize) -> core::result::Result<(), Error> {
+        let ep_number = ep_number as usize;
+        match self.tx_fifo_size_words[..self.endpoint_count].get(ep_number) {
+            Some(0) => {},
+            _ => return Err(Error::EndpointUnavailable),
+        }
+
+        let mut used = self.total_rx_buffer_size_words() as usize + RX_FIFO_EXTRA_WORDS;
+        for sz in &self.tx_fifo_size_words[..self.endpoint_count] {
             used += core::cmp::max(*sz as usize, 16);
         }
         used -= 16;
 
         let size_words = core::cmp::max((size + 3) / 4, 16);
-        if (used + size_words) > FIFO_DEPTH_WORDS as usize {
-            return Err(UsbError::EndpointMemoryOverflow);
+        if (used + size_words) > self.fifo_depth_words {
+            return Err(Error::FifoOverflow);
         }
 
         self.tx_fifo_size_words[ep_number] = size_words as u16;
@@ -165,19 +496,185 @@ impl EndpointMemoryAllocator {
         Ok(())
     }
 
-    /// Returns the size of memory allocated for OUT endpoints in words
+    pub fn free_tx_buffer(&mut self, ep_number: u8) {
+        if let Some(size) = self.tx_fifo_size_words.get_mut(ep_number as usize) {
+            *size = 0;
+        }
+    }
+
+    /// Returns the size of the RX FIFO space needed by the OUT endpoints in words
     pub fn total_rx_buffer_size_words(&self) -> u16 {
-        self.next_free_offset as u16
+        self.rx_fifo_size_words as u16
     }
 
     pub fn tx_fifo_size_words(&self, ep_number: u8) -> u16 {
-        let ep_number = ep_number as usize;
-        assert!(ep_number < self.tx_fifo_size_words.len());
-
-        self.tx_fifo_size_words[ep_number]
+        self.tx_fifo_size_words.get(ep_number as usize).copied().unwrap_or(0)
     }
 
     pub fn max_buffer_size_words(&self) -> usize {
         self.max_size_words
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+
+    const FIFO_DEPTH_WORDS: usize = 1024;
+
+    fn memory(size_words: usize) -> &'static mut [MaybeUninit<u32>] {
+        std::vec![MaybeUninit::uninit(); size_words].leak()
+    }
+
+    #[test]
+    fn rx_buffer_for_high_speed_bulk() {
+        let mut allocator = EndpointMemoryAllocator::new(memory(256), FIFO_DEPTH_WORDS, ENDPOINT_COUNT);
+
+        let buffer = allocator.allocate_rx_buffer(512).unwrap();
+        assert_eq!(buffer.capacity(), 512);
+        assert_eq!(allocator.total_rx_buffer_size_words(), 128);
+    }
+
+    #[test]
+    fn large_rx_buffer_takes_one_packet_of_fifo() {
+        let mut allocator = EndpointMemoryAllocator::new(memory(512), FIFO_DEPTH_WORDS, ENDPOINT_COUNT);
+
+        let buffer = allocator.allocate_rx_buffer_with_size(64, 2048).unwrap();
+        assert_eq!(buffer.capacity(), 2048);
+        assert_eq!(allocator.total_rx_buffer_size_words(), 16);
+
+        allocator.free_rx_buffer(&buffer);
+        assert_eq!(allocator.total_rx_buffer_size_words(), 0);
+    }
+
+    #[test]
+    fn direct_endpoint_takes_fifo_space_only() {
+        let mut allocator = EndpointMemoryAllocator::new(memory(16), FIFO_DEPTH_WORDS, ENDPOINT_COUNT);
+
+        let mut direct = allocator.allocate_rx_fifo(512).unwrap();
+        assert_eq!(direct.capacity(), 0);
+        assert_eq!(allocator.total_rx_buffer_size_words(), 128);
+        let mut buffer = allocator.allocate_rx_buffer(64).unwrap();
+        assert_eq!(allocator.total_rx_buffer_size_words(), 144);
+
+        allocator.begin_compaction();
+        allocator.relocate_rx_buffer(&mut direct);
+        allocator.relocate_rx_buffer(&mut buffer);
+        assert_eq!(allocator.total_rx_buffer_size_words(), 144);
+
+        allocator.free_rx_buffer(&direct);
+        assert_eq!(allocator.total_rx_buffer_size_words(), 16);
+    }
+
+    #[test]
+    fn rx_buffers_fit_into_the_fifo() {
+        let mut allocator = EndpointMemoryAllocator::new(memory(256), 64, 1);
+        allocator.allocate_tx_buffer(0, 64).unwrap();
+
+        // 30 + 16 words are taken by the RX FIFO overhead and TX FIFO 0
+        allocator.allocate_rx_buffer(64).unwrap();
+        assert!(matches!(allocator.allocate_rx_buffer(16), Err(Error::FifoOverflow)));
+        allocator.allocate_rx_buffer(8).unwrap();
+        assert_eq!(allocator.total_rx_buffer_size_words(), 18);
+    }
+
+    #[test]
+    fn compaction_reclaims_freed_buffers() {
+        let mut allocator = EndpointMemoryAllocator::new(memory(64), FIFO_DEPTH_WORDS, ENDPOINT_COUNT);
+
+        let mut first = allocator.allocate_rx_buffer(64).unwrap();
+        let second = allocator.allocate_rx_buffer(64).unwrap();
+        let mut third = allocator.allocate_rx_buffer(32).unwrap();
+        third.buffer[0].set(0x1234_5678);
+
+        allocator.free_rx_buffer(&second);
+        assert_eq!(allocator.total_rx_buffer_size_words(), 40);
+
+        allocator.begin_compaction();
+        allocator.relocate_rx_buffer(&mut first);
+        allocator.relocate_rx_buffer(&mut third);
+        assert_eq!(allocator.total_rx_buffer_size_words(), 24);
+        assert_eq!(third.buffer[0].get(), 0x1234_5678);
+        assert_eq!(third.as_ptr(), unsafe { first.as_ptr().add(16) });
+    }
+
+    #[test]
+    fn last_back_to_back_setup_packet_is_kept() {
+        let mut allocator = EndpointMemoryAllocator::new(memory(16), FIFO_DEPTH_WORDS, ENDPOINT_COUNT);
+        let mut buffer = allocator.allocate_rx_buffer(64).unwrap();
+
+        buffer.write_packet(&[0, 0, 0, 0, 0, 0, 0, 0, 0x80, 0x06, 0, 1, 0, 0, 0x40, 0]).unwrap();
+        buffer.complete_dma_setup(2).unwrap();
+        assert_eq!(buffer.setup_packet(), Some([0x80, 0x06, 0, 1, 0, 0, 0x40, 0]));
+    }
+
+    #[test]
+    fn retried_setup_replaces_pending_one() {
+        let mut allocator = EndpointMemoryAllocator::new(memory(16), FIFO_DEPTH_WORDS, ENDPOINT_COUNT);
+        let mut buffer = allocator.allocate_rx_buffer(64).unwrap();
+
+        buffer.write_packet(&[0x00, 0x05, 1, 0, 0, 0, 0, 0]).unwrap();
+        buffer.complete_dma(8, true).unwrap();
+        buffer.clear();
+        assert_eq!(buffer.setup_packet(), None);
+
+        buffer.write_packet(&[0x80, 0x06, 0, 1, 0, 0, 0x40, 0]).unwrap();
+        buffer.complete_dma(8, true).unwrap();
+        assert_eq!(buffer.setup_packet(), Some([0x80, 0x06, 0, 1, 0, 0, 0x40, 0]));
+    }
+
+    #[test]
+    fn full_packets_are_read_together() {
+        let mut allocator = EndpointMemoryAllocator::new(memory(16), FIFO_DEPTH_WORDS, ENDPOINT_COUNT);
+        let mut buffer = allocator.allocate_rx_buffer(64).unwrap();
+        buffer.enable_multi_packet(8);
+
+        let fifo = |first: u8| move |words: &[VolatileCell<u32>]| {
+            for (i, word) in words.iter().enumerate() {
+                word.set(u32::from_le_bytes([first + i as u8; 4]));
+            }
+        };
+        buffer.receive(8, false, fifo(0)).unwrap();
+        buffer.receive(8, false, fifo(2)).unwrap();
+        buffer.receive(4, false, fifo(4)).unwrap();
+        assert!(!buffer.can_accept(8, false));
+
+        let mut buf = [0; 12];
+        assert_eq!(buffer.read_packet(&mut buf).unwrap(), 8);
+        assert_eq!(buf[..8], [0, 0, 0, 0, 1, 1, 1, 1]);
+        assert_eq!(buffer.read_packet(&mut buf).unwrap(), 12);
+        assert_eq!(buf, [2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4]);
+        assert!(buffer.state() == EndpointBufferState::Empty);
+    }
+
+    #[test]
+    fn full_buffer_has_no_room_until_drained() {
+        let mut allocator = EndpointMemoryAllocator::new(memory(16), FIFO_DEPTH_WORDS, ENDPOINT_COUNT);
+        let mut buffer = allocator.allocate_rx_buffer_with_size(8, 16).unwrap();
+        buffer.enable_multi_packet(8);
+
+        buffer.receive(8, false, |_| {}).unwrap();
+        assert!(buffer.can_accept(8, false));
+        buffer.receive(8, false, |_| {}).unwrap();
+        assert!(!buffer.can_accept(8, false));
+
+        // Packets are appended behind the unread data, a partial read doesn't make room
+        let mut buf = [0; 8];
+        assert_eq!(buffer.read_packet(&mut buf).unwrap(), 8);
+        assert!(!buffer.can_accept(8, false));
+        assert_eq!(buffer.read_packet(&mut buf).unwrap(), 8);
+        assert!(buffer.can_accept(8, false));
+    }
+
+    #[test]
+    fn tx_fifo_for_high_speed_bulk() {
+        let mut allocator = EndpointMemoryAllocator::new(memory(0), FIFO_DEPTH_WORDS, ENDPOINT_COUNT);
+
+        allocator.allocate_tx_buffer(0, 64).unwrap();
+        allocator.allocate_tx_buffer(1, 512).unwrap();
+        assert_eq!(allocator.tx_fifo_size_words(0), 16);
+        assert_eq!(allocator.tx_fifo_size_words(1), 128);
+    }
+}
@@ -0,0 +1,98 @@
+use core::ops::BitOr;
+use crate::ral::otg_global::GINTSTS;
+
+/// Snapshot of the pending core interrupts (GINTSTS) and endpoint interrupts (DAINT), returned by
+/// [`UsbBus::read_events`](crate::UsbBus::read_events).
+///
+/// The flags are associated constants, test them with [`contains`](Events::contains).
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct Events {
+    bits: u32,
+    ep_in: u16,
+    ep_out: u16,
+}
+
+impl Events {
+    /// USB reset detected (USBRST).
+    pub const RESET: Events = Events::flag(GINTSTS::USBRST::mask);
+    /// Speed enumeration finished after a reset (ENUMDNE).
+    pub const ENUM_DONE: Events = Events::flag(GINTSTS::ENUMDNE::mask);
+    /// The bus has been idle for 3 ms (USBSUSP).
+    pub const SUSPEND: Events = Events::flag(GINTSTS::USBSUSP::mask);
+    /// The bus has been idle for 3 ms, or an erratic error occurred (ESUSP).
+    pub const EARLY_SUSPEND: Events = Events::flag(GINTSTS::ESUSP::mask);
+    /// Resume or remote wakeup signaling detected (WKUPINT).
+    pub const RESUME: Events = Events::flag(GINTSTS::WKUPINT::mask);
+    /// Start of (micro)frame (SOF).
+    pub const SOF: Events = Events::flag(GINTSTS::SOF::mask);
+    /// End of periodic frame (EOPF).
+    pub const END_OF_PERIODIC_FRAME: Events = Events::flag(GINTSTS::EOPF::mask);
+    /// OTG event, see [`UsbBus::next_otg_event`](crate::UsbBus::next_otg_event) (OTGINT).
+    pub const OTG: Events = Events::flag(GINTSTS::OTGINT::mask);
+    /// Session request, VBUS appeared (SRQINT).
+    pub const SESSION_REQUEST: Events = Events::flag(GINTSTS::SRQINT::mask);
+    /// The RX FIFO holds at least one packet (RXFLVL).
+    pub const RX_FIFO_NON_EMPTY: Events = Events::flag(GINTSTS::RXFLVL::mask);
+    /// An isochronous OUT packet has been dropped because the RX FIFO was full (ISOODRP).
+    pub const ISO_OUT_OVERFLOW: Events = Events::flag(GINTSTS::ISOODRP::mask);
+    /// An isochronous IN transfer didn't complete in the frame (IISOIXFR).
+    pub const INCOMPLETE_ISO_IN: Events = Events::flag(GINTSTS::IISOIXFR::mask);
+    /// An IN endpoint interrupt is pending, see [`ep_in`](Events::ep_in) (IEPINT).
+    pub const IN_ENDPOINT: Events = Events::flag(GINTSTS::IEPINT::mask);
+    /// An OUT endpoint interrupt is pending, see [`ep_out`](Events::ep_out) (OEPINT).
+    pub const OUT_ENDPOINT: Events = Events::flag(GINTSTS::OEPINT::mask);
+
+    const ALL_BITS: u32 = Self::RESET.bits | Self::ENUM_DONE.bits | Self::SUSPEND.bits
+        | Self::EARLY_SUSPEND.bits | Self::RESUME.bits | Self::SOF.bits
+        | Self::END_OF_PERIODIC_FRAME.bits | Self::OTG.bits | Self::SESSION_REQUEST.bits
+        | Self::RX_FIFO_NON_EMPTY.bits | Self::ISO_OUT_OVERFLOW.bits | Self::INCOMPLETE_ISO_IN.bits
+        | Self::IN_ENDPOINT.bits | Self::OUT_ENDPOINT.bits;
+
+    const fn flag(bits: u32) -> Events {
+        Events { bits, ep_in: 0, ep_out: 0 }
+    }
+
+    pub(crate) fn from_bits(gintsts: u32, daint: u32) -> Self {
+        Self {
+            bits: gintsts & Self::ALL_BITS,
+            ep_in: daint as u16,
+            ep_out: (daint >> 16) as u16,
+        }
+    }
+
+    pub(crate) fn bits(self) -> u32 {
+        self.bits
+    }
+
+    /// Returns true if all the flags of `other` are set.
+    pub fn contains(self, other: Events) -> bool {
+        self.bits & other.bits == other.bits
+    }
+
+    /// Returns true if no flag is set.
+    pub fn is_empty(self) -> bool {
+        self.bits == 0
+    }
+
+    /// Returns the IN endpoints with a pending interrupt, bit N for endpoint N.
+    pub fn ep_in(self) -> u16 {
+        self.ep_in
+    }
+
+    /// Returns the OUT endpoints with a pending interrupt, bit N for endpoint N.
+    pub fn ep_out(self) -> u16 {
+        self.ep_out
+    }
+}
+
+impl BitOr for Events {
+    type Output = Events;
+
+    fn bitor(self, other: Events) -> Events {
+        Events {
+            bits: self.bits | other.bits,
+            ep_in: self.ep_in | other.ep_in,
+            ep_out: self.ep_out | other.ep_out,
+        }
+    }
+}
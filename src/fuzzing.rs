@@ -0,0 +1,70 @@
+//! Entry points for the fuzz targets in `fuzz/`. Not a part of the public API.
+
+use core::mem::MaybeUninit;
+use usb_device::endpoint::{EndpointAddress, EndpointType};
+use usb_device::UsbDirection;
+use crate::bus::{EndpointAllocator, FifoLayout};
+use crate::config::Config;
+use crate::Error;
+use crate::ral::otg_device::ENDPOINT_COUNT;
+use crate::target::interrupt::CriticalSection;
+
+/// Maximum number of endpoints per direction the driver supports.
+pub const MAX_ENDPOINTS: usize = ENDPOINT_COUNT;
+
+/// The endpoint allocator of `UsbBus`, without the peripheral.
+pub struct Allocator {
+    inner: EndpointAllocator,
+}
+
+impl Allocator {
+    /// Creates an allocator for a peripheral with `fifo_depth_words` of FIFO RAM and
+    /// `endpoint_count` endpoints per direction.
+    pub fn new(
+        memory: &'static mut [MaybeUninit<u32>],
+        config: &Config,
+        high_speed: bool,
+        fifo_depth_words: usize,
+        endpoint_count: usize,
+    ) -> Self {
+        Self {
+            inner: EndpointAllocator::new(memory, config, high_speed, cfg!(feature = "hs") && config.dma, 0, fifo_depth_words, endpoint_count),
+        }
+    }
+
+    pub fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        ep_addr: Option<EndpointAddress>,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval: u8,
+    ) -> Result<EndpointAddress, Error> {
+        self.inner.alloc_ep(ep_dir, ep_addr, ep_type, max_packet_size, interval)
+    }
+
+    pub fn free_ep(&mut self, ep_addr: EndpointAddress) -> Result<(), Error> {
+        self.inner.free_ep(ep_addr, &Self::cs())
+    }
+
+    /// Packs the endpoint buffers together like `configure_all()` does.
+    pub fn compact_memory(&mut self) {
+        self.inner.compact_memory(&Self::cs())
+    }
+
+    /// Returns the FIFO layout `configure_all()` would program.
+    pub fn fifo_layout(&self) -> FifoLayout {
+        self.inner.fifo_layout()
+    }
+
+    /// Returns the address and the size in bytes of the endpoint memory used by `ep_addr`: the
+    /// buffer of an OUT endpoint or the DMA buffer of an IN endpoint.
+    pub fn buffer_memory(&self, ep_addr: EndpointAddress) -> Option<(usize, usize)> {
+        self.inner.buffer_memory(ep_addr, &Self::cs())
+    }
+
+    fn cs() -> CriticalSection {
+        // The allocator isn't shared with an interrupt handler here
+        unsafe { CriticalSection::new() }
+    }
+}
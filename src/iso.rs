@@ -0,0 +1,251 @@
+use usb_device::bus::UsbBus;
+use usb_device::endpoint::EndpointIn;
+use usb_device::{Result, UsbError};
+
+/// Payloads waiting to be sent by an [`IsoInScheduler`], one per (micro)frame.
+pub trait PayloadQueue {
+    /// Returns the next payload without removing it.
+    fn front(&self) -> Option<&[u8]>;
+
+    /// Removes the payload returned by `front`, once the endpoint has taken it.
+    fn pop_front(&mut self);
+}
+
+/// Feeds an isochronous IN endpoint with one payload per (micro)frame, e.g. the frames of a UVC
+/// video stream.
+///
+/// The driver selects the even or odd (micro)frame for every write, so a payload always goes
+/// out in the next (micro)frame the host polls the endpoint in, as given by its interval. A
+/// payload that misses its frame is dropped by the driver, see
+/// [`UsbBus::take_missed_iso_in`](crate::UsbBus::take_missed_iso_in), and the scheduler
+/// continues with the next one instead of falling behind.
+///
+/// Call [`poll`](Self::poll) from `UsbClass::endpoint_in_complete` for the endpoint, and after
+/// every `UsbDevice::poll` to start the stream. When the queue runs dry the scheduler sends a
+/// zero-length packet, which keeps the completions coming without sending stale data.
+pub struct IsoInScheduler {
+    sent: u32,
+    underruns: u32,
+}
+
+impl IsoInScheduler {
+    /// Creates a scheduler that hasn't sent anything yet.
+    pub const fn new() -> Self {
+        Self { sent: 0, underruns: 0 }
+    }
+
+    /// Returns the number of payloads handed to the endpoint.
+    pub fn sent(&self) -> u32 {
+        self.sent
+    }
+
+    /// Returns the number of (micro)frames the queue had no payload for.
+    pub fn underruns(&self) -> u32 {
+        self.underruns
+    }
+
+    /// Arms the endpoint with the next payload of `queue`, if the endpoint is done with the
+    /// previous one.
+    ///
+    /// Returns true if a payload has been armed and false if the endpoint is still busy or the
+    /// queue was empty. Payloads larger than the endpoint can send in a (micro)frame are dropped
+    /// with `UsbError::BufferOverflow`.
+    pub fn poll<B: UsbBus, Q: PayloadQueue>(&mut self, ep: &EndpointIn<B>, queue: &mut Q) -> Result<bool> {
+        let result = match queue.front() {
+            Some(payload) => ep.write(payload),
+            None => ep.write(&[]),
+        };
+
+        match result {
+            Ok(_) => {}
+            Err(UsbError::WouldBlock) => return Ok(false),
+            Err(UsbError::BufferOverflow) => {
+                queue.pop_front();
+                return Err(UsbError::BufferOverflow);
+            }
+            Err(err) => return Err(err),
+        }
+
+        if queue.front().is_some() {
+            queue.pop_front();
+            self.sent = self.sent.wrapping_add(1);
+            Ok(true)
+        } else {
+            self.underruns = self.underruns.wrapping_add(1);
+            Ok(false)
+        }
+    }
+}
+
+impl Default for IsoInScheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+    use std::vec::Vec;
+    use usb_device::bus::{PollResult, UsbBusAllocator};
+    use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
+    use usb_device::endpoint::{EndpointAddress, EndpointType, In};
+    use usb_device::UsbDirection;
+
+    impl PayloadQueue for VecDeque<Vec<u8>> {
+        fn front(&self) -> Option<&[u8]> {
+            VecDeque::front(self).map(Vec::as_slice)
+        }
+
+        fn pop_front(&mut self) {
+            VecDeque::pop_front(self);
+        }
+    }
+
+    /// Takes one packet of up to 192 bytes per frame, `next_frame` sends it.
+    struct Endpoint {
+        armed: Mutex<Option<usize>>,
+        sent: Mutex<Vec<usize>>,
+    }
+
+    impl Endpoint {
+        fn new() -> Self {
+            Self {
+                armed: Mutex::new(None),
+                sent: Mutex::new(Vec::new()),
+            }
+        }
+
+        fn next_frame(&self) {
+            if let Some(size) = self.armed.lock().unwrap().take() {
+                self.sent.lock().unwrap().push(size);
+            }
+        }
+
+        fn sent(&self) -> Vec<usize> {
+            self.sent.lock().unwrap().clone()
+        }
+    }
+
+    struct Bus<'a>(&'a Endpoint);
+
+    impl UsbBus for Bus<'_> {
+        fn alloc_ep(
+            &mut self,
+            ep_dir: UsbDirection,
+            _ep_addr: Option<EndpointAddress>,
+            _ep_type: EndpointType,
+            _max_packet_size: u16,
+            _interval: u8,
+        ) -> Result<EndpointAddress> {
+            Ok(EndpointAddress::from_parts(1, ep_dir))
+        }
+
+        fn enable(&mut self) {}
+
+        fn reset(&self) {}
+
+        fn set_device_address(&self, _addr: u8) {}
+
+        fn write(&self, _ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
+            let mut armed = self.0.armed.lock().unwrap();
+            if armed.is_some() {
+                return Err(UsbError::WouldBlock);
+            }
+            if buf.len() > 192 {
+                return Err(UsbError::BufferOverflow);
+            }
+            *armed = Some(buf.len());
+            Ok(buf.len())
+        }
+
+        fn read(&self, _ep_addr: EndpointAddress, _buf: &mut [u8]) -> Result<usize> {
+            Err(UsbError::WouldBlock)
+        }
+
+        fn set_stalled(&self, _ep_addr: EndpointAddress, _stalled: bool) {}
+
+        fn is_stalled(&self, _ep_addr: EndpointAddress) -> bool {
+            false
+        }
+
+        fn suspend(&self) {}
+
+        fn resume(&self) {}
+
+        fn poll(&self) -> PollResult {
+            PollResult::None
+        }
+    }
+
+    /// Finishes the bus allocation, endpoints can't be used before that.
+    fn freeze(alloc: &UsbBusAllocator<Bus>) {
+        let _ = UsbDeviceBuilder::new(alloc, UsbVidPid(0, 0)).build();
+    }
+
+    fn queue(sizes: &[usize]) -> VecDeque<Vec<u8>> {
+        sizes.iter().map(|&size| std::vec![0; size]).collect()
+    }
+
+    #[test]
+    fn one_payload_per_frame() {
+        let endpoint = Endpoint::new();
+        let alloc = UsbBusAllocator::new(Bus(&endpoint));
+        let ep = alloc.alloc::<In>(None, EndpointType::Isochronous, 192, 1).unwrap();
+        freeze(&alloc);
+
+        let mut queue = queue(&[100, 192, 20]);
+        let mut scheduler = IsoInScheduler::new();
+        assert!(scheduler.poll(&ep, &mut queue).unwrap());
+        // Nothing more until the frame has been sent
+        assert!(!scheduler.poll(&ep, &mut queue).unwrap());
+
+        for _ in 0..2 {
+            endpoint.next_frame();
+            assert!(scheduler.poll(&ep, &mut queue).unwrap());
+        }
+        endpoint.next_frame();
+        assert_eq!(endpoint.sent(), [100, 192, 20]);
+        assert_eq!(scheduler.sent(), 3);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn underruns_send_empty_packets() {
+        let endpoint = Endpoint::new();
+        let alloc = UsbBusAllocator::new(Bus(&endpoint));
+        let ep = alloc.alloc::<In>(None, EndpointType::Isochronous, 192, 1).unwrap();
+        freeze(&alloc);
+
+        let mut queue = queue(&[]);
+        let mut scheduler = IsoInScheduler::new();
+        assert!(!scheduler.poll(&ep, &mut queue).unwrap());
+        assert!(!scheduler.poll(&ep, &mut queue).unwrap());
+        endpoint.next_frame();
+
+        queue.push_back(std::vec![0; 50]);
+        assert!(scheduler.poll(&ep, &mut queue).unwrap());
+        endpoint.next_frame();
+        assert_eq!(endpoint.sent(), [0, 50]);
+        assert_eq!(scheduler.underruns(), 1);
+    }
+
+    #[test]
+    fn oversized_payloads_are_dropped() {
+        let endpoint = Endpoint::new();
+        let alloc = UsbBusAllocator::new(Bus(&endpoint));
+        let ep = alloc.alloc::<In>(None, EndpointType::Isochronous, 192, 1).unwrap();
+        freeze(&alloc);
+
+        let mut queue = queue(&[200, 10]);
+        let mut scheduler = IsoInScheduler::new();
+        assert!(matches!(scheduler.poll(&ep, &mut queue), Err(UsbError::BufferOverflow)));
+        assert!(scheduler.poll(&ep, &mut queue).unwrap());
+        endpoint.next_frame();
+        assert_eq!(endpoint.sent(), [10]);
+    }
+}
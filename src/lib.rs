@@ -1,26 +1,309 @@
 //! USB peripheral driver for Synopsys USB OTG peripherals.
+//!
+//! The driver doesn't panic in release builds: failures are reported as [`Error`]s or, through
+//! the `usb-device` API, as `UsbError`s, and out-of-range configuration values are ignored or
+//! clamped. The remaining assertions are `debug_assert!`s that check internal invariants.
+//!
+//! # High-speed bulk throughput
+//!
+//! With an external ULPI PHY, bulk transfers get close to the bus capacity when the CPU doesn't
+//! have to move every packet through the FIFO registers:
+//!
+//! * use 512-byte packets on the bulk endpoints,
+//! * give the bulk IN endpoints a TX FIFO of several packets with
+//!   [`Config::tx_fifo_size`], so that a single `write` queues back-to-back packets,
+//! * enable the core's DMA with [`Config::dma`] and tune [`Config::ahb_burst_length`]
+//!   (`BurstLength::Incr4` is a good start).
+//!
+//! `examples/hs_dma_throughput.rs` puts this together for an STM32F429 with a ULPI PHY and
+//! serves as the high-speed benchmark.
+//!
+//! # Layers
+//!
+//! [`UsbBus`] implements the `usb-device` API on top of [`dwc_otg::Core`], which drives the
+//! endpoint controls, the FIFOs and the interrupt status of the core without `usb-device` types.
+//! Other USB stacks can use the core layer alone: build without default features to drop the
+//! `usb-device` dependency together with `UsbBus`.
+//!
+//! # Code size
+//!
+//! The `iso` and `quirks` features are enabled by default. Devices without isochronous
+//! endpoints can disable `iso`, and devices that set [`Config::out_rearm_point`] can disable
+//! `quirks`, the table of core revisions that otherwise picks it; see the README for the savings.
+//!
+//! # Several peripherals
+//!
+//! Every `UsbBus` keeps its state and accesses its registers through
+//! [`UsbPeripheral::REGISTERS`], so e.g. OTG_FS and OTG_HS can run as two independent devices in
+//! one firmware. Enable both the `fs` and `hs` features in that case: the register layout of
+//! the high-speed core is a superset of the full-speed one, and the high-speed features are
+//! used only on peripherals with [`UsbPeripheral::HIGH_SPEED`] set.
+//!
+//! # OTG_HS with the embedded full-speed PHY
+//!
+//! High-speed peripherals can also run at full speed on the embedded FS transceiver, which
+//! gives a full-speed device the larger FIFO and the extra endpoints of the HS core. Build with
+//! the `hs` feature and select `PhyType::InternalFullSpeed`, either as
+//! [`UsbPeripheral::PHY_TYPE`] or at runtime with [`Config::phy`]. The core's DMA stays
+//! available in this configuration.
+//!
+//! On STM32F4 parts the ULPI clock (`OTGHSULPILPEN`) must be disabled in sleep mode, otherwise
+//! the core stops working while the CPU sleeps.
 
 #![no_std]
 
-#[cfg(all(feature = "fs", feature = "hs"))]
-compile_error!("choose only one USB mode");
+#[cfg(test)]
+extern crate std;
 
 #[cfg(not(any(feature = "fs", feature ="hs")))]
 compile_error!("select USB mode feature (fs/hs)");
 
+#[cfg(feature = "usb-device")]
 mod endpoint;
+#[cfg(feature = "usb-device")]
 mod endpoint_memory;
 
 mod target;
 
+/// Hardware layer: endpoint controls, FIFOs and interrupt status of the core.
+pub mod dwc_otg;
+
 /// USB peripheral driver.
+#[cfg(feature = "usb-device")]
 pub mod bus;
 
+/// Bus configuration.
+#[cfg(feature = "usb-device")]
+pub mod config;
+
+/// OTG status and events.
+pub mod otg;
+
+/// Interrupt events.
+pub mod events;
+
+/// Streaming reads from OUT endpoints.
+#[cfg(feature = "usb-device")]
+pub mod reader;
+
+/// Isochronous streaming.
+#[cfg(all(feature = "usb-device", feature = "iso"))]
+pub mod iso;
+
+/// Streaming writes to IN endpoints.
+#[cfg(feature = "usb-device")]
+pub mod writer;
+
+/// Driver event log.
+#[cfg(feature = "trace")]
+pub mod trace;
+
+#[cfg(feature = "usb-device")]
 pub use crate::bus::UsbBus;
+#[cfg(feature = "usb-device")]
+pub use crate::config::Config;
+#[cfg(feature = "usb-device")]
+pub use crate::endpoint::READ_QUEUE_LEN;
+#[cfg(feature = "usb-device")]
+pub use crate::endpoint_memory::EndpointMemory;
+#[cfg(all(feature = "usb-device", feature = "iso"))]
+pub use crate::iso::IsoInScheduler;
+#[cfg(feature = "usb-device")]
+pub use crate::reader::EndpointReader;
+#[cfg(feature = "usb-device")]
+pub use crate::writer::EndpointWriter;
 
 mod ral;
+#[cfg(feature = "usb-device")]
 mod transition;
 
+#[cfg(all(feature = "fuzzing", feature = "usb-device"))]
+#[doc(hidden)]
+pub mod fuzzing;
+
+/// USB PHY used by the peripheral.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum PhyType {
+    /// Embedded full-speed PHY.
+    InternalFullSpeed,
+    /// External high-speed PHY connected through the ULPI interface. Available only on High Speed
+    /// variants of the peripheral.
+    ExternalHighSpeed,
+    /// Embedded high-speed PHY connected through the UTMI interface, as found on the STM32F7x3
+    /// parts. The PHY controller must be clocked and configured in [`UsbPeripheral::enable`].
+    /// Available only on High Speed variants of the peripheral.
+    InternalHighSpeed,
+}
+
+/// Errors reported by the driver.
+///
+/// The methods of [`UsbBus`] outside of the `usb-device` API return them directly. The
+/// `usb-device` API only knows `UsbError`, which they convert into; the cause of a failed
+/// endpoint allocation is kept by [`UsbBus::alloc_error`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The core didn't become ready when enabled, the PHY clock is most likely missing. External
+    /// ULPI PHYs provide this 60 MHz clock, check their power supply, reset and crystal.
+    PhyClockMissing,
+    /// The configuration asks for a feature of high-speed cores (a high-speed PHY, DMA,
+    /// transmission thresholding or the AHB burst length) on a full-speed core.
+    CoreUnsupported,
+    /// The configuration is inconsistent, e.g. ULPI options without a ULPI PHY or a transmission
+    /// threshold without DMA or larger than the field allows.
+    InvalidConfig,
+    /// The FIFO RAM of the core, [`UsbPeripheral::FIFO_DEPTH_WORDS`], can't hold the FIFO of the
+    /// endpoint next to those allocated already.
+    FifoOverflow,
+    /// The endpoint memory passed to [`UsbBus::new`] is too small for the endpoint buffers.
+    EndpointMemoryOverflow,
+    /// The endpoint number is already allocated or beyond [`UsbPeripheral::ENDPOINT_COUNT`].
+    EndpointUnavailable,
+    /// All endpoint numbers of the direction are allocated.
+    EndpointsExhausted,
+    /// The endpoint hasn't been allocated, or has been freed.
+    EndpointNotAllocated,
+    /// The max packet size isn't valid for the transfer type at the speeds the core supports, or
+    /// asks for several transactions per microframe on an OUT endpoint, which the driver doesn't
+    /// support.
+    InvalidMaxPacketSize,
+    /// The polling interval of a periodic endpoint is out of range.
+    InvalidInterval,
+    /// The operation requires a suspended bus.
+    NotSuspended,
+    /// Remote wakeup isn't advertised by the configuration descriptor or hasn't been enabled by
+    /// the host.
+    RemoteWakeupDisabled,
+    /// The host hasn't enabled the host negotiation protocol.
+    HostNegotiationDisabled,
+    /// The endpoint buffer still holds a packet that hasn't been read.
+    BufferNotEmpty,
+    /// Isochronous endpoints need the `iso` feature.
+    IsochronousDisabled,
+    /// All the read buffers the endpoint can queue are in use.
+    ReadQueueFull,
+    /// In DMA mode, the endpoint memory lies outside the [`UsbPeripheral::DMA_REGIONS`] the
+    /// core can access.
+    DmaMemoryUnreachable,
+    /// Another endpoint has data in flight through the FIFOs, which can't be re-partitioned
+    /// under it.
+    TransferPending,
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Error::PhyClockMissing => "the core didn't become ready, the PHY clock is missing",
+            Error::CoreUnsupported => "the configuration requires a high-speed core",
+            Error::InvalidConfig => "the configuration is inconsistent",
+            Error::FifoOverflow => "the endpoint FIFOs don't fit into the FIFO RAM",
+            Error::EndpointMemoryOverflow => "the endpoint buffers don't fit into the endpoint memory",
+            Error::EndpointUnavailable => "the endpoint number is taken or out of range",
+            Error::EndpointsExhausted => "all endpoint numbers are taken",
+            Error::EndpointNotAllocated => "the endpoint isn't allocated",
+            Error::InvalidMaxPacketSize => "the max packet size is invalid for the transfer type",
+            Error::InvalidInterval => "the polling interval is out of range",
+            Error::NotSuspended => "the bus isn't suspended",
+            Error::RemoteWakeupDisabled => "remote wakeup isn't enabled",
+            Error::HostNegotiationDisabled => "host negotiation isn't enabled",
+            Error::BufferNotEmpty => "the endpoint buffer holds an unread packet",
+            Error::IsochronousDisabled => "isochronous endpoints are disabled",
+            Error::ReadQueueFull => "the read queue of the endpoint is full",
+            Error::DmaMemoryUnreachable => "the endpoint memory is out of reach of the DMA",
+            Error::TransferPending => "another endpoint has a transfer in progress",
+        })
+    }
+}
+
+#[cfg(feature = "usb-device")]
+impl From<Error> for usb_device::UsbError {
+    fn from(error: Error) -> Self {
+        use usb_device::UsbError;
+
+        match error {
+            Error::PhyClockMissing
+            | Error::NotSuspended
+            | Error::RemoteWakeupDisabled
+            | Error::HostNegotiationDisabled
+            | Error::BufferNotEmpty
+            | Error::TransferPending => UsbError::InvalidState,
+            Error::CoreUnsupported
+            | Error::InvalidConfig
+            | Error::InvalidMaxPacketSize
+            | Error::InvalidInterval
+            | Error::IsochronousDisabled
+            | Error::DmaMemoryUnreachable => UsbError::Unsupported,
+            Error::FifoOverflow | Error::EndpointMemoryOverflow => UsbError::EndpointMemoryOverflow,
+            Error::EndpointUnavailable | Error::EndpointNotAllocated => UsbError::InvalidEndpoint,
+            Error::EndpointsExhausted => UsbError::EndpointOverflow,
+            Error::ReadQueueFull => UsbError::WouldBlock,
+        }
+    }
+}
+
+/// Bus speed negotiated during the bus reset.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Speed {
+    /// Full speed, 12 Mbit/s.
+    Full,
+    /// High speed, 480 Mbit/s.
+    High,
+}
+
+/// Enumeration progress of the device since the last bus reset, see
+/// [`UsbBus::enumeration_state`](crate::UsbBus::enumeration_state).
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum EnumerationState {
+    /// No bus reset since the device was enabled or the session ended, e.g. on a charger.
+    Idle,
+    /// The host has reset the bus, but not assigned an address yet.
+    Reset,
+    /// The host has assigned an address, but not selected a configuration yet.
+    Addressed,
+    /// The host has selected a configuration.
+    Configured,
+    /// The host hasn't configured the device within the enumeration timeout after the bus reset.
+    TimedOut,
+}
+
+/// Frame and microframe of the last SOF received from the host.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Frame {
+    /// Frame number, 0 to 2047.
+    pub number: u16,
+    /// Microframe within the frame, 0 to 7, on a high-speed bus. Full-speed buses have no
+    /// microframes.
+    pub microframe: Option<u8>,
+}
+
+impl Frame {
+    /// Decodes DSTS.FNSOF, which counts microframes on a high-speed bus.
+    pub(crate) fn from_fnsof(fnsof: u16, high_speed: bool) -> Self {
+        if high_speed {
+            Self {
+                number: (fnsof >> 3) & 0x7ff,
+                microframe: Some((fnsof & 0x7) as u8),
+            }
+        } else {
+            Self {
+                number: fnsof & 0x7ff,
+                microframe: None,
+            }
+        }
+    }
+
+    /// Returns the time of the frame in 125 µs units, 14 bits that wrap around every 2048
+    /// frames. At full speed it's the start of the frame.
+    ///
+    /// The difference between two values, masked to 14 bits, is the elapsed time at microframe
+    /// resolution, e.g. for the feedback value of an asynchronous audio endpoint.
+    pub fn microframes(self) -> u16 {
+        (self.number << 3) | self.microframe.unwrap_or(0) as u16
+    }
+}
+
 /// A trait for device-specific USB peripherals. Implement this to add support for a new hardware
 /// platform. Peripherals that have this trait must have the same register block as STM32 USB OTG
 /// peripherals.
@@ -31,9 +314,141 @@ pub unsafe trait UsbPeripheral: Send + Sync {
     /// true for High Speed variants of the peripheral, false for Full Speed
     const HIGH_SPEED: bool;
 
-    /// FIFO size in 32-bit words
+    /// FIFO size in 32-bit words, e.g. 320 for OTG_FS and 1024 or 4096 for OTG_HS depending on
+    /// the family. The endpoint allocator never hands out more.
     const FIFO_DEPTH_WORDS: usize;
 
+    /// Number of endpoints per direction, including EP0.
+    ///
+    /// Defaults to 4 for full-speed and 6 for high-speed peripherals. Counts above 6 (without the
+    /// `hs` feature) or 9 (with it) are clamped.
+    const ENDPOINT_COUNT: usize = if Self::HIGH_SPEED { 6 } else { 4 };
+
+    /// PHY used by the peripheral, unless another one is selected with
+    /// [`Config::phy`](crate::config::Config::phy). High-speed operation requires
+    /// `PhyType::ExternalHighSpeed` or `PhyType::InternalHighSpeed`.
+    const PHY_TYPE: PhyType = PhyType::InternalFullSpeed;
+
+    /// true for device-only cores without the OTG features, as some licensed cores and vendor
+    /// integrations are. Their GUSBCFG has no FDMOD and SRPCAP bits, so the driver skips forcing
+    /// device mode and the mode switch delay, and it leaves the OTG, session request and ID pin
+    /// interrupts masked. VBUS can only be sensed externally then, see
+    /// [`vbus_present`](Self::vbus_present).
+    ///
+    /// false by default.
+    const DEVICE_ONLY: bool = false;
+
+    /// Address ranges of the RAM the core's DMA can access, e.g. the AXI SRAM but not the DTCM of
+    /// STM32H7 parts. In DMA mode, endpoints can't be allocated in endpoint memory outside of
+    /// them, see [`Error::DmaMemoryUnreachable`].
+    ///
+    /// Empty by default: every address is assumed to be reachable.
+    const DMA_REGIONS: &'static [core::ops::Range<usize>] = &[];
+
     /// Enables USB device on its peripheral bus
     fn enable();
+
+    /// Returns whether VBUS is present, for boards that sense it through a GPIO or ADC instead of
+    /// the dedicated VBUS pin.
+    ///
+    /// Consulted by every `poll()` when [`Config::vbus_sensing`] is disabled: the session starts
+    /// and ends with the returned value, like with the internal sensing. The default
+    /// implementation returns `None`, the session is then assumed to be always valid.
+    fn vbus_present() -> Option<bool> {
+        None
+    }
+
+    /// Switches the external VBUS power switch (charge pump enable) of an A-device on or off.
+    ///
+    /// The driver only operates in device mode, where the board must never drive VBUS, so it
+    /// switches VBUS off in `enable()`. Host and OTG role support will use this hook for port
+    /// power and overcurrent handling. The default implementation does nothing.
+    fn set_vbus_drive(_enabled: bool) {}
+
+    /// Blocks for at least `us` microseconds.
+    ///
+    /// Used for the timings required by the USB specification and the core: soft disconnect,
+    /// mode switching and remote wakeup signaling. The default implementation spins on peripheral
+    /// register reads and is only calibrated for core clocks up to about 200 MHz; override it with
+    /// a timer-based delay where accurate timing matters.
+    fn delay_us(us: u32) where Self: Sized {
+        crate::target::spin_delay::<Self>(us);
+    }
+
+    /// Prepares the MCU for its STOP mode while the bus is suspended, e.g. sets
+    /// SCB.SCR.SLEEPDEEP, so that the next `wfi` or `wfe` of the application stops the clocks.
+    ///
+    /// Called with [`Config::suspend_stop_mode`](crate::Config::suspend_stop_mode) enabled, once
+    /// the core is powered down. The default implementation does nothing.
+    fn prepare_stop() {}
+
+    /// Restarts the clocks the peripheral needs after the MCU has left STOP mode, e.g. the HSE
+    /// and the PLL, and undoes [`prepare_stop`](Self::prepare_stop).
+    ///
+    /// Called with [`Config::suspend_stop_mode`](crate::Config::suspend_stop_mode) enabled, on
+    /// resume, bus reset or remote wakeup, before the core is used again. It may run in the
+    /// interrupt handler, so it must not wait for other interrupts. The default implementation
+    /// does nothing.
+    fn restore_clocks() {}
+
+    /// Returns the current time of a monotonic clock, in units of the application's choice, e.g.
+    /// the counter of a free-running timer or the DWT cycle counter.
+    ///
+    /// Called by the interrupt handler for every OUT packet it receives, the result is reported
+    /// by [`UsbBus::packet_timestamp`](crate::UsbBus::packet_timestamp). It must be fast. The
+    /// default implementation returns `None` and compiles to nothing.
+    fn timestamp() -> Option<u32> {
+        None
+    }
+
+    /// Returns a free-running microsecond counter that wraps around at `u32::MAX`, e.g. a 1 MHz
+    /// timer.
+    ///
+    /// Lets `poll()` finish the waits the interrupt handler starts, the reconnect after an
+    /// erratic error or a brown-out and the VBUS debounce, once their time has passed. The
+    /// default implementation returns `None`: `poll()` then blocks for the rest of such a wait,
+    /// outside of the critical section.
+    ///
+    /// Either way nothing but `poll()` finishes these waits, the core doesn't interrupt when their
+    /// time is up. In interrupt mode `poll()` must be called periodically while
+    /// [`UsbBus::needs_poll`](crate::UsbBus::needs_poll) returns true.
+    fn micros() -> Option<u32> {
+        None
+    }
+
+    /// Writes the data cache lines covering `len` bytes at `address` back to memory, e.g. with
+    /// `SCB::clean_dcache_by_address`, so that the core's DMA reads what the CPU has written.
+    ///
+    /// Called in DMA mode for the endpoint memory an IN packet has just been copied into, before
+    /// the endpoint is enabled, and for an EP0 buffer the driver has modified. The range isn't
+    /// aligned to cache lines, round it outwards. Cortex-M7 parts with the D-cache enabled must
+    /// implement it unless the endpoint memory isn't cacheable; the default implementation does
+    /// nothing.
+    fn clean_dcache(_address: usize, _len: usize) {}
+
+    /// Discards the data cache lines covering `len` bytes at `address`, e.g. with
+    /// `SCB::invalidate_dcache_by_address`, so that the CPU reads what the core's DMA has
+    /// written instead of stale cached data.
+    ///
+    /// Called in DMA mode for the endpoint memory of an OUT endpoint once a packet has arrived,
+    /// before the driver reads it. The range isn't aligned to cache lines, round it outwards;
+    /// the lines it shares with the rest of the endpoint memory are safe to discard. The default
+    /// implementation does nothing.
+    fn invalidate_dcache(_address: usize, _len: usize) {}
+
+    /// Unmasks the OTG interrupt in the interrupt controller, e.g. with `NVIC::unmask` or in the
+    /// PLIC of a RISC-V part, together with the dedicated wakeup interrupt of parts that have one
+    /// (OTG_FS_WKUP and its EXTI line), or masks them when `enabled` is false.
+    ///
+    /// Called by [`UsbBus::enable_interrupts`](crate::UsbBus::enable_interrupts) and
+    /// [`UsbBus::disable_interrupts`](crate::UsbBus::disable_interrupts). The default
+    /// implementation does nothing, the application then sets up the interrupt controller itself.
+    fn set_interrupts_enabled(_enabled: bool) {}
+
+    /// Sets the priority of the OTG interrupt and the wakeup interrupt, in the encoding of the
+    /// interrupt controller, e.g. the NVIC priority byte whose upper bits the part implements.
+    ///
+    /// Called by [`UsbBus::enable_interrupts`](crate::UsbBus::enable_interrupts) before the
+    /// interrupts are unmasked. The default implementation does nothing.
+    fn set_interrupt_priority(_priority: u8) {}
 }
@@ -0,0 +1,139 @@
+#[cfg(feature = "usb-device")]
+use crate::ral::otg_global::{GOTGCTL, GOTGINT};
+
+/// Snapshot of the OTG control and status register (GOTGCTL).
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct OtgStatus {
+    /// A-session is valid (VBUS above the A-device session valid threshold).
+    pub a_session_valid: bool,
+    /// B-session is valid (VBUS above the B-device session valid threshold).
+    pub b_session_valid: bool,
+    /// Connector ID status: `true` for a B-device (ID pin floating), `false` for an A-device.
+    pub b_device: bool,
+    /// Long debounce time is used (the device is connected after a host-initiated SRP).
+    pub long_debounce: bool,
+    /// The last session request was successful.
+    pub session_request_success: bool,
+    /// The last host negotiation was successful.
+    pub host_negotiation_success: bool,
+}
+
+impl OtgStatus {
+    #[cfg(feature = "usb-device")]
+    pub(crate) fn from_bits(bits: u32) -> Self {
+        let is_set = |mask: u32| bits & mask != 0;
+        Self {
+            a_session_valid: is_set(GOTGCTL::ASVLD::mask),
+            b_session_valid: is_set(GOTGCTL::BSVLD::mask),
+            b_device: is_set(GOTGCTL::CIDSTS::mask),
+            long_debounce: is_set(GOTGCTL::DBCT::mask),
+            session_request_success: is_set(GOTGCTL::SRQSCS::mask),
+            host_negotiation_success: is_set(GOTGCTL::HNGSCS::mask),
+        }
+    }
+}
+
+/// Role of the peripheral on the bus.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum OtgRole {
+    /// Peripheral (B-device side, or A-device after HNP).
+    Device,
+    /// Host (A-device side, or B-device after HNP).
+    Host,
+}
+
+/// OTG and VBUS session events reported by [`UsbBus::next_otg_event`](crate::UsbBus::next_otg_event).
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum OtgEvent {
+    /// VBUS appeared, a new session has been detected (SRQINT).
+    SessionStart,
+    /// VBUS went away, the session has ended (GOTGINT.SEDET).
+    SessionEnd,
+    /// The session request initiated by the device succeeded (GOTGINT.SRSSCHG, GOTGCTL.SRQSCS).
+    SessionRequestSuccess,
+    /// The session request initiated by the device failed (GOTGINT.SRSSCHG, GOTGCTL.SRQSCS).
+    SessionRequestFailure,
+    /// The host negotiation succeeded (GOTGINT.HNSSCHG, GOTGCTL.HNGSCS).
+    HostNegotiationSuccess,
+    /// The host negotiation failed (GOTGINT.HNSSCHG, GOTGCTL.HNGSCS).
+    HostNegotiationFailure,
+    /// The other device requested host negotiation (GOTGINT.HNGDET).
+    HostNegotiationDetected,
+    /// The A-device timed out waiting for the B-device to connect (GOTGINT.ADTOCHG).
+    ADeviceTimeout,
+    /// The connection debounce is complete after a session start (GOTGINT.DBCDNE).
+    DebounceDone,
+}
+
+impl OtgEvent {
+    #[cfg(feature = "usb-device")]
+    const ALL: [OtgEvent; 9] = [
+        OtgEvent::SessionStart,
+        OtgEvent::SessionEnd,
+        OtgEvent::SessionRequestSuccess,
+        OtgEvent::SessionRequestFailure,
+        OtgEvent::HostNegotiationSuccess,
+        OtgEvent::HostNegotiationFailure,
+        OtgEvent::HostNegotiationDetected,
+        OtgEvent::ADeviceTimeout,
+        OtgEvent::DebounceDone,
+    ];
+
+    #[cfg(feature = "usb-device")]
+    pub(crate) fn mask(self) -> u16 {
+        1 << self as u16
+    }
+
+    #[cfg(feature = "usb-device")]
+    /// Decodes the GOTGINT flags into an event mask, `status` is the GOTGCTL value.
+    ///
+    /// Session end is left out, the bus handles it together with the connection state.
+    pub(crate) fn from_interrupt_flags(flags: u32, status: u32) -> u16 {
+        let mut events = 0;
+        let mut push = |flag: u32, event: OtgEvent| {
+            if flags & flag != 0 {
+                events |= event.mask();
+            }
+        };
+
+        if status & GOTGCTL::SRQSCS::mask != 0 {
+            push(GOTGINT::SRSSCHG::mask, OtgEvent::SessionRequestSuccess);
+        } else {
+            push(GOTGINT::SRSSCHG::mask, OtgEvent::SessionRequestFailure);
+        }
+        if status & GOTGCTL::HNGSCS::mask != 0 {
+            push(GOTGINT::HNSSCHG::mask, OtgEvent::HostNegotiationSuccess);
+        } else {
+            push(GOTGINT::HNSSCHG::mask, OtgEvent::HostNegotiationFailure);
+        }
+        push(GOTGINT::HNGDET::mask, OtgEvent::HostNegotiationDetected);
+        push(GOTGINT::ADTOCHG::mask, OtgEvent::ADeviceTimeout);
+        push(GOTGINT::DBCDNE::mask, OtgEvent::DebounceDone);
+
+        events
+    }
+
+    #[cfg(feature = "usb-device")]
+    /// Removes the first pending event from the `pending` event mask.
+    pub(crate) fn pop(pending: &mut u16) -> Option<OtgEvent> {
+        let event = Self::ALL.iter().copied().find(|event| *pending & event.mask() != 0)?;
+        *pending &= !event.mask();
+        Some(event)
+    }
+}
+
+#[cfg(all(test, feature = "usb-device"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interrupt_flags_are_decoded_by_status() {
+        let flags = GOTGINT::SRSSCHG::mask | GOTGINT::HNSSCHG::mask | GOTGINT::DBCDNE::mask;
+        let mut pending = OtgEvent::from_interrupt_flags(flags, GOTGCTL::SRQSCS::mask);
+
+        assert_eq!(OtgEvent::pop(&mut pending), Some(OtgEvent::SessionRequestSuccess));
+        assert_eq!(OtgEvent::pop(&mut pending), Some(OtgEvent::HostNegotiationFailure));
+        assert_eq!(OtgEvent::pop(&mut pending), Some(OtgEvent::DebounceDone));
+        assert_eq!(OtgEvent::pop(&mut pending), None);
+    }
+}
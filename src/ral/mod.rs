@@ -3,61 +3,99 @@
 pub use stm32ral::{read_reg, write_reg, modify_reg};
 
 pub mod otg_global {
-    #[cfg(feature = "fs")]
+    #[cfg(not(feature = "hs"))]
     pub use stm32ral::otg_fs_global::*;
     #[cfg(feature = "hs")]
     pub use stm32ral::otg_hs_global::*;
-    #[cfg(feature = "fs")]
-    pub use stm32ral::otg_fs_global::OTG_FS_GLOBAL as OTG_GLOBAL;
-    #[cfg(feature = "hs")]
-    pub use stm32ral::otg_hs_global::OTG_HS_GLOBAL as OTG_GLOBAL;
 }
 
 pub mod otg_device {
-    #[cfg(feature = "fs")]
+    #[cfg(not(feature = "hs"))]
     pub use stm32ral::otg_fs_device::*;
     #[cfg(feature = "hs")]
     pub use stm32ral::otg_hs_device::*;
-    #[cfg(feature = "fs")]
-    pub use stm32ral::otg_fs_device::OTG_FS_DEVICE as OTG_DEVICE;
-    #[cfg(feature = "hs")]
-    pub use stm32ral::otg_hs_device::OTG_HS_DEVICE as OTG_DEVICE;
+
+    /// Maximum number of endpoints per direction the driver can manage, including EP0
+    #[cfg(all(feature = "usb-device", not(feature = "hs")))]
+    pub const ENDPOINT_COUNT: usize = 6;
+    #[cfg(all(feature = "usb-device", feature = "hs"))]
+    pub const ENDPOINT_COUNT: usize = 9;
 }
 
 pub mod otg_pwrclk {
     pub use stm32ral::otg_s_pwrclk::*;
-    #[cfg(feature = "fs")]
-    pub use stm32ral::otg_s_pwrclk::OTG_FS_PWRCLK as OTG_PWRCLK;
-    #[cfg(feature = "hs")]
-    pub use stm32ral::otg_s_pwrclk::OTG_HS_PWRCLK as OTG_PWRCLK;
 }
 
 pub mod otg_fifo {
     use stm32ral::RWRegister;
 
-    #[cfg(feature = "fs")]
-    pub const FIFO_DEPTH_WORDS: u32 = 320;
-    #[cfg(feature = "hs")]
-    pub const FIFO_DEPTH_WORDS: u32 = 1024;
+    /// Returns the push/pop register of the FIFO `channel`, `None` beyond the 16 the core
+    /// decodes
+    #[inline(always)]
+    pub fn instance(base_address: usize, channel: usize) -> Option<&'static RWRegister<u32>> {
+        if channel > 15 {
+            return None;
+        }
+        let address = base_address + 0x1000 + channel * 0x1000;
+        Some(unsafe { &*(address as *const RWRegister<u32>) })
+    }
 
+    /// Returns the register the RX FIFO is popped through
     #[inline(always)]
-    pub fn instance(channel: usize) -> &'static RWRegister<u32> {
-        #[cfg(feature = "fs")]
-        let base_address = 0x5000_0000;
-        #[cfg(feature = "hs")]
-        let base_address = 0x4004_0000;
+    pub fn rx(base_address: usize) -> &'static RWRegister<u32> {
+        unsafe { &*((base_address + 0x1000) as *const RWRegister<u32>) }
+    }
+}
 
-        assert!(channel <= 15);
-        let address = base_address + 0x1000 + channel * 0x1000;
-        unsafe { &*(address as *const RWRegister<u32>) }
+#[cfg(feature = "usb-device")]
+pub mod tx_fifo {
+    use stm32ral::RWRegister;
+    use core::marker::PhantomData;
+
+    #[cfg(not(feature = "hs"))]
+    pub use stm32ral::otg_fs_global::DIEPTXF1 as DIEPTXF;
+
+    #[cfg(feature = "hs")]
+    pub use stm32ral::otg_hs_global::DIEPTXF1 as DIEPTXF;
+
+    #[repr(C)]
+    pub struct RegisterBlock {
+        pub DIEPTXF: RWRegister<u32>,
+    }
+
+    pub struct Instance {
+        pub(crate) addr: usize,
+        pub(crate) _marker: PhantomData<*const RegisterBlock>,
+    }
+
+    impl ::core::ops::Deref for Instance {
+        type Target = RegisterBlock;
+        #[inline(always)]
+        fn deref(&self) -> &RegisterBlock {
+            unsafe { &*(self.addr as *const _) }
+        }
+    }
+
+    /// Returns the DIEPTXFx register block of a non-zero IN endpoint, `None` for EP0 and beyond
+    /// EP15
+    #[inline(always)]
+    pub fn instance(base_address: usize, index: u8) -> Option<Instance> {
+        if !(1..=15).contains(&index) {
+            return None;
+        }
+        Some(Instance {
+            addr: base_address + 0x104 + 0x4 * (index as usize - 1),
+            _marker: PhantomData,
+        })
     }
 }
 
+#[cfg(feature = "usb-device")]
 pub mod endpoint_in {
     use stm32ral::RWRegister;
     use core::marker::PhantomData;
 
-    #[cfg(feature = "fs")]
+    #[cfg(not(feature = "hs"))]
     pub use stm32ral::otg_fs_device::{
         DIEPCTL1 as DIEPCTL,
         DIEPINT1 as DIEPINT,
@@ -73,6 +111,7 @@ pub mod endpoint_in {
         DTXFSTS1 as DTXFSTS,
     };
 
+    #[repr(C)]
     pub struct RegisterBlock {
         pub DIEPCTL: RWRegister<u32>,
         _reserved0: u32,
@@ -85,7 +124,7 @@ pub mod endpoint_in {
     }
 
     pub struct Instance {
-        pub(crate) addr: u32,
+        pub(crate) addr: usize,
         pub(crate) _marker: PhantomData<*const RegisterBlock>,
     }
 
@@ -98,24 +137,20 @@ pub mod endpoint_in {
     }
 
     #[inline(always)]
-    pub fn instance(index: u8) -> Instance {
-        #[cfg(feature = "fs")]
-        let base_address = 0x5000_0000;
-        #[cfg(feature = "hs")]
-        let base_address = 0x4004_0000;
-
+    pub fn instance(base_address: usize, index: u8) -> Instance {
         Instance {
-            addr: base_address + 0x900 + 0x20 * (index as u32),
+            addr: base_address + 0x900 + 0x20 * index as usize,
             _marker: PhantomData,
         }
     }
 }
 
+#[cfg(feature = "usb-device")]
 pub mod endpoint0_out {
     use stm32ral::RWRegister;
     use core::marker::PhantomData;
 
-    #[cfg(feature = "fs")]
+    #[cfg(not(feature = "hs"))]
     pub use stm32ral::otg_fs_device::{
         DOEPCTL0,
         DOEPINT0,
@@ -129,17 +164,22 @@ pub mod endpoint0_out {
         DOEPTSIZ0,
     };
 
+    #[repr(C)]
     pub struct RegisterBlock {
         pub DOEPCTL0: RWRegister<u32>,
         _reserved0: u32,
         pub DOEPINT0: RWRegister<u32>,
         _reserved1: u32,
         pub DOEPTSIZ0: RWRegister<u32>,
-        _reserved2: [u32; 3],
+        #[cfg(feature = "hs")]
+        pub DOEPDMA0: RWRegister<u32>,
+        #[cfg(not(feature = "hs"))]
+        _reserved2: u32,
+        _reserved3: [u32; 2],
     }
 
     pub struct Instance {
-        pub(crate) addr: u32,
+        pub(crate) addr: usize,
         pub(crate) _marker: PhantomData<*const RegisterBlock>,
     }
 
@@ -152,12 +192,7 @@ pub mod endpoint0_out {
     }
 
     #[inline(always)]
-    pub fn instance() -> Instance {
-        #[cfg(feature = "fs")]
-        let base_address = 0x5000_0000;
-        #[cfg(feature = "hs")]
-        let base_address = 0x4004_0000;
-
+    pub fn instance(base_address: usize) -> Instance {
         Instance {
             addr: base_address + 0xb00,
             _marker: PhantomData,
@@ -165,35 +200,34 @@ pub mod endpoint0_out {
     }
 }
 
+#[cfg(feature = "usb-device")]
 pub mod endpoint_out {
     use stm32ral::RWRegister;
     use core::marker::PhantomData;
 
-    #[cfg(feature = "fs")]
+    #[cfg(not(feature = "hs"))]
     pub use stm32ral::otg_fs_device::{
         DOEPCTL1 as DOEPCTL,
-        DOEPINT1 as DOEPINT,
         DOEPTSIZ1 as DOEPTSIZ,
     };
 
     #[cfg(feature = "hs")]
     pub use stm32ral::otg_hs_device::{
         DOEPCTL1 as DOEPCTL,
-        DOEPINT1 as DOEPINT,
         DOEPTSIZ1 as DOEPTSIZ,
     };
 
+    #[repr(C)]
     pub struct RegisterBlock {
         pub DOEPCTL: RWRegister<u32>,
-        _reserved0: u32,
-        pub DOEPINT: RWRegister<u32>,
-        _reserved1: u32,
+        _reserved0: [u32; 3],
         pub DOEPTSIZ: RWRegister<u32>,
-        _reserved2: [u32; 3],
+        _reserved2: u32,
+        _reserved3: [u32; 2],
     }
 
     pub struct Instance {
-        pub(crate) addr: u32,
+        pub(crate) addr: usize,
         pub(crate) _marker: PhantomData<*const RegisterBlock>,
     }
 
@@ -206,14 +240,66 @@ pub mod endpoint_out {
     }
 
     #[inline(always)]
-    pub fn instance(index: u8) -> Instance {
-        #[cfg(feature = "fs")]
-        let base_address = 0x5000_0000;
+    pub fn instance(base_address: usize, index: u8) -> Instance {
+        Instance {
+            addr: base_address + 0xb00 + 0x20 * index as usize,
+            _marker: PhantomData,
+        }
+    }
+}
+
+/// Registers of any endpoint, IN or OUT, EP0 included. The control, interrupt and DMA address
+/// registers of both directions sit at the same offsets and the bits used through this block
+/// (EPENA, EPDIS, SNAK, CNAK, STALL, USBAEP, XFRC, EPDISD) have the same positions, so a
+/// single function indexed at runtime serves every endpoint.
+pub mod endpoint {
+    use stm32ral::RWRegister;
+    use core::marker::PhantomData;
+
+    #[cfg(not(feature = "hs"))]
+    pub use stm32ral::otg_fs_device::{
+        DIEPCTL1 as DEPCTL,
+        DIEPINT1 as DEPINT,
+    };
+
+    #[cfg(feature = "hs")]
+    pub use stm32ral::otg_hs_device::{
+        DIEPCTL1 as DEPCTL,
+        DIEPINT1 as DEPINT,
+    };
+
+    #[repr(C)]
+    pub struct RegisterBlock {
+        pub DEPCTL: RWRegister<u32>,
+        _reserved0: u32,
+        pub DEPINT: RWRegister<u32>,
+        _reserved1: u32,
+        _reserved2: u32,
         #[cfg(feature = "hs")]
-        let base_address = 0x4004_0000;
+        pub DEPDMA: RWRegister<u32>,
+        #[cfg(not(feature = "hs"))]
+        _reserved3: u32,
+        _reserved4: [u32; 2],
+    }
+
+    pub struct Instance {
+        pub(crate) addr: usize,
+        pub(crate) _marker: PhantomData<*const RegisterBlock>,
+    }
+
+    impl ::core::ops::Deref for Instance {
+        type Target = RegisterBlock;
+        #[inline(always)]
+        fn deref(&self) -> &RegisterBlock {
+            unsafe { &*(self.addr as *const _) }
+        }
+    }
 
+    /// The OUT endpoint registers follow the IN ones 0x200 bytes later.
+    #[inline(always)]
+    pub fn instance(base_address: usize, out: bool, index: u8) -> Instance {
         Instance {
-            addr: base_address + 0xb00 + 0x20 * (index as u32),
+            addr: base_address + 0x900 + ((out as usize) << 9) + 0x20 * (index as usize & 0xf),
             _marker: PhantomData,
         }
     }
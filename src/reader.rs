@@ -0,0 +1,246 @@
+use usb_device::bus::UsbBus;
+use usb_device::endpoint::EndpointOut;
+use usb_device::{Result, UsbError};
+
+/// Receives a whole transfer from an OUT endpoint into a buffer without blocking.
+///
+/// [`start`](Self::start) hands over the buffer, [`poll`](Self::poll) moves everything the
+/// endpoint has received into it and returns the received data once the transfer has ended, that
+/// is when the buffer is full or the host has sent a short packet. Call `poll` whenever
+/// `UsbDevice::poll` reports the endpoint, e.g. from `UsbClass::endpoint_out`.
+///
+/// Together with [`EndpointWriter`](crate::EndpointWriter) this lets a class handle every phase
+/// of a bulk-only transport, e.g. a 31-byte command block followed by up to 64 KiB of data, with
+/// one call each. A receive buffer of several packets (see
+/// [`Config::rx_buffer_size`](crate::Config::rx_buffer_size)) lets a single `poll` collect
+/// back-to-back packets.
+pub struct EndpointReader<'d> {
+    buf: &'d mut [u8],
+    received: usize,
+}
+
+impl<'d> EndpointReader<'d> {
+    /// Creates an idle reader.
+    pub fn new() -> Self {
+        Self {
+            buf: &mut [],
+            received: 0,
+        }
+    }
+
+    /// Returns true if the reader has no transfer in progress.
+    pub fn is_idle(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Returns the number of bytes received so far.
+    pub fn received(&self) -> usize {
+        self.received
+    }
+
+    /// Starts a transfer of up to `buf.len()` bytes.
+    ///
+    /// Fails with `UsbError::WouldBlock` while the previous transfer is in progress, and with
+    /// `UsbError::InvalidState` for an empty buffer.
+    pub fn start(&mut self, buf: &'d mut [u8]) -> Result<()> {
+        if !self.is_idle() {
+            return Err(UsbError::WouldBlock);
+        }
+        if buf.is_empty() {
+            return Err(UsbError::InvalidState);
+        }
+
+        self.buf = buf;
+        self.received = 0;
+        Ok(())
+    }
+
+    /// Reads the data the endpoint has received so far.
+    ///
+    /// Returns the received part of the buffer once the transfer has ended and
+    /// `UsbError::WouldBlock` before that. Other errors leave the transfer in progress, so `poll`
+    /// can be retried or the transfer dropped with [`cancel`](Self::cancel).
+    pub fn poll<B: UsbBus>(&mut self, ep: &EndpointOut<B>) -> Result<&'d mut [u8]> {
+        if self.is_idle() {
+            return Err(UsbError::InvalidState);
+        }
+
+        let packet_size = ep.max_packet_size() as usize;
+        loop {
+            let size = ep.read(&mut self.buf[self.received..])?;
+            self.received += size;
+
+            // A short packet ends the transfer before the buffer is full
+            if self.received == self.buf.len() || size % packet_size != 0 || size == 0 {
+                let buf = core::mem::take(&mut self.buf);
+                return Ok(&mut buf[..self.received]);
+            }
+        }
+    }
+
+    /// Drops the transfer in progress and returns the number of bytes received.
+    pub fn cancel(&mut self) -> usize {
+        self.buf = &mut [];
+        self.received
+    }
+}
+
+impl Default for EndpointReader<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+    use std::vec::Vec;
+    use usb_device::bus::{PollResult, UsbBusAllocator};
+    use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
+    use usb_device::endpoint::{EndpointAddress, EndpointType, Out};
+    use usb_device::UsbDirection;
+
+    /// Returns the queued data one `read()` at a time.
+    struct Endpoint {
+        received: Mutex<VecDeque<Vec<u8>>>,
+    }
+
+    impl Endpoint {
+        fn new() -> Self {
+            Self {
+                received: Mutex::new(VecDeque::new()),
+            }
+        }
+
+        fn receive(&self, size: usize) {
+            let data = (0..size).map(|i| i as u8).collect();
+            self.received.lock().unwrap().push_back(data);
+        }
+    }
+
+    struct Bus<'a>(&'a Endpoint);
+
+    impl UsbBus for Bus<'_> {
+        fn alloc_ep(
+            &mut self,
+            ep_dir: UsbDirection,
+            _ep_addr: Option<EndpointAddress>,
+            _ep_type: EndpointType,
+            _max_packet_size: u16,
+            _interval: u8,
+        ) -> Result<EndpointAddress> {
+            Ok(EndpointAddress::from_parts(1, ep_dir))
+        }
+
+        fn enable(&mut self) {}
+
+        fn reset(&self) {}
+
+        fn set_device_address(&self, _addr: u8) {}
+
+        fn write(&self, _ep_addr: EndpointAddress, _buf: &[u8]) -> Result<usize> {
+            Err(UsbError::WouldBlock)
+        }
+
+        fn read(&self, _ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<usize> {
+            let mut received = self.0.received.lock().unwrap();
+            let data = received.front().ok_or(UsbError::WouldBlock)?;
+            if data.len() > buf.len() {
+                return Err(UsbError::BufferOverflow);
+            }
+            buf[..data.len()].copy_from_slice(data);
+            Ok(received.pop_front().unwrap().len())
+        }
+
+        fn set_stalled(&self, _ep_addr: EndpointAddress, _stalled: bool) {}
+
+        fn is_stalled(&self, _ep_addr: EndpointAddress) -> bool {
+            false
+        }
+
+        fn suspend(&self) {}
+
+        fn resume(&self) {}
+
+        fn poll(&self) -> PollResult {
+            PollResult::None
+        }
+    }
+
+    /// Finishes the bus allocation, endpoints can't be used before that.
+    fn freeze(alloc: &UsbBusAllocator<Bus>) {
+        let _ = UsbDeviceBuilder::new(alloc, UsbVidPid(0, 0)).build();
+    }
+
+    #[test]
+    fn full_buffer_ends_the_transfer() {
+        let endpoint = Endpoint::new();
+        let alloc = UsbBusAllocator::new(Bus(&endpoint));
+        let ep = alloc.bulk::<Out>(64);
+        freeze(&alloc);
+
+        let mut buf = [0; 192];
+        let mut reader = EndpointReader::new();
+        reader.start(&mut buf).unwrap();
+        assert!(matches!(reader.poll(&ep), Err(UsbError::WouldBlock)));
+
+        endpoint.receive(64);
+        assert!(matches!(reader.poll(&ep), Err(UsbError::WouldBlock)));
+        assert_eq!(reader.received(), 64);
+
+        // Buffered packets are collected by a single poll
+        endpoint.receive(64);
+        endpoint.receive(64);
+        assert_eq!(reader.poll(&ep).unwrap().len(), 192);
+        assert!(reader.is_idle());
+    }
+
+    #[test]
+    fn short_packet_ends_the_transfer() {
+        let endpoint = Endpoint::new();
+        let alloc = UsbBusAllocator::new(Bus(&endpoint));
+        let ep = alloc.bulk::<Out>(64);
+        freeze(&alloc);
+
+        let mut buf = [0; 512];
+        let mut reader = EndpointReader::new();
+        reader.start(&mut buf).unwrap();
+        endpoint.receive(128);
+        endpoint.receive(31);
+        let data = reader.poll(&ep).unwrap();
+        assert_eq!(data.len(), 159);
+        assert_eq!(data[128..], (0..31).collect::<Vec<u8>>()[..]);
+
+        let mut buf = [0; 512];
+        reader.start(&mut buf).unwrap();
+        endpoint.receive(64);
+        endpoint.receive(0);
+        assert_eq!(reader.poll(&ep).unwrap().len(), 64);
+    }
+
+    #[test]
+    fn busy_reader_refuses_new_buffers() {
+        let endpoint = Endpoint::new();
+        let alloc = UsbBusAllocator::new(Bus(&endpoint));
+        let ep = alloc.bulk::<Out>(64);
+        freeze(&alloc);
+
+        let mut first = [0; 128];
+        let mut second = [0; 128];
+        let mut third = [0; 128];
+        let mut reader = EndpointReader::new();
+        assert!(matches!(reader.start(&mut []), Err(UsbError::InvalidState)));
+        reader.start(&mut first).unwrap();
+        assert!(matches!(reader.start(&mut second), Err(UsbError::WouldBlock)));
+
+        endpoint.receive(64);
+        assert!(matches!(reader.poll(&ep), Err(UsbError::WouldBlock)));
+        assert_eq!(reader.cancel(), 64);
+        assert!(matches!(reader.poll(&ep), Err(UsbError::InvalidState)));
+        reader.start(&mut third).unwrap();
+    }
+}
@@ -5,16 +5,20 @@
 use vcell::VolatileCell;
 use core::marker::PhantomData;
 
-#[cfg(feature = "cortex-m")]
+#[cfg(all(feature = "cortex-m", not(test)))]
 pub use cortex_m::interrupt;
-#[cfg(feature = "riscv")]
+#[cfg(all(feature = "riscv", not(test)))]
 pub use riscv::interrupt;
 
-use crate::ral::{otg_global, otg_device, otg_pwrclk, otg_fifo};
+use crate::ral::{read_reg, otg_global, otg_device, otg_pwrclk, otg_fifo};
 use crate::UsbPeripheral;
 
-pub fn fifo_write(channel: impl Into<usize>, mut buf: &[u8]) {
-    let fifo = otg_fifo::instance(channel.into());
+/// Pushes `buf` into the TX FIFO `channel`, returns false without writing if there's no such FIFO.
+pub fn fifo_write(base_address: usize, channel: usize, mut buf: &[u8]) -> bool {
+    let fifo = match otg_fifo::instance(base_address, channel) {
+        Some(fifo) => fifo,
+        None => return false,
+    };
 
     while buf.len() >= 4 {
         let mut u32_bytes = [0u8; 4];
@@ -27,10 +31,11 @@ pub fn fifo_write(channel: impl Into<usize>, mut buf: &[u8]) {
         u32_bytes[..buf.len()].copy_from_slice(buf);
         fifo.write(u32::from_ne_bytes(u32_bytes));
     }
+    true
 }
 
-pub fn fifo_read(mut buf: &mut [u8]) {
-    let fifo = otg_fifo::instance(0);
+pub fn fifo_read(base_address: usize, mut buf: &mut [u8]) {
+    let fifo = otg_fifo::rx(base_address);
 
     while buf.len() >= 4 {
         let word = fifo.read();
@@ -45,8 +50,16 @@ pub fn fifo_read(mut buf: &mut [u8]) {
     }
 }
 
-pub fn fifo_read_into(buf: &[VolatileCell<u32>]) {
-    let fifo = otg_fifo::instance(0);
+pub fn fifo_discard(base_address: usize, size: usize) {
+    let fifo = otg_fifo::rx(base_address);
+
+    for _ in 0..size.div_ceil(4) {
+        fifo.read();
+    }
+}
+
+pub fn fifo_read_into(base_address: usize, buf: &[VolatileCell<u32>]) {
+    let fifo = otg_fifo::rx(base_address);
 
     for p in buf {
         let word = fifo.read();
@@ -54,11 +67,20 @@ pub fn fifo_read_into(buf: &[VolatileCell<u32>]) {
     }
 }
 
+/// Busy-waits for roughly `us` microseconds, reading a peripheral register takes several AHB
+/// cycles.
+pub fn spin_delay<USB: UsbPeripheral>(us: u32) {
+    let regs = UsbRegisters::<USB>::new();
+    for _ in 0..us.saturating_mul(25) {
+        read_reg!(otg_device, regs.device, DCTL);
+    }
+}
+
 /// Wrapper around device-specific peripheral that provides unified register interface
 pub struct UsbRegisters<USB> {
-    pub global: otg_global::Instance,
-    pub device: otg_device::Instance,
-    pub pwrclk: otg_pwrclk::Instance,
+    pub global: &'static otg_global::RegisterBlock,
+    pub device: &'static otg_device::RegisterBlock,
+    pub pwrclk: &'static otg_pwrclk::RegisterBlock,
     _marker: PhantomData<USB>,
 }
 
@@ -66,11 +88,68 @@ unsafe impl<USB> Send for UsbRegisters<USB> {}
 
 impl<USB: UsbPeripheral> UsbRegisters<USB> {
     pub fn new() -> Self {
-        Self {
-            global: unsafe { otg_global::OTG_GLOBAL::steal() },
-            device: unsafe { otg_device::OTG_DEVICE::steal() },
-            pwrclk: unsafe { otg_pwrclk::OTG_PWRCLK::steal() },
-            _marker: PhantomData,
+        let base_address = Self::base_address();
+        unsafe {
+            Self {
+                global: &*(base_address as *const otg_global::RegisterBlock),
+                device: &*((base_address + 0x800) as *const otg_device::RegisterBlock),
+                pwrclk: &*((base_address + 0xe00) as *const otg_pwrclk::RegisterBlock),
+                _marker: PhantomData,
+            }
         }
     }
+
+    /// Returns the address of the peripheral's register block, which the endpoint and FIFO
+    /// registers are located relative to.
+    pub fn base_address() -> usize {
+        USB::REGISTERS as usize
+    }
+}
+
+/// Critical sections for the tests. The loom models run the interrupt handler in a thread of its
+/// own, so a critical section is a lock shared by all the threads. It can be nested like the real
+/// one.
+#[cfg(test)]
+pub mod interrupt {
+    use core::cell::Cell;
+    #[cfg(loom)]
+    use loom::sync::Mutex as Lock;
+    #[cfg(not(loom))]
+    use std::sync::Mutex as Lock;
+
+    pub use cortex_m::interrupt::{CriticalSection, Mutex};
+
+    #[cfg(loom)]
+    loom::lazy_static! {
+        static ref LOCK: Lock<()> = Lock::new(());
+    }
+    #[cfg(not(loom))]
+    static LOCK: Lock<()> = Lock::new(());
+
+    #[cfg(loom)]
+    loom::thread_local! {
+        static DEPTH: Cell<usize> = Cell::new(0);
+    }
+    #[cfg(not(loom))]
+    std::thread_local! {
+        static DEPTH: Cell<usize> = const { Cell::new(0) };
+    }
+
+    pub fn free<F, R>(f: F) -> R
+    where
+        F: FnOnce(&CriticalSection) -> R,
+    {
+        let outermost = DEPTH.with(|depth| {
+            depth.set(depth.get() + 1);
+            depth.get() == 1
+        });
+        // A failed test poisons the lock, the others go on
+        let guard = if outermost { Some(LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner())) } else { None };
+
+        let result = f(unsafe { &CriticalSection::new() });
+
+        drop(guard);
+        DEPTH.with(|depth| depth.set(depth.get() - 1));
+        result
+    }
 }
@@ -0,0 +1,149 @@
+/// Number of records the trace log keeps, older records are overwritten.
+pub const TRACE_LOG_LEN: usize = 64;
+
+/// Driver event recorded in the trace log.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TraceEvent {
+    /// The bus reset is over and the speed has been enumerated (ENUMDNE).
+    Reset,
+    /// The device is configured for high speed, but the bus reset has enumerated full speed
+    /// because the chirp went unanswered (DSTS.ENUMSPD).
+    FullSpeedFallback,
+    /// The bus has been suspended (USBSUSP).
+    Suspend,
+    /// The host has resumed the bus (WKUPINT).
+    Resume,
+    /// VBUS has appeared.
+    SessionStart,
+    /// VBUS has gone away.
+    SessionEnd,
+    /// A SETUP packet has been received on EP0.
+    Setup,
+    /// A data packet of `size` bytes has been received on the OUT endpoint `ep_number`.
+    OutPacket {
+        /// Endpoint number
+        ep_number: u8,
+        /// Packet size in bytes
+        size: u16,
+    },
+    /// The IN endpoint `ep_number` has completed a write.
+    InComplete {
+        /// Endpoint number
+        ep_number: u8,
+    },
+    /// The isochronous IN endpoints in `endpoints`, one bit per endpoint number, missed their
+    /// (micro)frame and their packets have been dropped.
+    IsoInMissed {
+        /// Endpoint bitmap
+        endpoints: u16,
+    },
+    /// A received packet has been dropped, on the OUT endpoint `ep_number` if it's known.
+    RxOverflow {
+        /// Endpoint number
+        ep_number: Option<u8>,
+    },
+    /// The device address has been programmed (DCFG.DAD).
+    AddressSet {
+        /// Device address
+        address: u8,
+    },
+    /// The PHY reported an erratic error, the device reconnects (DSTS.EERR).
+    ErraticError,
+    /// The host hasn't configured the device within the enumeration timeout after the bus reset.
+    EnumerationTimeout,
+}
+
+/// Trace log entry: an event and the (micro)frame it happened in.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TraceRecord {
+    /// Number of the last SOF received when the event was recorded (DSTS.FNSOF), to align the
+    /// log with the SOF packets of a USB analyzer capture. On a high-speed bus it counts
+    /// microframes, [`Frame`](crate::Frame) decodes it.
+    pub frame_number: u16,
+    /// What happened
+    pub event: TraceEvent,
+}
+
+/// Trace records in the order they were recorded. When the log is full, a new record replaces
+/// the oldest one.
+pub(crate) struct TraceLog {
+    records: [Option<TraceRecord>; TRACE_LOG_LEN],
+    /// Index of the oldest record
+    head: usize,
+    len: usize,
+    lost: u32,
+}
+
+impl TraceLog {
+    pub const fn new() -> Self {
+        Self {
+            records: [None; TRACE_LOG_LEN],
+            head: 0,
+            len: 0,
+            lost: 0,
+        }
+    }
+
+    pub fn push(&mut self, record: TraceRecord) {
+        if self.len == TRACE_LOG_LEN {
+            self.pop();
+            self.lost = self.lost.wrapping_add(1);
+        }
+        self.records[(self.head + self.len) % TRACE_LOG_LEN] = Some(record);
+        self.len += 1;
+    }
+
+    pub fn pop(&mut self) -> Option<TraceRecord> {
+        let record = self.records[self.head].take()?;
+        self.head = (self.head + 1) % TRACE_LOG_LEN;
+        self.len -= 1;
+        Some(record)
+    }
+
+    /// Returns the number of records overwritten before they were read.
+    pub fn lost(&self) -> u32 {
+        self.lost
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(frame_number: u16) -> TraceRecord {
+        TraceRecord {
+            frame_number,
+            event: TraceEvent::InComplete { ep_number: 1 },
+        }
+    }
+
+    #[test]
+    fn records_are_read_in_order() {
+        let mut log = TraceLog::new();
+        assert_eq!(log.pop(), None);
+
+        log.push(record(1));
+        log.push(record(2));
+        assert_eq!(log.pop(), Some(record(1)));
+        log.push(record(3));
+        assert_eq!(log.pop(), Some(record(2)));
+        assert_eq!(log.pop(), Some(record(3)));
+        assert_eq!(log.pop(), None);
+        assert_eq!(log.lost(), 0);
+    }
+
+    #[test]
+    fn full_log_overwrites_the_oldest_records() {
+        let mut log = TraceLog::new();
+        for frame_number in 0..TRACE_LOG_LEN as u16 + 2 {
+            log.push(record(frame_number));
+        }
+
+        assert_eq!(log.lost(), 2);
+        assert_eq!(log.pop(), Some(record(2)));
+        let remaining = core::iter::from_fn(|| log.pop()).count();
+        assert_eq!(remaining, TRACE_LOG_LEN - 1);
+    }
+}
@@ -0,0 +1,317 @@
+use usb_device::bus::UsbBus;
+use usb_device::endpoint::EndpointIn;
+use usb_device::{Result, UsbError};
+
+/// Sends a slice of any length through an IN endpoint without blocking.
+///
+/// [`start`](Self::start) writes the first chunk of the data, [`poll`](Self::poll) continues
+/// with the next one once the endpoint has sent the previous one. Call `poll` whenever
+/// `UsbDevice::poll` reports activity, the IN completion of the endpoint is what it waits for.
+/// The data is copied into the TX FIFO by every write, so the slice is no longer needed once
+/// `poll` returns `Ok(())`.
+///
+/// A chunk is one packet by default. With a TX FIFO of several packets (see
+/// [`Config::tx_fifo_size`](crate::Config::tx_fifo_size)), [`chunk_size`](Self::chunk_size) lets
+/// a single write queue back-to-back packets, which keeps bulk transfers going between two polls.
+///
+/// Data ending with a full packet is followed by a zero-length packet, so that the host sees
+/// the end of the transfer. Disable this with [`zlp`](Self::zlp) for fixed-length transfers.
+pub struct EndpointWriter<'d> {
+    data: &'d [u8],
+    chunk_size: usize,
+    zlp: bool,
+    zlp_pending: bool,
+}
+
+impl<'d> EndpointWriter<'d> {
+    /// Creates an idle writer that sends one packet at a time.
+    pub const fn new() -> Self {
+        Self {
+            data: &[],
+            chunk_size: 0,
+            zlp: true,
+            zlp_pending: false,
+        }
+    }
+
+    /// Sets the number of bytes passed to a single endpoint write. It is rounded down to a
+    /// multiple of the max packet size.
+    ///
+    /// If the driver refuses a chunk this large, the writer falls back to single packets.
+    pub fn chunk_size(mut self, bytes: usize) -> Self {
+        self.chunk_size = bytes;
+        self
+    }
+
+    /// Enables or disables the zero-length packet after data ending with a full packet.
+    ///
+    /// Enabled by default.
+    pub fn zlp(mut self, enabled: bool) -> Self {
+        self.zlp = enabled;
+        self
+    }
+
+    /// Returns true if the writer has nothing left to send.
+    pub fn is_idle(&self) -> bool {
+        self.data.is_empty() && !self.zlp_pending
+    }
+
+    /// Returns the number of bytes not written to the endpoint yet.
+    pub fn remaining(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Starts sending `data` and writes its first chunk if the endpoint is ready.
+    ///
+    /// Fails with `UsbError::WouldBlock` while the previous data is still being sent.
+    pub fn start<B: UsbBus>(&mut self, ep: &EndpointIn<B>, data: &'d [u8]) -> Result<()> {
+        if !self.is_idle() {
+            return Err(UsbError::WouldBlock);
+        }
+
+        self.data = data;
+        // A short last packet ends the transfer by itself
+        let packet_size = ep.max_packet_size() as usize;
+        self.zlp_pending = self.zlp && !data.is_empty() && (data.len() - 1) % packet_size == packet_size - 1;
+        match self.poll(ep) {
+            Ok(()) | Err(UsbError::WouldBlock) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Writes the next chunk if the endpoint is ready for it.
+    ///
+    /// Returns `Ok(())` once all the data has been written, `UsbError::WouldBlock` while there is
+    /// more to send. Other errors leave the remaining data in place, so `poll` can be retried or
+    /// the transfer dropped with [`cancel`](Self::cancel).
+    pub fn poll<B: UsbBus>(&mut self, ep: &EndpointIn<B>) -> Result<()> {
+        let packet_size = ep.max_packet_size() as usize;
+        loop {
+            if self.data.is_empty() {
+                if !self.zlp_pending {
+                    return Ok(());
+                }
+                ep.write(&[])?;
+                self.zlp_pending = false;
+                return Ok(());
+            }
+
+            let chunk_size = core::cmp::max(self.chunk_size / packet_size, 1) * packet_size;
+            let size = core::cmp::min(self.data.len(), chunk_size);
+            match ep.write(&self.data[..size]) {
+                Ok(written) => self.data = &self.data[written..],
+                Err(UsbError::BufferOverflow) if size > packet_size => {
+                    // The TX FIFO is smaller than the chunk
+                    self.chunk_size = 0;
+                    continue;
+                }
+                Err(err) => return Err(err),
+            }
+
+            // The endpoint takes the next chunk once this one is sent
+            if !self.is_idle() {
+                return Err(UsbError::WouldBlock);
+            }
+        }
+    }
+
+    /// Drops the data not written yet and returns its length.
+    pub fn cancel(&mut self) -> usize {
+        let remaining = self.data.len();
+        self.data = &[];
+        self.zlp_pending = false;
+        remaining
+    }
+}
+
+impl Default for EndpointWriter<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::sync::Mutex;
+    use std::vec::Vec;
+    use usb_device::bus::{PollResult, UsbBusAllocator};
+    use usb_device::endpoint::{EndpointAddress, EndpointType};
+    use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
+    use usb_device::UsbDirection;
+
+    /// Accepts one write at a time of up to `fifo_size` bytes, until `complete` is called.
+    struct Endpoint {
+        fifo_size: usize,
+        busy: Mutex<bool>,
+        writes: Mutex<Vec<usize>>,
+    }
+
+    impl Endpoint {
+        fn new(fifo_size: usize) -> Self {
+            Self {
+                fifo_size,
+                busy: Mutex::new(false),
+                writes: Mutex::new(Vec::new()),
+            }
+        }
+
+        fn complete(&self) {
+            *self.busy.lock().unwrap() = false;
+        }
+
+        fn writes(&self) -> Vec<usize> {
+            self.writes.lock().unwrap().clone()
+        }
+    }
+
+    struct Bus<'a>(&'a Endpoint);
+
+    impl UsbBus for Bus<'_> {
+        fn alloc_ep(
+            &mut self,
+            ep_dir: UsbDirection,
+            _ep_addr: Option<EndpointAddress>,
+            _ep_type: EndpointType,
+            _max_packet_size: u16,
+            _interval: u8,
+        ) -> Result<EndpointAddress> {
+            Ok(EndpointAddress::from_parts(1, ep_dir))
+        }
+
+        fn enable(&mut self) {}
+
+        fn reset(&self) {}
+
+        fn set_device_address(&self, _addr: u8) {}
+
+        fn write(&self, _ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
+            let mut busy = self.0.busy.lock().unwrap();
+            if *busy {
+                return Err(UsbError::WouldBlock);
+            }
+            if buf.len() > self.0.fifo_size {
+                return Err(UsbError::BufferOverflow);
+            }
+            *busy = true;
+            self.0.writes.lock().unwrap().push(buf.len());
+            Ok(buf.len())
+        }
+
+        fn read(&self, _ep_addr: EndpointAddress, _buf: &mut [u8]) -> Result<usize> {
+            Err(UsbError::WouldBlock)
+        }
+
+        fn set_stalled(&self, _ep_addr: EndpointAddress, _stalled: bool) {}
+
+        fn is_stalled(&self, _ep_addr: EndpointAddress) -> bool {
+            false
+        }
+
+        fn suspend(&self) {}
+
+        fn resume(&self) {}
+
+        fn poll(&self) -> PollResult {
+            PollResult::None
+        }
+    }
+
+    /// Finishes the bus allocation, endpoints can't be used before that.
+    fn freeze(alloc: &UsbBusAllocator<Bus>) {
+        let _ = UsbDeviceBuilder::new(alloc, UsbVidPid(0, 0)).build();
+    }
+
+    const DATA: [u8; 200] = [0x55; 200];
+
+    /// Polls the writer after every IN completion until it's done.
+    fn send(endpoint: &Endpoint, writer: &mut EndpointWriter, ep: &EndpointIn<Bus>, data: &'static [u8]) {
+        writer.start(ep, data).unwrap();
+        for _ in 0..data.len() + 1 {
+            // Nothing is written until the endpoint is done with the previous chunk
+            assert!(matches!(writer.poll(ep), Err(UsbError::WouldBlock)) || writer.is_idle());
+            endpoint.complete();
+            if writer.poll(ep).is_ok() {
+                return;
+            }
+        }
+        panic!("the writer didn't finish");
+    }
+
+    #[test]
+    fn data_is_split_into_packets() {
+        let endpoint = Endpoint::new(64);
+        let alloc = UsbBusAllocator::new(Bus(&endpoint));
+        let ep = alloc.bulk::<usb_device::endpoint::In>(64);
+        freeze(&alloc);
+
+        let mut writer = EndpointWriter::new();
+        send(&endpoint, &mut writer, &ep, &DATA);
+        assert_eq!(endpoint.writes(), [64, 64, 64, 8]);
+        assert!(writer.is_idle());
+        assert_eq!(writer.remaining(), 0);
+    }
+
+    #[test]
+    fn full_packet_is_followed_by_zlp() {
+        let endpoint = Endpoint::new(64);
+        let alloc = UsbBusAllocator::new(Bus(&endpoint));
+        let ep = alloc.bulk::<usb_device::endpoint::In>(64);
+        freeze(&alloc);
+
+        let mut writer = EndpointWriter::new();
+        send(&endpoint, &mut writer, &ep, &DATA[..128]);
+        assert_eq!(endpoint.writes(), [64, 64, 0]);
+
+        let mut writer = EndpointWriter::new().zlp(false);
+        endpoint.complete();
+        send(&endpoint, &mut writer, &ep, &DATA[..128]);
+        assert_eq!(endpoint.writes(), [64, 64, 0, 64, 64]);
+    }
+
+    #[test]
+    fn chunks_are_whole_packets() {
+        let endpoint = Endpoint::new(256);
+        let alloc = UsbBusAllocator::new(Bus(&endpoint));
+        let ep = alloc.bulk::<usb_device::endpoint::In>(64);
+        freeze(&alloc);
+
+        let mut writer = EndpointWriter::new().chunk_size(150);
+        send(&endpoint, &mut writer, &ep, &DATA);
+        assert_eq!(endpoint.writes(), [128, 72]);
+    }
+
+    #[test]
+    fn oversized_chunks_fall_back_to_packets() {
+        let endpoint = Endpoint::new(64);
+        let alloc = UsbBusAllocator::new(Bus(&endpoint));
+        let ep = alloc.bulk::<usb_device::endpoint::In>(64);
+        freeze(&alloc);
+
+        let mut writer = EndpointWriter::new().chunk_size(512);
+        send(&endpoint, &mut writer, &ep, &DATA);
+        assert_eq!(endpoint.writes(), [64, 64, 64, 8]);
+    }
+
+    #[test]
+    fn busy_writer_refuses_new_data() {
+        let endpoint = Endpoint::new(64);
+        let alloc = UsbBusAllocator::new(Bus(&endpoint));
+        let ep = alloc.bulk::<usb_device::endpoint::In>(64);
+        freeze(&alloc);
+
+        let mut writer = EndpointWriter::new();
+        writer.start(&ep, &DATA).unwrap();
+        assert!(matches!(writer.start(&ep, &DATA), Err(UsbError::WouldBlock)));
+        assert_eq!(writer.remaining(), 136);
+
+        assert_eq!(writer.cancel(), 136);
+        assert!(writer.is_idle());
+        writer.start(&ep, &DATA[..10]).unwrap();
+        endpoint.complete();
+        writer.poll(&ep).unwrap();
+        assert_eq!(endpoint.writes(), [64, 10]);
+    }
+}